@@ -0,0 +1,57 @@
+use crate::MockDiscord;
+use json::{object, JsonValue};
+use tungstenite::{client::AutoStream, Message, WebSocket};
+
+/// A minimal gateway client, enough to receive the payloads the mock sends so tests can feed them
+/// to the code under test
+pub struct GatewayClient {
+    ws: WebSocket<AutoStream>,
+}
+
+impl GatewayClient {
+    pub fn connect(server: &MockDiscord) -> GatewayClient {
+        let (ws, _) = tungstenite::connect(server.gateway_url()).expect("Unable to connect");
+        GatewayClient { ws }
+    }
+
+    /// The next payload of any op
+    pub fn recv(&mut self) -> JsonValue {
+        loop {
+            if let Message::Text(text) = self.ws.read_message().expect("Gateway closed") {
+                return json::parse(&text).expect("Invalid json");
+            }
+        }
+    }
+
+    /// The data of the next dispatch of `event`, skipping everything before it
+    pub fn recv_dispatch(&mut self, event: &str) -> JsonValue {
+        loop {
+            let payload = self.recv();
+            if payload["op"] == 0 && payload["t"] == event {
+                return payload["d"].clone();
+            }
+        }
+    }
+
+    pub fn send(&mut self, payload: JsonValue) {
+        self.ws
+            .write_message(Message::Text(payload.dump()))
+            .expect("Unable to send");
+    }
+
+    /// Wait for `HELLO`, identify and return the `READY` data
+    pub fn identify(&mut self) -> JsonValue {
+        let hello = self.recv();
+        assert_eq!(hello["op"], 10);
+        assert!(hello["d"]["heartbeat_interval"].as_u64().is_some());
+
+        self.send(object! {
+            "op" => 2,
+            "d" => object! {
+                "token" => "mock-token",
+                "properties" => object! { "$os" => "linux" },
+            },
+        });
+        self.recv_dispatch("READY")
+    }
+}
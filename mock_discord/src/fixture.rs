@@ -0,0 +1,281 @@
+use json::{object, JsonValue};
+
+/// The world the mock server presents to a client
+///
+/// Every object is stored as the raw json Discord would send, so tests can add any field the
+/// plugin happens to read
+#[derive(Clone, Debug)]
+pub struct Fixture {
+    pub user: JsonValue,
+    pub guilds: Vec<JsonValue>,
+    pub private_channels: Vec<JsonValue>,
+    /// Thread channels, which are not part of their guild's channel list
+    pub threads: Vec<JsonValue>,
+    /// Messages per channel, oldest first
+    pub messages: Vec<(u64, Vec<JsonValue>)>,
+}
+
+pub const USER_ID: u64 = 100;
+pub const OTHER_USER_ID: u64 = 101;
+pub const GUILD_ID: u64 = 200;
+pub const GENERAL_CHANNEL_ID: u64 = 300;
+pub const VOICE_CHANNEL_ID: u64 = 301;
+pub const DM_CHANNEL_ID: u64 = 400;
+pub const THREAD_ID: u64 = 310;
+pub const ARCHIVED_THREAD_ID: u64 = 311;
+
+impl Default for Fixture {
+    /// A single guild with one text and one voice channel, an active and an archived thread in the
+    /// text channel, and one DM
+    fn default() -> Fixture {
+        let user = user(USER_ID, "weecord");
+        let other = user_json(OTHER_USER_ID, "alice");
+
+        let guild = object! {
+            "id" => GUILD_ID.to_string(),
+            "name" => "Test Guild",
+            "icon" => JsonValue::Null,
+            "owner_id" => OTHER_USER_ID.to_string(),
+            "region" => "us-east",
+            "afk_timeout" => 300,
+            "verification_level" => 0,
+            "default_message_notifications" => 0,
+            "explicit_content_filter" => 0,
+            "mfa_level" => 0,
+            "features" => JsonValue::new_array(),
+            "emojis" => JsonValue::new_array(),
+            "roles" => vec![role(GUILD_ID, "@everyone")],
+            "member_count" => 2,
+            "large" => false,
+            "joined_at" => "2020-01-01T00:00:00.000000+00:00",
+            "members" => vec![member(&user), member(&other)],
+            "presences" => JsonValue::new_array(),
+            "voice_states" => JsonValue::new_array(),
+            "channels" => vec![
+                guild_channel(GENERAL_CHANNEL_ID, "general", 0, 0),
+                guild_channel(VOICE_CHANNEL_ID, "voice", 2, 1),
+            ],
+        };
+
+        let dm = object! {
+            "id" => DM_CHANNEL_ID.to_string(),
+            "type" => 1,
+            "last_message_id" => JsonValue::Null,
+            "recipients" => vec![other.clone()],
+        };
+
+        let messages = vec![(
+            GENERAL_CHANNEL_ID,
+            vec![
+                message(1000, GENERAL_CHANNEL_ID, Some(GUILD_ID), &other, "hello"),
+                message(
+                    1001,
+                    GENERAL_CHANNEL_ID,
+                    Some(GUILD_ID),
+                    &other,
+                    "**world**",
+                ),
+            ],
+        )];
+
+        let threads = vec![
+            thread(THREAD_ID, GUILD_ID, GENERAL_CHANNEL_ID, "ideas", false),
+            thread(
+                ARCHIVED_THREAD_ID,
+                GUILD_ID,
+                GENERAL_CHANNEL_ID,
+                "old plans",
+                true,
+            ),
+        ];
+
+        Fixture {
+            user,
+            guilds: vec![guild],
+            private_channels: vec![dm],
+            threads,
+            messages,
+        }
+    }
+}
+
+impl Fixture {
+    /// The `READY` dispatch sent after a successful identify
+    pub fn ready(&self, session_id: &str) -> JsonValue {
+        object! {
+            "v" => 6,
+            "user" => self.user.clone(),
+            "session_id" => session_id,
+            "guilds" => self.guilds.clone(),
+            "private_channels" => self.private_channels.clone(),
+            "presences" => JsonValue::new_array(),
+            "relationships" => JsonValue::new_array(),
+            "read_state" => JsonValue::new_array(),
+            "user_guild_settings" => JsonValue::new_array(),
+            "user_settings" => object! {
+                "guild_positions" => self.guilds.iter().map(|g| g["id"].clone()).collect::<Vec<_>>(),
+            },
+            "_trace" => vec!["mock-discord"],
+        }
+    }
+
+    pub fn guild(&self, id: u64) -> Option<&JsonValue> {
+        self.guilds
+            .iter()
+            .find(|g| g["id"] == id.to_string().as_str())
+    }
+
+    pub fn channel(&self, id: u64) -> Option<JsonValue> {
+        let id_str = id.to_string();
+        for guild in &self.guilds {
+            for channel in guild["channels"].members() {
+                if channel["id"] == id_str.as_str() {
+                    let mut channel = channel.clone();
+                    channel["guild_id"] = guild["id"].clone();
+                    return Some(channel);
+                }
+            }
+        }
+        self.private_channels
+            .iter()
+            .chain(&self.threads)
+            .find(|c| c["id"] == id_str.as_str())
+            .cloned()
+    }
+
+    /// The items of a guild's member list, without presences everyone is offline
+    pub fn member_list(&self, guild: u64) -> Option<Vec<JsonValue>> {
+        let members = &self.guild(guild)?["members"];
+        let mut items = vec![object! {
+            "group" => object! { "id" => "offline", "count" => members.len() },
+        }];
+        items.extend(members.members().map(|m| object! { "member" => m.clone() }));
+        Some(items)
+    }
+
+    pub fn guild_for_channel(&self, id: u64) -> Option<u64> {
+        self.channel(id)
+            .and_then(|c| c["guild_id"].as_str().and_then(|g| g.parse().ok()))
+    }
+
+    pub fn messages(&self, channel: u64) -> &[JsonValue] {
+        self.messages
+            .iter()
+            .find(|(id, _)| *id == channel)
+            .map(|(_, msgs)| msgs.as_slice())
+            .unwrap_or(&[])
+    }
+
+    pub fn push_message(&mut self, channel: u64, msg: JsonValue) {
+        match self.messages.iter_mut().find(|(id, _)| *id == channel) {
+            Some((_, msgs)) => msgs.push(msg),
+            None => self.messages.push((channel, vec![msg])),
+        }
+    }
+}
+
+pub fn user_json(id: u64, name: &str) -> JsonValue {
+    object! {
+        "id" => id.to_string(),
+        "username" => name,
+        "discriminator" => "0001",
+        "avatar" => JsonValue::Null,
+        "bot" => false,
+    }
+}
+
+fn user(id: u64, name: &str) -> JsonValue {
+    let mut user = user_json(id, name);
+    user["email"] = JsonValue::Null;
+    user["verified"] = true.into();
+    user["mfa_enabled"] = false.into();
+    user
+}
+
+fn member(user: &JsonValue) -> JsonValue {
+    object! {
+        "user" => user.clone(),
+        "nick" => JsonValue::Null,
+        "roles" => JsonValue::new_array(),
+        "joined_at" => "2020-01-01T00:00:00.000000+00:00",
+        "deaf" => false,
+        "mute" => false,
+    }
+}
+
+fn role(id: u64, name: &str) -> JsonValue {
+    object! {
+        "id" => id.to_string(),
+        "name" => name,
+        "color" => 0,
+        "hoist" => false,
+        "position" => 0,
+        // VIEW_CHANNEL | SEND_MESSAGES | READ_MESSAGE_HISTORY
+        "permissions" => 0x400 | 0x800 | 0x10000,
+        "managed" => false,
+        "mentionable" => false,
+    }
+}
+
+fn guild_channel(id: u64, name: &str, kind: u8, position: i64) -> JsonValue {
+    object! {
+        "id" => id.to_string(),
+        "name" => name,
+        "type" => kind,
+        "position" => position,
+        "permission_overwrites" => JsonValue::new_array(),
+        "nsfw" => false,
+        "topic" => JsonValue::Null,
+        "last_message_id" => JsonValue::Null,
+        "parent_id" => JsonValue::Null,
+    }
+}
+
+pub fn thread(id: u64, guild: u64, parent: u64, name: &str, archived: bool) -> JsonValue {
+    object! {
+        "id" => id.to_string(),
+        "guild_id" => guild.to_string(),
+        "parent_id" => parent.to_string(),
+        "owner_id" => OTHER_USER_ID.to_string(),
+        "name" => name,
+        // Public thread
+        "type" => 11,
+        "last_message_id" => JsonValue::Null,
+        "thread_metadata" => object! {
+            "archived" => archived,
+            "auto_archive_duration" => 1440,
+            "archive_timestamp" => "2020-01-01T00:00:00.000000+00:00",
+            "locked" => false,
+        },
+    }
+}
+
+pub fn message(
+    id: u64,
+    channel: u64,
+    guild: Option<u64>,
+    author: &JsonValue,
+    content: &str,
+) -> JsonValue {
+    let mut msg = object! {
+        "id" => id.to_string(),
+        "channel_id" => channel.to_string(),
+        "author" => author.clone(),
+        "content" => content,
+        "timestamp" => "2020-01-01T00:00:00.000000+00:00",
+        "edited_timestamp" => JsonValue::Null,
+        "tts" => false,
+        "mention_everyone" => false,
+        "mentions" => JsonValue::new_array(),
+        "mention_roles" => JsonValue::new_array(),
+        "attachments" => JsonValue::new_array(),
+        "embeds" => JsonValue::new_array(),
+        "reactions" => JsonValue::new_array(),
+        "pinned" => false,
+        "type" => 0,
+    };
+    if let Some(guild) = guild {
+        msg["guild_id"] = guild.to_string().into();
+    }
+    msg
+}
@@ -0,0 +1,203 @@
+use crate::Shared;
+use json::{object, JsonValue};
+use std::{
+    io,
+    net::{TcpListener, TcpStream},
+    sync::mpsc,
+    thread,
+    time::Duration,
+};
+use tungstenite::{Error, Message, WebSocket};
+
+const HEARTBEAT_INTERVAL: u64 = 41250;
+const POLL_INTERVAL: Duration = Duration::from_millis(20);
+
+pub(crate) fn serve(listener: TcpListener, state: Shared) {
+    for stream in listener.incoming() {
+        let stream = match stream {
+            Ok(stream) => stream,
+            Err(_) => continue,
+        };
+        let state = state.clone();
+        thread::spawn(move || {
+            let _ = session(stream, state);
+        });
+    }
+}
+
+fn session(stream: TcpStream, state: Shared) -> tungstenite::Result<()> {
+    let mut ws = tungstenite::accept(stream).map_err(|e| match e {
+        tungstenite::HandshakeError::Failure(e) => e,
+        tungstenite::HandshakeError::Interrupted(_) => {
+            Error::Io(io::Error::from(io::ErrorKind::WouldBlock))
+        },
+    })?;
+    // Poll so dispatches injected from other threads are delivered promptly
+    ws.get_ref().set_read_timeout(Some(POLL_INTERVAL))?;
+
+    let (tx, rx) = mpsc::channel();
+
+    send(
+        &mut ws,
+        object! {
+            "op" => 10,
+            "d" => object! { "heartbeat_interval" => HEARTBEAT_INTERVAL },
+        },
+    )?;
+
+    loop {
+        while let Ok(payload) = rx.try_recv() {
+            ws.write_message(Message::Text(payload))?;
+        }
+
+        match ws.read_message() {
+            Ok(Message::Text(text)) => {
+                if let Ok(payload) = json::parse(&text) {
+                    handle_op(&mut ws, &state, &tx, payload)?;
+                }
+            },
+            Ok(Message::Close(_)) => return Ok(()),
+            Ok(_) => {},
+            Err(Error::Io(ref e))
+                if e.kind() == io::ErrorKind::WouldBlock || e.kind() == io::ErrorKind::TimedOut => {
+            },
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+fn handle_op(
+    ws: &mut WebSocket<TcpStream>,
+    state: &Shared,
+    tx: &mpsc::Sender<String>,
+    payload: JsonValue,
+) -> tungstenite::Result<()> {
+    let op = payload["op"].as_u8();
+    if op != Some(1) {
+        state.lock().unwrap().record_op(payload.clone());
+    }
+
+    match op {
+        // Heartbeat
+        Some(1) => send(ws, object! { "op" => 11 }),
+        // Identify
+        Some(2) => {
+            let ready = {
+                let mut state = state.lock().unwrap();
+                state.add_session(tx.clone());
+                let session_id = format!("mock-session-{}", state.next_id());
+                let ready = state.fixture.ready(&session_id);
+                state.dispatch_payload("READY", ready)
+            };
+            ws.write_message(Message::Text(ready))
+        },
+        // Resume
+        Some(6) => {
+            let resumed = {
+                let mut state = state.lock().unwrap();
+                state.add_session(tx.clone());
+                state.dispatch_payload("RESUMED", object! { "_trace" => vec!["mock-discord"] })
+            };
+            ws.write_message(Message::Text(resumed))
+        },
+        // Request guild members
+        Some(8) => {
+            let chunk = {
+                let mut state = state.lock().unwrap();
+                let data = &payload["d"];
+                let guild_ids = if data["guild_id"].is_array() {
+                    data["guild_id"].members().cloned().collect()
+                } else {
+                    vec![data["guild_id"].clone()]
+                };
+                let user_ids: Vec<_> = data["user_ids"].members().cloned().collect();
+
+                let mut chunks = Vec::new();
+                for guild_id in guild_ids {
+                    let guild = guild_id
+                        .as_str()
+                        .and_then(|id| id.parse().ok())
+                        .and_then(|id| state.fixture.guild(id));
+                    let members: Vec<_> = match guild {
+                        Some(guild) => guild["members"]
+                            .members()
+                            .filter(|m| user_ids.is_empty() || user_ids.contains(&m["user"]["id"]))
+                            .cloned()
+                            .collect(),
+                        None => continue,
+                    };
+                    let mut event = object! {
+                        "guild_id" => guild_id,
+                        "members" => members,
+                    };
+                    if !data["nonce"].is_null() {
+                        event["nonce"] = data["nonce"].clone();
+                    }
+                    chunks.push(state.dispatch_payload("GUILD_MEMBERS_CHUNK", event));
+                }
+                chunks
+            };
+            for chunk in chunk {
+                ws.write_message(Message::Text(chunk))?;
+            }
+            Ok(())
+        },
+        // Lazy guild subscription, every subscribed range is synced
+        Some(14) => {
+            let updates = {
+                let mut state = state.lock().unwrap();
+                let data = &payload["d"];
+                let items = match data["guild_id"]
+                    .as_str()
+                    .and_then(|id| id.parse().ok())
+                    .and_then(|id| state.fixture.member_list(id))
+                {
+                    Some(items) => items,
+                    None => return Ok(()),
+                };
+
+                let mut updates = Vec::new();
+                for (_, ranges) in data["channels"].entries() {
+                    let ops: Vec<_> = ranges
+                        .members()
+                        .filter_map(|range| {
+                            let start = range[0].as_usize()?;
+                            let end = range[1].as_usize()?;
+                            let synced: Vec<_> = items
+                                .iter()
+                                .skip(start)
+                                .take(end + 1 - start)
+                                .cloned()
+                                .collect();
+                            Some(object! {
+                                "op" => "SYNC",
+                                "range" => vec![start, end],
+                                "items" => synced,
+                            })
+                        })
+                        .collect();
+                    let event = object! {
+                        "guild_id" => data["guild_id"].clone(),
+                        "id" => "everyone",
+                        "member_count" => items.len() - 1,
+                        "online_count" => 0,
+                        "groups" => vec![items[0]["group"].clone()],
+                        "ops" => ops,
+                    };
+                    updates.push(state.dispatch_payload("GUILD_MEMBER_LIST_UPDATE", event));
+                }
+                updates
+            };
+            for update in updates {
+                ws.write_message(Message::Text(update))?;
+            }
+            Ok(())
+        },
+        // Presence and voice state updates are only recorded
+        _ => Ok(()),
+    }
+}
+
+fn send(ws: &mut WebSocket<TcpStream>, payload: JsonValue) -> tungstenite::Result<()> {
+    ws.write_message(Message::Text(payload.dump()))
+}
@@ -0,0 +1,323 @@
+use crate::{fixture, Request, Shared};
+use json::{object, JsonValue};
+use std::{
+    io::{self, BufRead, BufReader, Read, Write},
+    net::{TcpListener, TcpStream},
+    thread,
+};
+
+pub(crate) fn serve(listener: TcpListener, state: Shared) {
+    for stream in listener.incoming() {
+        let stream = match stream {
+            Ok(stream) => stream,
+            Err(_) => continue,
+        };
+        let state = state.clone();
+        thread::spawn(move || {
+            let _ = connection(stream, &state);
+        });
+    }
+}
+
+fn connection(stream: TcpStream, state: &Shared) -> io::Result<()> {
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut stream = stream;
+
+    loop {
+        let mut request_line = String::new();
+        if reader.read_line(&mut request_line)? == 0 {
+            return Ok(());
+        }
+        let mut parts = request_line.split_whitespace();
+        let method = parts.next().unwrap_or_default().to_owned();
+        let target = parts.next().unwrap_or_default().to_owned();
+
+        let mut content_length = 0;
+        let mut keep_alive = true;
+        loop {
+            let mut header = String::new();
+            reader.read_line(&mut header)?;
+            let header = header.trim();
+            if header.is_empty() {
+                break;
+            }
+            if let Some(idx) = header.find(':') {
+                let (name, value) = (&header[..idx], header[idx + 1..].trim());
+                match name.to_lowercase().as_str() {
+                    "content-length" => content_length = value.parse().unwrap_or(0),
+                    "connection" => keep_alive = !value.eq_ignore_ascii_case("close"),
+                    _ => {},
+                }
+            }
+        }
+
+        let mut body = vec![0; content_length];
+        reader.read_exact(&mut body)?;
+        let body = String::from_utf8_lossy(&body).into_owned();
+
+        let (path, query) = match target.find('?') {
+            Some(idx) => (&target[..idx], &target[idx + 1..]),
+            None => (target.as_str(), ""),
+        };
+
+        state.lock().unwrap().record_request(Request {
+            method: method.clone(),
+            path: path.to_owned(),
+            body: body.clone(),
+        });
+
+        let (status, response) = route(state, &method, path, query, &body);
+        let response = response.map(|r| r.dump()).unwrap_or_default();
+
+        write!(
+            stream,
+            "HTTP/1.1 {} {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\n\r\n{}",
+            status,
+            reason(status),
+            response.len(),
+            response
+        )?;
+        stream.flush()?;
+
+        if !keep_alive {
+            return Ok(());
+        }
+    }
+}
+
+fn route(
+    state: &Shared,
+    method: &str,
+    path: &str,
+    query: &str,
+    body: &str,
+) -> (u16, Option<JsonValue>) {
+    let segments: Vec<_> = path
+        .trim_start_matches("/api")
+        .split('/')
+        .filter(|s| !s.is_empty())
+        .collect();
+    // Strip the api version
+    let segments = match segments.first() {
+        Some(v) if v.starts_with('v') => &segments[1..],
+        _ => &segments[..],
+    };
+
+    let mut state = state.lock().unwrap();
+
+    match (method, segments) {
+        ("GET", ["gateway"]) => (200, Some(object! { "url" => state.gateway_url.clone() })),
+        ("GET", ["gateway", "bot"]) => (
+            200,
+            Some(object! {
+                "url" => state.gateway_url.clone(),
+                "shards" => 1,
+                "session_start_limit" => object! {
+                    "total" => 1000,
+                    "remaining" => 1000,
+                    "reset_after" => 0,
+                },
+            }),
+        ),
+        ("GET", ["users", "@me"]) => (200, Some(state.fixture.user.clone())),
+        ("GET", ["users", "@me", "guilds"]) => {
+            let guilds: Vec<_> = state
+                .fixture
+                .guilds
+                .iter()
+                .map(|g| {
+                    object! {
+                        "id" => g["id"].clone(),
+                        "name" => g["name"].clone(),
+                        "icon" => g["icon"].clone(),
+                        "owner" => g["owner_id"] == state.fixture.user["id"],
+                        "permissions" => 0x400 | 0x800 | 0x10000,
+                    }
+                })
+                .collect();
+            (200, Some(guilds.into()))
+        },
+        ("GET", ["guilds", guild, "channels"]) => {
+            match parse_id(guild).and_then(|id| state.fixture.guild(id)) {
+                Some(guild) => {
+                    let channels: Vec<_> = guild["channels"]
+                        .members()
+                        .map(|c| {
+                            let mut c = c.clone();
+                            c["guild_id"] = guild["id"].clone();
+                            c
+                        })
+                        .collect();
+                    (200, Some(channels.into()))
+                },
+                None => not_found(),
+            }
+        },
+        ("GET", ["guilds", guild, "members", user]) => {
+            let member = parse_id(guild)
+                .and_then(|id| state.fixture.guild(id))
+                .and_then(|g| {
+                    g["members"]
+                        .members()
+                        .find(|m| m["user"]["id"] == *user)
+                        .cloned()
+                });
+            match member {
+                Some(member) => (200, Some(member)),
+                None => not_found(),
+            }
+        },
+        ("GET", ["channels", channel]) => {
+            match parse_id(channel).and_then(|id| state.fixture.channel(id)) {
+                Some(channel) => (200, Some(channel)),
+                None => not_found(),
+            }
+        },
+        ("GET", ["channels", channel, "messages"]) => {
+            let channel = match parse_id(channel).filter(|&id| state.fixture.channel(id).is_some())
+            {
+                Some(channel) => channel,
+                None => return not_found(),
+            };
+            let limit = query_param(query, "limit")
+                .and_then(|l| l.parse().ok())
+                .unwrap_or(50);
+            // Discord returns the newest messages first
+            let msgs: Vec<_> = state
+                .fixture
+                .messages(channel)
+                .iter()
+                .rev()
+                .take(limit)
+                .cloned()
+                .collect();
+            (200, Some(msgs.into()))
+        },
+        ("GET", ["channels", _, "pins"]) => (200, Some(JsonValue::new_array())),
+        ("GET", ["guilds", guild, "threads", "active"]) => {
+            let threads: Vec<_> = state
+                .fixture
+                .threads
+                .iter()
+                .filter(|t| t["guild_id"] == *guild && t["thread_metadata"]["archived"] == false)
+                .cloned()
+                .collect();
+            (
+                200,
+                Some(object! {
+                    "threads" => threads,
+                    "members" => JsonValue::new_array(),
+                }),
+            )
+        },
+        ("GET", ["channels", channel, "threads", "archived", "public"]) => {
+            let threads: Vec<_> = state
+                .fixture
+                .threads
+                .iter()
+                .filter(|t| t["parent_id"] == *channel && t["thread_metadata"]["archived"] == true)
+                .cloned()
+                .collect();
+            (
+                200,
+                Some(object! {
+                    "threads" => threads,
+                    "members" => JsonValue::new_array(),
+                    "has_more" => false,
+                }),
+            )
+        },
+        ("PUT", ["channels", channel, "thread-members", "@me"]) => {
+            let known = parse_id(channel).map_or(false, |id| {
+                state
+                    .fixture
+                    .threads
+                    .iter()
+                    .any(|t| t["id"] == id.to_string().as_str())
+            });
+            if known {
+                (204, None)
+            } else {
+                not_found()
+            }
+        },
+        ("POST", ["channels", channel, "messages"]) => {
+            let channel = match parse_id(channel).filter(|&id| state.fixture.channel(id).is_some())
+            {
+                Some(channel) => channel,
+                None => return not_found(),
+            };
+            let request = json::parse(body).unwrap_or_else(|_| JsonValue::new_object());
+            let id = state.next_id();
+            let guild = state.fixture.guild_for_channel(channel);
+            let author = state.fixture.user.clone();
+            let mut msg = fixture::message(
+                id,
+                channel,
+                guild,
+                &author,
+                request["content"].as_str().unwrap_or_default(),
+            );
+            if !request["message_reference"].is_null() {
+                msg["message_reference"] = request["message_reference"].clone();
+            }
+            state.record_sent_message(msg.clone());
+            state.fixture.push_message(channel, msg.clone());
+            state.broadcast("MESSAGE_CREATE", msg.clone());
+            (200, Some(msg))
+        },
+        ("POST", ["channels", channel, "typing"]) => {
+            if let Some(channel) = parse_id(channel) {
+                let user = state.fixture.user["id"].clone();
+                let mut event = object! {
+                    "channel_id" => channel.to_string(),
+                    "user_id" => user,
+                    "timestamp" => 0,
+                };
+                if let Some(guild) = state.fixture.guild_for_channel(channel) {
+                    event["guild_id"] = guild.to_string().into();
+                }
+                state.broadcast("TYPING_START", event);
+            }
+            (204, None)
+        },
+        ("POST", ["channels", _, "messages", _, "ack"]) => {
+            (200, Some(object! { "token" => JsonValue::Null }))
+        },
+        _ => not_found(),
+    }
+}
+
+fn parse_id(id: &str) -> Option<u64> {
+    id.parse().ok()
+}
+
+fn query_param<'a>(query: &'a str, name: &str) -> Option<&'a str> {
+    query.split('&').find_map(|pair| {
+        let mut pair = pair.splitn(2, '=');
+        if pair.next() == Some(name) {
+            pair.next()
+        } else {
+            None
+        }
+    })
+}
+
+fn not_found() -> (u16, Option<JsonValue>) {
+    (
+        404,
+        Some(object! {
+            "message" => "404: Not Found",
+            "code" => 0,
+        }),
+    )
+}
+
+fn reason(status: u16) -> &'static str {
+    match status {
+        200 => "OK",
+        204 => "No Content",
+        404 => "Not Found",
+        _ => "Unknown",
+    }
+}
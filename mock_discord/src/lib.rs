@@ -0,0 +1,197 @@
+//! An offline stand-in for the Discord gateway and REST api
+//!
+//! `MockDiscord::start` binds two local ports, one speaking the REST api and one speaking the
+//! gateway protocol. Everything a client does is recorded so tests can assert on it, and
+//! arbitrary gateway events can be injected with `MockDiscord::dispatch`. `GatewayClient` receives
+//! the payloads the mock sends, for feeding them to the code under test.
+
+use json::{object, JsonValue};
+use std::{
+    io,
+    net::{SocketAddr, TcpListener},
+    sync::{mpsc::Sender, Arc, Mutex},
+    thread,
+    time::{Duration, Instant},
+};
+
+mod client;
+mod fixture;
+mod gateway;
+mod http;
+
+pub use client::GatewayClient;
+pub use fixture::*;
+
+/// A REST request made by the client
+#[derive(Clone, Debug, PartialEq)]
+pub struct Request {
+    pub method: String,
+    pub path: String,
+    pub body: String,
+}
+
+pub(crate) struct State {
+    pub(crate) fixture: Fixture,
+    pub(crate) gateway_url: String,
+    next_id: u64,
+    seq: u64,
+    sessions: Vec<Sender<String>>,
+    requests: Vec<Request>,
+    gateway_ops: Vec<JsonValue>,
+    sent_messages: Vec<JsonValue>,
+}
+
+impl State {
+    /// Generate a snowflake that will not collide with any fixture ids
+    pub(crate) fn next_id(&mut self) -> u64 {
+        self.next_id += 1;
+        self.next_id
+    }
+
+    pub(crate) fn dispatch_payload(&mut self, event: &str, data: JsonValue) -> String {
+        self.seq += 1;
+        object! {
+            "op" => 0,
+            "t" => event,
+            "s" => self.seq,
+            "d" => data,
+        }
+        .dump()
+    }
+
+    /// Send a dispatch to every identified session, dropping sessions that have gone away
+    pub(crate) fn broadcast(&mut self, event: &str, data: JsonValue) {
+        let payload = self.dispatch_payload(event, data);
+        self.sessions.retain(|s| s.send(payload.clone()).is_ok());
+    }
+
+    pub(crate) fn add_session(&mut self, session: Sender<String>) {
+        self.sessions.push(session);
+    }
+
+    pub(crate) fn record_request(&mut self, request: Request) {
+        self.requests.push(request);
+    }
+
+    pub(crate) fn record_op(&mut self, op: JsonValue) {
+        self.gateway_ops.push(op);
+    }
+
+    pub(crate) fn record_sent_message(&mut self, msg: JsonValue) {
+        self.sent_messages.push(msg);
+    }
+}
+
+pub(crate) type Shared = Arc<Mutex<State>>;
+
+pub struct MockDiscord {
+    rest_addr: SocketAddr,
+    gateway_addr: SocketAddr,
+    state: Shared,
+}
+
+impl MockDiscord {
+    /// Start serving `fixture` on two random local ports
+    pub fn start(fixture: Fixture) -> io::Result<MockDiscord> {
+        let rest = TcpListener::bind("127.0.0.1:0")?;
+        let gateway = TcpListener::bind("127.0.0.1:0")?;
+        let rest_addr = rest.local_addr()?;
+        let gateway_addr = gateway.local_addr()?;
+
+        let state = Arc::new(Mutex::new(State {
+            fixture,
+            gateway_url: format!("ws://{}", gateway_addr),
+            next_id: 10_000,
+            seq: 0,
+            sessions: Vec::new(),
+            requests: Vec::new(),
+            gateway_ops: Vec::new(),
+            sent_messages: Vec::new(),
+        }));
+
+        {
+            let state = Arc::clone(&state);
+            thread::Builder::new()
+                .name("mock rest".into())
+                .spawn(move || http::serve(rest, state))?;
+        }
+        {
+            let state = Arc::clone(&state);
+            thread::Builder::new()
+                .name("mock gateway".into())
+                .spawn(move || gateway::serve(gateway, state))?;
+        }
+
+        Ok(MockDiscord {
+            rest_addr,
+            gateway_addr,
+            state,
+        })
+    }
+
+    /// Base url of the REST api, equivalent to `https://discordapp.com/api/v6`
+    pub fn api_url(&self) -> String {
+        format!("http://{}/api/v6", self.rest_addr)
+    }
+
+    pub fn gateway_url(&self) -> String {
+        format!("ws://{}", self.gateway_addr)
+    }
+
+    /// Send a gateway event to every connected client
+    pub fn dispatch(&self, event: &str, data: JsonValue) {
+        self.state.lock().unwrap().broadcast(event, data);
+    }
+
+    /// Dispatch a `MESSAGE_CREATE` and add the message to the channel history
+    pub fn receive_message(&self, channel: u64, author: &JsonValue, content: &str) -> JsonValue {
+        let mut state = self.state.lock().unwrap();
+        let id = state.next_id();
+        let guild = state.fixture.guild_for_channel(channel);
+        let msg = fixture::message(id, channel, guild, author, content);
+        state.fixture.push_message(channel, msg.clone());
+        state.broadcast("MESSAGE_CREATE", msg.clone());
+        msg
+    }
+
+    /// Dispatch a `TYPING_START` for `user` in `channel`
+    pub fn start_typing(&self, channel: u64, user: u64, timestamp: u64) {
+        let mut state = self.state.lock().unwrap();
+        let mut event = object! {
+            "channel_id" => channel.to_string(),
+            "user_id" => user.to_string(),
+            "timestamp" => timestamp,
+        };
+        if let Some(guild) = state.fixture.guild_for_channel(channel) {
+            event["guild_id"] = guild.to_string().into();
+        }
+        state.broadcast("TYPING_START", event);
+    }
+
+    /// All REST requests received so far
+    pub fn requests(&self) -> Vec<Request> {
+        self.state.lock().unwrap().requests.clone()
+    }
+
+    /// All gateway payloads received so far (excluding heartbeats)
+    pub fn gateway_ops(&self) -> Vec<JsonValue> {
+        self.state.lock().unwrap().gateway_ops.clone()
+    }
+
+    /// All messages created through the REST api
+    pub fn sent_messages(&self) -> Vec<JsonValue> {
+        self.state.lock().unwrap().sent_messages.clone()
+    }
+
+    /// Poll `check` until it returns true or `timeout` elapses
+    pub fn wait_for(&self, timeout: Duration, check: impl Fn(&MockDiscord) -> bool) -> bool {
+        let start = Instant::now();
+        while start.elapsed() < timeout {
+            if check(self) {
+                return true;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        check(self)
+    }
+}
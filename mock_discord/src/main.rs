@@ -0,0 +1,10 @@
+use mock_discord::{Fixture, MockDiscord};
+
+fn main() {
+    let server = MockDiscord::start(Fixture::default()).expect("Unable to start mock server");
+    println!("REST api: {}", server.api_url());
+    println!("Gateway: {}", server.gateway_url());
+    loop {
+        std::thread::park();
+    }
+}
@@ -0,0 +1,234 @@
+use json::{object, JsonValue};
+use mock_discord::*;
+use std::{io::Read, io::Write, net::TcpStream, time::Duration};
+
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+fn rest(server: &MockDiscord, method: &str, path: &str, body: &str) -> (u16, JsonValue) {
+    let url = server.api_url();
+    let addr = url
+        .trim_start_matches("http://")
+        .split('/')
+        .next()
+        .unwrap()
+        .to_owned();
+    let mut stream = TcpStream::connect(addr).unwrap();
+    write!(
+        stream,
+        "{} /api/v6{} HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\nContent-Length: {}\r\n\r\n{}",
+        method,
+        path,
+        body.len(),
+        body
+    )
+    .unwrap();
+
+    let mut response = String::new();
+    stream.read_to_string(&mut response).unwrap();
+    let status = response[9..12].parse().unwrap();
+    let body = &response[response.find("\r\n\r\n").unwrap() + 4..];
+    (status, json::parse(body).unwrap_or(JsonValue::Null))
+}
+
+#[test]
+fn ready_contains_fixture() {
+    let server = MockDiscord::start(Fixture::default()).unwrap();
+    let mut gateway = GatewayClient::connect(&server);
+    let ready = gateway.identify();
+
+    assert_eq!(ready["user"]["id"], USER_ID.to_string().as_str());
+    assert_eq!(ready["guilds"].len(), 1);
+    let channels: Vec<_> = ready["guilds"][0]["channels"]
+        .members()
+        .map(|c| c["name"].as_str().unwrap().to_owned())
+        .collect();
+    assert_eq!(channels, vec!["general", "voice"]);
+    assert_eq!(
+        ready["private_channels"][0]["id"],
+        DM_CHANNEL_ID.to_string().as_str()
+    );
+}
+
+#[test]
+fn heartbeat_is_acked() {
+    let server = MockDiscord::start(Fixture::default()).unwrap();
+    let mut gateway = GatewayClient::connect(&server);
+    gateway.identify();
+
+    gateway.send(object! { "op" => 1, "d" => 1 });
+    loop {
+        if gateway.recv()["op"] == 11 {
+            break;
+        }
+    }
+}
+
+#[test]
+fn message_create_is_dispatched() {
+    let server = MockDiscord::start(Fixture::default()).unwrap();
+    let mut gateway = GatewayClient::connect(&server);
+    gateway.identify();
+
+    let author = user_json(OTHER_USER_ID, "alice");
+    server.receive_message(GENERAL_CHANNEL_ID, &author, "ping");
+
+    let msg = gateway.recv_dispatch("MESSAGE_CREATE");
+    assert_eq!(msg["content"], "ping");
+    assert_eq!(msg["guild_id"], GUILD_ID.to_string().as_str());
+    assert_eq!(msg["author"]["username"], "alice");
+}
+
+#[test]
+fn typing_start_is_dispatched() {
+    let server = MockDiscord::start(Fixture::default()).unwrap();
+    let mut gateway = GatewayClient::connect(&server);
+    gateway.identify();
+
+    server.start_typing(GENERAL_CHANNEL_ID, OTHER_USER_ID, 1234);
+
+    let typing = gateway.recv_dispatch("TYPING_START");
+    assert_eq!(typing["user_id"], OTHER_USER_ID.to_string().as_str());
+    assert_eq!(
+        typing["channel_id"],
+        GENERAL_CHANNEL_ID.to_string().as_str()
+    );
+    assert_eq!(typing["timestamp"], 1234);
+}
+
+#[test]
+fn member_request_is_answered_with_chunk() {
+    let server = MockDiscord::start(Fixture::default()).unwrap();
+    let mut gateway = GatewayClient::connect(&server);
+    gateway.identify();
+
+    gateway.send(object! {
+        "op" => 8,
+        "d" => object! {
+            "guild_id" => GUILD_ID.to_string(),
+            "user_ids" => vec![OTHER_USER_ID.to_string()],
+            "nonce" => GENERAL_CHANNEL_ID.to_string(),
+        },
+    });
+
+    let chunk = gateway.recv_dispatch("GUILD_MEMBERS_CHUNK");
+    assert_eq!(chunk["nonce"], GENERAL_CHANNEL_ID.to_string().as_str());
+    assert_eq!(chunk["members"].len(), 1);
+    assert_eq!(chunk["members"][0]["user"]["username"], "alice");
+}
+
+#[test]
+fn guild_subscription_is_answered_with_member_list() {
+    let server = MockDiscord::start(Fixture::default()).unwrap();
+    let mut gateway = GatewayClient::connect(&server);
+    gateway.identify();
+
+    gateway.send(object! {
+        "op" => 14,
+        "d" => object! {
+            "guild_id" => GUILD_ID.to_string(),
+            "typing" => true,
+            "activities" => true,
+            "channels" => object! {
+                &GENERAL_CHANNEL_ID.to_string() => vec![vec![0, 99]],
+            },
+        },
+    });
+
+    let update = gateway.recv_dispatch("GUILD_MEMBER_LIST_UPDATE");
+    assert_eq!(update["guild_id"], GUILD_ID.to_string().as_str());
+    assert_eq!(update["groups"][0]["id"], "offline");
+    assert_eq!(update["groups"][0]["count"], 2);
+    let sync = &update["ops"][0];
+    assert_eq!(sync["op"], "SYNC");
+    assert_eq!(sync["range"], json::array![0, 99]);
+    assert_eq!(sync["items"][0]["group"]["id"], "offline");
+    let names: Vec<_> = sync["items"]
+        .members()
+        .skip(1)
+        .map(|item| item["member"]["user"]["username"].to_string())
+        .collect();
+    assert_eq!(names, vec!["weecord", "alice"]);
+    assert!(server.wait_for(TIMEOUT, |s| s.gateway_ops().iter().any(|op| op["op"] == 14)));
+}
+
+#[test]
+fn rest_serves_history_newest_first() {
+    let server = MockDiscord::start(Fixture::default()).unwrap();
+
+    let (status, msgs) = rest(
+        &server,
+        "GET",
+        &format!("/channels/{}/messages?limit=25", GENERAL_CHANNEL_ID),
+        "",
+    );
+    assert_eq!(status, 200);
+    let contents: Vec<_> = msgs.members().map(|m| m["content"].to_string()).collect();
+    assert_eq!(contents, vec!["**world**", "hello"]);
+
+    let (status, _) = rest(&server, "GET", "/channels/1/messages", "");
+    assert_eq!(status, 404);
+}
+
+#[test]
+fn sent_messages_are_recorded_and_echoed() {
+    let server = MockDiscord::start(Fixture::default()).unwrap();
+    let mut gateway = GatewayClient::connect(&server);
+    gateway.identify();
+
+    let (status, msg) = rest(
+        &server,
+        "POST",
+        &format!("/channels/{}/messages", GENERAL_CHANNEL_ID),
+        r#"{"content":"hi there"}"#,
+    );
+    assert_eq!(status, 200);
+    assert_eq!(msg["author"]["id"], USER_ID.to_string().as_str());
+
+    let echoed = gateway.recv_dispatch("MESSAGE_CREATE");
+    assert_eq!(echoed["id"], msg["id"]);
+    assert_eq!(server.sent_messages().len(), 1);
+    assert_eq!(server.sent_messages()[0]["content"], "hi there");
+    assert!(server
+        .requests()
+        .iter()
+        .any(|r| r.method == "POST" && r.path.ends_with("/messages")));
+}
+
+#[test]
+fn threads_are_listed_and_joined() {
+    let server = MockDiscord::start(Fixture::default()).unwrap();
+
+    let (status, active) = rest(
+        &server,
+        "GET",
+        &format!("/guilds/{}/threads/active", GUILD_ID),
+        "",
+    );
+    assert_eq!(status, 200);
+    assert_eq!(active["threads"].len(), 1);
+    assert_eq!(active["threads"][0]["name"], "ideas");
+
+    let (status, archived) = rest(
+        &server,
+        "GET",
+        &format!("/channels/{}/threads/archived/public", GENERAL_CHANNEL_ID),
+        "",
+    );
+    assert_eq!(status, 200);
+    assert_eq!(archived["threads"][0]["name"], "old plans");
+
+    let (status, _) = rest(
+        &server,
+        "PUT",
+        &format!("/channels/{}/thread-members/@me", THREAD_ID),
+        "",
+    );
+    assert_eq!(status, 204);
+    let (status, _) = rest(
+        &server,
+        "PUT",
+        &format!("/channels/{}/thread-members/@me", GENERAL_CHANNEL_ID),
+        "",
+    );
+    assert_eq!(status, 404);
+}
@@ -1,81 +1,280 @@
 use lazy_static::lazy_static;
-pub use simple_ast::MarkdownNode;
-use simple_ast::{regex::Regex, Parser, Rule, Styled};
-
-pub fn parse_markdown(str: &str) -> Styled<MarkdownNode> {
-    use simple_ast::markdown_rules::*;
-    let rules: &[&dyn Rule<MarkdownNode>] = &[
-        &Escape,
-        &Newline,
-        &Bold,
-        &Underline,
-        &Italic,
-        &Strikethrough,
-        &Spoiler,
-        &BlockQuote::new(),
-        &Code,
-        &InlineCode,
-        &Text,
-    ];
-
-    Parser::with_rules(rules).parse(str)
-}
+use simple_ast::regex::Regex;
+
+mod markdown;
+#[cfg(feature = "onig")]
+mod sed;
+
+// Sed edits search from an offset so `^`, `\b` and lookbehinds see the text before each match,
+// simple_ast only offers that through oniguruma
+#[cfg(not(feature = "onig"))]
+compile_error!("parsing needs the `onig` feature, sed edits are not implemented for `pcre`");
+
+pub use markdown::{parse_markdown, MarkdownNode};
+pub use sed::SedExpr;
 
 pub fn weechat_arg_strip(str: &str) -> String {
     str.trim().replace(' ', "_")
 }
 
 lazy_static! {
-    static ref LINE_SUB_REGEX: Regex =
-        Regex::new(r"^(\d)?s/(.*?(?<!\\))/(.*?(?<!\\))(?:/|$)(\w+)?").unwrap();
-    static ref REACTION_REGEX: Regex =
-        Regex::new(r"^(\d)?([\+\-])(.).*$").unwrap();
+    static ref LINE_EDIT_REGEX: Regex = Regex::new(r"\A(\d+|[a-z]\d)?s(\S[\s\S]*)\z").unwrap();
+    static ref REACTION_REGEX: Regex = Regex::new(r"\A(\d+|[a-z]\d)?([\+\-])(\S+)\z").unwrap();
+    static ref SHORTCODE_REGEX: Regex = Regex::new(r"\A:([\w+-]+):\z").unwrap();
+    static ref REPLY_REGEX: Regex = Regex::new(r"\A(\d+|[a-z]\d)?\^\s+([\s\S]+)\z").unwrap();
+}
+
+/// The message an edit, reaction or reply applies to
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Target<'a> {
+    /// The nth most recent message, counting from 1
+    Line(usize),
+    /// The reference tag printed before a message, such as `a3`
+    Ref(&'a str),
 }
 
 #[derive(Debug)]
 pub enum LineEdit<'a> {
-    Sub {
-        line: usize,
-        old: &'a str,
-        new: &'a str,
-        options: Option<&'a str>,
-    },
-    Delete {
-        line: usize,
-    },
+    Sub { target: Target<'a>, expr: SedExpr },
+    Delete { target: Target<'a> },
 }
 
+#[derive(Debug, PartialEq)]
+pub enum ReactionEmoji<'a> {
+    /// A single Unicode emoji (one grapheme cluster, so flags and skin tones are kept whole)
+    Unicode(&'a str),
+    /// A `:name:` shortcode, either a Unicode shortcode or the name of a custom emoji
+    Named(&'a str),
+}
 
 #[derive(Debug)]
 pub struct Reaction<'a> {
     pub add: bool,
-    pub unicode: &'a str,
-    pub line: usize,
+    pub emoji: ReactionEmoji<'a>,
+    pub target: Target<'a>,
+}
+
+#[derive(Debug)]
+pub struct Reply<'a> {
+    pub target: Target<'a>,
+    pub content: &'a str,
+}
+
+#[derive(Debug, Default, PartialEq)]
+pub struct SearchQuery<'a> {
+    pub channel: Option<&'a str>,
+    pub author: Option<&'a str>,
+    pub has: Vec<&'a str>,
+    pub terms: Vec<&'a str>,
 }
 
-pub fn parse_line_edit(input: &str) -> Option<LineEdit> {
-    let caps = LINE_SUB_REGEX.captures(input)?;
+/// Parse a message target, either a line number or a reference tag, missing targets default to
+/// the most recent message
+pub fn parse_target(input: Option<&str>) -> Option<Target> {
+    match input {
+        None => Some(Target::Line(1)),
+        Some(input) if input.starts_with(|c: char| c.is_ascii_lowercase()) => {
+            let mut chars = input.chars();
+            match (chars.next(), chars.next(), chars.next()) {
+                (Some(_), Some(digit), None) if digit.is_ascii_digit() => Some(Target::Ref(input)),
+                _ => None,
+            }
+        },
+        Some(input) => input.parse().ok().filter(|&l| l > 0).map(Target::Line),
+    }
+}
+
+/// Parse a sed style edit, `s/pattern/replacement/flags` with an optional target before the `s`.
+/// An empty pattern and replacement deletes the message. Returns an error for edits whose pattern
+/// is not a valid regex.
+pub fn parse_line_edit(input: &str) -> Option<Result<LineEdit, String>> {
+    let caps = LINE_EDIT_REGEX.captures(input)?;
 
-    let line = caps.at(1).and_then(|l| l.parse().ok()).unwrap_or(1);
-    let old = caps.at(2)?;
-    let new = caps.at(3)?;
+    let target = parse_target(caps.at(1))?;
+    let raw = sed::split(caps.at(2)?)?;
 
-    if old.is_empty() && new.is_empty() {
-        Some(LineEdit::Delete { line })
+    if raw.pattern.is_empty() && raw.replacement.is_empty() {
+        Some(Ok(LineEdit::Delete { target }))
     } else {
-        Some(LineEdit::Sub {
-            line,
-            old,
-            new,
-            options: caps.at(4),
-        })
+        Some(SedExpr::compile(&raw).map(|expr| LineEdit::Sub { target, expr }))
     }
 }
 
 pub fn parse_reaction(input: &str) -> Option<Reaction> {
+    use unicode_segmentation::UnicodeSegmentation;
+
     let caps = REACTION_REGEX.captures(input)?;
-    let line = caps.at(1).and_then(|l| l.parse().ok()).unwrap_or(1);
-    let unicode_opt = caps.at(3);
+    let target = parse_target(caps.at(1))?;
     let add = caps.at(2) == Some("+");
-    unicode_opt.map(|unicode| Reaction{ add, unicode, line })
+    let emoji = caps.at(3)?;
+
+    let emoji = if let Some(name) = SHORTCODE_REGEX.captures(emoji).and_then(|caps| caps.at(1)) {
+        ReactionEmoji::Named(name)
+    } else if emoji.graphemes(true).count() == 1 && is_emoji(emoji) {
+        ReactionEmoji::Unicode(emoji)
+    } else {
+        return None;
+    };
+    Some(Reaction { add, emoji, target })
+}
+
+/// Whether a single grapheme is an emoji, so `+!` or `-.` are not taken for reactions
+fn is_emoji(grapheme: &str) -> bool {
+    // Keycaps start with a digit, `#` or `*`
+    if grapheme.ends_with('\u{20E3}') {
+        return true;
+    }
+    match grapheme.chars().next() {
+        Some(c) => matches!(
+            c as u32,
+            0xA9 | 0xAE
+                | 0x203C
+                | 0x2049
+                | 0x2122
+                | 0x2139
+                | 0x2194..=0x2199
+                | 0x21A9..=0x21AA
+                | 0x231A..=0x23FF
+                | 0x24C2
+                | 0x25AA..=0x25FE
+                | 0x2600..=0x27BF
+                | 0x2934..=0x2935
+                | 0x2B05..=0x2B55
+                | 0x3030
+                | 0x303D
+                | 0x3297
+                | 0x3299
+                | 0x1F000..=0x1FAFF
+        ),
+        None => false,
+    }
+}
+
+pub fn parse_reply(input: &str) -> Option<Reply> {
+    let caps = REPLY_REGEX.captures(input)?;
+    let target = parse_target(caps.at(1))?;
+    let content = caps.at(2)?;
+    Some(Reply { target, content })
+}
+
+/// Parse a search query of the form `[in:#channel] [from:user] [has:link] terms`
+pub fn parse_search(input: &str) -> SearchQuery {
+    let mut query = SearchQuery::default();
+    for word in input.split_whitespace() {
+        if word.starts_with("in:") {
+            query.channel = Some(word["in:".len()..].trim_start_matches('#'));
+        } else if word.starts_with("from:") {
+            query.author = Some(word["from:".len()..].trim_start_matches('@'));
+        } else if word.starts_with("has:") {
+            query.has.push(&word["has:".len()..]);
+        } else {
+            query.terms.push(word);
+        }
+    }
+    query
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse_line_edit, parse_reaction, parse_reply, LineEdit, ReactionEmoji, Target};
+
+    #[test]
+    fn parses_edit_targets() {
+        match parse_line_edit("s///") {
+            Some(Ok(LineEdit::Delete { target })) => assert_eq!(target, Target::Line(1)),
+            other => panic!("unexpected {:?}", other),
+        }
+        match parse_line_edit("a3s|foo|bar|g") {
+            Some(Ok(LineEdit::Sub { target, expr })) => {
+                assert_eq!(target, Target::Ref("a3"));
+                assert_eq!(expr.apply("foo foo").as_deref(), Some("bar bar"));
+            },
+            other => panic!("unexpected {:?}", other),
+        }
+        match parse_line_edit(r"2s/a\nb/c/") {
+            Some(Ok(LineEdit::Sub { target, expr })) => {
+                assert_eq!(target, Target::Line(2));
+                assert_eq!(expr.apply("a\nb").as_deref(), Some("c"));
+            },
+            other => panic!("unexpected {:?}", other),
+        }
+    }
+
+    #[test]
+    fn rejects_plain_messages_and_bad_patterns() {
+        assert!(parse_line_edit("so what").is_none());
+        assert!(parse_line_edit("hello\ns/a/b/").is_none());
+        assert!(parse_line_edit("s/a/b/ and more").is_none());
+        assert!(matches!(parse_line_edit("s/(/x/"), Some(Err(_))));
+    }
+
+    #[test]
+    fn parses_reactions() {
+        let reaction = parse_reaction("+👍").unwrap();
+        assert!(reaction.add);
+        assert_eq!(reaction.target, Target::Line(1));
+        assert_eq!(reaction.emoji, ReactionEmoji::Unicode("👍"));
+
+        // Flags and skin tones are several code points but a single grapheme
+        for emoji in &["🇫🇷", "👍🏽", "👩‍👩‍👧", "❤️", "1️⃣"] {
+            let reaction = parse_reaction(&format!("+{}", emoji)).unwrap();
+            assert_eq!(reaction.emoji, ReactionEmoji::Unicode(*emoji));
+        }
+
+        let reaction = parse_reaction("2+:thumbsup:").unwrap();
+        assert_eq!(reaction.target, Target::Line(2));
+        assert_eq!(reaction.emoji, ReactionEmoji::Named("thumbsup"));
+
+        let reaction = parse_reaction("a3-:custom_emoji:").unwrap();
+        assert!(!reaction.add);
+        assert_eq!(reaction.target, Target::Ref("a3"));
+        assert_eq!(reaction.emoji, ReactionEmoji::Named("custom_emoji"));
+    }
+
+    #[test]
+    fn rejects_messages_that_are_not_reactions() {
+        assert!(parse_reaction("+").is_none());
+        assert!(parse_reaction("+a").is_none());
+        assert!(parse_reaction("+1").is_none());
+        assert!(parse_reaction("+!").is_none());
+        assert!(parse_reaction("-.").is_none());
+        assert!(parse_reaction("+=").is_none());
+        assert!(parse_reaction("+é").is_none());
+        assert!(parse_reaction("+👍👍").is_none());
+        assert!(parse_reaction("+:not closed").is_none());
+        assert!(parse_reaction("aa+👍").is_none());
+        assert!(parse_reaction("a+👍").is_none());
+        assert!(parse_reaction("0+👍").is_none());
+        assert!(parse_reaction("- item").is_none());
+        assert!(parse_reaction("some text\n+👍").is_none());
+        assert!(parse_reaction("+👍\nsome text").is_none());
+    }
+
+    #[test]
+    fn parses_replies() {
+        let reply = parse_reply("^ sounds good").unwrap();
+        assert_eq!(reply.target, Target::Line(1));
+        assert_eq!(reply.content, "sounds good");
+
+        let reply = parse_reply("3^ me too").unwrap();
+        assert_eq!(reply.target, Target::Line(3));
+        assert_eq!(reply.content, "me too");
+
+        let reply = parse_reply("b7^  ^ this").unwrap();
+        assert_eq!(reply.target, Target::Ref("b7"));
+        assert_eq!(reply.content, "^ this");
+
+        let reply = parse_reply("^ first line\nsecond line").unwrap();
+        assert_eq!(reply.content, "first line\nsecond line");
+    }
+
+    #[test]
+    fn rejects_messages_that_are_not_replies() {
+        assert!(parse_reply("^").is_none());
+        assert!(parse_reply("^no space").is_none());
+        assert!(parse_reply("0^ zero").is_none());
+        assert!(parse_reply("ab^ long ref").is_none());
+        assert!(parse_reply("2 ^ spaced").is_none());
+        assert!(parse_reply("hello\n^ foo").is_none());
+    }
 }
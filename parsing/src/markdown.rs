@@ -0,0 +1,478 @@
+//! Discord markdown. Header and list lines are split off first, simple_ast then parses the
+//! styling of each part. Links, timestamps and mentions are found in a pass before simple_ast so
+//! their text is never styled (`_` in urls is common), and put back into the tree afterwards.
+
+use simple_ast::{markdown_rules::*, regex::Regex, Parser, Rule};
+use std::{rc::Rc, sync::RwLock};
+
+type AstNode = simple_ast::MarkdownNode;
+
+/// Surround the index of a token while simple_ast parses the text around it
+const TOKEN_START: char = '\u{E000}';
+const TOKEN_END: char = '\u{E001}';
+
+lazy_static::lazy_static! {
+    static ref TOKEN_REGEX: Regex = Regex::new(concat!(
+        // Masked link, [text](url), the url may hold balanced parentheses
+        r"\[([^\[\]\n]+)\]\((https?://(?:[^\s()]|\([^\s()]*\))+)\)",
+        // Link with its embed suppressed, <url>
+        r"|<(https?://[^\s>]+)>",
+        // Timestamp, <t:seconds> or <t:seconds:style>
+        r"|<t:(-?\d+)(?::([tTdDfFR]))?>",
+        // User, role and channel mentions
+        r"|<@!?(\d+)>|<@&(\d+)>|<#(\d+)>",
+        // Not part of an email address or a word
+        r"|(?<![\w@])@(everyone|here)",
+        // Autolink, trailing punctuation and styling characters are not part of the url
+        r"|(https?://[^\s<>`]*[^\s<>`*_~|.,:;'\x22)\]!?])",
+    ))
+    .unwrap();
+    static ref HEADER_REGEX: Regex = Regex::new(r"\A(#{1,3}) +(\S.*)\z").unwrap();
+    static ref LIST_ITEM_REGEX: Regex = Regex::new(r"\A( *)([-*]|\d{1,9}\.) +(\S.*)\z").unwrap();
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum MarkdownNode {
+    Bold(Vec<MarkdownNode>),
+    Italic(Vec<MarkdownNode>),
+    Underline(Vec<MarkdownNode>),
+    Strikethrough(Vec<MarkdownNode>),
+    Spoiler(Vec<MarkdownNode>),
+    BlockQuote(Vec<MarkdownNode>),
+    SingleBlockQuote(Vec<MarkdownNode>),
+    Text(String),
+    InlineCode(String),
+    /// Language and code
+    Code(String, String),
+    Link(String),
+    /// A link written as `<url>`, which Discord shows without an embed
+    SuppressedLink(String),
+    MaskedLink {
+        text: String,
+        url: String,
+    },
+    /// Unix timestamp in seconds and the display style, one of `tTdDfFR`
+    Timestamp(i64, Option<char>),
+    UserMention(u64),
+    RoleMention(u64),
+    ChannelMention(u64),
+    /// `@everyone` or `@here`, without the `@`
+    EveryoneMention(String),
+    /// A `#`, `##` or `###` line and its level
+    Header(u8, Vec<MarkdownNode>),
+    /// A `-`, `*` or `1.` line, nested by two spaces of indentation per level
+    ListItem {
+        depth: usize,
+        /// The number of an ordered list item
+        number: Option<u64>,
+        content: Vec<MarkdownNode>,
+    },
+}
+
+pub fn parse_markdown(input: &str) -> Vec<MarkdownNode> {
+    let mut out = Vec::new();
+    // Lines that are not headers or list items, parsed together so blocks can span them
+    let mut text = String::new();
+    let mut in_code = false;
+
+    let mut lines = input.split('\n').peekable();
+    while let Some(line) = lines.next() {
+        let block = if in_code { None } else { parse_block(line) };
+        if line.matches("```").count() % 2 == 1 {
+            in_code = !in_code;
+        }
+
+        match block {
+            Some(block) => {
+                out.extend(parse_inline(&std::mem::take(&mut text)));
+                out.push(block);
+            },
+            None => text.push_str(line),
+        }
+        if lines.peek().is_some() {
+            text.push('\n');
+        }
+    }
+    out.extend(parse_inline(&text));
+
+    out
+}
+
+/// Parse a header or list item line
+fn parse_block(line: &str) -> Option<MarkdownNode> {
+    if let Some(caps) = HEADER_REGEX.captures(line) {
+        let level = caps.at(1)?.len() as u8;
+        return Some(MarkdownNode::Header(level, parse_inline(caps.at(2)?)));
+    }
+
+    let caps = LIST_ITEM_REGEX.captures(line)?;
+    let depth = caps.at(1)?.len() / 2;
+    let number = caps.at(2)?.trim_end_matches('.').parse().ok();
+    Some(MarkdownNode::ListItem {
+        depth,
+        number,
+        content: parse_inline(caps.at(3)?),
+    })
+}
+
+fn parse_inline(input: &str) -> Vec<MarkdownNode> {
+    if input.is_empty() {
+        return Vec::new();
+    }
+
+    let rules: &[&dyn Rule<AstNode>] = &[
+        &Escape,
+        &Newline,
+        &Bold,
+        &Underline,
+        &Italic,
+        &Strikethrough,
+        &Spoiler,
+        &BlockQuote::new(),
+        &Code,
+        &InlineCode,
+        &Text,
+    ];
+
+    let (text, tokens) = extract_tokens(input);
+    let ast = Parser::with_rules(rules).parse(&text);
+    convert(&ast.0, &tokens)
+}
+
+/// A token found before parsing and the text it was parsed from
+type Token = (MarkdownNode, String);
+
+/// Replace every token with a placeholder holding its index
+fn extract_tokens(input: &str) -> (String, Vec<Token>) {
+    // Placeholders could be confused with text that already contains them
+    if input.contains(TOKEN_START) || input.contains(TOKEN_END) {
+        return (input.to_owned(), Vec::new());
+    }
+
+    let mut text = String::new();
+    let mut tokens = Vec::new();
+    let mut rest = input;
+    while let Some(caps) = TOKEN_REGEX.captures(rest) {
+        let raw = match caps.at(0) {
+            Some(raw) if !raw.is_empty() => raw,
+            _ => break,
+        };
+        let start = raw.as_ptr() as usize - rest.as_ptr() as usize;
+        text.push_str(&rest[..start]);
+
+        // An escaped token is left for simple_ast to unescape, only its first character is
+        // skipped so a token inside it can still be found
+        let backslashes = text.chars().rev().take_while(|&c| c == '\\').count();
+        if backslashes % 2 == 1 {
+            let first = raw.chars().next().map_or(1, char::len_utf8);
+            text.push_str(&raw[..first]);
+            rest = &rest[start + first..];
+            continue;
+        }
+
+        let node = if let (Some(text), Some(url)) = (caps.at(1), caps.at(2)) {
+            Some(MarkdownNode::MaskedLink {
+                text: text.to_owned(),
+                url: url.to_owned(),
+            })
+        } else if let Some(url) = caps.at(3) {
+            Some(MarkdownNode::SuppressedLink(url.to_owned()))
+        } else if let Some(seconds) = caps.at(4) {
+            let style = caps.at(5).and_then(|style| style.chars().next());
+            seconds
+                .parse()
+                .ok()
+                .map(|seconds| MarkdownNode::Timestamp(seconds, style))
+        } else if let Some(id) = caps.at(6) {
+            id.parse().ok().map(MarkdownNode::UserMention)
+        } else if let Some(id) = caps.at(7) {
+            id.parse().ok().map(MarkdownNode::RoleMention)
+        } else if let Some(id) = caps.at(8) {
+            id.parse().ok().map(MarkdownNode::ChannelMention)
+        } else if let Some(name) = caps.at(9) {
+            Some(MarkdownNode::EveryoneMention(name.to_owned()))
+        } else {
+            caps.at(10).map(|url| MarkdownNode::Link(url.to_owned()))
+        };
+
+        match node {
+            Some(node) => {
+                text.push(TOKEN_START);
+                text.push_str(&tokens.len().to_string());
+                text.push(TOKEN_END);
+                tokens.push((node, raw.to_owned()));
+            },
+            None => text.push_str(raw),
+        }
+        rest = &rest[start + raw.len()..];
+    }
+    text.push_str(rest);
+
+    (text, tokens)
+}
+
+/// Convert simple_ast's tree, putting tokens back in place of their placeholders
+fn convert(nodes: &[Rc<RwLock<AstNode>>], tokens: &[Token]) -> Vec<MarkdownNode> {
+    let mut out = Vec::new();
+    // Adjacent text is joined so a placeholder is never split between nodes
+    let mut text = String::new();
+
+    for node in nodes {
+        let node = node.read().unwrap();
+        let converted = match &*node {
+            AstNode::Text(string) => {
+                text.push_str(string);
+                continue;
+            },
+            AstNode::Bold(styles) => MarkdownNode::Bold(convert(styles, tokens)),
+            AstNode::Italic(styles) => MarkdownNode::Italic(convert(styles, tokens)),
+            AstNode::Underline(styles) => MarkdownNode::Underline(convert(styles, tokens)),
+            AstNode::Strikethrough(styles) => MarkdownNode::Strikethrough(convert(styles, tokens)),
+            AstNode::Spoiler(styles) => MarkdownNode::Spoiler(convert(styles, tokens)),
+            AstNode::BlockQuote(styles) => MarkdownNode::BlockQuote(convert(styles, tokens)),
+            AstNode::SingleBlockQuote(styles) => {
+                MarkdownNode::SingleBlockQuote(convert(styles, tokens))
+            },
+            // Code is shown as written
+            AstNode::InlineCode(code) => MarkdownNode::InlineCode(restore(code, tokens)),
+            AstNode::Code(language, code) => {
+                MarkdownNode::Code(language.to_string(), restore(code, tokens))
+            },
+        };
+        expand_text(&mut out, &std::mem::take(&mut text), tokens);
+        out.push(converted);
+    }
+    expand_text(&mut out, &text, tokens);
+
+    out
+}
+
+/// Split text on its placeholders, passing each token and the text between them to `f`
+fn split_placeholders<'a>(
+    text: &'a str,
+    tokens: &'a [Token],
+    mut f: impl FnMut(&'a str, Option<&'a Token>),
+) {
+    let mut rest = text;
+    while let Some(start) = rest.find(TOKEN_START) {
+        let after = &rest[start + TOKEN_START.len_utf8()..];
+        let token = after.find(TOKEN_END).and_then(|end| {
+            let token = after[..end]
+                .parse::<usize>()
+                .ok()
+                .and_then(|i| tokens.get(i))?;
+            Some((token, &after[end + TOKEN_END.len_utf8()..]))
+        });
+        match token {
+            Some((token, after)) => {
+                f(&rest[..start], Some(token));
+                rest = after;
+            },
+            None => {
+                f(&rest[..start + TOKEN_START.len_utf8()], None);
+                rest = after;
+            },
+        }
+    }
+    f(rest, None);
+}
+
+fn expand_text(out: &mut Vec<MarkdownNode>, text: &str, tokens: &[Token]) {
+    let mut pending = String::new();
+    split_placeholders(text, tokens, |text, token| {
+        pending.push_str(text);
+        if let Some((node, _)) = token {
+            if !pending.is_empty() {
+                out.push(MarkdownNode::Text(std::mem::take(&mut pending)));
+            }
+            out.push(node.clone());
+        }
+    });
+    if !pending.is_empty() {
+        out.push(MarkdownNode::Text(pending));
+    }
+}
+
+fn restore(text: &str, tokens: &[Token]) -> String {
+    let mut out = String::new();
+    split_placeholders(text, tokens, |text, token| {
+        out.push_str(text);
+        if let Some((_, raw)) = token {
+            out.push_str(raw);
+        }
+    });
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse_markdown, MarkdownNode::*};
+
+    fn text(s: &str) -> super::MarkdownNode {
+        Text(s.to_owned())
+    }
+
+    #[test]
+    fn autolinks_are_not_styled() {
+        assert_eq!(
+            parse_markdown("see https://example.com/a_b_c_d, ok"),
+            vec![
+                text("see "),
+                Link("https://example.com/a_b_c_d".to_owned()),
+                text(", ok")
+            ]
+        );
+        assert_eq!(
+            parse_markdown("**https://example.com**"),
+            vec![Bold(vec![Link("https://example.com".to_owned())])]
+        );
+    }
+
+    #[test]
+    fn suppressed_and_masked_links() {
+        assert_eq!(
+            parse_markdown("<https://example.com>"),
+            vec![SuppressedLink("https://example.com".to_owned())]
+        );
+        assert_eq!(
+            parse_markdown("read [the docs](https://example.com/docs)"),
+            vec![
+                text("read "),
+                MaskedLink {
+                    text: "the docs".to_owned(),
+                    url: "https://example.com/docs".to_owned()
+                }
+            ]
+        );
+    }
+
+    #[test]
+    fn timestamps_and_mentions() {
+        assert_eq!(
+            parse_markdown("<t:1618953630:R> <t:0>"),
+            vec![
+                Timestamp(1_618_953_630, Some('R')),
+                text(" "),
+                Timestamp(0, None)
+            ]
+        );
+        assert_eq!(
+            parse_markdown("<@1> <@!2> <@&3> <#4> @here"),
+            vec![
+                UserMention(1),
+                text(" "),
+                UserMention(2),
+                text(" "),
+                RoleMention(3),
+                text(" "),
+                ChannelMention(4),
+                text(" "),
+                EveryoneMention("here".to_owned())
+            ]
+        );
+    }
+
+    #[test]
+    fn everyone_inside_words_is_text() {
+        assert_eq!(
+            parse_markdown("mail@here.com foo@everyone @@here"),
+            vec![text("mail@here.com foo@everyone @@here")]
+        );
+        assert_eq!(
+            parse_markdown("(@everyone)"),
+            vec![text("("), EveryoneMention("everyone".to_owned()), text(")")]
+        );
+    }
+
+    #[test]
+    fn escaped_tokens_are_text() {
+        assert_eq!(parse_markdown(r"\<@1>"), vec![text("<@1>")]);
+        assert_eq!(parse_markdown(r"\@everyone"), vec![text("@everyone")]);
+        assert_eq!(parse_markdown(r"\\<@1>"), vec![text("\\"), UserMention(1)]);
+        assert_eq!(
+            parse_markdown(r"\<https://example.com>"),
+            vec![text("<"), Link("https://example.com".to_owned()), text(">")]
+        );
+    }
+
+    #[test]
+    fn nested_spoilers_keep_their_styles() {
+        assert_eq!(
+            parse_markdown("||**a** <@1>||"),
+            vec![Spoiler(vec![
+                Bold(vec![text("a")]),
+                text(" "),
+                UserMention(1)
+            ])]
+        );
+    }
+
+    #[test]
+    fn code_is_left_as_written() {
+        assert_eq!(
+            parse_markdown("`<@1> https://example.com`"),
+            vec![InlineCode("<@1> https://example.com".to_owned())]
+        );
+    }
+
+    #[test]
+    fn masked_link_urls_keep_balanced_parentheses() {
+        assert_eq!(
+            parse_markdown("[Rust](https://en.wikipedia.org/wiki/Rust_(programming_language))."),
+            vec![
+                MaskedLink {
+                    text: "Rust".to_owned(),
+                    url: "https://en.wikipedia.org/wiki/Rust_(programming_language)".to_owned()
+                },
+                text(".")
+            ]
+        );
+    }
+
+    #[test]
+    fn headers() {
+        assert_eq!(
+            parse_markdown("# Title\n### **Small**"),
+            vec![
+                Header(1, vec![text("Title")]),
+                text("\n"),
+                Header(3, vec![Bold(vec![text("Small")])])
+            ]
+        );
+        assert_eq!(parse_markdown("#hashtag"), vec![text("#hashtag")]);
+        assert_eq!(parse_markdown("#### too deep"), vec![text("#### too deep")]);
+    }
+
+    #[test]
+    fn lists() {
+        assert_eq!(
+            parse_markdown("intro\n- one\n  * _two_\n3. three"),
+            vec![
+                text("intro\n"),
+                ListItem {
+                    depth: 0,
+                    number: None,
+                    content: vec![text("one")]
+                },
+                text("\n"),
+                ListItem {
+                    depth: 1,
+                    number: None,
+                    content: vec![Italic(vec![text("two")])]
+                },
+                text("\n"),
+                ListItem {
+                    depth: 0,
+                    number: Some(3),
+                    content: vec![text("three")]
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn code_blocks_hide_headers_and_lists() {
+        let nodes = parse_markdown("```\n# not a header\n- nor a list\n```");
+        assert!(matches!(nodes.as_slice(), [Code(..)]), "{:?}", nodes);
+    }
+}
@@ -0,0 +1,306 @@
+//! Sed style substitutions, `s/pattern/replacement/flags`, used to edit sent messages
+
+use simple_ast::regex::{Regex, Region, SearchOptions};
+
+/// Delimiters that may be used in place of `/`
+const DELIMITERS: &str = "/|#!@~";
+/// Characters with a meaning in patterns, an escaped delimiter among them stays escaped so it is
+/// matched literally
+const REGEX_SPECIAL: &str = "|#!@~.*+?()[]{}^$";
+
+pub struct SedExpr {
+    regex: Regex,
+    replacement: Vec<Part>,
+    /// Replace every match instead of only the first
+    global: bool,
+}
+
+#[derive(Debug, PartialEq)]
+enum Part {
+    Literal(String),
+    Group(usize),
+}
+
+/// A parsed expression before its pattern is compiled
+#[derive(Debug, PartialEq)]
+pub(crate) struct RawExpr {
+    pub pattern: String,
+    pub replacement: String,
+    pub flags: String,
+}
+
+impl std::fmt::Debug for SedExpr {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.debug_struct("SedExpr")
+            .field("replacement", &self.replacement)
+            .field("global", &self.global)
+            .finish()
+    }
+}
+
+/// Split `<d>pattern<d>replacement[<d>flags]` on its delimiter, the closing delimiter may only be
+/// left out when using `/`
+pub(crate) fn split(input: &str) -> Option<RawExpr> {
+    let mut chars = input.chars();
+    let delim = chars.next().filter(|c| DELIMITERS.contains(*c))?;
+    let rest = chars.as_str();
+
+    let (pattern, rest) = take_until(rest, delim, true)?;
+    let (replacement, rest) = match take_until(rest, delim, false) {
+        Some(found) => found,
+        None if delim == '/' => (unescape_replacement(rest, delim), ""),
+        None => return None,
+    };
+
+    if !rest.chars().all(|c| c == 'g' || c == 'i') {
+        return None;
+    }
+
+    Some(RawExpr {
+        pattern,
+        replacement,
+        flags: rest.to_owned(),
+    })
+}
+
+/// Take text up to the next unescaped delimiter, returning it and the text after the delimiter
+fn take_until(input: &str, delim: char, is_pattern: bool) -> Option<(String, &str)> {
+    let mut escaped = false;
+    for (i, c) in input.char_indices() {
+        if escaped {
+            escaped = false;
+        } else if c == '\\' {
+            escaped = true;
+        } else if c == delim {
+            let text = &input[..i];
+            let text = if is_pattern {
+                unescape_pattern(text, delim)
+            } else {
+                unescape_replacement(text, delim)
+            };
+            return Some((text, &input[i + delim.len_utf8()..]));
+        }
+    }
+    None
+}
+
+/// Turn `\<delim>` into the delimiter, keeping it escaped if it means something in a pattern
+fn unescape_pattern(pattern: &str, delim: char) -> String {
+    if REGEX_SPECIAL.contains(delim) {
+        return pattern.to_owned();
+    }
+    pattern.replace(&format!("\\{}", delim), &delim.to_string())
+}
+
+/// Replacements are kept escaped until they are parsed into parts, only the delimiter is unescaped
+fn unescape_replacement(replacement: &str, delim: char) -> String {
+    replacement.replace(&format!("\\{}", delim), &delim.to_string())
+}
+
+/// Parse a replacement, `&` and `\0` insert the whole match and `\1` to `\9` a capture group
+fn parse_replacement(replacement: &str) -> Vec<Part> {
+    let mut parts = Vec::new();
+    let mut literal = String::new();
+    let mut chars = replacement.chars();
+
+    while let Some(c) = chars.next() {
+        let group = match c {
+            '&' => Some(0),
+            '\\' => match chars.next() {
+                Some(d) if d.is_ascii_digit() => d.to_digit(10).map(|d| d as usize),
+                Some('n') => {
+                    literal.push('\n');
+                    None
+                },
+                Some(other) => {
+                    literal.push(other);
+                    None
+                },
+                None => {
+                    literal.push('\\');
+                    None
+                },
+            },
+            c => {
+                literal.push(c);
+                None
+            },
+        };
+        if let Some(group) = group {
+            if !literal.is_empty() {
+                parts.push(Part::Literal(std::mem::take(&mut literal)));
+            }
+            parts.push(Part::Group(group));
+        }
+    }
+    if !literal.is_empty() {
+        parts.push(Part::Literal(literal));
+    }
+    parts
+}
+
+impl SedExpr {
+    pub(crate) fn compile(raw: &RawExpr) -> Result<SedExpr, String> {
+        let pattern = if raw.flags.contains('i') {
+            format!("(?i){}", raw.pattern)
+        } else {
+            raw.pattern.clone()
+        };
+        let regex =
+            Regex::new(&pattern).map_err(|_| format!("invalid pattern \"{}\"", raw.pattern))?;
+
+        Ok(SedExpr {
+            regex,
+            replacement: parse_replacement(&raw.replacement),
+            global: raw.flags.contains('g'),
+        })
+    }
+
+    /// Apply the substitution, returns `None` if the pattern does not match
+    pub fn apply(&self, input: &str) -> Option<String> {
+        let mut output = String::new();
+        let mut offset = 0;
+        let mut matched = false;
+        // Where the previous match ended, an empty match right after it is skipped like sed does
+        let mut last_end = None;
+        let mut region = Region::new();
+
+        while offset <= input.len() {
+            // The whole message is searched from the offset, so `^`, `\b` and lookbehinds still
+            // see the text before it
+            let found = self.regex.search_with_options(
+                input,
+                offset,
+                input.len(),
+                SearchOptions::SEARCH_OPTION_NONE,
+                Some(&mut region),
+            );
+            let (start, end) = match found.and_then(|_| region.pos(0)) {
+                Some(pos) => pos,
+                None => break,
+            };
+
+            output.push_str(&input[offset..start]);
+            if start == end && last_end == Some(start) {
+                // Step over one character so the search moves forward
+                match input[start..].chars().next() {
+                    Some(c) => {
+                        output.push(c);
+                        offset = start + c.len_utf8();
+                        continue;
+                    },
+                    None => {
+                        offset = start;
+                        break;
+                    },
+                }
+            }
+
+            for part in &self.replacement {
+                match part {
+                    Part::Literal(text) => output.push_str(text),
+                    Part::Group(group) => {
+                        if let Some((start, end)) = region.pos(*group) {
+                            output.push_str(&input[start..end]);
+                        }
+                    },
+                }
+            }
+            matched = true;
+            offset = end;
+            last_end = Some(end);
+
+            if !self.global || (offset == input.len() && start == end) {
+                break;
+            }
+        }
+
+        if !matched {
+            return None;
+        }
+        output.push_str(&input[offset.min(input.len())..]);
+        Some(output)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{split, SedExpr};
+
+    fn sed(expr: &str, input: &str) -> Option<String> {
+        let raw = split(expr).expect("expression should parse");
+        SedExpr::compile(&raw).unwrap().apply(input)
+    }
+
+    #[test]
+    fn replaces_first_match_or_all_with_g() {
+        assert_eq!(sed("/o/0/", "foo boo").as_deref(), Some("f0o boo"));
+        assert_eq!(sed("/o/0/g", "foo boo").as_deref(), Some("f00 b00"));
+        assert_eq!(sed("/x/y/", "foo"), None);
+    }
+
+    #[test]
+    fn supports_regex_and_capture_groups() {
+        assert_eq!(
+            sed(r"/(\w+) (\w+)/\2 \1/", "hello world").as_deref(),
+            Some("world hello")
+        );
+        assert_eq!(sed(r"/\d+/[&]/g", "a1b22").as_deref(), Some("a[1]b[22]"));
+        assert_eq!(sed(r"/o/\&/", "foo").as_deref(), Some("f&o"));
+    }
+
+    #[test]
+    fn ignores_case_with_i() {
+        assert_eq!(
+            sed("/HELLO/bye/i", "Hello there").as_deref(),
+            Some("bye there")
+        );
+        assert_eq!(sed("/HELLO/bye/", "Hello there"), None);
+    }
+
+    #[test]
+    fn alternate_delimiters_and_escapes() {
+        assert_eq!(
+            sed("|/usr/bin|/opt/bin|", "at /usr/bin/rustc").as_deref(),
+            Some("at /opt/bin/rustc")
+        );
+        assert_eq!(sed(r"/a\/b/c/", "a/b").as_deref(), Some("c"));
+        assert_eq!(sed(r"#a\#b#c#", "a#b").as_deref(), Some("c"));
+        assert!(split("|a|b").is_none());
+        assert!(split("/a/b/xyz").is_none());
+        assert!(split("a/b/").is_none());
+    }
+
+    #[test]
+    fn edits_multi_line_messages() {
+        assert_eq!(
+            sed("/^-/*/g", "- one\n- two").as_deref(),
+            Some("* one\n* two")
+        );
+        assert_eq!(sed("/^a/b/g", "aaa").as_deref(), Some("baa"));
+        assert_eq!(sed(r"/one\ntwo/both/", "one\ntwo").as_deref(), Some("both"));
+    }
+
+    #[test]
+    fn global_matches_see_the_text_before_them() {
+        assert_eq!(sed(r"/\bo/0/g", "oo foo o").as_deref(), Some("0o foo 0"));
+        assert_eq!(sed(r"/(?<=b)b/c/g", "bbb").as_deref(), Some("bcc"));
+        assert_eq!(sed("/^a/b/g", "aa\naa").as_deref(), Some("ba\nba"));
+    }
+
+    #[test]
+    fn only_unambiguous_delimiters_are_accepted() {
+        for delim in &['|', '#', '!', '@', '~'] {
+            assert!(split(&format!("{0}a{0}b{0}", delim)).is_some());
+        }
+        for delim in &[':', ',', '=', '_', ';', '%'] {
+            assert!(split(&format!("{0}a{0}b{0}", delim)).is_none());
+        }
+    }
+
+    #[test]
+    fn empty_matches_move_forward() {
+        assert_eq!(sed("/x*/-/g", "ab").as_deref(), Some("-a-b-"));
+        assert_eq!(sed("/x*/-/g", "xa").as_deref(), Some("-a-"));
+    }
+}
@@ -1,4 +1,4 @@
-use crate::utils::BufferExt;
+use crate::{utils::BufferExt, weechat_utils::BufferHost};
 use serenity::model::id::{ChannelId, GuildId};
 use std::borrow::Cow;
 use weechat::{bar::BarItem, ConfigOption, Weechat};
@@ -8,80 +8,116 @@ pub struct BarHandles {
     _channel_name: BarItem<()>,
     _full_name: BarItem<()>,
     _typing_indicator: BarItem<()>,
+    _connection: BarItem<()>,
 }
 
 pub fn init(weechat: &Weechat) -> BarHandles {
     let _guild_name = weechat.new_bar_item(
         "buffer_guild_name",
-        |_, _, buffer| {
-            buffer
-                .get_localvar("guild_name")
-                .map(Cow::into_owned)
-                .unwrap_or_default()
-        },
+        |_, _, buffer| guild_name(&buffer),
         None,
     );
 
     let _channel_name = weechat.new_bar_item(
         "buffer_channel_name",
-        |_, _, buffer| {
-            buffer
-                .get_localvar("channel")
-                .map(Cow::into_owned)
-                .unwrap_or_default()
-        },
+        |_, _, buffer| channel_name(&buffer),
         None,
     );
 
     let _full_name = weechat.new_bar_item(
         "buffer_discord_full_name",
-        |_, _, buffer| {
-            let guild_name = buffer.get_localvar("guild_name");
-            let channel_name = buffer.get_localvar("channel");
-            match (guild_name, channel_name) {
-                // i don't think the second pattern is possible
-                (Some(name), None) | (None, Some(name)) => format!("{}", name),
-                (Some(guild_name), Some(channel_name)) => {
-                    format!("{}:{}", guild_name, channel_name)
-                },
-                (None, None) => String::new(),
-            }
-        },
+        |_, _, buffer| full_name(&buffer),
         None,
     );
 
     let _typing_indicator = weechat.new_bar_item(
         "discord_typing",
         |_, _, buffer| {
-            if let Some(channel_id) = buffer.channel_id() {
-                let weechat = buffer.get_weechat();
-                let config = &crate::upgrade_plugin(&weechat).config;
-                let max_users = config.user_typing_list_max.value() as usize;
-                let expanded = config.user_typing_list_expanded.value();
-                let guild_id = buffer.guild_id();
-
-                if expanded {
-                    expanded_typing_list(channel_id, guild_id, max_users)
-                } else {
-                    terse_typing_list(channel_id, guild_id, max_users)
-                }
-            } else {
-                "".into()
-            }
+            let weechat = buffer.get_weechat();
+            let config = &crate::upgrade_plugin(&weechat).config;
+            let max_users = config.user_typing_list_max.value() as usize;
+            let expanded = config.user_typing_list_expanded.value();
+            typing_indicator(&buffer, max_users, expanded)
         },
         None,
     );
 
+    let _connection = weechat.new_bar_item(
+        "discord_connection",
+        |_, _, buffer| connection(&buffer),
+        None,
+    );
+
     BarHandles {
         _guild_name,
         _channel_name,
         _full_name,
         _typing_indicator,
+        _connection,
+    }
+}
+
+fn guild_name(buffer: &impl BufferHost) -> String {
+    buffer
+        .get_localvar("guild_name")
+        .map(Cow::into_owned)
+        .unwrap_or_default()
+}
+
+fn channel_name(buffer: &impl BufferHost) -> String {
+    buffer
+        .get_localvar("channel")
+        .map(Cow::into_owned)
+        .unwrap_or_default()
+}
+
+fn full_name(buffer: &impl BufferHost) -> String {
+    let guild_name = buffer.get_localvar("guild_name");
+    let channel_name = buffer.get_localvar("channel");
+    match (guild_name, channel_name) {
+        // i don't think the second pattern is possible
+        (Some(name), None) | (None, Some(name)) => format!("{}", name),
+        (Some(guild_name), Some(channel_name)) => format!("{}:{}", guild_name, channel_name),
+        (None, None) => String::new(),
+    }
+}
+
+fn typing_indicator(buffer: &impl BufferHost, max_users: usize, expanded: bool) -> String {
+    if let Some(channel_id) = buffer.channel_id() {
+        let guild_id = buffer.guild_id();
+        let account = buffer.account();
+
+        if expanded {
+            expanded_typing_list(&account, channel_id, guild_id, max_users)
+        } else {
+            terse_typing_list(&account, channel_id, guild_id, max_users)
+        }
+    } else {
+        "".into()
     }
 }
 
-fn terse_typing_list(channel_id: ChannelId, guild_id: Option<GuildId>, max_names: usize) -> String {
-    let (head, has_more) = get_users_for_typing_list(channel_id, guild_id, max_names);
+fn connection(buffer: &impl BufferHost) -> String {
+    use crate::discord::ConnectionState::*;
+    match crate::discord::connection_state(&buffer.account()) {
+        Connecting => "discord: connecting".into(),
+        Connected => "discord: connected".into(),
+        Reconnecting { attempt, delay } => format!(
+            "discord: reconnecting in {}s (attempt {})",
+            delay.as_secs(),
+            attempt
+        ),
+        Disconnected => "".into(),
+    }
+}
+
+fn terse_typing_list(
+    account: &str,
+    channel_id: ChannelId,
+    guild_id: Option<GuildId>,
+    max_names: usize,
+) -> String {
+    let (head, has_more) = get_users_for_typing_list(account, channel_id, guild_id, max_names);
 
     let mut users = head.join(", ");
     if has_more {
@@ -95,11 +131,12 @@ fn terse_typing_list(channel_id: ChannelId, guild_id: Option<GuildId>, max_names
 }
 
 fn expanded_typing_list(
+    account: &str,
     channel_id: ChannelId,
     guild_id: Option<GuildId>,
     max_names: usize,
 ) -> String {
-    let (head, has_more) = get_users_for_typing_list(channel_id, guild_id, max_names);
+    let (head, has_more) = get_users_for_typing_list(account, channel_id, guild_id, max_names);
 
     if head.is_empty() {
         "".into()
@@ -118,6 +155,7 @@ fn expanded_typing_list(
 }
 
 fn get_users_for_typing_list(
+    account: &str,
     channel_id: ChannelId,
     guild_id: Option<GuildId>,
     max_names: usize,
@@ -126,7 +164,7 @@ fn get_users_for_typing_list(
         .lock()
         .entries
         .iter()
-        .filter(|e| e.guild_id == guild_id && e.channel_id == channel_id)
+        .filter(|e| e.account == account && e.guild_id == guild_id && e.channel_id == channel_id)
         .map(|e| e.user_name.clone())
         .collect::<Vec<_>>();
     users.dedup();
@@ -137,3 +175,63 @@ fn get_users_for_typing_list(
     };
     (head.to_vec(), has_more)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{connection, full_name, typing_indicator};
+    use crate::{
+        discord::{TypingEntry, TYPING_EVENTS},
+        weechat_utils::{host::fake::FakeBuffer, BufferHost},
+    };
+    use serenity::model::id::{ChannelId, GuildId, UserId};
+
+    fn typing(account: &str, channel: u64, user: u64, name: &str) -> TypingEntry {
+        TypingEntry {
+            account: account.to_owned(),
+            channel_id: ChannelId(channel),
+            guild_id: Some(GuildId(200)),
+            user: UserId(user),
+            user_name: name.to_owned(),
+            time: 0,
+        }
+    }
+
+    #[test]
+    fn full_name_joins_guild_and_channel() {
+        let buffer = FakeBuffer::default();
+        assert_eq!(full_name(&buffer), "");
+
+        buffer.set_localvar("guild_name", "Test Guild");
+        assert_eq!(full_name(&buffer), "Test Guild");
+
+        buffer.set_localvar("channel", "general");
+        assert_eq!(full_name(&buffer), "Test Guild:general");
+    }
+
+    #[test]
+    fn typing_indicator_lists_the_buffer_channel() {
+        TYPING_EVENTS.lock().entries.extend(vec![
+            typing("bar-items", 300, 101, "alice"),
+            typing("bar-items", 300, 102, "bob"),
+            typing("bar-items", 301, 103, "carol"),
+            typing("other", 300, 104, "dave"),
+        ]);
+
+        let buffer = FakeBuffer::default();
+        buffer.set_localvar("account", "bar-items");
+        buffer.set_localvar("guildid", "200");
+        buffer.set_localvar("channelid", "300");
+
+        assert_eq!(typing_indicator(&buffer, 5, false), "typing: alice, bob");
+        assert_eq!(typing_indicator(&buffer, 1, false), "typing: alice, ...");
+        assert_eq!(
+            typing_indicator(&buffer, 5, true),
+            "alice and bob are typing"
+        );
+        assert_eq!(
+            typing_indicator(&buffer, 1, true),
+            "Several people are typing..."
+        );
+        assert_eq!(connection(&buffer), "");
+    }
+}
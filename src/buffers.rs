@@ -1,9 +1,10 @@
 use crate::{
+    discord::{notify, threads::Thread},
     on_main,
     sync::on_main_blocking,
     utils,
     utils::{BufferExt, ChannelExt},
-    weechat_utils::{BufferManager, MessageManager},
+    weechat_utils::{BufferHost, BufferManager, MessageManager, WeechatHost},
     Discord,
 };
 use indexmap::IndexMap;
@@ -28,21 +29,45 @@ pub fn init(weechat: &Weechat) -> BufferManager {
     BufferManager::new(Weechat::from_ptr(weechat.as_ptr()))
 }
 
-pub fn create_buffers(ready_data: &Ready) {
-    let ctx = match crate::discord::get_ctx() {
-        Some(ctx) => ctx,
-        _ => return,
-    };
-    let current_user = ctx.cache.read().user.clone();
+/// The REST data guild buffers are created from, so buffer creation can be run against
+/// `mock_discord` as well as Discord
+pub trait GuildSource {
+    fn guilds(&self, user: &CurrentUser) -> Result<Vec<GuildInfo>, String>;
+    fn member(&self, guild: GuildId, user: UserId) -> Option<Member>;
+    fn channels(&self, guild: GuildId) -> Result<HashMap<ChannelId, GuildChannel>, String>;
+}
 
-    let guilds = match current_user.guilds(ctx) {
-        Ok(guilds) => guilds,
-        Err(e) => {
-            crate::plugin_print(&format!("Error getting user guilds: {:?}", e));
-            vec![]
-        },
-    };
-    let mut map: HashMap<_, _> = guilds.iter().map(|g| (g.id, g)).collect();
+impl GuildSource for Context {
+    fn guilds(&self, user: &CurrentUser) -> Result<Vec<GuildInfo>, String> {
+        user.guilds(self).map_err(|e| format!("{:?}", e))
+    }
+
+    fn member(&self, guild: GuildId, user: UserId) -> Option<Member> {
+        guild.member(self, user).ok()
+    }
+
+    fn channels(&self, guild: GuildId) -> Result<HashMap<ChannelId, GuildChannel>, String> {
+        guild.channels(self).map_err(|e| format!("{:?}", e))
+    }
+}
+
+/// A guild buffer and the channel buffers under it
+pub struct GuildLayout {
+    pub guild: GuildInfo,
+    /// The nick shown in the guild's channel buffers
+    pub nick: String,
+    /// Sorted by their position in the guild
+    pub channels: Vec<GuildChannel>,
+}
+
+/// The guilds of the current user in the order of their client, with their channels
+pub fn guild_layout(
+    source: &impl GuildSource,
+    current_user: &CurrentUser,
+    ready_data: &Ready,
+) -> Result<Vec<GuildLayout>, String> {
+    let guilds = source.guilds(current_user)?;
+    let mut map: HashMap<_, _> = guilds.into_iter().map(|g| (g.id, g)).collect();
 
     let mut sorted_guilds = VecDeque::new();
 
@@ -54,44 +79,67 @@ pub fn create_buffers(ready_data: &Ready) {
     }
 
     // Prepend any remaning guilds
-    for guild in map.values() {
+    for (_, guild) in map {
         sorted_guilds.push_front(guild);
     }
 
-    for guild in &sorted_guilds {
-        let guild_settings = ready_data.user_guild_settings.get(&guild.id.into());
-        let guild_muted;
-        let mut channel_muted = HashMap::new();
-        if let Some(guild_settings) = guild_settings {
-            guild_muted = guild_settings.muted;
-            for (channel_id, channel_override) in &guild_settings.channel_overrides {
-                channel_muted.insert(channel_id, channel_override.muted);
-            }
-        } else {
-            guild_muted = false;
-        }
-        create_guild_buffer(guild.id, &guild.name);
+    sorted_guilds
+        .into_iter()
+        .map(|guild| {
+            // TODO: Colors?
+            let nick = if let Some(current_member) = source.member(guild.id, current_user.id) {
+                format!("@{}", current_member.display_name())
+            } else {
+                format!("@{}", current_user.name)
+            };
+            let mut channels: Vec<_> = source
+                .channels(guild.id)?
+                .into_iter()
+                .map(|(_, c)| c)
+                .collect();
+            channels.sort_by_key(|g| g.position);
+
+            Ok(GuildLayout {
+                guild,
+                nick,
+                channels,
+            })
+        })
+        .collect()
+}
+
+pub fn create_buffers(account: &str, ready_data: &Ready) {
+    let ctx = match crate::discord::get_ctx(account) {
+        Some(ctx) => ctx,
+        _ => return,
+    };
+    let current_user = ctx.cache.read().user.clone();
+
+    let layout = match guild_layout(&*ctx, &current_user, ready_data) {
+        Ok(layout) => layout,
+        Err(e) => {
+            crate::plugin_print(&format!("Error getting guilds: {}", e));
+            vec![]
+        },
+    };
+
+    for GuildLayout {
+        guild,
+        nick,
+        channels,
+    } in &layout
+    {
+        create_guild_buffer(account, guild.id, &guild.name);
 
-        // TODO: Colors?
-        let nick = if let Ok(current_member) = guild.id.member(ctx, current_user.id) {
-            format!("@{}", current_member.display_name())
-        } else {
-            format!("@{}", current_user.name)
-        };
-        let channels = guild.id.channels(ctx).expect("Unable to fetch channels");
-        let mut channels = channels.values().collect::<Vec<_>>();
-        channels.sort_by_key(|g| g.position);
         for channel in channels {
-            let is_muted =
-                guild_muted || channel_muted.get(&channel.id).cloned().unwrap_or_default();
-            create_buffer_from_channel(&ctx.cache, &guild.name, &channel, &nick, is_muted);
+            create_buffer_from_channel(account, &ctx.cache, &guild.name, channel, nick);
         }
     }
 }
 
 // TODO: Merge these functions
-pub fn create_autojoin_buffers(_ready: &Ready) {
-    let ctx = match crate::discord::get_ctx() {
+pub fn create_autojoin_buffers(account: &str, _ready: &Ready) {
+    let ctx = match crate::discord::get_ctx(account) {
         Some(ctx) => ctx,
         _ => return,
     };
@@ -99,9 +147,21 @@ pub fn create_autojoin_buffers(_ready: &Ready) {
     let current_user = ctx.cache.read().user.clone();
 
     // TODO: Add sorting
-    let mut autojoin_items: Vec<_> = on_main_blocking(|weecord| weecord.config.autojoin_channels());
+    let mut autojoin_items: Vec<_> = on_main_blocking(|weecord| {
+        weecord
+            .config
+            .account(account)
+            .map(|account| account.autojoin_channels())
+            .unwrap_or_default()
+    });
 
-    let watched_items: Vec<_> = on_main_blocking(|weecord| weecord.config.watched_channels());
+    let watched_items: Vec<_> = on_main_blocking(|weecord| {
+        weecord
+            .config
+            .account(account)
+            .map(|account| account.watched_channels())
+            .unwrap_or_default()
+    });
 
     let watched_channels = utils::flatten_guilds(&ctx, &watched_items);
 
@@ -113,7 +173,7 @@ pub fn create_autojoin_buffers(_ready: &Ready) {
                 None => continue,
             };
             let last_msg = match channel
-                .to_channel_cached(ctx)
+                .to_channel_cached(&*ctx)
                 .and_then(|c| c.last_message())
             {
                 Some(msg) => msg,
@@ -127,7 +187,7 @@ pub fn create_autojoin_buffers(_ready: &Ready) {
     }
 
     for channel_id in cache.all_private_channels() {
-        let channel = match channel_id.to_channel_cached(&ctx) {
+        let channel = match channel_id.to_channel_cached(&*ctx) {
             Some(ch) => ch,
             None => continue,
         };
@@ -144,10 +204,11 @@ pub fn create_autojoin_buffers(_ready: &Ready) {
     // flatten guilds into channels
     let autojoin_channels = utils::flatten_guilds(&ctx, &autojoin_items);
 
-    create_buffers_from_flat_items(&ctx, &current_user, &autojoin_channels);
+    create_buffers_from_flat_items(account, &ctx, &current_user, &autojoin_channels);
 }
 
 pub fn create_buffers_from_flat_items(
+    account: &str,
     ctx: &Context,
     current_user: &CurrentUser,
     channels: &IndexMap<Option<GuildId>, Vec<ChannelId>>,
@@ -170,14 +231,13 @@ pub fn create_buffers_from_flat_items(
                 };
                 let nick = &nick;
 
-                create_guild_buffer(guild.id, &guild.name);
+                create_guild_buffer(account, guild.id, &guild.name);
 
                 parking_lot::RwLockReadGuard::unlock_fair(guild);
 
                 for channel in channels {
-                    // TODO: Muting
                     let () = on_main_blocking(move |_| {
-                        let ctx = match crate::discord::get_ctx() {
+                        let ctx = match crate::discord::get_ctx(account) {
                             Some(ctx) => ctx,
                             _ => return,
                         };
@@ -197,17 +257,17 @@ pub fn create_buffers_from_flat_items(
                         };
 
                         create_buffer_from_channel(
+                            account,
                             &ctx.cache,
                             &guild.name,
                             &channel.read(),
                             &nick,
-                            false,
                         );
                     });
                 }
             },
             (None, channels) => {
-                let ctx = match crate::discord::get_ctx() {
+                let ctx = match crate::discord::get_ctx(account) {
                     Some(ctx) => ctx,
                     _ => return,
                 };
@@ -216,28 +276,31 @@ pub fn create_buffers_from_flat_items(
 
                 for channel_id in channels {
                     let nick = format!("@{}", nick);
-                    let channel = if let Ok(channel) = channel_id.to_channel(ctx) {
+                    let channel = if let Ok(channel) = channel_id.to_channel(&*ctx) {
                         channel
                     } else {
                         crate::plugin_print("cache miss");
                         continue;
                     };
 
+                    let account = account.to_owned();
                     match channel {
                         channel @ Channel::Private(_) => on_main(move |weecord| {
-                            let ctx = match crate::discord::get_ctx() {
+                            let ctx = match crate::discord::get_ctx(&account) {
                                 Some(ctx) => ctx,
                                 _ => return,
                             };
-                            create_buffer_from_dm(&ctx.cache, weecord, channel, &nick, false);
+                            create_buffer_from_dm(
+                                &account, &ctx.cache, weecord, channel, &nick, false,
+                            );
                         }),
 
                         channel @ Channel::Group(_) => on_main(move |weecord| {
-                            let ctx = match crate::discord::get_ctx() {
+                            let ctx = match crate::discord::get_ctx(&account) {
                                 Some(ctx) => ctx,
                                 _ => return,
                             };
-                            create_buffer_from_group(&ctx.cache, weecord, channel, &nick);
+                            create_buffer_from_group(&account, &ctx.cache, weecord, channel, &nick);
                         }),
                         _ => unreachable!(),
                     }
@@ -258,11 +321,12 @@ fn user_online(cache: &Cache, user_id: UserId) -> bool {
     }
 }
 
-pub fn create_guild_buffer(id: GuildId, name: &str) {
-    let guild_name_id = utils::buffer_id_for_guild(id);
+pub fn create_guild_buffer(account: &str, id: GuildId, name: &str) {
+    let guild_name_id = utils::buffer_id_for_guild(account, id);
     let () = on_main_blocking(move |weecord| {
         let buffer = weecord.buffer_manager.get_or_create_buffer(&guild_name_id);
 
+        buffer.set_localvar("account", account);
         buffer.set_localvar("guild_name", name);
         buffer.set_localvar("server", name);
         buffer.set_short_name(name);
@@ -272,11 +336,11 @@ pub fn create_guild_buffer(id: GuildId, name: &str) {
 }
 
 pub fn create_buffer_from_channel(
+    account: &str,
     cache: &CacheRwLock,
     guild_name: &str,
     channel: &GuildChannel,
     nick: &str,
-    muted: bool,
 ) {
     let current_user = cache.read().user.clone();
     if let Ok(perms) = channel.permissions_for_user(cache, current_user.id) {
@@ -293,19 +357,22 @@ pub fn create_buffer_from_channel(
         ChannelType::__Nonexhaustive => unreachable!(),
     };
 
-    let name_id = utils::buffer_id_for_channel(Some(channel.guild_id), channel.id);
+    let name_id = utils::buffer_id_for_channel(account, Some(channel.guild_id), channel.id);
     let has_unread = cache
         .read()
         .read_state
         .get(&channel.id)
         .map(|rs| rs.last_message_id)
         != channel.last_message_id;
+    let mute = notify::guild_settings(account, channel.guild_id).channel_mute(channel.id);
+    let muted = mute.is_muted();
 
     let () = on_main_blocking(|weecord| {
         let buffer = weecord.buffer_manager.get_or_create_buffer(&name_id);
 
         buffer.set_short_name(&channel.name);
 
+        buffer.set_localvar("account", account);
         buffer.set_localvar("channelid", &channel.id.0.to_string());
         buffer.set_localvar("guildid", &channel.guild_id.0.to_string());
         buffer.set_localvar("channel", &channel.name);
@@ -313,30 +380,127 @@ pub fn create_buffer_from_channel(
         buffer.set_localvar("server", guild_name);
         buffer.set_localvar("type", channel_type);
         buffer.set_localvar("nick", &nick);
+        notify::channel_notify(account, cache, channel.guild_id, channel.id).to_buffer(&**buffer);
         if has_unread && !muted {
             buffer.set_hotlist(HotlistPriority::Message);
         }
 
-        let mut title = if let Some(ref topic) = channel.topic {
-            if !topic.is_empty() {
-                format!("{} | {}", channel.name, topic)
-            } else {
-                channel.name.clone()
-            }
-        } else {
-            channel.name.clone()
-        };
+        buffer.set_title(&channel_title(channel, muted));
+        mute.to_buffer(&**buffer);
+    });
+}
 
-        if muted {
-            title += " (muted)";
-        }
-        buffer.set_title(&title);
-        buffer.set_localvar("muted", &(muted as u8).to_string());
+/// Create the buffer of a thread, its short name nests it under the parent channel
+pub fn create_buffer_from_thread(
+    account: &str,
+    guild_name: &str,
+    parent_name: &str,
+    thread: &Thread,
+    nick: &str,
+) {
+    let name_id = utils::buffer_id_for_channel(account, Some(thread.guild_id), thread.id);
+
+    let () = on_main_blocking(|weecord| {
+        let buffer = weecord.buffer_manager.get_or_create_buffer(&name_id);
+
+        buffer.set_short_name(&format!("{}/{}", parent_name, thread.name));
+
+        buffer.set_localvar("account", account);
+        buffer.set_localvar("channelid", &thread.id.0.to_string());
+        buffer.set_localvar("guildid", &thread.guild_id.0.to_string());
+        buffer.set_localvar("parentid", &thread.parent_id.0.to_string());
+        buffer.set_localvar("parent_name", parent_name);
+        buffer.set_localvar("channel", &thread.name);
+        buffer.set_localvar("guild_name", guild_name);
+        buffer.set_localvar("server", guild_name);
+        buffer.set_localvar("type", "channel");
+        buffer.set_localvar("nick", &nick);
+
+        buffer.set_title(&thread_title(parent_name, thread));
     });
 }
 
+/// Update the title of an open thread buffer, threads are renamed and archived
+pub fn refresh_thread(weecord: &Discord, account: &str, thread: &Thread) {
+    let name_id = utils::buffer_id_for_channel(account, Some(thread.guild_id), thread.id);
+    if let Some(buffer) = weecord.buffer_manager.get_buffer(&name_id) {
+        let parent_name = buffer
+            .get_localvar("parent_name")
+            .map(|name| name.into_owned())
+            .unwrap_or_default();
+        buffer.set_title(&thread_title(&parent_name, thread));
+    }
+}
+
+fn thread_title(parent_name: &str, thread: &Thread) -> String {
+    let mut title = format!("{} | thread in #{}", thread.name, parent_name);
+    if thread.archived {
+        title += " (archived)";
+    }
+    title
+}
+
+fn channel_title(channel: &GuildChannel, muted: bool) -> String {
+    let mut title = match channel.topic {
+        Some(ref topic) if !topic.is_empty() => format!("{} | {}", channel.name, topic),
+        _ => channel.name.clone(),
+    };
+
+    if muted {
+        title += " (muted)";
+    }
+    title
+}
+
+/// Apply changed notification and mute settings to the open channel buffers of a guild
+pub fn refresh_notify(weecord: &Discord, account: &str, guild: GuildId) {
+    let ctx = match crate::discord::get_ctx(account) {
+        Some(ctx) => ctx,
+        _ => return,
+    };
+
+    for buffer in weecord.buffer_manager.buffers() {
+        if buffer.account() != account || buffer.guild_id() != Some(guild) {
+            continue;
+        }
+        refresh_buffer_notify(account, &ctx.cache, guild, &buffer);
+    }
+}
+
+/// Timed mutes end without an event, so a buffer still marked as muted is refreshed once it is
+/// used after its mute is over
+pub fn expire_mute(account: &str, cache: &CacheRwLock, buffer: &MessageManager) {
+    let guild = match buffer.guild_id() {
+        Some(guild) => guild,
+        None => return,
+    };
+    if buffer.get_localvar("muted").as_deref() == Some("1") && !utils::buffer_is_muted(&**buffer) {
+        refresh_buffer_notify(account, cache, guild, buffer);
+    }
+}
+
+fn refresh_buffer_notify(
+    account: &str,
+    cache: &CacheRwLock,
+    guild: GuildId,
+    buffer: &MessageManager,
+) {
+    let channel = match buffer.channel_id() {
+        Some(channel) => channel,
+        None => return,
+    };
+    notify::channel_notify(account, cache, guild, channel).to_buffer(&**buffer);
+
+    let mute = notify::guild_settings(account, guild).channel_mute(channel);
+    mute.to_buffer(&**buffer);
+    if let Some(channel) = channel.to_channel_cached(cache).and_then(Channel::guild) {
+        buffer.set_title(&channel_title(&channel.read(), mute.is_muted()));
+    }
+}
+
 // TODO: Reduce code duplication
 pub fn create_buffer_from_dm(
+    account: &str,
     cache: &CacheRwLock,
     weecord: &crate::Discord,
     channel: Channel,
@@ -349,10 +513,11 @@ pub fn create_buffer_from_dm(
     };
     let channel = channel.read();
 
-    let name_id = utils::buffer_id_for_channel(None, channel.id);
+    let name_id = utils::buffer_id_for_channel(account, None, channel.id);
     let buffer = weecord.buffer_manager.get_or_create_buffer(&name_id);
 
     buffer.set_short_name(&channel.name());
+    buffer.set_localvar("account", account);
     buffer.set_localvar("channelid", &channel.id.0.to_string());
     buffer.set_localvar("nick", &nick);
 
@@ -377,6 +542,7 @@ pub fn create_buffer_from_dm(
 }
 
 pub fn create_buffer_from_group(
+    account: &str,
     cache: &CacheRwLock,
     weecord: &Discord,
     channel: Channel,
@@ -398,11 +564,12 @@ pub fn create_buffer_from_group(
             .join(", ")
     );
 
-    let name_id = utils::buffer_id_for_channel(None, channel.channel_id);
+    let name_id = utils::buffer_id_for_channel(account, None, channel.channel_id);
 
     let buffer = weecord.buffer_manager.get_or_create_buffer(&name_id);
 
     buffer.set_short_name(&channel.name());
+    buffer.set_localvar("account", account);
     buffer.set_localvar("channelid", &channel.channel_id.0.to_string());
     buffer.set_localvar("nick", &nick);
     buffer.set_title(&title);
@@ -419,12 +586,13 @@ pub fn create_buffer_from_group(
     }
 }
 
-pub fn create_pins_buffer(weecord: &Discord, channel: &Channel) {
+pub fn create_pins_buffer(weecord: &Discord, account: &str, channel: &Channel) {
     let buffer_name = format!("Pins.{}", channel.id().0);
 
     let buffer = weecord.buffer_manager.get_or_create_buffer(&buffer_name);
     buffer.switch_to();
 
+    buffer.set_localvar("account", account);
     buffer.set_title(&format!("Pinned messages in #{}", channel.name()));
     buffer.set_full_name(&format!("Pinned messages in ${}", channel.name()));
     buffer.set_short_name(&format!("#{} pins", channel.name()));
@@ -440,20 +608,21 @@ pub fn load_pin_buffer_history(buffer: &MessageManager) {
     buffer.set_history_loaded();
     buffer.clear();
     let buffer_name = buffer.get_name().to_string();
+    let account = buffer.account();
 
     std::thread::spawn(move || {
-        let ctx = match crate::discord::get_ctx() {
+        let ctx = match crate::discord::get_ctx(&account) {
             Some(ctx) => ctx,
             _ => return,
         };
 
-        let pins = match channel.pins(ctx) {
+        let pins = match channel.pins(&*ctx) {
             Ok(pins) => pins,
             Err(_) => return,
         };
 
         on_main(move |weecord| {
-            let ctx = match crate::discord::get_ctx() {
+            let ctx = match crate::discord::get_ctx(&account) {
                 Some(ctx) => ctx,
                 _ => return,
             };
@@ -493,71 +662,304 @@ pub fn load_history(
     buffer.set_history_loaded();
 
     let buffer_name = buffer.get_name().to_string();
+    let account = buffer.account();
+
+    let weechat = buffer.get_weechat();
+    let store = crate::upgrade_plugin(&weechat).message_store.clone();
 
     std::thread::spawn(move || {
-        let ctx = match crate::discord::get_ctx() {
+        // Draw the locally stored messages right away, only newer messages need to be fetched
+        let stored = store.load(&account, channel, fetch_count as usize);
+        let newest_stored = stored.last().map(|msg| msg.id);
+        if !stored.is_empty() {
+            let account = account.clone();
+            let buffer_name = buffer_name.clone();
+            on_main(move |weecord| {
+                let cache = crate::discord::get_ctx(&account)
+                    .map(|ctx| ctx.cache.clone())
+                    .unwrap_or_default();
+                if let Some(buf) = weecord.buffer_manager.get_buffer(&buffer_name) {
+                    buf.merge_messages(&cache, &stored);
+                }
+            });
+        }
+
+        let ctx = match crate::discord::get_ctx(&account) {
             Some(ctx) => ctx,
             _ => return,
         };
 
-        if let Ok(msgs) = channel.messages(ctx, |retriever| retriever.limit(fetch_count as u64)) {
-            on_main(move |weechat| {
-                let ctx = match crate::discord::get_ctx() {
-                    Some(ctx) => ctx,
-                    _ => return,
-                };
-                let mut unknown_users = HashSet::new();
-                let buf = match weechat.buffer_manager.get_buffer(&buffer_name) {
-                    Some(buf) => buf,
-                    None => return,
-                };
+        let Missed { msgs, gap } = match newest_stored {
+            Some(newest_stored) => {
+                fetch_messages_after(&ctx, channel, newest_stored, fetch_count as u64)
+            },
+            None => {
+                match channel.messages(&*ctx, |retriever| retriever.limit(fetch_count as u64)) {
+                    Ok(msgs) => Missed {
+                        msgs: msgs.into_iter().rev().collect(),
+                        gap: false,
+                    },
+                    Err(_) => return,
+                }
+            },
+        };
+
+        on_main(move |weechat| {
+            let ctx = match crate::discord::get_ctx(&account) {
+                Some(ctx) => ctx,
+                _ => return,
+            };
+            let mut unknown_users = HashSet::new();
+            let buf = match weechat.buffer_manager.get_buffer(&buffer_name) {
+                Some(buf) => buf,
+                None => return,
+            };
+
+            if gap {
+                weechat.message_store.clear(&account, channel);
+                print_gap(&buf);
+            }
+            weechat.message_store.insert(&account, &msgs);
 
-                if let Some(read_state) = ctx.cache.read().read_state.get(&channel) {
-                    let unread_in_page = msgs.iter().any(|m| m.id == read_state.last_message_id);
-
-                    if unread_in_page {
-                        let mut backlog = true;
-                        for msg in msgs.into_iter().rev() {
-                            unknown_users.extend(buf.add_message(&ctx.cache, &msg, false));
-
-                            if backlog {
-                                buf.mark_read();
-                                buf.clear_hotlist();
-                            }
-                            if msg.id == read_state.last_message_id {
-                                backlog = false;
-                            }
+            // Messages may have arrived while fetching, merging skips any already printed
+            if let Some(read_state) = ctx.cache.read().read_state.get(&channel) {
+                let unread_in_page = msgs.iter().any(|m| m.id == read_state.last_message_id);
+
+                if unread_in_page {
+                    let mut backlog = true;
+                    for msg in msgs {
+                        unknown_users.extend(buf.merge_messages(&ctx.cache, &[msg.clone()]));
+
+                        if backlog {
+                            buf.mark_read();
+                            buf.clear_hotlist();
                         }
-                    } else {
-                        buf.mark_read();
-                        buf.clear_hotlist();
-                        for msg in msgs.into_iter().rev() {
-                            unknown_users.extend(buf.add_message(&ctx.cache, &msg, false));
+                        if msg.id == read_state.last_message_id {
+                            backlog = false;
                         }
                     }
                 } else {
-                    for msg in msgs.into_iter().rev() {
-                        unknown_users.extend(buf.add_message(&ctx.cache, &msg, false));
-                    }
+                    buf.mark_read();
+                    buf.clear_hotlist();
+                    unknown_users.extend(buf.merge_messages(&ctx.cache, &msgs));
                 }
-                if let Some(guild) = guild {
-                    let msg = json::object! {
-                        "op" =>  OpCode::GetGuildMembers.num(),
-                        "d" => json::object! {
-                            "guild_id" => guild.0.to_string(),
-                            "user_ids" => (unknown_users.iter().map(|id| id.to_string())).collect::<Vec<_>>(),
-                            "nonce" => channel.0.to_string(),
-                        }
-                    };
-                    ctx.shard
-                        .websocket_message(gateway::Message::Text(msg.to_string()));
-                }
-                let _ = completion_sender.send(());
-            });
-        }
+            } else {
+                unknown_users.extend(buf.merge_messages(&ctx.cache, &msgs));
+            }
+            if let Some(guild) = guild {
+                let msg = json::object! {
+                    "op" =>  OpCode::GetGuildMembers.num(),
+                    "d" => json::object! {
+                        "guild_id" => guild.0.to_string(),
+                        "user_ids" => (unknown_users.iter().map(|id| id.to_string())).collect::<Vec<_>>(),
+                        "nonce" => channel.0.to_string(),
+                    }
+                };
+                ctx.shard
+                    .websocket_message(gateway::Message::Text(msg.to_string()));
+            }
+            let _ = completion_sender.send(());
+        });
+    });
+}
+
+/// Load the page of messages before the oldest message in a buffer, calling this repeatedly walks
+/// further back through the history
+pub fn load_older_history(buffer: &MessageManager, fetch_count: i32) {
+    let channel = if let Some(channel) = buffer.channel_id() {
+        channel
+    } else {
+        return;
+    };
+    let oldest = match buffer.first_message_id() {
+        Some(oldest) => oldest,
+        None => {
+            buffer.print("No history loaded yet, use /discord rehistory");
+            return;
+        },
+    };
+
+    let buffer_name = buffer.get_name().to_string();
+    let account = buffer.account();
+
+    std::thread::spawn(move || {
+        let ctx = match crate::discord::get_ctx(&account) {
+            Some(ctx) => ctx,
+            _ => return,
+        };
+
+        let mut msgs = match channel.messages(&*ctx, |retriever| {
+            retriever.before(oldest).limit(fetch_count as u64)
+        }) {
+            Ok(msgs) => msgs,
+            Err(_) => return,
+        };
+        msgs.sort_by_key(|msg| msg.id);
+
+        on_main(move |weecord| {
+            let ctx = match crate::discord::get_ctx(&account) {
+                Some(ctx) => ctx,
+                _ => return,
+            };
+            let buf = match weecord.buffer_manager.get_buffer(&buffer_name) {
+                Some(buf) => buf,
+                None => return,
+            };
+
+            if msgs.is_empty() {
+                buf.print("No older messages");
+                return;
+            }
+            weecord.message_store.insert(&account, &msgs);
+            buf.prepend_messages(&ctx.cache, &msgs);
+        });
+    });
+}
+
+/// Replace the history of a buffer with the messages surrounding a message
+pub fn load_history_around(buffer: &MessageManager, message: MessageId, fetch_count: i32) {
+    let channel = if let Some(channel) = buffer.channel_id() {
+        channel
+    } else {
+        return;
+    };
+
+    buffer.clear();
+    buffer.set_history_loaded();
+
+    let buffer_name = buffer.get_name().to_string();
+    let account = buffer.account();
+
+    std::thread::spawn(move || {
+        let ctx = match crate::discord::get_ctx(&account) {
+            Some(ctx) => ctx,
+            _ => return,
+        };
+
+        let mut msgs = match channel.messages(&*ctx, |retriever| {
+            retriever.around(message).limit(fetch_count as u64)
+        }) {
+            Ok(msgs) => msgs,
+            Err(_) => return,
+        };
+        msgs.sort_by_key(|msg| msg.id);
+
+        on_main(move |weecord| {
+            let ctx = match crate::discord::get_ctx(&account) {
+                Some(ctx) => ctx,
+                _ => return,
+            };
+            // Not stored, the message log only holds history contiguous with the newest messages
+            if let Some(buf) = weecord.buffer_manager.get_buffer(&buffer_name) {
+                buf.merge_messages(&ctx.cache, &msgs);
+            }
+        });
     });
 }
 
+/// Messages sent after a known message, oldest first
+struct Missed {
+    msgs: Vec<Message>,
+    /// More messages were sent than were fetched, `msgs` only holds the newest of them
+    gap: bool,
+}
+
+/// Fetch the messages sent after a message, giving up after `limit` messages and fetching only the
+/// newest `limit` instead
+fn fetch_messages_after(ctx: &Context, channel: ChannelId, after: MessageId, limit: u64) -> Missed {
+    let mut msgs = Vec::new();
+    let mut after = after;
+    loop {
+        let mut page = match channel.messages(ctx, |retriever| retriever.after(after).limit(100)) {
+            Ok(page) => page,
+            Err(_) => break,
+        };
+        page.sort_by_key(|msg| msg.id);
+        let full_page = page.len() == 100;
+        if let Some(newest) = page.last() {
+            after = newest.id;
+        }
+        msgs.extend(page);
+        if !full_page {
+            break;
+        }
+
+        if msgs.len() as u64 >= limit {
+            if let Ok(mut latest) = channel.messages(ctx, |retriever| retriever.limit(limit)) {
+                latest.sort_by_key(|msg| msg.id);
+                return Missed {
+                    msgs: latest,
+                    gap: true,
+                };
+            }
+            break;
+        }
+    }
+    Missed { msgs, gap: false }
+}
+
+/// Mark where messages that were not fetched are missing from a buffer
+fn print_gap(buffer: &MessageManager) {
+    let weechat = buffer.get_weechat();
+    buffer.print(&format!(
+        "{}	Some messages were skipped, use /discord rehistory to reload the history",
+        weechat.get_prefix("network")
+    ));
+}
+
+/// Fetch every message sent since the newest message in each open buffer, used after
+/// reconnecting so history has no gaps
+pub fn backfill_history(account: &str) {
+    let ctx = match crate::discord::get_ctx(account) {
+        Some(ctx) => ctx,
+        _ => return,
+    };
+
+    let (fetch_count, buffers): (i32, Vec<(String, ChannelId, MessageId)>) =
+        on_main_blocking(|weecord| {
+            let buffers = weecord
+                .buffer_manager
+                .buffers()
+                .iter()
+                .filter(|buffer| buffer.history_loaded() && buffer.account() == account)
+                .filter_map(|buffer| {
+                    Some((
+                        buffer.get_name().to_string(),
+                        buffer.channel_id()?,
+                        buffer.last_message_id()?,
+                    ))
+                })
+                .collect();
+            (weecord.config.message_fetch_count.value(), buffers)
+        });
+
+    for (buffer_name, channel, last_message) in buffers {
+        let Missed { msgs: missed, gap } =
+            fetch_messages_after(&ctx, channel, last_message, fetch_count as u64);
+        if missed.is_empty() {
+            continue;
+        }
+
+        let account = account.to_owned();
+        on_main(move |weecord| {
+            let ctx = match crate::discord::get_ctx(&account) {
+                Some(ctx) => ctx,
+                _ => return,
+            };
+            if gap {
+                weecord.message_store.clear(&account, channel);
+            }
+            weecord.message_store.insert(&account, &missed);
+            if let Some(buffer) = weecord.buffer_manager.get_buffer(&buffer_name) {
+                if gap {
+                    print_gap(&buffer);
+                }
+                buffer.merge_messages(&ctx.cache, &missed);
+            }
+        });
+    }
+}
+
 pub fn load_dm_nicks(buffer: &MessageManager, channel: &PrivateChannel) {
     let weechat = buffer.get_weechat();
     let use_presence = crate::upgrade_plugin(&weechat).config.use_presence.value();
@@ -568,7 +970,7 @@ pub fn load_dm_nicks(buffer: &MessageManager, channel: &PrivateChannel) {
         buffer.set_nicks_loaded();
         buffer.enable_nicklist();
 
-        let ctx = match crate::discord::get_ctx() {
+        let ctx = match crate::discord::get_ctx(&buffer.account()) {
             Some(ctx) => ctx,
             _ => return,
         };
@@ -593,7 +995,10 @@ pub fn load_dm_nicks(buffer: &MessageManager, channel: &PrivateChannel) {
                 color: &utils::nick_color(&weechat, &cache.user.name),
                 prefix: &utils::format_user_status_prefix(
                     &weechat,
-                    Some(*crate::command::LAST_STATUS.lock()),
+                    crate::command::LAST_STATUS
+                        .lock()
+                        .get(&buffer.account())
+                        .copied(),
                 ),
                 ..Default::default()
             },
@@ -624,40 +1029,51 @@ pub fn load_nicks(buffer: &Buffer) {
     buffer.set_nicks_loaded();
     buffer.enable_nicklist();
 
-    let sealed_buffer = buffer.seal();
+    let buffer_name = buffer.get_name().to_string();
+    let account = buffer.account();
 
     std::thread::spawn(move || {
-        let ctx = match crate::discord::get_ctx() {
+        let ctx = match crate::discord::get_ctx(&account) {
             Some(ctx) => ctx,
             _ => return,
         };
 
-        let guild = guild_id.to_guild_cached(ctx).expect("No guild cache item");
+        let guild = guild_id
+            .to_guild_cached(&*ctx)
+            .expect("No guild cache item");
+
+        // Large guilds don't send their members, their nicklist is drawn from the member list
+        if crate::discord::member_list::is_large(&guild.read()) {
+            return;
+        }
 
-        // TODO: What to do with more than 1000 members?
         // NOTE: using `guild.read().members` 403s and invalidates a users verification status
         let members: Vec<_> = guild.read().members.values().cloned().collect();
 
         drop(guild);
 
         let () = on_main_blocking(move |weechat| {
-            let ctx = match crate::discord::get_ctx() {
+            let ctx = match crate::discord::get_ctx(&account) {
                 Some(ctx) => ctx,
                 _ => return,
             };
 
             let use_presence = weechat.config.use_presence.value();
 
-            let buffer = sealed_buffer.unseal(&weechat);
-            let guild = guild_id.to_guild_cached(ctx).expect("No guild cache item");
+            let buffer = match weechat.buffer_manager.get_buffer(&buffer_name) {
+                Some(buffer) => buffer,
+                None => return,
+            };
+            let guild = guild_id
+                .to_guild_cached(&*ctx)
+                .expect("No guild cache item");
 
             let has_crown = guild_has_crown(&guild.read());
 
             for member in members {
                 add_member_to_nicklist(
-                    weechat,
-                    &ctx,
-                    &buffer,
+                    &ctx.cache,
+                    &*buffer,
                     channel_id,
                     &guild,
                     &member,
@@ -669,20 +1085,21 @@ pub fn load_nicks(buffer: &Buffer) {
     });
 }
 
-fn add_member_to_nicklist(
-    weechat: &Weechat,
-    ctx: &Context,
-    buffer: &Buffer,
+fn add_member_to_nicklist<B: BufferHost>(
+    cache: &CacheRwLock,
+    buffer: &MessageManager<B>,
     channel_id: ChannelId,
     guild: &Arc<RwLock<Guild>>,
     member: &Member,
     use_presence: bool,
     guild_has_crown: bool,
 ) {
+    let weechat = &buffer.host();
     let user = member.user.read();
+    buffer.unlist_member(user.id);
     // the current user does not seem to usually have a presence, assume they are online
     let online = if use_presence {
-        user_online(&*ctx.cache.read(), user.id)
+        user_online(&*cache.read(), user.id)
     } else {
         false
     };
@@ -694,22 +1111,21 @@ fn add_member_to_nicklist(
     }
 
     let role_name;
-    let role_color;
     // TODO: Change offline/online color somehow?
     if user.bot {
         role_name = BOT_GROUP_NAME.to_owned();
-        role_color = "gray".to_string();
+        buffer.nicklist_add_group(&role_name, "gray");
     } else if !online && use_presence {
         role_name = OFFLINE_GROUP_NAME.to_owned();
-        role_color = "grey".to_string();
-    } else if let Some((highest_hoisted, highest)) = utils::find_highest_roles(&ctx.cache, &member)
-    {
+        buffer.nicklist_add_group(&role_name, "grey");
+    } else if let Some((highest_hoisted, highest)) = utils::find_highest_roles(cache, &member) {
         role_name = format!(
             "{}|{}",
             99999 - highest_hoisted.position,
             highest_hoisted.name
         );
-        role_color = crate::utils::rgb_to_ansi(highest.colour).to_string();
+        let role_color = crate::utils::rgb_to_ansi(highest.colour).to_string();
+        buffer.add_role_group(highest_hoisted.id, &role_name, &role_color);
     } else {
         // Can't find a role, add user to generic bucket
         if use_presence {
@@ -718,60 +1134,78 @@ fn add_member_to_nicklist(
             } else {
                 role_name = OFFLINE_GROUP_NAME.to_owned();
             }
-            role_color = "grey".to_string();
+            buffer.nicklist_add_group(&role_name, "grey");
         } else {
-            buffer.add_nick(
-                weechat::NickArgs {
-                    name: member.display_name().as_ref(),
-                    color: &utils::nick_color(&weechat, member.display_name().as_ref()),
-                    ..Default::default()
-                },
-                None,
-            );
+            let name = unique_nick(buffer, &user, member.display_name().into_owned());
+            buffer.list_member(user.id, None, &name, &utils::nick_color(weechat, &name));
             return;
         }
     }
-    let group = match buffer.search_nicklist_group(&role_name) {
-        Some(group) => group,
-        None => buffer.add_group(&role_name, &role_color, true, None),
-    };
 
+    let nicklist_name = unique_nick(
+        buffer,
+        &user,
+        nicklist_name(weechat, &guild.read(), member, guild_has_crown),
+    );
+
+    buffer.list_member(
+        user.id,
+        Some(&role_name),
+        &nicklist_name,
+        &utils::nick_color(weechat, &nicklist_name),
+    );
+}
+
+/// WeeChat refuses a nick already in the buffer, members sharing a display name are told apart by
+/// their discriminator
+fn unique_nick<B: BufferHost>(buffer: &MessageManager<B>, user: &User, nick: String) -> String {
+    if buffer.nick_listed_by_other(&nick, user.id) {
+        format!("{}#{:04}", nick, user.discriminator)
+    } else {
+        nick
+    }
+}
+
+/// The name a member is listed under, the guild owner gets a crown
+fn nicklist_name(
+    weechat: &impl WeechatHost,
+    guild: &Guild,
+    member: &Member,
+    guild_has_crown: bool,
+) -> String {
     // TODO: Only show crown if there are no roles
-    let nicklist_name = if guild_has_crown && guild.read().owner_id == user.id {
-        format!("{} {}♛", member.display_name(), weechat.color("214"))
+    if guild_has_crown && guild.owner_id == member.user_id() {
+        crowned_name(weechat, &member.display_name())
     } else {
         member.display_name().into_owned()
-    };
+    }
+}
 
-    buffer.add_nick(
-        weechat::NickArgs {
-            name: nicklist_name.as_ref(),
-            color: &utils::nick_color(&weechat, &nicklist_name),
-            ..Default::default()
-        },
-        Some(&group),
-    );
+fn crowned_name(weechat: &impl WeechatHost, name: &str) -> String {
+    format!("{} {}♛", name, weechat.color("214"))
 }
 
-pub fn update_nick() {
-    let ctx = match crate::discord::get_ctx() {
+pub fn update_nick(account: &str) {
+    let ctx = match crate::discord::get_ctx(account) {
         Some(ctx) => ctx,
         _ => return,
     };
     let current_user = ctx.cache.read().user.clone();
 
-    for guild in current_user.guilds(ctx).expect("Unable to fetch guilds") {
+    for guild in current_user.guilds(&*ctx).expect("Unable to fetch guilds") {
         // TODO: Colors?
-        let nick = if let Ok(current_member) = guild.id.member(ctx, current_user.id) {
+        let nick = if let Ok(current_member) = guild.id.member(&*ctx, current_user.id) {
             format!("@{}", current_member.display_name())
         } else {
             format!("@{}", current_user.name)
         };
 
-        let channels = guild.id.channels(ctx).expect("Unable to fetch channels");
+        let channels = guild.id.channels(&*ctx).expect("Unable to fetch channels");
+        let account = account.to_owned();
         on_main(move |weechat| {
             for channel_id in channels.keys() {
-                let string_channel = utils::buffer_id_for_channel(Some(guild.id), *channel_id);
+                let string_channel =
+                    utils::buffer_id_for_channel(&account, Some(guild.id), *channel_id);
                 let nick = nick.to_owned();
                 if let Some(buffer) = weechat.buffer_search("weecord", &string_channel) {
                     buffer.set_localvar("nick", &nick);
@@ -782,53 +1216,104 @@ pub fn update_nick() {
     }
 }
 
-pub fn update_member_nick(old: &Option<Member>, new: &Member) {
-    let old_nick = if let Some(old) = old.as_ref().map(Member::display_name) {
-        old
-    } else {
-        // TODO: Rebuild entire nicklist?
-        return;
-    };
-    let new_nick = new.display_name();
-    let new = new.clone();
-    let guild_id = new.guild_id;
+/// Re-add a member to the nicklist of every open buffer of the guild, moving them to the group
+/// matching their current presence, roles and name
+pub fn refresh_member(account: &str, guild_id: GuildId, user_id: UserId) {
+    refresh_members(account, guild_id, move |_, _| vec![user_id]);
+}
 
-    if old_nick != new_nick {
-        let old_nick = old_nick.to_owned().to_string();
-        let ctx = match crate::discord::get_ctx() {
+/// Redraw the group of a role and every member with it or listed under it, used when the role is
+/// created, changed or deleted
+pub fn refresh_role_members(account: &str, guild_id: GuildId, role_id: RoleId) {
+    refresh_members(account, guild_id, move |buffer, guild| {
+        let mut members = buffer.remove_role_group(role_id);
+        members.extend(
+            guild
+                .members
+                .values()
+                .filter(|member| member.roles.contains(&role_id))
+                .map(|member| member.user_id()),
+        );
+        members.sort();
+        members.dedup();
+        members
+    });
+}
+
+/// Re-add members to the nicklists of a guild's buffers, `stale` removes whatever is out of date
+/// from a buffer and returns the members to add back
+fn refresh_members(
+    account: &str,
+    guild_id: GuildId,
+    stale: impl Fn(&MessageManager, &Guild) -> Vec<UserId> + Send + 'static,
+) {
+    let account = account.to_owned();
+    on_main(move |weecord| {
+        let ctx = match crate::discord::get_ctx(&account) {
             Some(ctx) => ctx,
             _ => return,
         };
+        let guild = match guild_id.to_guild_cached(&*ctx) {
+            Some(guild) => guild,
+            None => return,
+        };
+        if crate::discord::member_list::is_large(&guild.read()) {
+            return;
+        }
+        let has_crown = guild_has_crown(&guild.read());
+        let use_presence = weecord.config.use_presence.value();
 
-        let channels = guild_id.channels(ctx).expect("Unable to fetch channels");
-
-        on_main(move |weechat| {
-            let ctx = match crate::discord::get_ctx() {
-                Some(ctx) => ctx,
-                _ => return,
+        for buffer in guild_nicklist_buffers(weecord, &account, guild_id) {
+            let channel_id = match buffer.channel_id() {
+                Some(channel_id) => channel_id,
+                None => continue,
             };
-            for channel_id in channels.keys() {
-                let string_channel = utils::buffer_id_for_channel(Some(guild_id), *channel_id);
-                if let Some(buffer) = weechat.buffer_search("weecord", &string_channel) {
-                    if let Some(nick) = buffer.search_nick(&old_nick, None) {
-                        nick.remove();
-                        if let Some(guild) = guild_id.to_guild_cached(&ctx) {
-                            add_member_to_nicklist(
-                                weechat,
-                                &ctx,
-                                &buffer,
-                                *channel_id,
-                                &guild,
-                                &new,
-                                false,
-                                guild_has_crown(&guild.read()),
-                            );
-                        }
-                    }
-                }
+            let user_ids = stale(&*buffer, &guild.read());
+            for user_id in user_ids {
+                let member = match guild.read().members.get(&user_id) {
+                    Some(member) => member.clone(),
+                    None => continue,
+                };
+                add_member_to_nicklist(
+                    &ctx.cache,
+                    &*buffer,
+                    channel_id,
+                    &guild,
+                    &member,
+                    use_presence,
+                    has_crown,
+                );
             }
+        }
+    });
+}
+
+/// Remove a member that left the guild from every open buffer of the guild
+pub fn remove_member(account: &str, guild_id: GuildId, user_id: UserId) {
+    let account = account.to_owned();
+    on_main(move |weecord| {
+        for buffer in guild_nicklist_buffers(weecord, &account, guild_id) {
+            buffer.unlist_member(user_id);
+        }
+    });
+}
+
+/// Open buffers of a guild whose nicklist has been loaded
+fn guild_nicklist_buffers(
+    weecord: &Discord,
+    account: &str,
+    guild_id: GuildId,
+) -> Vec<Arc<MessageManager>> {
+    weecord
+        .buffer_manager
+        .buffers()
+        .into_iter()
+        .filter(|buffer| {
+            buffer.nicks_loaded()
+                && buffer.account() == account
+                && buffer.guild_id() == Some(guild_id)
         })
-    }
+        .collect()
 }
 
 fn guild_has_crown(guild: &Guild) -> bool {
@@ -839,3 +1324,251 @@ fn guild_has_crown(guild: &Guild) -> bool {
     }
     true
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        add_member_to_nicklist, guild_layout, GuildSource, BOT_GROUP_NAME, OFFLINE_GROUP_NAME,
+    };
+    use crate::{
+        discord::rest::Api,
+        weechat_utils::{host::fake::FakeBuffer, MessageManager},
+    };
+    use mock_discord::{Fixture, GatewayClient, MockDiscord, GUILD_ID};
+    use reqwest::Method;
+    use serenity::{
+        cache::CacheRwLock,
+        model::{event::ReadyEvent, prelude::*},
+        prelude::RwLock,
+    };
+    use std::{collections::HashMap, sync::Arc};
+
+    /// Fetches guilds from `mock_discord` the way serenity fetches them from Discord
+    struct MockSource<'a> {
+        api: Api<'a>,
+    }
+
+    impl MockSource<'_> {
+        fn get(&self, path: &str) -> Result<serde_json::Value, String> {
+            self.api
+                .request("mock-token", Method::GET, path, &[])?
+                .json()
+                .map_err(|e| e.to_string())
+        }
+    }
+
+    impl GuildSource for MockSource<'_> {
+        fn guilds(&self, _user: &CurrentUser) -> Result<Vec<GuildInfo>, String> {
+            serde_json::from_value(self.get("/users/@me/guilds")?).map_err(|e| e.to_string())
+        }
+
+        fn member(&self, guild: GuildId, user: UserId) -> Option<Member> {
+            let mut member = self
+                .get(&format!("/guilds/{}/members/{}", guild.0, user.0))
+                .ok()?;
+            // Like serenity, fill in the guild the member was fetched from
+            member["guild_id"] = guild.0.to_string().into();
+            serde_json::from_value(member).ok()
+        }
+
+        fn channels(&self, guild: GuildId) -> Result<HashMap<ChannelId, GuildChannel>, String> {
+            let channels = self.get(&format!("/guilds/{}/channels", guild.0))?;
+            let channels: Vec<GuildChannel> =
+                serde_json::from_value(channels).map_err(|e| e.to_string())?;
+            Ok(channels.into_iter().map(|c| (c.id, c)).collect())
+        }
+    }
+
+    fn user(id: u64, name: &str, bot: bool) -> serde_json::Value {
+        serde_json::json!({
+            "id": id.to_string(),
+            "username": name,
+            "discriminator": format!("{:04}", id % 10000),
+            "avatar": null,
+            "bot": bot,
+        })
+    }
+
+    fn member(user: serde_json::Value) -> Member {
+        serde_json::from_value(serde_json::json!({
+            "guild_id": "200",
+            "user": user,
+            "nick": null,
+            "roles": [],
+            "joined_at": "2020-01-01T00:00:00.000000+00:00",
+            "deaf": false,
+            "mute": false,
+        }))
+        .unwrap()
+    }
+
+    fn guild() -> Arc<RwLock<Guild>> {
+        let guild: Guild = serde_json::from_value(serde_json::json!({
+            "id": "200",
+            "name": "Test Guild",
+            "icon": null,
+            "splash": null,
+            "owner_id": "999",
+            "region": "us-east",
+            "afk_channel_id": null,
+            "afk_timeout": 300,
+            "verification_level": 0,
+            "default_message_notifications": 0,
+            "explicit_content_filter": 0,
+            "mfa_level": 0,
+            "application_id": null,
+            "system_channel_id": null,
+            "features": [],
+            "emojis": [],
+            "roles": [{
+                "id": "200",
+                "name": "@everyone",
+                "color": 0,
+                "hoist": false,
+                "position": 0,
+                "permissions": 0x400 | 0x800 | 0x10000,
+                "managed": false,
+                "mentionable": false,
+            }],
+            "member_count": 0,
+            "large": false,
+            "joined_at": "2020-01-01T00:00:00.000000+00:00",
+            "members": [],
+            "presences": [],
+            "voice_states": [],
+            "channels": [{
+                "id": "300",
+                "guild_id": "200",
+                "name": "general",
+                "type": 0,
+                "position": 0,
+                "permission_overwrites": [],
+                "nsfw": false,
+            }],
+        }))
+        .unwrap();
+        Arc::new(RwLock::new(guild))
+    }
+
+    #[test]
+    fn nicklist_groups_bots_and_roleless_members() {
+        let cache = CacheRwLock::default();
+        let buffer = MessageManager::new(FakeBuffer::default());
+        let guild = guild();
+
+        for member in &[
+            member(user(101, "alice", false)),
+            member(user(102, "robot", true)),
+        ] {
+            add_member_to_nicklist(
+                &cache,
+                &buffer,
+                ChannelId(300),
+                &guild,
+                member,
+                false,
+                false,
+            );
+        }
+
+        let nicklist = buffer.nicklist.borrow();
+        assert_eq!(nicklist.get(&None), Some(&vec!["alice".to_owned()]));
+        assert_eq!(
+            nicklist.get(&Some(BOT_GROUP_NAME.to_owned())),
+            Some(&vec!["robot".to_owned()])
+        );
+    }
+
+    #[test]
+    fn relisting_moves_and_unlisting_removes_members() {
+        let cache = CacheRwLock::default();
+        let buffer = MessageManager::new(FakeBuffer::default());
+        let guild = guild();
+        let alice = member(user(101, "alice", false));
+        let add = |use_presence| {
+            add_member_to_nicklist(
+                &cache,
+                &buffer,
+                ChannelId(300),
+                &guild,
+                &alice,
+                use_presence,
+                false,
+            )
+        };
+
+        add(false);
+        assert_eq!(
+            buffer.nicklist.borrow().get(&None),
+            Some(&vec!["alice".to_owned()])
+        );
+
+        // Without a presence alice is offline once presences are shown
+        add(true);
+        assert_eq!(
+            buffer.nicklist.borrow().get(&None),
+            Some(&Vec::<String>::new())
+        );
+        assert_eq!(
+            buffer
+                .nicklist
+                .borrow()
+                .get(&Some(OFFLINE_GROUP_NAME.to_owned())),
+            Some(&vec!["alice".to_owned()])
+        );
+
+        assert!(buffer.unlist_member(UserId(101)));
+        assert!(!buffer.unlist_member(UserId(101)));
+        assert!(buffer.nicklist.borrow().values().all(Vec::is_empty));
+    }
+
+    #[test]
+    fn members_sharing_a_name_get_distinct_nicks() {
+        let cache = CacheRwLock::default();
+        let buffer = MessageManager::new(FakeBuffer::default());
+        let guild = guild();
+
+        for id in &[101, 102] {
+            add_member_to_nicklist(
+                &cache,
+                &buffer,
+                ChannelId(300),
+                &guild,
+                &member(user(*id, "alex", false)),
+                false,
+                false,
+            );
+        }
+        assert_eq!(
+            buffer.nicklist.borrow().get(&None),
+            Some(&vec!["alex".to_owned(), "alex#0102".to_owned()])
+        );
+
+        buffer.unlist_member(UserId(101));
+        assert_eq!(
+            buffer.nicklist.borrow().get(&None),
+            Some(&vec!["alex#0102".to_owned()])
+        );
+        assert!(buffer.unlist_member(UserId(102)));
+    }
+
+    #[test]
+    fn lays_out_guild_buffers_from_the_mock() {
+        let server = MockDiscord::start(Fixture::default()).unwrap();
+        let api_url = server.api_url();
+        let source = MockSource {
+            api: Api::new(&api_url),
+        };
+        let mut gateway = GatewayClient::connect(&server);
+        let ready: ReadyEvent = serde_json::from_str(&gateway.identify().dump()).unwrap();
+
+        let layout = guild_layout(&source, &ready.ready.user, &ready.ready).unwrap();
+
+        assert_eq!(layout.len(), 1);
+        assert_eq!(layout[0].guild.id, GuildId(GUILD_ID));
+        assert_eq!(layout[0].guild.name, "Test Guild");
+        assert_eq!(layout[0].nick, "@weecord");
+        let channels: Vec<_> = layout[0].channels.iter().map(|c| c.name.as_str()).collect();
+        assert_eq!(channels, vec!["general", "voice"]);
+    }
+}
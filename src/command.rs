@@ -1,44 +1,63 @@
 use crate::{
-    buffers, discord, on_main_blocking, plugin_print, upgrade_plugin, utils,
+    buffers,
+    config::AccountConfig,
+    discord,
+    discord::{notify, threads},
+    on_main, on_main_blocking, plugin_print, upgrade_plugin, utils,
     utils::{BufferExt, ChannelExt, GuildOrChannel},
-    weechat_utils::MessageManager,
+    weechat_utils::{BufferHost, MessageManager, WeechatHost},
     Discord,
 };
 use lazy_static::lazy_static;
 use parking_lot::Mutex;
-use serenity::model::{gateway::Activity, user::OnlineStatus};
-use std::{borrow::Cow, collections::VecDeque, sync::Arc};
-use weechat::{Buffer, CommandHook, ConfigOption, ReturnCode, Weechat};
+use serenity::model::{
+    channel::{Channel, ReactionType},
+    gateway::Activity,
+    id::{ChannelId, GuildId},
+    user::OnlineStatus,
+};
+use std::{
+    borrow::Cow,
+    collections::{HashMap, VecDeque},
+    sync::Arc,
+};
+use weechat::{CommandHook, ConfigOption, ReturnCode, Weechat};
 
 lazy_static! {
-    // Tracks the last set status for use in setting the current game presence
-    pub static ref LAST_STATUS: Arc<Mutex<OnlineStatus>> = Arc::new(Mutex::new(OnlineStatus::Online));
+    // Tracks the last set status of each account for use in setting the current game presence
+    pub static ref LAST_STATUS: Arc<Mutex<HashMap<String, OnlineStatus>>> =
+        Arc::new(Mutex::new(HashMap::new()));
 }
 
 pub fn init(weechat: &Weechat) -> Vec<CommandHook<()>> {
-    let mut hooks = Vec::new();
-    hooks.push(weechat.hook_command(
-        CMD_DESCRIPTION,
-        |_, buffer, args| run_command(&buffer, &args.collect::<Vec<_>>().join(" ")),
-        None,
-    ));
-    hooks.push(weechat.hook_command(
-        weechat::CommandDescription {
-            name: "me",
-            description: "Send an italicized message to Discord.",
-            args: "",
-            args_description: "",
-            completion: "",
-        },
-        |_, buffer, args| {
-            run_command(
-                &buffer,
-                &("/discord me ".to_string() + &args.skip(1).collect::<Vec<_>>().join(" ")),
-            )
-        },
-        None,
-    ));
-    return hooks;
+    vec![
+        weechat.hook_command(
+            CMD_DESCRIPTION,
+            |_, buffer, args| {
+                let weecord = upgrade_plugin(&buffer.get_weechat());
+                run_command(weecord, &buffer, &args.collect::<Vec<_>>().join(" "))
+            },
+            None,
+        ),
+        weechat.hook_command(
+            weechat::CommandDescription {
+                name: "me",
+                description: "Send an italicized message to Discord.",
+                args: "",
+                args_description: "",
+                completion: "",
+            },
+            |_, buffer, args| {
+                let weecord = upgrade_plugin(&buffer.get_weechat());
+                run_command(
+                    weecord,
+                    &buffer,
+                    &("/discord me ".to_string() + &args.skip(1).collect::<Vec<_>>().join(" ")),
+                )
+            },
+            None,
+        ),
+    ]
 }
 
 #[derive(Clone, Debug)]
@@ -67,116 +86,253 @@ impl<'a> Args<'a> {
     }
 }
 
-fn run_command(buffer: &Buffer, cmd: &str) {
-    let weechat = buffer.get_weechat();
-    let weecord = upgrade_plugin(&weechat);
+/// Split a leading `-account <name>` from a command, returning the account and the remaining
+/// command
+fn split_account(cmd: &str) -> (Option<&str>, Cow<str>) {
+    let mut words = cmd.splitn(4, ' ');
+    let (base, flag, account) = (words.next(), words.next(), words.next());
+    match (base, flag, account) {
+        (Some(base), Some("-account"), Some(account)) => {
+            let rest = words.next().unwrap_or_default();
+            (Some(account), format!("{} {}", base, rest).into())
+        },
+        _ => (None, cmd.into()),
+    }
+}
 
-    let args = Args::from_cmd(cmd);
+/// Every `/discord` subcommand
+const COMMANDS: &[&str] = &[
+    "connect",
+    "disconnect",
+    "accounts",
+    "queue",
+    "irc-mode",
+    "discord-mode",
+    "token",
+    "autostart",
+    "noautostart",
+    "query",
+    "join",
+    "watch",
+    "nowatch",
+    "watched",
+    "autojoin",
+    "noautojoin",
+    "autojoined",
+    "status",
+    "pins",
+    "pinned",
+    "reactions",
+    "threads",
+    "thread",
+    "reveal",
+    "notify",
+    "mute",
+    "unmute",
+    "game",
+    "upload",
+    "me",
+    "tableflip",
+    "unflip",
+    "shrug",
+    "spoiler",
+    "more",
+    "search",
+    "jump",
+    "rehistory",
+];
+
+/// Resolve the account a command acts on and strip its `-account` flag, commands act on the
+/// account of the current buffer unless one is given. Returns `None` after printing why if the
+/// command can't be run.
+fn prepare(
+    weechat: &impl WeechatHost,
+    buffer: &impl BufferHost,
+    accounts: &[&str],
+    cmd: &str,
+) -> Option<(String, String)> {
+    let (account, cmd) = split_account(cmd);
+    let account = account
+        .map(ToOwned::to_owned)
+        .unwrap_or_else(|| buffer.account());
+    if !accounts.contains(&account.as_str()) {
+        weechat.plugin_print(&format!("Unknown account \"{}\"", account));
+        return None;
+    }
 
-    if args.base.is_empty() {
-        plugin_print("no action provided.");
-        plugin_print("see /help discord for more information");
-        return;
+    let base = Args::from_cmd(&cmd).base;
+    if base.is_empty() {
+        weechat.plugin_print("no action provided.");
+        weechat.plugin_print("see /help discord for more information");
+        return None;
+    }
+    if !COMMANDS.contains(&base) {
+        weechat.plugin_print("Unknown command");
+        return None;
     }
 
+    Some((account, cmd.into_owned()))
+}
+
+fn run_command(weecord: &Discord, buffer: &impl BufferHost, cmd: &str) {
+    let weechat = buffer.host();
+
+    let accounts: Vec<_> = weecord
+        .config
+        .accounts
+        .iter()
+        .map(|account| account.name.as_str())
+        .collect();
+    let (account, cmd) = match prepare(&weechat, buffer, &accounts, cmd) {
+        Some(prepared) => prepared,
+        None => return,
+    };
+    let account = match weecord.config.account(&account) {
+        Some(account) => account,
+        None => return,
+    };
+    let args = Args::from_cmd(&cmd);
+
     match args.base {
-        "connect" => weecord.connect(),
-        "disconnect" => disconnect(weecord),
-        "irc-mode" => irc_mode(weecord),
-        "discord-mode" => discord_mode(weecord),
-        "token" => token(weecord, &args),
-        "autostart" => autostart(weecord),
-        "noautostart" => noautostart(weecord),
+        "connect" => weecord.connect(&account.name),
+        "disconnect" => disconnect(&account.name),
+        "accounts" => accounts(weecord),
+        "queue" => queue(weecord),
+        "irc-mode" => irc_mode(weecord, account),
+        "discord-mode" => discord_mode(weecord, account),
+        "token" => token(account, &args),
+        "autostart" => autostart(account),
+        "noautostart" => noautostart(account),
         "query" => {
-            crate::hook::handle_query(&args);
+            crate::hook::handle_query(&account.name, &args);
         },
         "join" => {
-            join(weecord, &args, true);
+            join(weecord, &account.name, &args, true);
         },
-        "watch" => watch(weecord, &args),
-        "nowatch" => nowatch(weecord, &args),
-        "watched" => watched(weecord),
-        "autojoin" => autojoin(weecord, &args, buffer),
-        "noautojoin" => noautojoin(weecord, &args),
-        "autojoined" => autojoined(weecord),
-        "status" => status(&args),
+        "watch" => watch(account, &args),
+        "nowatch" => nowatch(account, &args),
+        "watched" => watched(weecord, account),
+        "autojoin" => autojoin(weecord, account, &args, buffer),
+        "noautojoin" => noautojoin(account, &args),
+        "autojoined" => autojoined(weecord, account),
+        "status" => status(&account.name, &args),
         "pins" | "pinned" => pins(weecord, buffer),
-        "game" => game(&args),
+        "reactions" => reactions(weecord, &account.name, &args, buffer),
+        "threads" => list_threads(&account.name, buffer),
+        "thread" => open_thread(&account.name, &args, buffer),
+        "reveal" => {
+            let buffer_name = buffer.name().to_string();
+            if let Some(manager) = weecord.buffer_manager.get_buffer(&buffer_name) {
+                reveal(weecord, &account.name, &args, buffer, &manager);
+            }
+        },
+        "notify" => notify(&account.name, &args, buffer),
+        "mute" => mute(&account.name, &args, buffer, true),
+        "unmute" => mute(&account.name, &args, buffer, false),
+        "game" => game(&account.name, &args),
         "upload" => upload(&args, buffer),
         "me" | "tableflip" | "unflip" | "shrug" | "spoiler" => {
             discord_fmt(args.base, args.rest, buffer)
         },
+        "more" => {
+            let buffer_name = buffer.name().to_string();
+            if let Some(buffer) = weecord.buffer_manager.get_buffer(&buffer_name) {
+                more(weecord, &args, &*buffer);
+            }
+        },
+        "search" => crate::search::search(&account.name, buffer, args.rest),
+        "jump" => match args.args.front() {
+            Some(n) => crate::search::jump(weecord, buffer, n),
+            None => plugin_print("jump requires a result number"),
+        },
         "rehistory" => {
-            let buffer_name = buffer.get_name().to_string();
+            let buffer_name = buffer.name().to_string();
             if let Some(buffer) = weecord.buffer_manager.get_buffer(&buffer_name) {
                 rehistory(weecord, &args, &*buffer);
             }
         },
-        _ => {
-            plugin_print("Unknown command");
-        },
+        // Anything else is rejected by `prepare`
+        _ => {},
     };
 }
 
-fn disconnect(_weechat: &Weechat) {
-    let mut discord = crate::discord::DISCORD.lock();
-    if discord.is_some() {
-        if let Some(discord) = discord.take() {
-            discord.shutdown();
-        };
+fn disconnect(account: &str) {
+    let discord = crate::discord::DISCORD.lock().remove(account);
+    if let Some(discord) = discord {
+        discord.shutdown();
         plugin_print("Disconnected");
     } else {
         plugin_print("Already disconnected");
     }
 }
 
-fn irc_mode(weechat: &Weechat) {
-    if crate::utils::get_irc_mode(weechat) {
+fn accounts(weecord: &Discord) {
+    weecord.print("");
+    weecord.print(&format!("Accounts: ({})", weecord.config.accounts.len()));
+    for account in &weecord.config.accounts {
+        let state = match discord::connection_state(&account.name) {
+            discord::ConnectionState::Connecting => "connecting",
+            discord::ConnectionState::Connected => "connected",
+            discord::ConnectionState::Reconnecting { .. } => "reconnecting",
+            discord::ConnectionState::Disconnected => "disconnected",
+        };
+        weecord.print(&format!("  {} ({})", account.name, state));
+    }
+}
+
+fn queue(weecord: &Discord) {
+    let stats = crate::sync::stats();
+    weecord.print("");
+    weecord.print(&format!(
+        "Main thread queue: {} waiting (peak {}), {} run in {} wakeups",
+        stats.depth, stats.peak_depth, stats.processed, stats.wakeups
+    ));
+}
+
+fn irc_mode(weechat: &Weechat, account: &AccountConfig) {
+    if crate::utils::get_irc_mode(weechat, &account.name) {
         plugin_print("irc-mode already enabled")
     } else {
-        let weecord = crate::upgrade_plugin(weechat);
-        let before = weecord.config.irc_mode.value();
-        let change = weecord.config.irc_mode.set(true);
+        let before = account.irc_mode.value();
+        let change = account.irc_mode.set(true);
         format_option_change("irc_mode", "true", Some(&before), change);
         plugin_print("irc-mode enabled")
     }
 }
 
-fn discord_mode(weechat: &Weechat) {
-    if !crate::utils::get_irc_mode(weechat) {
+fn discord_mode(weechat: &Weechat, account: &AccountConfig) {
+    if !crate::utils::get_irc_mode(weechat, &account.name) {
         plugin_print("discord-mode already enabled")
     } else {
-        let weecord = crate::upgrade_plugin(weechat);
-        let before = weecord.config.irc_mode.value();
-        let change = weecord.config.irc_mode.set(false);
+        let before = account.irc_mode.value();
+        let change = account.irc_mode.set(false);
         format_option_change("irc_mode", "false", Some(&before), change);
         plugin_print("discord-mode enabled")
     }
 }
 
-fn token(weechat: &Weechat, args: &Args) {
+fn token(account: &AccountConfig, args: &Args) {
     if args.args.is_empty() {
         plugin_print("token requires an argument");
     } else {
-        let weecord = crate::upgrade_plugin(weechat);
         let new_value = args.rest.trim_matches('"');
-        weecord.config.token.set(new_value);
+        account.token.set(new_value);
 
         plugin_print("Set Discord token");
     }
 }
 
-fn autostart(weechat: &Weechat) {
-    crate::upgrade_plugin(weechat).config.autostart.set(true);
+fn autostart(account: &AccountConfig) {
+    account.autostart.set(true);
     plugin_print("Discord will now load on startup");
 }
 
-fn noautostart(weechat: &Weechat) {
-    crate::upgrade_plugin(weechat).config.autostart.set(false);
+fn noautostart(account: &AccountConfig) {
+    account.autostart.set(false);
     plugin_print("Discord will not load on startup");
 }
 
-pub(crate) fn join(_weechat: &Weechat, args: &Args, verbose: bool) -> ReturnCode {
+pub(crate) fn join(_weechat: &Weechat, account: &str, args: &Args, verbose: bool) -> ReturnCode {
     if args.args.is_empty() && verbose {
         plugin_print("join requires an guild name and optional channel name");
         ReturnCode::Error
@@ -188,7 +344,7 @@ pub(crate) fn join(_weechat: &Weechat, args: &Args, verbose: bool) -> ReturnCode
         };
         let channel_name = args.next();
 
-        let ctx = match discord::get_ctx() {
+        let ctx = match discord::get_ctx(account) {
             Some(ctx) => ctx,
             _ => return ReturnCode::Error,
         };
@@ -198,14 +354,14 @@ pub(crate) fn join(_weechat: &Weechat, args: &Args, verbose: bool) -> ReturnCode
                 crate::utils::search_channel(&ctx.cache, guild_name, channel_name)
             {
                 let guild = guild.read();
-                buffers::create_guild_buffer(guild.id, &guild.name);
+                buffers::create_guild_buffer(account, guild.id, &guild.name);
                 // TODO: Add correct nick handling
                 buffers::create_buffer_from_channel(
+                    account,
                     &ctx.cache,
                     &guild.name,
                     &channel.read(),
                     &ctx.cache.read().user.name,
-                    false,
                 );
                 return ReturnCode::OkEat;
             }
@@ -216,7 +372,12 @@ pub(crate) fn join(_weechat: &Weechat, args: &Args, verbose: bool) -> ReturnCode
 
             let channels = utils::flatten_guilds(&ctx, &[GuildOrChannel::Guild(guild_id)]);
 
-            buffers::create_buffers_from_flat_items(&ctx, &ctx.cache.read().user, &channels);
+            buffers::create_buffers_from_flat_items(
+                account,
+                &ctx,
+                &ctx.cache.read().user,
+                &channels,
+            );
             return ReturnCode::OkEat;
         }
         if verbose {
@@ -227,8 +388,12 @@ pub(crate) fn join(_weechat: &Weechat, args: &Args, verbose: bool) -> ReturnCode
     }
 }
 
-fn resolve_channel_id(guild_name: &str, channel_name: Option<&str>) -> Option<String> {
-    let ctx = match discord::get_ctx() {
+fn resolve_channel_id(
+    account: &str,
+    guild_name: &str,
+    channel_name: Option<&str>,
+) -> Option<String> {
+    let ctx = match discord::get_ctx(account) {
         Some(ctx) => ctx,
         _ => return None,
     };
@@ -258,7 +423,7 @@ fn add_item(items: Cow<str>, new_item: String) -> String {
     items.push(&new_item);
     items.sort_unstable();
     items.dedup();
-    return items.join(",");
+    items.join(",")
 }
 
 fn remove_item(items: Cow<str>, old_item: String) -> String {
@@ -266,10 +431,10 @@ fn remove_item(items: Cow<str>, old_item: String) -> String {
         .split(',')
         .filter(|i| !i.is_empty() && i != &old_item.as_str())
         .collect();
-    return items.join(",");
+    items.join(",")
 }
 
-fn watch(weechat: &Weechat, args: &Args) {
+fn watch(account: &AccountConfig, args: &Args) {
     if args.args.is_empty() {
         plugin_print("watch requires a guild name and optional channel name");
         return;
@@ -281,15 +446,18 @@ fn watch(weechat: &Weechat, args: &Args) {
     };
     let channel_name = args.next();
 
-    let new_channel_id = match resolve_channel_id(guild_name, channel_name.cloned()) {
+    let new_channel_id = match resolve_channel_id(&account.name, guild_name, channel_name.cloned())
+    {
         Some(cid) => cid,
         None => return,
     };
 
-    let weecord = crate::upgrade_plugin(weechat);
-    let new_watched = add_item(weecord.config.watched_channels.value(), new_channel_id);
+    let new_watched = add_item(account.watched_channels.value(), new_channel_id);
+    let name = &account.name;
     let () = on_main_blocking(|weecord| {
-        weecord.config.watched_channels.set(&new_watched);
+        if let Some(account) = weecord.config.account(name) {
+            account.watched_channels.set(&new_watched);
+        }
     });
     if let Some(channel_name) = channel_name {
         plugin_print(&format!("Now watching {} in {}", guild_name, channel_name))
@@ -298,7 +466,7 @@ fn watch(weechat: &Weechat, args: &Args) {
     }
 }
 
-fn nowatch(weechat: &Weechat, args: &Args) {
+fn nowatch(account: &AccountConfig, args: &Args) {
     if args.args.is_empty() {
         plugin_print("nowatch requires a guild name and optional channel name");
         return;
@@ -310,15 +478,18 @@ fn nowatch(weechat: &Weechat, args: &Args) {
     };
     let channel_name = args.next();
 
-    let new_channel_id = match resolve_channel_id(guild_name, channel_name.cloned()) {
+    let new_channel_id = match resolve_channel_id(&account.name, guild_name, channel_name.cloned())
+    {
         Some(cid) => cid,
         None => return,
     };
 
-    let weecord = crate::upgrade_plugin(weechat);
-    let new_watched = remove_item(weecord.config.watched_channels.value(), new_channel_id);
+    let new_watched = remove_item(account.watched_channels.value(), new_channel_id);
+    let name = &account.name;
     let () = on_main_blocking(|weecord| {
-        weecord.config.watched_channels.set(&new_watched);
+        if let Some(account) = weecord.config.account(name) {
+            account.watched_channels.set(&new_watched);
+        }
     });
     if let Some(channel_name) = channel_name {
         plugin_print(&format!(
@@ -330,16 +501,16 @@ fn nowatch(weechat: &Weechat, args: &Args) {
     }
 }
 
-fn watched(weechat: &Weechat) {
+fn watched(weechat: &Weechat, account: &AccountConfig) {
     let mut channels = Vec::new();
     let mut guilds = Vec::new();
 
-    let ctx = match discord::get_ctx() {
+    let ctx = match discord::get_ctx(&account.name) {
         Some(ctx) => ctx,
         _ => return,
     };
 
-    for watched_item in crate::upgrade_plugin(weechat).config.watched_channels() {
+    for watched_item in account.watched_channels() {
         match watched_item {
             utils::GuildOrChannel::Guild(guild) => guilds.push(guild),
             utils::GuildOrChannel::Channel(guild, channel) => channels.push((guild, channel)),
@@ -355,17 +526,17 @@ fn watched(weechat: &Weechat) {
 
     weechat.print(&format!("Watched Servers: ({})", guilds.len()));
     for guild in guilds {
-        if let Some(guild) = guild.to_guild_cached(ctx) {
+        if let Some(guild) = guild.to_guild_cached(&*ctx) {
             weechat.print(&format!("  {}", guild.read().name));
         }
     }
 
     weechat.print(&format!("Watched Channels: ({})", channels.len()));
     for (guild, channel) in channels {
-        if let Ok(channel) = channel.to_channel(ctx) {
+        if let Ok(channel) = channel.to_channel(&*ctx) {
             let channel_name = channel.name();
             if let Some(guild) = guild {
-                let guild_name = if let Some(guild) = guild.to_guild_cached(&ctx) {
+                let guild_name = if let Some(guild) = guild.to_guild_cached(&*ctx) {
                     guild.read().name.to_owned()
                 } else {
                     guild.0.to_string()
@@ -380,7 +551,7 @@ fn watched(weechat: &Weechat) {
     }
 }
 
-fn autojoin(weechat: &Weechat, args: &Args, buffer: &Buffer) {
+fn autojoin(weecord: &Discord, account: &AccountConfig, args: &Args, buffer: &impl BufferHost) {
     if args.args.is_empty() {
         plugin_print("autojoin requires a guild name and optional channel name");
         return;
@@ -392,27 +563,31 @@ fn autojoin(weechat: &Weechat, args: &Args, buffer: &Buffer) {
     };
     let channel_name = opts.next();
 
-    let new_channel_id = match resolve_channel_id(guild_name, channel_name.cloned()) {
+    let new_channel_id = match resolve_channel_id(&account.name, guild_name, channel_name.cloned())
+    {
         Some(cid) => cid,
         None => return,
     };
 
-    let weecord = crate::upgrade_plugin(weechat);
-    let new_autojoined = add_item(weecord.config.autojoin_channels.value(), new_channel_id);
-    weecord.config.autojoin_channels.set(&new_autojoined);
+    let new_autojoined = add_item(account.autojoin_channels.value(), new_channel_id);
+    account.autojoin_channels.set(&new_autojoined);
 
     if let Some(channel_name) = channel_name {
         plugin_print(&format!(
             "Now autojoining {} in {}",
             guild_name, channel_name
         ));
-        run_command(buffer, &format!("/discord join {}", args.rest));
+        run_command(
+            weecord,
+            buffer,
+            &format!("/discord -account {} join {}", account.name, args.rest),
+        );
     } else {
         plugin_print(&format!("Now autojoining all channels in {}", guild_name))
     }
 }
 
-fn noautojoin(weechat: &Weechat, args: &Args) {
+fn noautojoin(account: &AccountConfig, args: &Args) {
     if args.args.is_empty() {
         plugin_print("noautojoin requires a guild name and optional channel name");
         return;
@@ -424,14 +599,13 @@ fn noautojoin(weechat: &Weechat, args: &Args) {
     };
     let channel_name = opts.next();
 
-    let channel_id = match resolve_channel_id(guild_name, channel_name.cloned()) {
+    let channel_id = match resolve_channel_id(&account.name, guild_name, channel_name.cloned()) {
         Some(cid) => cid,
         None => return,
     };
 
-    let weecord = crate::upgrade_plugin(weechat);
-    let new_autojoined = remove_item(weecord.config.autojoin_channels.value(), channel_id);
-    weecord.config.autojoin_channels.set(&new_autojoined);
+    let new_autojoined = remove_item(account.autojoin_channels.value(), channel_id);
+    account.autojoin_channels.set(&new_autojoined);
 
     if let Some(channel_name) = channel_name {
         plugin_print(&format!(
@@ -446,16 +620,16 @@ fn noautojoin(weechat: &Weechat, args: &Args) {
     }
 }
 
-fn autojoined(weechat: &Weechat) {
+fn autojoined(weechat: &Weechat, account: &AccountConfig) {
     let mut channels = Vec::new();
     let mut guilds = Vec::new();
 
-    let ctx = match discord::get_ctx() {
+    let ctx = match discord::get_ctx(&account.name) {
         Some(ctx) => ctx,
         _ => return,
     };
 
-    for autojoined_item in crate::upgrade_plugin(weechat).config.autojoin_channels() {
+    for autojoined_item in account.autojoin_channels() {
         match autojoined_item {
             utils::GuildOrChannel::Guild(guild) => guilds.push(guild),
             utils::GuildOrChannel::Channel(guild, channel) => channels.push((guild, channel)),
@@ -471,17 +645,17 @@ fn autojoined(weechat: &Weechat) {
 
     weechat.print(&format!("Autojoin Servers: ({})", guilds.len()));
     for guild in guilds {
-        if let Some(guild) = guild.to_guild_cached(ctx) {
+        if let Some(guild) = guild.to_guild_cached(&*ctx) {
             weechat.print(&format!("  {}", guild.read().name));
         }
     }
 
     weechat.print(&format!("Autojoin Channels: ({})", channels.len()));
     for (guild, channel) in channels {
-        if let Ok(channel) = channel.to_channel(ctx) {
+        if let Ok(channel) = channel.to_channel(&*ctx) {
             let channel_name = channel.name();
             if let Some(guild) = guild {
-                let guild_name = if let Some(guild) = guild.to_guild_cached(&ctx) {
+                let guild_name = if let Some(guild) = guild.to_guild_cached(&*ctx) {
                     guild.read().name.to_owned()
                 } else {
                     guild.0.to_string()
@@ -496,8 +670,8 @@ fn autojoined(weechat: &Weechat) {
     }
 }
 
-fn status(args: &Args) {
-    let ctx = match crate::discord::get_ctx() {
+fn status(account: &str, args: &Args) {
+    let ctx = match crate::discord::get_ctx(account) {
         Some(ctx) => ctx,
         _ => return,
     };
@@ -518,11 +692,11 @@ fn status(args: &Args) {
         },
     };
     ctx.set_presence(None, status);
-    *LAST_STATUS.lock() = status;
+    LAST_STATUS.lock().insert(account.to_owned(), status);
     plugin_print(&format!("Status set to {} {:#?}", status_str, status));
 }
 
-fn pins(weechat: &Discord, buffer: &Buffer) {
+fn pins(weechat: &Discord, buffer: &impl BufferHost) {
     let channel = buffer.channel_id();
 
     let channel_id = match channel {
@@ -530,22 +704,230 @@ fn pins(weechat: &Discord, buffer: &Buffer) {
         None => return,
     };
 
-    let ctx = match crate::discord::get_ctx() {
+    let account = buffer.account();
+    let ctx = match crate::discord::get_ctx(&account) {
         Some(ctx) => ctx,
         _ => return,
     };
 
-    let channel = match channel_id.to_channel_cached(ctx) {
+    let channel = match channel_id.to_channel_cached(&*ctx) {
         Some(ch) => ch,
         None => return,
     };
 
-    buffers::create_pins_buffer(weechat, &channel);
+    buffers::create_pins_buffer(weechat, &account, &channel);
     buffers::load_pin_buffer_history_for_id(channel.id());
 }
 
-fn game(args: &Args) {
-    let ctx = match crate::discord::get_ctx() {
+fn list_threads(account: &str, buffer: &impl BufferHost) {
+    let (guild, channel) = match (buffer.guild_id(), buffer.channel_id()) {
+        (Some(guild), Some(channel)) => (guild, channel),
+        _ => {
+            plugin_print("threads must be run from a guild channel buffer");
+            return;
+        },
+    };
+    let buffer_name = buffer.name().to_string();
+    let account = account.to_owned();
+
+    std::thread::spawn(move || {
+        let ctx = match discord::get_ctx(&account) {
+            Some(ctx) => ctx,
+            _ => return,
+        };
+        let api = discord::rest::Api::discord_v9();
+        match threads::fetch(&api, &ctx.http.token, guild, channel) {
+            Ok(fetched) => {
+                for thread in fetched {
+                    threads::insert(&account, thread);
+                }
+            },
+            Err(e) => {
+                plugin_print(&format!("Unable to fetch threads: {}", e));
+                return;
+            },
+        }
+
+        let channel_threads = threads::for_channel(&account, channel);
+        on_main(move |weecord| {
+            let buffer = match weecord.buffer_manager.get_buffer(&buffer_name) {
+                Some(buffer) => buffer,
+                None => return,
+            };
+            if channel_threads.is_empty() {
+                buffer.print("No threads");
+                return;
+            }
+            buffer.print("Threads:");
+            for thread in channel_threads {
+                buffer.print(&format!(
+                    "    {}{} ({})",
+                    thread.name,
+                    if thread.archived { " [archived]" } else { "" },
+                    thread.id.0
+                ));
+            }
+            buffer.print("Use /discord thread <name> to open a thread");
+        });
+    });
+}
+
+fn open_thread(account: &str, args: &Args, buffer: &impl BufferHost) {
+    if args.rest.is_empty() {
+        plugin_print("thread requires a thread name or id");
+        return;
+    }
+    let ctx = match discord::get_ctx(account) {
+        Some(ctx) => ctx,
+        _ => return,
+    };
+
+    // Prefer threads of the current channel, ids can name a thread of any channel
+    let target = args.rest.to_lowercase();
+    let thread = buffer
+        .channel_id()
+        .map(|channel| threads::for_channel(account, channel))
+        .unwrap_or_default()
+        .into_iter()
+        .find(|thread| thread.name.to_lowercase() == target)
+        .or_else(|| {
+            let id = target.parse().ok()?;
+            threads::get(account, ChannelId(id))
+        });
+    let thread = match thread {
+        Some(thread) => thread,
+        None => {
+            plugin_print("Unknown thread, use /discord threads to list them");
+            return;
+        },
+    };
+
+    let guild_name = match thread.guild_id.to_guild_cached(&*ctx) {
+        Some(guild) => guild.read().name.clone(),
+        None => return,
+    };
+    let parent_name = match thread
+        .parent_id
+        .to_channel_cached(&*ctx)
+        .and_then(Channel::guild)
+    {
+        Some(parent) => parent.read().name.clone(),
+        None => return,
+    };
+
+    let account = account.to_owned();
+    std::thread::spawn(move || {
+        let ctx = match discord::get_ctx(&account) {
+            Some(ctx) => ctx,
+            _ => return,
+        };
+        let api = discord::rest::Api::discord_v9();
+        if let Err(e) = threads::join(&api, &ctx.http.token, thread.id) {
+            plugin_print(&format!("Unable to join thread: {}", e));
+            return;
+        }
+
+        let nick = ctx.cache.read().user.name.clone();
+        buffers::create_buffer_from_thread(&account, &guild_name, &parent_name, &thread, &nick);
+        let buffer_name = utils::buffer_id_for_channel(&account, Some(thread.guild_id), thread.id);
+        on_main(move |weecord| {
+            if let Some(buffer) = weecord.buffer_manager.get_buffer(&buffer_name) {
+                buffer.switch_to();
+            }
+        });
+    });
+}
+
+fn reactions(weecord: &Discord, account: &str, args: &Args, buffer: &impl BufferHost) {
+    let (guild, channel) = match buffer.channel_id() {
+        Some(channel) => (buffer.guild_id(), channel),
+        None => {
+            plugin_print("reactions must be run from a channel buffer");
+            return;
+        },
+    };
+    let target = match parsing::parse_target(args.args.front().copied()) {
+        Some(target) => target,
+        None => {
+            plugin_print("reactions requires a message number or reference");
+            return;
+        },
+    };
+    // Messages printed in the buffer are found here, older ones are fetched on the worker thread
+    let printed = utils::find_target_message(weecord, buffer, target, None).map(|msg| msg.id);
+    let line = match target {
+        parsing::Target::Line(n) => Some(n),
+        parsing::Target::Ref(_) => None,
+    };
+    if let (None, parsing::Target::Ref(tag)) = (printed, target) {
+        plugin_print(&format!("No message is tagged {}", tag));
+        return;
+    }
+    let buffer_name = buffer.name().to_string();
+    let account = account.to_owned();
+
+    std::thread::spawn(move || {
+        let ctx = match discord::get_ctx(&account) {
+            Some(ctx) => ctx,
+            _ => return,
+        };
+        let msg = match (printed, line) {
+            (Some(id), _) => channel.message(&ctx.http, id),
+            (None, Some(n)) => utils::get_nth_message(&ctx, channel, n),
+            (None, None) => return,
+        };
+        let msg = match msg {
+            Ok(msg) => msg,
+            Err(e) => {
+                plugin_print(&format!("Unable to fetch message: {}", e));
+                return;
+            },
+        };
+
+        let mut lines = Vec::new();
+        for reaction in &msg.reactions {
+            let emoji = match &reaction.reaction_type {
+                ReactionType::Custom { name, .. } => {
+                    format!(":{}:", name.as_deref().unwrap_or("unknown"))
+                },
+                ReactionType::Unicode(unicode) => unicode.clone(),
+                _ => continue,
+            };
+            let users = msg
+                .reaction_users(&*ctx, reaction.reaction_type.clone(), Some(100), None)
+                .unwrap_or_default();
+            let names = users
+                .iter()
+                .map(|user| match guild {
+                    Some(guild) => user
+                        .nick_in(&*ctx, guild)
+                        .unwrap_or_else(|| user.name.clone()),
+                    None => user.name.clone(),
+                })
+                .collect::<Vec<_>>()
+                .join(", ");
+            lines.push(format!("    {} ({}): {}", emoji, reaction.count, names));
+        }
+
+        on_main(move |weecord| {
+            let buffer = match weecord.buffer_manager.get_buffer(&buffer_name) {
+                Some(buffer) => buffer,
+                None => return,
+            };
+            if lines.is_empty() {
+                buffer.print("No reactions");
+                return;
+            }
+            buffer.print("Reactions:");
+            for line in lines {
+                buffer.print(&line);
+            }
+        });
+    });
+}
+
+fn game(account: &str, args: &Args) {
+    let ctx = match crate::discord::get_ctx(account) {
         Some(ctx) => ctx,
         _ => return,
     };
@@ -569,10 +951,15 @@ fn game(args: &Args) {
         })
     };
 
-    ctx.set_presence(activity, *LAST_STATUS.lock());
+    let status = LAST_STATUS
+        .lock()
+        .get(account)
+        .copied()
+        .unwrap_or(OnlineStatus::Online);
+    ctx.set_presence(activity, status);
 }
 
-fn upload(args: &Args, buffer: &Buffer) {
+fn upload(args: &Args, buffer: &impl BufferHost) {
     if args.args.is_empty() {
         plugin_print("upload requires an argument");
     } else {
@@ -600,11 +987,11 @@ fn upload(args: &Args, buffer: &Buffer) {
         } else {
             return;
         };
-        let ctx = match crate::discord::get_ctx() {
+        let ctx = match crate::discord::get_ctx(&buffer.account()) {
             Some(ctx) => ctx,
             _ => return,
         };
-        match channel.send_files(ctx, vec![full], |m| m) {
+        match channel.send_files(&*ctx, vec![full], |m| m) {
             Ok(_) => plugin_print("File uploaded successfully"),
             Err(e) => {
                 if let serenity::Error::Model(serenity::model::ModelError::MessageTooLong(_)) = e {
@@ -643,7 +1030,7 @@ fn format_option_change<T: std::fmt::Display>(
     plugin_print(&msg);
 }
 
-fn discord_fmt(cmd: &str, msg: &str, buffer: &Buffer) {
+fn discord_fmt(cmd: &str, msg: &str, buffer: &impl BufferHost) {
     let msg = match cmd {
         "me" => format!("_{}_", msg),
         "tableflip" => format!("{} (╯°□°）╯︵ ┻━┻", msg),
@@ -659,7 +1046,7 @@ fn discord_fmt(cmd: &str, msg: &str, buffer: &Buffer) {
         return;
     };
 
-    let ctx = match crate::discord::get_ctx() {
+    let ctx = match crate::discord::get_ctx(&buffer.account()) {
         Some(ctx) => ctx,
         _ => return,
     };
@@ -677,6 +1064,150 @@ fn rehistory(weecord: &Discord, args: &Args, buffer: &MessageManager) {
     buffers::load_history(buffer, crossbeam_channel::unbounded().0, count);
 }
 
+fn notify(account: &str, args: &Args, buffer: &impl BufferHost) {
+    let level = match args
+        .args
+        .front()
+        .and_then(|level| notify::NotifyLevel::parse(level))
+    {
+        Some(level) => level,
+        None => {
+            plugin_print("notify requires one of all, mentions or none");
+            return;
+        },
+    };
+    let (guild, channel) = match settings_target(buffer, "notify") {
+        Some(target) => target,
+        None => return,
+    };
+    let account = account.to_owned();
+
+    std::thread::spawn(move || {
+        let ctx = match discord::get_ctx(&account) {
+            Some(ctx) => ctx,
+            _ => return,
+        };
+        if let Err(e) = notify::set_level(&ctx.http.token, &account, guild, channel, level) {
+            plugin_print(&format!("Unable to change notifications: {}", e));
+            return;
+        }
+        on_main(move |weecord| {
+            buffers::refresh_notify(weecord, &account, guild);
+            let scope = if channel.is_some() {
+                "this channel"
+            } else {
+                "this guild"
+            };
+            plugin_print(&format!(
+                "Notifications for {} set to {}",
+                scope,
+                level.name()
+            ));
+        });
+    });
+}
+
+fn mute(account: &str, args: &Args, buffer: &impl BufferHost, muted: bool) {
+    let duration = match args.args.front() {
+        Some(duration) if muted => match notify::parse_duration(duration) {
+            Some(duration) => Some(duration),
+            None => {
+                plugin_print("mute durations are a number followed by s, m, h, d or w");
+                return;
+            },
+        },
+        _ => None,
+    };
+    let duration_arg = args.args.front().copied().unwrap_or_default().to_owned();
+    let command = if muted { "mute" } else { "unmute" };
+    let (guild, channel) = match settings_target(buffer, command) {
+        Some(target) => target,
+        None => return,
+    };
+    let account = account.to_owned();
+
+    std::thread::spawn(move || {
+        let ctx = match discord::get_ctx(&account) {
+            Some(ctx) => ctx,
+            _ => return,
+        };
+        if let Err(e) = notify::set_mute(&ctx.http.token, &account, guild, channel, muted, duration)
+        {
+            plugin_print(&format!("Unable to {}: {}", command, e));
+            return;
+        }
+        let scope = if channel.is_some() {
+            "this channel"
+        } else {
+            "this guild"
+        };
+        // Titles of timed mutes are updated by `buffers::expire_mute` once they are over
+        on_main(move |weecord| {
+            buffers::refresh_notify(weecord, &account, guild);
+            match (muted, duration) {
+                (true, Some(_)) => plugin_print(&format!("Muted {} for {}", scope, duration_arg)),
+                (true, None) => plugin_print(&format!("Muted {}", scope)),
+                (false, _) => plugin_print(&format!("Unmuted {}", scope)),
+            }
+        });
+    });
+}
+
+/// The guild, and the channel unless run from a guild buffer, whose settings a command changes
+fn settings_target(
+    buffer: &impl BufferHost,
+    command: &str,
+) -> Option<(GuildId, Option<ChannelId>)> {
+    let guild = match buffer.guild_id() {
+        Some(guild) => guild,
+        None => {
+            plugin_print(&format!(
+                "{} must be run from a guild or guild channel buffer",
+                command
+            ));
+            return None;
+        },
+    };
+    Some((guild, buffer.channel_id()))
+}
+
+fn reveal(
+    weecord: &Discord,
+    account: &str,
+    args: &Args,
+    buffer: &impl BufferHost,
+    manager: &MessageManager,
+) {
+    let target = match parsing::parse_target(args.args.front().copied()) {
+        Some(target) => target,
+        None => {
+            plugin_print("reveal requires a message number or reference");
+            return;
+        },
+    };
+    let ctx = match discord::get_ctx(account) {
+        Some(ctx) => ctx,
+        _ => return,
+    };
+    match utils::find_target_message(weecord, buffer, target, None) {
+        Some(msg) => {
+            manager.reveal_spoilers(msg.id);
+            manager.replace_message(&ctx.cache, &msg.id, &msg);
+        },
+        None => plugin_print("reveal only works for messages shown in the buffer"),
+    }
+}
+
+fn more(weecord: &Discord, args: &Args, buffer: &MessageManager) {
+    let default_fetch_count = weecord.config.message_fetch_count.value();
+    let count = args
+        .args
+        .front()
+        .and_then(|c| c.parse::<i32>().ok())
+        .unwrap_or(default_fetch_count);
+    buffers::load_older_history(buffer, count);
+}
+
 const CMD_DESCRIPTION: weechat::CommandDescription = weechat::CommandDescription {
     name: "discord",
     description: "\
@@ -684,6 +1215,9 @@ Discord from the comfort of your favorite command-line IRC client!
 Source code available at https://github.com/terminal-discord/weechat-discord
 Originally by https://github.com/khyperia/weechat-discord",
     args: "
+    [-account <account>] <command>
+    accounts
+    queue
     connect
     disconnect
     join
@@ -693,6 +1227,13 @@ Originally by https://github.com/khyperia/weechat-discord",
     watched
     autojoined
     pins
+    reactions [<n>|<ref>]
+    threads
+    thread <name>|<id>
+    reveal [<n>|<ref>]
+    notify all|mentions|none
+    mute [<duration>]
+    unmute
     irc-mode
     discord-mode
     autostart
@@ -704,8 +1245,14 @@ Originally by https://github.com/khyperia/weechat-discord",
     unflip
     shrug
     spoiler
-    rehistory",
+    search [in:#<channel>] [from:<user>] [has:link|embed|file|image|video] <terms>
+    jump <n>
+    rehistory
+    more [<count>]",
     args_description: "
+    -account: run the command for the given account instead of the account of the current buffer
+    accounts: list configured accounts and their connection state
+    queue: show how many jobs wait for the main thread and how many have run
     connect: sign in to discord and open chat buffers
     disconnect: sign out of Discord
     join: join a channel in irc mode by providing guild name and channel name
@@ -717,11 +1264,21 @@ Originally by https://github.com/khyperia/weechat-discord",
     watched: List watched guilds and channels
     autojoined: List autojoined guilds and channels
     pins: Show a list of pinned messages for the current channel
+    reveal: show the hidden spoilers of the nth most recent message (default 1) or a tagged message
+    notify: set when the current guild or channel notifies, for every message, only mentions or never
+    mute: mute the current guild or channel here and on Discord, for a duration such as 30m, 8h or 1d, or until unmuted
+    unmute: unmute the current guild or channel
+    reactions: list who reacted with which emoji to the nth most recent message (default 1) or a tagged message
+    threads: list the active and archived threads of the current channel
+    thread: join a thread of the current channel by name, or any thread by id, and open it in a buffer
     autostart: automatically sign into discord on start
     noautostart: disable autostart
     status: set your Discord online status
     token: set Discord login token
     rehistory: reload the history in the current buffer
+    more: load messages older than the oldest message in the current buffer
+    search: search the messages of the current guild or DM, results are listed in a search buffer
+    jump: open the channel of a result in the search buffer around the matching message
     upload: upload a file to the current channel
 
 Examples:
@@ -730,9 +1287,14 @@ Examples:
   /discord autostart
   /discord disconnect
   /discord upload file.txt
+  /discord -account work join MyServer general
+  /discord search in:#general from:someone has:link release
 ",
     completion:
-"connect || \
+"-account %(weecord_account_completion) || \
+accounts || \
+queue || \
+connect || \
 disconnect || \
 query %(weecord_dm_completion) || \
 watch %(weecord_guild_completion) %(weecord_channel_completion) || \
@@ -744,6 +1306,13 @@ noautojoin %(weecord_guild_completion) %(weecord_channel_completion) || \
 irc-mode || \
 discord-mode || \
 pins || \
+reactions || \
+threads || \
+thread || \
+reveal || \
+notify all|mentions|none || \
+mute 15m|1h|8h|1d || \
+unmute || \
 token || \
 autostart || \
 noautostart || \
@@ -756,5 +1325,85 @@ unflip || \
 shrug || \
 spoiler || \
 rehistory || \
+more || \
+search || \
+jump || \
 join %(weecord_guild_completion) %(weecord_channel_completion)",
 };
+
+#[cfg(test)]
+mod tests {
+    use super::{prepare, settings_target};
+    use crate::weechat_utils::{
+        host::fake::{FakeBuffer, FakeHost},
+        BufferHost,
+    };
+    use serenity::model::id::{ChannelId, GuildId};
+
+    const ACCOUNTS: &[&str] = &["main", "work"];
+
+    #[test]
+    fn commands_act_on_the_buffer_account() {
+        let host = FakeHost::default();
+        let buffer = FakeBuffer::default();
+
+        assert_eq!(
+            prepare(&host, &buffer, ACCOUNTS, "/discord mute 8h"),
+            Some(("main".to_owned(), "/discord mute 8h".to_owned()))
+        );
+
+        buffer.set_localvar("account", "work");
+        assert_eq!(
+            prepare(&host, &buffer, ACCOUNTS, "/discord pins"),
+            Some(("work".to_owned(), "/discord pins".to_owned()))
+        );
+        assert_eq!(
+            prepare(
+                &host,
+                &buffer,
+                ACCOUNTS,
+                "/discord -account main status idle"
+            ),
+            Some(("main".to_owned(), "/discord status idle".to_owned()))
+        );
+        assert!(host.printed.borrow().is_empty());
+    }
+
+    #[test]
+    fn rejected_commands_say_why() {
+        let host = FakeHost::default();
+        let buffer = FakeBuffer::default();
+
+        assert_eq!(
+            prepare(&host, &buffer, ACCOUNTS, "/discord -account home connect"),
+            None
+        );
+        assert_eq!(prepare(&host, &buffer, ACCOUNTS, "/discord"), None);
+        assert_eq!(
+            prepare(&host, &buffer, ACCOUNTS, "/discord frobnicate"),
+            None
+        );
+        assert_eq!(
+            *host.printed.borrow(),
+            vec![
+                "Unknown account \"home\"",
+                "no action provided.",
+                "see /help discord for more information",
+                "Unknown command",
+            ]
+        );
+    }
+
+    #[test]
+    fn settings_apply_to_the_buffer_channel() {
+        let buffer = FakeBuffer::default();
+        buffer.set_localvar("guildid", "200");
+        assert_eq!(settings_target(&buffer, "mute"), Some((GuildId(200), None)));
+
+        buffer.set_localvar("channelid", "300");
+        assert_eq!(
+            settings_target(&buffer, "mute"),
+            Some((GuildId(200), Some(ChannelId(300))))
+        );
+    }
+}
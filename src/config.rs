@@ -1,19 +1,50 @@
 use crate::{utils, utils::GuildOrChannel};
 use weechat::{
-    BooleanOption, ConfigOption, ConfigSectionInfo, IntegerOption, StringOption, Weechat,
+    BooleanOption, ConfigOption, ConfigSection, ConfigSectionInfo, IntegerOption, StringOption,
+    Weechat,
 };
 
-pub struct Config {
+/// The account configured in the `main` section, its buffers keep their unprefixed names
+pub const MAIN_ACCOUNT: &str = "main";
+
+/// Sections that are not accounts, no account may share their name
+const RESERVED_SECTIONS: &[&str] = &[MAIN_ACCOUNT, "look"];
+
+/// Options for a single Discord account, stored in the `weecord.<account>` section
+pub struct AccountConfig {
+    pub name: String,
     pub token: StringOption,
     pub watched_channels: StringOption,
     pub autojoin_channels: StringOption,
     pub autostart: BooleanOption,
+    pub irc_mode: BooleanOption,
+}
+
+/// How messages are drawn, stored in the `weecord.look` section
+pub struct LookConfig {
+    pub code: StringOption,
+    pub code_keyword: StringOption,
+    pub code_string: StringOption,
+    pub code_number: StringOption,
+    pub code_comment: StringOption,
+    pub diff_added: StringOption,
+    pub diff_removed: StringOption,
+    pub diff_hunk: StringOption,
+    pub show_spoilers: StringOption,
+}
+
+pub struct Config {
+    pub accounts: Vec<AccountConfig>,
+    pub extra_accounts: StringOption,
     pub use_presence: BooleanOption,
     pub send_typing_events: BooleanOption,
-    pub irc_mode: BooleanOption,
     pub message_fetch_count: IntegerOption,
+    pub message_log: BooleanOption,
     pub user_typing_list_max: IntegerOption,
     pub user_typing_list_expanded: BooleanOption,
+    pub custom_emoji: IntegerOption,
+    pub message_refs: BooleanOption,
+    pub look: LookConfig,
     pub config: weechat::Config<()>,
 }
 
@@ -27,9 +58,10 @@ pub fn init(weechat: &Weechat) -> Config {
 
     let section = config.new_section(section_info);
 
-    let token = section.new_string_option(
-        "token",
-        "Discord auth token. Supports secure data",
+    let extra_accounts = section.new_string_option(
+        "accounts",
+        "List of additional accounts, each configured in its own weecord.<account> section \
+         (requires reloading the plugin)",
         "",
         "",
         false,
@@ -37,35 +69,7 @@ pub fn init(weechat: &Weechat) -> Config {
         None::<()>,
     );
 
-    let watched_channels = section.new_string_option(
-        "watched_channels",
-        "List of channels to open when a message is received",
-        "",
-        "",
-        false,
-        None,
-        None::<()>,
-    );
-
-    let autojoin_channels = section.new_string_option(
-        "autojoin_channels",
-        "List of channels to automatically open on connecting (irc mode only)",
-        "",
-        "",
-        false,
-        None,
-        None::<()>,
-    );
-
-    let autostart = section.new_boolean_option(
-        "autostart",
-        "Automatically connect to Discord when weechat starts",
-        false,
-        false,
-        false,
-        None,
-        None::<()>,
-    );
+    let main_account = account_options(&section, MAIN_ACCOUNT);
 
     let use_presence = section.new_boolean_option(
         "use_presence",
@@ -87,16 +91,6 @@ pub fn init(weechat: &Weechat) -> Config {
         None::<()>,
     );
 
-    let irc_mode = section.new_boolean_option(
-        "irc_mode",
-        r#"Enable "IRC-Mode" where only the channels you choose will be automatically joined"#,
-        false,
-        false,
-        false,
-        None,
-        None::<()>,
-    );
-
     let message_fetch_count = section.new_integer_option(
         "message_load_count",
         "How many messages will be fetched when a buffer is loaded",
@@ -110,6 +104,17 @@ pub fn init(weechat: &Weechat) -> Config {
         None::<()>,
     );
 
+    let message_log = section.new_boolean_option(
+        "message_log",
+        "Keep a log of the messages shown in buffers on disk, used to draw history before it is \
+         fetched and while disconnected (requires reloading the plugin)",
+        true,
+        true,
+        false,
+        None,
+        None::<()>,
+    );
+
     let user_typing_list_max = section.new_integer_option(
         "user_typing_list_max",
         "How many users will be displayed at most in the typing indicator",
@@ -133,24 +138,241 @@ pub fn init(weechat: &Weechat) -> Config {
         None::<()>,
     );
 
+    let custom_emoji = section.new_integer_option(
+        "custom_emoji",
+        "How custom emoji are shown: only their name, or their name and image url",
+        "name|name_url",
+        0,
+        0,
+        "name",
+        "name",
+        false,
+        None,
+        None::<()>,
+    );
+
+    let message_refs = section.new_boolean_option(
+        "message_refs",
+        "Show a short reference tag such as [a3] before each message, usable to edit, delete, \
+         react or reply to it. Messages only get tags while this is on, targets such as a3+ \
+         match nothing otherwise",
+        false,
+        false,
+        false,
+        None,
+        None::<()>,
+    );
+
+    let look = look_options(&mut config);
+
+    // Account sections must exist before WeeChat reads the file, or it warns about unknown
+    // sections, so the list of accounts is looked up in the file first
+    let mut accounts = vec![main_account];
+    for name in configured_accounts(weechat) {
+        let name = name.as_str();
+        if RESERVED_SECTIONS.contains(&name) {
+            weechat.print(&format!(
+                "discord: Ignoring account \"{}\", the name is reserved",
+                name
+            ));
+            continue;
+        }
+        if accounts.iter().any(|account| account.name == name) {
+            continue;
+        }
+        let section_info: ConfigSectionInfo<()> = ConfigSectionInfo {
+            name,
+            ..Default::default()
+        };
+        let section = config.new_section(section_info);
+        accounts.push(account_options(&section, name));
+    }
+
     config.read();
 
     Config {
-        token,
-        watched_channels,
-        autojoin_channels,
-        autostart,
+        accounts,
+        extra_accounts,
         use_presence,
         send_typing_events,
-        irc_mode,
         message_fetch_count,
+        message_log,
         user_typing_list_max,
         user_typing_list_expanded,
+        custom_emoji,
+        message_refs,
+        look,
         config,
     }
 }
 
+/// The names in `weecord.main.accounts`, read straight from `weecord.conf`
+fn configured_accounts(weechat: &Weechat) -> Vec<String> {
+    // WeeChat 3.2 moved the configuration out of `weechat_dir`
+    let dir = weechat
+        .info_get("weechat_config_dir", "")
+        .or_else(|| weechat.info_get("weechat_dir", ""));
+    let file = match dir.and_then(|dir| {
+        std::fs::read_to_string(std::path::Path::new(dir.as_ref()).join("weecord.conf")).ok()
+    }) {
+        Some(file) => file,
+        None => return Vec::new(),
+    };
+
+    parse_accounts_option(&file)
+        .split(',')
+        .map(str::trim)
+        .filter(|name| !name.is_empty())
+        .map(str::to_owned)
+        .collect()
+}
+
+/// The value of `accounts` in the `[main]` section of a WeeChat configuration file
+fn parse_accounts_option(file: &str) -> String {
+    let mut in_main = false;
+    for line in file.lines().map(str::trim) {
+        if line.starts_with('[') {
+            in_main = line == "[main]";
+            continue;
+        }
+        if !in_main {
+            continue;
+        }
+
+        let mut parts = line.splitn(2, '=');
+        if parts.next().map(str::trim) == Some("accounts") {
+            let value = parts.next().unwrap_or_default().trim();
+            return value
+                .trim_start_matches('"')
+                .trim_end_matches('"')
+                .replace("\\\"", "\"")
+                .replace("\\\\", "\\");
+        }
+    }
+    String::new()
+}
+
+fn look_options(config: &mut weechat::Config<()>) -> LookConfig {
+    let section_info: ConfigSectionInfo<()> = ConfigSectionInfo {
+        name: "look",
+        ..Default::default()
+    };
+    let section = config.new_section(section_info);
+
+    let color_option = |name: &str, description: &str, default: &str| {
+        section.new_string_option(name, description, default, default, false, None, None::<()>)
+    };
+
+    LookConfig {
+        code: color_option("code", "Color of text in code blocks", "*8"),
+        code_keyword: color_option(
+            "code_keyword",
+            "Color of keywords in highlighted code blocks",
+            "lightmagenta",
+        ),
+        code_string: color_option(
+            "code_string",
+            "Color of strings in highlighted code blocks",
+            "green",
+        ),
+        code_number: color_option(
+            "code_number",
+            "Color of numbers in highlighted code blocks",
+            "cyan",
+        ),
+        code_comment: color_option(
+            "code_comment",
+            "Color of comments in highlighted code blocks",
+            "/darkgray",
+        ),
+        diff_added: color_option("diff_added", "Color of added lines in diff blocks", "green"),
+        diff_removed: color_option(
+            "diff_removed",
+            "Color of removed lines in diff blocks",
+            "red",
+        ),
+        diff_hunk: color_option("diff_hunk", "Color of hunk headers in diff blocks", "cyan"),
+        show_spoilers: section.new_string_option(
+            "show_spoilers",
+            "Comma separated ids of guilds whose spoilers are shown instead of hidden, \
+             \"dm\" for direct messages or \"*\" for everywhere",
+            "",
+            "",
+            false,
+            None,
+            None::<()>,
+        ),
+    }
+}
+
+fn account_options(section: &ConfigSection, name: &str) -> AccountConfig {
+    let token = section.new_string_option(
+        "token",
+        "Discord auth token. Supports secure data",
+        "",
+        "",
+        false,
+        None,
+        None::<()>,
+    );
+
+    let watched_channels = section.new_string_option(
+        "watched_channels",
+        "List of channels to open when a message is received",
+        "",
+        "",
+        false,
+        None,
+        None::<()>,
+    );
+
+    let autojoin_channels = section.new_string_option(
+        "autojoin_channels",
+        "List of channels to automatically open on connecting (irc mode only)",
+        "",
+        "",
+        false,
+        None,
+        None::<()>,
+    );
+
+    let autostart = section.new_boolean_option(
+        "autostart",
+        "Automatically connect to Discord when weechat starts",
+        false,
+        false,
+        false,
+        None,
+        None::<()>,
+    );
+
+    let irc_mode = section.new_boolean_option(
+        "irc_mode",
+        r#"Enable "IRC-Mode" where only the channels you choose will be automatically joined"#,
+        false,
+        false,
+        false,
+        None,
+        None::<()>,
+    );
+
+    AccountConfig {
+        name: name.to_owned(),
+        token,
+        watched_channels,
+        autojoin_channels,
+        autostart,
+        irc_mode,
+    }
+}
+
 impl Config {
+    pub fn account(&self, name: &str) -> Option<&AccountConfig> {
+        self.accounts.iter().find(|account| account.name == name)
+    }
+}
+
+impl AccountConfig {
     pub fn autojoin_channels(&self) -> Vec<GuildOrChannel> {
         self.autojoin_channels
             .value()
@@ -169,3 +391,22 @@ impl Config {
             .collect()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::parse_accounts_option;
+
+    #[test]
+    fn reads_the_accounts_of_the_main_section() {
+        let file = r#"
+[main]
+token = "abc"
+accounts = "work,home"
+
+[work]
+accounts = "ignored"
+"#;
+        assert_eq!(parse_accounts_option(file), "work,home");
+        assert_eq!(parse_accounts_option("[look]\naccounts = \"x\""), "");
+    }
+}
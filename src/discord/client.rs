@@ -1,40 +1,180 @@
 use super::event_handler::Handler;
-use crate::Discord;
+use crate::{on_main, try_on_main_blocking, Discord};
+use lazy_static::lazy_static;
 use serenity::{client::bridge::gateway::ShardManager, model::gateway::Ready, prelude::*};
 use std::{
-    sync::{mpsc, Arc},
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        mpsc, Arc,
+    },
     thread,
+    time::{Duration, Instant},
 };
 
+const INITIAL_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_BACKOFF: Duration = Duration::from_secs(300);
+/// How long a reconnect waits for the main thread before trying again
+const MAIN_THREAD_TIMEOUT: Duration = Duration::from_secs(30);
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ConnectionState {
+    Connecting,
+    Connected,
+    Reconnecting { attempt: u32, delay: Duration },
+    Disconnected,
+}
+
+lazy_static! {
+    static ref CONNECTION_STATES: Mutex<HashMap<String, ConnectionState>> =
+        Mutex::new(HashMap::new());
+}
+
+pub fn connection_state(account: &str) -> ConnectionState {
+    CONNECTION_STATES
+        .lock()
+        .get(account)
+        .copied()
+        .unwrap_or(ConnectionState::Disconnected)
+}
+
+/// Update the connection state of an account and redraw the connection bar item
+pub fn set_connection_state(account: &str, state: ConnectionState) {
+    CONNECTION_STATES.lock().insert(account.to_owned(), state);
+    on_main(|weecord| weecord.update_bar_item("discord_connection"));
+}
+
 pub struct DiscordClient {
-    shard_manager: Arc<Mutex<ShardManager>>,
+    account: String,
+    shard_manager: Arc<Mutex<Arc<Mutex<ShardManager>>>>,
+    stopped: Arc<AtomicBool>,
 }
 
 impl DiscordClient {
     pub fn start(
         weecord: &Discord,
+        account: &str,
         token: &str,
     ) -> Result<(DiscordClient, mpsc::Receiver<Ready>), serenity::Error> {
         let (tx, rx) = mpsc::channel();
-        let handler = Handler::new(weecord, Arc::new(Mutex::new(tx)));
+        let tx = Arc::new(Mutex::new(tx));
+        let handler = Handler::new(weecord, account, Arc::clone(&tx));
 
-        let mut client = Client::new(token, handler)?;
+        let client = Client::new(token, handler)?;
 
-        let shard_manager = client.shard_manager.clone();
-        thread::spawn(move || {
-            if let Err(e) = client.start_shards(1) {
-                crate::on_main(move |weecord| {
-                    weecord.print(&format!(
-                        "discord: An error occurred connecting to discord: {}",
-                        e
-                    ));
-                });
-            }
-        });
-        Ok((DiscordClient { shard_manager }, rx))
+        let shard_manager = Arc::new(Mutex::new(client.shard_manager.clone()));
+        let stopped = Arc::new(AtomicBool::new(false));
+
+        // Can't redraw the bar item yet, we may still be initializing the plugin
+        CONNECTION_STATES
+            .lock()
+            .insert(account.to_owned(), ConnectionState::Connecting);
+
+        {
+            let account = account.to_owned();
+            let token = token.to_owned();
+            let shard_manager = Arc::clone(&shard_manager);
+            let stopped = Arc::clone(&stopped);
+            thread::Builder::new()
+                .name("Discord supervisor".into())
+                .spawn(move || supervise(client, &account, &token, &tx, &shard_manager, &stopped))
+                .expect("Unable to name thread");
+        }
+
+        Ok((
+            DiscordClient {
+                account: account.to_owned(),
+                shard_manager,
+                stopped,
+            },
+            rx,
+        ))
     }
 
     pub fn shutdown(&self) {
-        self.shard_manager.lock().shutdown_all();
+        self.stopped.store(true, Ordering::SeqCst);
+        self.shard_manager.lock().lock().shutdown_all();
+        set_connection_state(&self.account, ConnectionState::Disconnected);
+    }
+}
+
+/// Run the client, creating a new one with exponential backoff whenever the shard dies
+fn supervise(
+    mut client: Client,
+    account: &str,
+    token: &str,
+    sender: &Arc<Mutex<mpsc::Sender<Ready>>>,
+    shard_manager: &Mutex<Arc<Mutex<ShardManager>>>,
+    stopped: &AtomicBool,
+) {
+    let mut attempt = 0;
+    loop {
+        let started = Instant::now();
+        let result = client.start_shards(1);
+
+        if stopped.load(Ordering::SeqCst) {
+            return;
+        }
+
+        if let Err(e) = result {
+            crate::plugin_print(&format!("Lost connection to Discord ({}): {}", account, e));
+        } else {
+            crate::plugin_print(&format!("Lost connection to Discord ({})", account));
+        }
+
+        // A connection that stayed up for a while starts over with a short delay
+        if started.elapsed() > MAX_BACKOFF {
+            attempt = 0;
+        }
+
+        loop {
+            attempt += 1;
+            let delay = backoff(attempt);
+            set_connection_state(account, ConnectionState::Reconnecting { attempt, delay });
+            thread::sleep(delay);
+
+            if stopped.load(Ordering::SeqCst) {
+                return;
+            }
+
+            let sender = Arc::clone(sender);
+            let handler = match try_on_main_blocking(MAIN_THREAD_TIMEOUT, move |weecord| {
+                Handler::new(weecord, account, sender)
+            }) {
+                Ok(handler) => handler,
+                Err(e) => {
+                    crate::plugin_print(&format!(
+                        "Unable to reconnect to Discord ({}): {}",
+                        account, e
+                    ));
+                    continue;
+                },
+            };
+            match Client::new(token, handler) {
+                Ok(new_client) => {
+                    client = new_client;
+                    break;
+                },
+                Err(e) => crate::plugin_print(&format!(
+                    "Unable to reconnect to Discord ({}): {}",
+                    account, e
+                )),
+            }
+        }
+
+        {
+            // Checked under the lock so a shutdown either sees the new shard manager or is seen here
+            let mut shard_manager = shard_manager.lock();
+            if stopped.load(Ordering::SeqCst) {
+                return;
+            }
+            *shard_manager = client.shard_manager.clone();
+        }
+        set_connection_state(account, ConnectionState::Connecting);
     }
 }
+
+fn backoff(attempt: u32) -> Duration {
+    let factor = 2u32.saturating_pow(attempt.saturating_sub(1));
+    std::cmp::min(INITIAL_BACKOFF * factor, MAX_BACKOFF)
+}
@@ -1,5 +1,9 @@
 use crate::{
-    buffers, discord, on_main, on_main_blocking, utils, weechat_utils::MessageManager, Discord,
+    buffers,
+    discord::{self, member_list, threads},
+    on_main, on_main_blocking, utils,
+    weechat_utils::{BufferHost, MessageManager},
+    Discord,
 };
 use lazy_static::lazy_static;
 use serenity::{
@@ -8,7 +12,7 @@ use serenity::{
     prelude::*,
 };
 use std::{
-    collections::HashMap,
+    collections::{HashMap, HashSet},
     sync::{mpsc::Sender, Arc},
     thread,
     time::{Duration, SystemTime, UNIX_EPOCH},
@@ -18,6 +22,7 @@ const MAX_TYPING_EVENTS: usize = 50;
 
 #[derive(Debug, PartialEq, Eq, Ord)]
 pub struct TypingEntry {
+    pub account: String,
     pub channel_id: ChannelId,
     pub guild_id: Option<GuildId>,
     pub user: UserId,
@@ -51,21 +56,29 @@ impl TypingTracker {
 }
 
 lazy_static! {
+    // Guilds the current user's member was fetched or is being fetched from, see `cache_own_member`
+    static ref OWN_MEMBER_FETCHES: Mutex<HashSet<(UserId, GuildId)>> = Mutex::new(HashSet::new());
     pub static ref TYPING_EVENTS: Arc<Mutex<TypingTracker>> = Arc::new(Mutex::new(TypingTracker {
         entries: Vec::new(),
     }));
 }
 
 pub struct Handler {
+    account: String,
     sender: Arc<Mutex<Sender<Ready>>>,
     watched_channels: Vec<utils::GuildOrChannel>,
 }
 
 impl Handler {
-    pub fn new(weecord: &Discord, sender: Arc<Mutex<Sender<Ready>>>) -> Handler {
-        let watched_channels = weecord.config.watched_channels();
+    pub fn new(weecord: &Discord, account: &str, sender: Arc<Mutex<Sender<Ready>>>) -> Handler {
+        let watched_channels = weecord
+            .config
+            .account(account)
+            .map(|account| account.watched_channels())
+            .unwrap_or_default();
 
         Handler {
+            account: account.to_owned(),
             sender,
             watched_channels,
         }
@@ -76,6 +89,7 @@ impl EventHandler for Handler {
     fn channel_create(&self, _ctx: Context, channel: Arc<RwLock<GuildChannel>>) {
         let channel = channel.read();
         print_guild_status_message(
+            &self.account,
             channel.guild_id,
             &format!(
                 "New {} channel `{}` created",
@@ -88,6 +102,7 @@ impl EventHandler for Handler {
     fn channel_delete(&self, _ctx: Context, channel: Arc<RwLock<GuildChannel>>) {
         let channel = channel.read();
         print_guild_status_message(
+            &self.account,
             channel.guild_id,
             &format!("Channel `{}` deleted", channel.name()),
         );
@@ -117,6 +132,7 @@ impl EventHandler for Handler {
                     if let Some(guild_id) = guild_id {
                         if new.name != old.name {
                             print_guild_status_message(
+                                &self.account,
                                 guild_id,
                                 &format!("Category `{}` renamed to `{}`", old.name, new.name),
                             );
@@ -131,6 +147,7 @@ impl EventHandler for Handler {
 
                     if new.name != old.name {
                         print_guild_status_message(
+                            &self.account,
                             new.guild_id,
                             &format!("Category `{}` renamed to `{}`", old.name, new.name),
                         );
@@ -141,15 +158,55 @@ impl EventHandler for Handler {
         }
     }
 
-    fn guild_member_update(&self, ctx: Context, old: Option<Member>, new: Member) {
+    fn guild_member_addition(&self, _ctx: Context, guild_id: GuildId, new_member: Member) {
+        buffers::refresh_member(&self.account, guild_id, new_member.user_id());
+    }
+
+    fn guild_member_removal(
+        &self,
+        _ctx: Context,
+        guild_id: GuildId,
+        user: User,
+        _member_data_if_available: Option<Member>,
+    ) {
+        buffers::remove_member(&self.account, guild_id, user.id);
+    }
+
+    fn guild_member_update(&self, ctx: Context, _old: Option<Member>, new: Member) {
+        buffers::refresh_member(&self.account, new.guild_id, new.user_id());
+
+        let account = self.account.clone();
         thread::spawn(move || {
-            buffers::update_member_nick(&old, &new);
             if ctx.cache.read().user.id == new.user_id() {
-                buffers::update_nick();
+                buffers::update_nick(&account);
             }
         });
     }
 
+    fn guild_role_create(&self, _ctx: Context, guild_id: GuildId, new: Role) {
+        buffers::refresh_role_members(&self.account, guild_id, new.id);
+    }
+
+    fn guild_role_delete(
+        &self,
+        _ctx: Context,
+        guild_id: GuildId,
+        removed_role_id: RoleId,
+        _removed_role_data_if_available: Option<Role>,
+    ) {
+        buffers::refresh_role_members(&self.account, guild_id, removed_role_id);
+    }
+
+    fn guild_role_update(
+        &self,
+        _ctx: Context,
+        guild_id: GuildId,
+        _old_data_if_available: Option<Role>,
+        new: Role,
+    ) {
+        buffers::refresh_role_members(&self.account, guild_id, new.id);
+    }
+
     fn guild_members_chunk(
         &self,
         ctx: Context,
@@ -157,12 +214,18 @@ impl EventHandler for Handler {
         _offline_members: HashMap<UserId, Member>,
         nonce: Option<String>,
     ) {
+        let account = self.account.clone();
         on_main(move |weecord| {
             if let Some(channel_id) = nonce {
-                if let Ok(channel_id) = channel_id.parse::<u64>().map(|id| ChannelId(id)) {
-                    if let Some(buffer) = weecord
-                        .buffer_manager
-                        .get_buffer(&utils::buffer_id_for_channel(Some(guild_id), channel_id))
+                if let Ok(channel_id) = channel_id.parse::<u64>().map(ChannelId) {
+                    if let Some(buffer) =
+                        weecord
+                            .buffer_manager
+                            .get_buffer(&utils::buffer_id_for_channel(
+                                &account,
+                                Some(guild_id),
+                                channel_id,
+                            ))
                     {
                         buffer.redraw_buffer(&ctx.cache);
                     }
@@ -172,17 +235,26 @@ impl EventHandler for Handler {
     }
 
     fn message(&self, ctx: Context, msg: Message) {
-        let string_channel = utils::buffer_id_for_channel(msg.guild_id, msg.channel_id);
+        let account = &self.account;
+        let string_channel = utils::buffer_id_for_channel(account, msg.guild_id, msg.channel_id);
+        if let Some(guild_id) = msg.guild_id {
+            if !msg.mention_roles.is_empty() {
+                cache_own_member(&ctx, guild_id);
+            }
+        }
         let () = on_main_blocking(move |weecord| {
             if let Some(buffer) = weecord.buffer_manager.get_buffer(&string_channel) {
-                print_message(&ctx.cache, &msg, &buffer);
+                weecord.message_store.insert(account, &[msg.clone()]);
+                buffers::expire_mute(account, &ctx.cache, &buffer);
+                print_message(&ctx.cache, &msg, &*buffer);
             } else {
                 match msg.channel_id.to_channel(&ctx) {
                     chan @ Ok(Channel::Private(_)) => {
                         if let Some(buffer) = weecord.buffer_manager.get_buffer(&string_channel) {
-                            print_message(&ctx.cache, &msg, &buffer);
+                            print_message(&ctx.cache, &msg, &*buffer);
                         } else {
                             buffers::create_buffer_from_dm(
+                                account,
                                 &ctx.cache,
                                 &weecord,
                                 chan.unwrap(),
@@ -193,9 +265,10 @@ impl EventHandler for Handler {
                     },
                     chan @ Ok(Channel::Group(_)) => {
                         if let Some(buffer) = weecord.buffer_manager.get_buffer(&string_channel) {
-                            print_message(&ctx.cache, &msg, &buffer);
+                            print_message(&ctx.cache, &msg, &*buffer);
                         } else {
                             buffers::create_buffer_from_group(
+                                account,
                                 &ctx.cache,
                                 &weecord,
                                 chan.unwrap(),
@@ -231,14 +304,13 @@ impl EventHandler for Handler {
                                     format!("@{}", current_user.name)
                                 };
 
-                                buffers::create_guild_buffer(guild.id, &guild.name);
-                                // TODO: Muting
+                                buffers::create_guild_buffer(account, guild.id, &guild.name);
                                 buffers::create_buffer_from_channel(
+                                    account,
                                     &ctx.cache,
                                     &guild.name,
                                     &channel,
                                     &nick,
-                                    false,
                                 );
                                 break;
                             }
@@ -251,7 +323,7 @@ impl EventHandler for Handler {
     }
 
     fn message_delete(&self, ctx: Context, channel_id: ChannelId, deleted_message_id: MessageId) {
-        delete_message(&ctx, channel_id, deleted_message_id)
+        delete_message(&self.account, &ctx, channel_id, deleted_message_id)
     }
 
     fn message_delete_bulk(
@@ -261,7 +333,7 @@ impl EventHandler for Handler {
         deleted_messages_ids: Vec<MessageId>,
     ) {
         for message_id in deleted_messages_ids {
-            delete_message(&ctx, channel_id, message_id)
+            delete_message(&self.account, &ctx, channel_id, message_id)
         }
     }
 
@@ -279,19 +351,23 @@ impl EventHandler for Handler {
             },
             Some(Channel::Group(channel)) => (None, channel.read().channel_id, event.id),
             Some(Channel::Private(channel)) => (None, channel.read().id, event.id),
-            _ => return,
+            _ => match threads::get(&self.account, event.channel_id) {
+                Some(thread) => (Some(thread.guild_id), thread.id, event.id),
+                None => return,
+            },
         };
 
-        let buffer_name = utils::buffer_id_for_channel(guild_id, channel_id);
+        let buffer_name = utils::buffer_id_for_channel(&self.account, guild_id, channel_id);
 
+        let account = self.account.clone();
         thread::spawn(move || {
             on_main(move |weecord| {
-                let ctx = match crate::discord::get_ctx() {
+                let ctx = match crate::discord::get_ctx(&account) {
                     Some(ctx) => ctx,
                     _ => return,
                 };
                 let msg = match channel_id
-                    .messages(ctx, |retriever| retriever.limit(1).around(message_id))
+                    .messages(&*ctx, |retriever| retriever.limit(1).around(message_id))
                     .ok()
                     .and_then(|mut msgs| msgs.pop())
                 {
@@ -300,6 +376,7 @@ impl EventHandler for Handler {
                 };
 
                 if let Some(buffer) = weecord.buffer_manager.get_buffer(&buffer_name) {
+                    weecord.message_store.insert(&account, &[msg.clone()]);
                     buffer.replace_message(&ctx.cache, &message_id, &msg);
                 }
             });
@@ -307,11 +384,17 @@ impl EventHandler for Handler {
     }
 
     fn reaction_add(&self, ctx: Context, reaction: Reaction) {
-        reaction_update(ctx, reaction)
+        reaction_update(&self.account, ctx, reaction)
     }
 
     fn reaction_remove(&self, ctx: Context, reaction: Reaction) {
-        reaction_update(ctx, reaction)
+        reaction_update(&self.account, ctx, reaction)
+    }
+
+    fn presence_update(&self, _ctx: Context, new_data: PresenceUpdateEvent) {
+        if let Some(guild_id) = new_data.guild_id {
+            buffers::refresh_member(&self.account, guild_id, new_data.presence.user_id);
+        }
     }
 
     fn ready(&self, ctx: Context, ready: Ready) {
@@ -340,12 +423,23 @@ impl EventHandler for Handler {
             }
         }
         if let Some(presence) = ctx.cache.read().presences.get(&ready.user.id) {
-            *crate::command::LAST_STATUS.lock() = presence.status;
+            crate::command::LAST_STATUS
+                .lock()
+                .insert(self.account.clone(), presence.status);
         }
 
-        unsafe {
-            crate::discord::CONTEXT = Some(ctx);
+        discord::notify::load(&self.account, &ready);
+        {
+            let token = ctx.http.token.clone();
+            let user = ready.user.id;
+            thread::spawn(move || {
+                if let Err(e) = discord::load_premium(&token, user) {
+                    crate::plugin_print(&format!("Unable to check for Nitro: {}", e));
+                }
+            });
         }
+        discord::set_ctx(&self.account, ctx);
+        discord::client::set_connection_state(&self.account, discord::ConnectionState::Connected);
         let _ = self.sender.lock().send(ready);
     }
 
@@ -361,6 +455,7 @@ impl EventHandler for Handler {
             // TODO: Resolve guild nick names
             let mut typing_events = TYPING_EVENTS.lock();
             typing_events.entries.push(TypingEntry {
+                account: self.account.clone(),
                 channel_id: event.channel_id,
                 guild_id: event.guild_id,
                 user: event.user_id,
@@ -393,22 +488,90 @@ impl EventHandler for Handler {
         }
     }
 
+    // Events the serenity fork does not know about
+    fn unknown(&self, ctx: Context, name: String, raw: serde_json::Value) {
+        match name.as_str() {
+            "GUILD_MEMBER_LIST_UPDATE" => member_list::handle_update(&self.account, &ctx, &raw),
+            "USER_GUILD_SETTINGS_UPDATE" => {
+                if let Some(guild_id) = discord::notify::update(&self.account, &raw) {
+                    let account = self.account.clone();
+                    on_main(move |weecord| buffers::refresh_notify(weecord, &account, guild_id));
+                }
+            },
+            "THREAD_CREATE" => {
+                if let Some(thread) = threads::Thread::from_value(&raw) {
+                    if raw["newly_created"].as_bool().unwrap_or_default() {
+                        announce_thread(&self.account, &ctx, &thread);
+                    }
+                    threads::insert(&self.account, thread);
+                }
+            },
+            "THREAD_UPDATE" => {
+                if let Some(thread) = threads::Thread::from_value(&raw) {
+                    threads::insert(&self.account, thread.clone());
+                    let account = self.account.clone();
+                    on_main(move |weecord| buffers::refresh_thread(weecord, &account, &thread));
+                }
+            },
+            "THREAD_DELETE" => {
+                let id = raw["id"].as_str().and_then(|id| id.parse().ok());
+                if let Some(thread) =
+                    id.and_then(|id| threads::remove(&self.account, ChannelId(id)))
+                {
+                    let buffer_name = utils::buffer_id_for_channel(
+                        &self.account,
+                        Some(thread.guild_id),
+                        thread.id,
+                    );
+                    on_main(move |weecord| {
+                        if let Some(buffer) = weecord.buffer_manager.get_buffer(&buffer_name) {
+                            let prefix = weecord.get_prefix("network").to_owned();
+                            buffer.print(&(prefix + "\tThis thread was deleted"));
+                        }
+                    });
+                }
+            },
+            // Sent when gaining access to a channel, with the active threads in it
+            "THREAD_LIST_SYNC" => {
+                for thread in raw["threads"].as_array().into_iter().flatten() {
+                    if let Some(thread) = threads::Thread::from_value(thread) {
+                        threads::insert(&self.account, thread);
+                    }
+                }
+            },
+            _ => {},
+        }
+    }
+
     fn user_update(&self, _ctx: Context, _old: CurrentUser, _new: CurrentUser) {
-        thread::spawn(|| {
+        let account = self.account.clone();
+        thread::spawn(move || {
             // TODO: Update nicklist (and/or just rework all nick stuff)
-            buffers::update_nick();
+            buffers::update_nick(&account);
         });
     }
 }
 
-fn delete_message(ctx: &Context, channel_id: ChannelId, deleted_message_id: MessageId) {
-    if let Some(channel) = ctx.cache.read().channels.get(&channel_id) {
-        let guild_id = channel.read().guild_id;
-        let buffer_name = utils::buffer_id_for_channel(Some(guild_id), channel_id);
+fn delete_message(
+    account: &str,
+    ctx: &Context,
+    channel_id: ChannelId,
+    deleted_message_id: MessageId,
+) {
+    let guild_id = match ctx.cache.read().channels.get(&channel_id) {
+        Some(channel) => Some(channel.read().guild_id),
+        None => threads::get(account, channel_id).map(|thread| thread.guild_id),
+    };
+    if let Some(guild_id) = guild_id {
+        let buffer_name = utils::buffer_id_for_channel(account, Some(guild_id), channel_id);
 
+        let account = account.to_owned();
         on_main(move |weecord| {
+            weecord
+                .message_store
+                .delete(&account, channel_id, deleted_message_id);
             if let Some(buffer) = weecord.buffer_manager.get_buffer(&buffer_name) {
-                let ctx = match discord::get_ctx() {
+                let ctx = match discord::get_ctx(&account) {
                     Some(ctx) => ctx,
                     _ => return,
                 };
@@ -419,25 +582,29 @@ fn delete_message(ctx: &Context, channel_id: ChannelId, deleted_message_id: Mess
     }
 }
 
-fn reaction_update(ctx: Context, reaction: Reaction) {
+fn reaction_update(account: &str, ctx: Context, reaction: Reaction) {
     let guild_id = match ctx.cache.read().channel(&reaction.channel_id) {
         Some(Channel::Guild(channel)) => {
             let channel = channel.read();
             Some(channel.guild_id)
         },
-        _ => return,
+        _ => match threads::get(account, reaction.channel_id) {
+            Some(thread) => Some(thread.guild_id),
+            None => return,
+        },
     };
-    let buffer_name = utils::buffer_id_for_channel(guild_id, reaction.channel_id);
+    let buffer_name = utils::buffer_id_for_channel(account, guild_id, reaction.channel_id);
+    let account = account.to_owned();
     thread::spawn(move || {
         on_main(move |weecord| {
             if let Some(buffer) = weecord.buffer_manager.get_buffer(&buffer_name) {
-                let ctx = match crate::discord::get_ctx() {
+                let ctx = match crate::discord::get_ctx(&account) {
                     Some(ctx) => ctx,
                     _ => return,
                 };
                 let msg = match reaction
                     .channel_id
-                    .messages(ctx, |retriever| {
+                    .messages(&*ctx, |retriever| {
                         retriever.limit(1).around(reaction.message_id)
                     })
                     .ok()
@@ -446,20 +613,70 @@ fn reaction_update(ctx: Context, reaction: Reaction) {
                     Some(msgs) => msgs,
                     None => return,
                 };
+                weecord.message_store.insert(&account, &[msg.clone()]);
                 buffer.replace_message(&ctx.cache, &reaction.message_id, &msg);
             }
         });
     });
 }
 
-fn print_message(cache: &CacheRwLock, msg: &Message, buffer: &MessageManager) {
-    let muted = utils::buffer_is_muted(&buffer);
+fn print_message<B: BufferHost>(cache: &CacheRwLock, msg: &Message, buffer: &MessageManager<B>) {
+    let muted = utils::buffer_is_muted(&**buffer);
     let notify = !msg.is_own(cache) && !muted;
     buffer.add_message(cache, &msg, notify);
 }
 
-fn print_guild_status_message(guild_id: GuildId, msg: &str) {
-    let buffer_id = utils::buffer_id_for_guild(guild_id);
+/// Tell the parent channel that a thread was started and how to open it
+fn announce_thread(account: &str, ctx: &Context, thread: &threads::Thread) {
+    let owner = thread
+        .owner_id
+        .and_then(|id| id.to_user_cached(&ctx.cache))
+        .map(|user| user.read().name.clone())
+        .unwrap_or_else(|| "Someone".to_owned());
+    let buffer_name =
+        utils::buffer_id_for_channel(account, Some(thread.guild_id), thread.parent_id);
+    let msg = format!(
+        "{} started a thread `{}`, use /discord thread {} to open it",
+        owner, thread.name, thread.id.0
+    );
+
+    on_main(move |weecord| {
+        if let Some(buffer) = weecord.buffer_manager.get_buffer(&buffer_name) {
+            let prefix = weecord.get_prefix("network").to_owned();
+            buffer.print(&(prefix + "\t" + msg.as_ref()));
+        }
+    })
+}
+
+/// Role mentions are matched against the current user's roles in the cache, large guilds only send
+/// some of their members so fetch ours if it is missing
+///
+/// The member is fetched before the message is printed, once per guild unless the request fails.
+fn cache_own_member(ctx: &Context, guild_id: GuildId) {
+    let guild = match guild_id.to_guild_cached(&ctx.cache) {
+        Some(guild) => guild,
+        None => return,
+    };
+    let user_id = ctx.cache.read().user.id;
+    if guild.read().members.contains_key(&user_id) {
+        return;
+    }
+    if !OWN_MEMBER_FETCHES.lock().insert((user_id, guild_id)) {
+        return;
+    }
+    match ctx.http.get_member(guild_id.0, user_id.0) {
+        Ok(member) => {
+            guild.write().members.insert(user_id, member);
+        },
+        // Try again on the next role mention
+        Err(_) => {
+            OWN_MEMBER_FETCHES.lock().remove(&(user_id, guild_id));
+        },
+    }
+}
+
+fn print_guild_status_message(account: &str, guild_id: GuildId, msg: &str) {
+    let buffer_id = utils::buffer_id_for_guild(account, guild_id);
 
     let msg = msg.to_owned();
     on_main(move |weechat| {
@@ -469,3 +686,84 @@ fn print_guild_status_message(guild_id: GuildId, msg: &str) {
         }
     })
 }
+
+#[cfg(test)]
+mod tests {
+    use super::print_message;
+    use crate::{
+        discord::{notify, rest},
+        weechat_utils::{host::fake::FakeBuffer, MessageManager},
+    };
+    use mock_discord::{Fixture, GatewayClient, MockDiscord, GENERAL_CHANNEL_ID, OTHER_USER_ID};
+    use reqwest::Method;
+    use serenity::{
+        cache::CacheRwLock,
+        model::{channel::Message, event::ReadyEvent},
+    };
+
+    #[test]
+    fn prints_history_and_dispatches_from_the_mock() {
+        let server = MockDiscord::start(Fixture::default()).unwrap();
+        let api_url = server.api_url();
+        let api = rest::Api::new(&api_url);
+        let mut gateway = GatewayClient::connect(&server);
+
+        let mut ready: ReadyEvent = serde_json::from_str(&gateway.identify().dump()).unwrap();
+        let cache = CacheRwLock::default();
+        cache.write().update(&mut ready);
+        notify::load("mock", &ready.ready);
+
+        let buffer = MessageManager::new(FakeBuffer::default());
+        let path = format!("/channels/{}/messages", GENERAL_CHANNEL_ID);
+        let history: Vec<Message> = api
+            .request("mock-token", Method::GET, &path, &[])
+            .unwrap()
+            .json()
+            .unwrap();
+        for msg in history.iter().rev() {
+            print_message(&cache, msg, &buffer);
+        }
+
+        let author = mock_discord::user_json(OTHER_USER_ID, "alice");
+        server.receive_message(GENERAL_CHANNEL_ID, &author, "ping");
+        let msg: Message =
+            serde_json::from_str(&gateway.recv_dispatch("MESSAGE_CREATE").dump()).unwrap();
+        print_message(&cache, &msg, &buffer);
+
+        api.send_json(
+            "mock-token",
+            Method::POST,
+            &path,
+            &serde_json::json!({ "content": "pong" }),
+        )
+        .unwrap();
+        let own: Message =
+            serde_json::from_str(&gateway.recv_dispatch("MESSAGE_CREATE").dump()).unwrap();
+        print_message(&cache, &own, &buffer);
+
+        assert_eq!(
+            buffer.messages(),
+            vec![
+                "{reset}alice{reset}\thello",
+                "{reset}alice{reset}\t{bold}world{-bold}",
+                "{reset}alice{reset}\tping",
+                "{reset}weecord{reset}\tpong",
+            ]
+        );
+        let tags: Vec<_> = buffer
+            .lines
+            .borrow()
+            .iter()
+            .map(|l| l.tags.clone())
+            .collect();
+        assert_eq!(
+            tags,
+            vec![
+                "notify_message",
+                "notify_message",
+                "notify_message",
+                "notify_none"
+            ]
+        );
+    }
+}
@@ -1,64 +1,95 @@
+use crate::{
+    discord::syntax::{self, TokenKind},
+    utils::ChannelExt,
+    weechat_utils::WeechatHost,
+};
+use chrono::{Local, TimeZone};
+use lazy_static::lazy_static;
 use parsing::{self, MarkdownNode};
-use std::{rc::Rc, sync::RwLock};
-use weechat::Weechat;
+use regex::{Captures, Regex};
+use serenity::{
+    cache::CacheRwLock,
+    model::id::{ChannelId, RoleId},
+};
 
-pub fn discord_to_weechat(weechat: &Weechat, msg: &str) -> String {
-    let ast = parsing::parse_markdown(msg);
+/// Hidden spoilers are drawn as a bar of this glyph
+const SPOILER_GLYPH: &str = "█";
+/// Width of the placeholder for a timestamp inside a hidden spoiler
+const TIMESTAMP_WIDTH: usize = 8;
 
-    let mut out = String::new();
-    for node in &ast.0 {
-        out.push_str(&discord_to_weechat_reducer(
-            &weechat,
-            &*node.read().unwrap(),
-        ))
-    }
-    out
+/// Render markdown with WeeChat colors, spoilers are hidden unless `reveal_spoilers` is set. Role
+/// and channel mentions are shown by name when they are in the cache.
+pub fn discord_to_weechat(
+    weechat: &impl WeechatHost,
+    cache: &CacheRwLock,
+    msg: &str,
+    reveal_spoilers: bool,
+) -> String {
+    collect_styles(
+        weechat,
+        cache,
+        reveal_spoilers,
+        &parsing::parse_markdown(msg),
+    )
 }
 
-fn collect_styles(weechat: &Weechat, styles: &[Rc<RwLock<MarkdownNode>>]) -> String {
+fn collect_styles(
+    weechat: &impl WeechatHost,
+    cache: &CacheRwLock,
+    reveal: bool,
+    styles: &[MarkdownNode],
+) -> String {
     styles
         .iter()
-        .map(|s| discord_to_weechat_reducer(&weechat, &*s.read().unwrap()))
+        .map(|s| discord_to_weechat_reducer(weechat, cache, reveal, s))
         .collect::<Vec<_>>()
         .join("")
 }
 
-// TODO: Spoilers, code syntax highlighting?
-// TODO: if the whole line is wrapped in *, render as CTCP ACTION rather than
-// as fully italicized message.
-fn discord_to_weechat_reducer(weechat: &Weechat, node: &MarkdownNode) -> String {
+fn discord_to_weechat_reducer(
+    weechat: &impl WeechatHost,
+    cache: &CacheRwLock,
+    reveal: bool,
+    node: &MarkdownNode,
+) -> String {
     use MarkdownNode::*;
     match node {
         Bold(styles) => format!(
             "{}{}{}",
             weechat.color("bold"),
-            collect_styles(weechat, styles),
+            collect_styles(weechat, cache, reveal, styles),
             weechat.color("-bold")
         ),
         Italic(styles) => format!(
             "{}{}{}",
             weechat.color("italic"),
-            collect_styles(weechat, styles),
+            collect_styles(weechat, cache, reveal, styles),
             weechat.color("-italic")
         ),
         Underline(styles) => format!(
             "{}{}{}",
             weechat.color("underline"),
-            collect_styles(weechat, styles),
+            collect_styles(weechat, cache, reveal, styles),
             weechat.color("-underline")
         ),
         Strikethrough(styles) => format!(
             "{}~~{}~~{}",
             weechat.color("red"),
-            collect_styles(weechat, styles),
+            collect_styles(weechat, cache, reveal, styles),
             weechat.color("-red")
         ),
-        Spoiler(styles) => format!(
+        Spoiler(styles) if reveal => format!(
             "{}||{}||{}",
             weechat.color("italic"),
-            collect_styles(weechat, styles),
+            collect_styles(weechat, cache, reveal, styles),
             weechat.color("-italic")
         ),
+        Spoiler(styles) => format!(
+            "{}||{}||{}",
+            weechat.color("8"),
+            SPOILER_GLYPH.repeat(hidden_len(styles).max(1)),
+            weechat.color("resetcolor")
+        ),
         Text(string) => string.to_owned(),
         InlineCode(string) => format!(
             "{}{}{}",
@@ -66,27 +97,248 @@ fn discord_to_weechat_reducer(weechat: &Weechat, node: &MarkdownNode) -> String
             string,
             weechat.color("reset")
         ),
-        Code(language, text) => {
-            let (fmt, reset) = (weechat.color("*8"), weechat.color("reset"));
-
-            format!(
-                "```{}\n{}\n```",
-                language,
-                text.lines()
-                    .map(|l| format!("{}{}{}", fmt, l, reset))
-                    .collect::<Vec<_>>()
-                    .join("\n"),
-            )
+        Code(language, text) => format!(
+            "```{}\n{}\n```",
+            language,
+            highlight_code(weechat, language, text)
+        ),
+        BlockQuote(styles) => {
+            format_block_quote(collect_styles(weechat, cache, reveal, styles).lines())
         },
-        BlockQuote(styles) => format_block_quote(collect_styles(weechat, styles).lines()),
         SingleBlockQuote(styles) => format_block_quote(
-            collect_styles(weechat, styles)
+            collect_styles(weechat, cache, reveal, styles)
                 .lines()
                 .map(strip_leading_bracket),
         ),
+        Link(url) => format!(
+            "{}{}{}",
+            weechat.color("underline"),
+            url,
+            weechat.color("-underline")
+        ),
+        SuppressedLink(url) => format!(
+            "{}<{}{}{}{}>{}",
+            weechat.color("8"),
+            weechat.color("underline"),
+            url,
+            weechat.color("-underline"),
+            weechat.color("8"),
+            weechat.color("resetcolor")
+        ),
+        // The url is always shown so the text can't disguise where a link goes
+        MaskedLink { text, url } => format!(
+            "{}{}{} {}({}){}",
+            weechat.color("underline"),
+            text,
+            weechat.color("-underline"),
+            weechat.color("8"),
+            url,
+            weechat.color("resetcolor")
+        ),
+        Timestamp(seconds, style) => format!(
+            "{}{}{}",
+            weechat.color("cyan"),
+            format_timestamp(*seconds, *style),
+            weechat.color("resetcolor")
+        ),
+        UserMention(id) => format_mention(weechat, &format!("@{}", id)),
+        RoleMention(id) => {
+            let name = RoleId(*id)
+                .to_role_cached(cache)
+                .map(|role| format!("@{}", role.name))
+                .unwrap_or_else(|| format!("@&{}", id));
+            format_mention(weechat, &name)
+        },
+        ChannelMention(id) => {
+            let name = ChannelId(*id)
+                .to_channel_cached(cache)
+                .map(|channel| format!("#{}", channel.name()))
+                .unwrap_or_else(|| format!("#{}", id));
+            format_mention(weechat, &name)
+        },
+        EveryoneMention(name) => format!(
+            "{}@{}{}",
+            weechat.color("yellow"),
+            name,
+            weechat.color("resetcolor")
+        ),
+        // Top level headers are also underlined to stand out from the smaller ones
+        Header(1, styles) => format!(
+            "{}{}{}{}{}",
+            weechat.color("bold"),
+            weechat.color("underline"),
+            collect_styles(weechat, cache, reveal, styles),
+            weechat.color("-underline"),
+            weechat.color("-bold")
+        ),
+        Header(_, styles) => format!(
+            "{}{}{}",
+            weechat.color("bold"),
+            collect_styles(weechat, cache, reveal, styles),
+            weechat.color("-bold")
+        ),
+        ListItem {
+            depth,
+            number,
+            content,
+        } => {
+            let marker = match number {
+                Some(number) => format!("{}.", number),
+                None => "•".to_owned(),
+            };
+            format!(
+                "{}{} {}",
+                "  ".repeat(*depth),
+                marker,
+                collect_styles(weechat, cache, reveal, content)
+            )
+        },
     }
 }
 
+/// The text of a message that is italic as a whole, like those sent with `/me`, without the
+/// surrounding `_` or `*`
+pub fn action_text(msg: &str) -> Option<&str> {
+    let trimmed = msg.trim();
+    let marker = trimmed.chars().next().filter(|&c| c == '_' || c == '*')?;
+    if trimmed.len() < 3 || !trimmed.ends_with(marker) {
+        return None;
+    }
+    match parsing::parse_markdown(trimmed).as_slice() {
+        [MarkdownNode::Italic(_)] => Some(&trimmed[1..trimmed.len() - 1]),
+        _ => None,
+    }
+}
+
+/// The number of characters a spoiler hides, so its placeholder has the same width
+fn hidden_len(styles: &[MarkdownNode]) -> usize {
+    use MarkdownNode::*;
+    styles
+        .iter()
+        .map(|node| match node {
+            Bold(styles)
+            | Italic(styles)
+            | Underline(styles)
+            | Strikethrough(styles)
+            | Spoiler(styles)
+            | BlockQuote(styles)
+            | SingleBlockQuote(styles)
+            | Header(_, styles)
+            | ListItem {
+                content: styles, ..
+            } => hidden_len(styles),
+            Text(text) | InlineCode(text) | Code(_, text) | Link(text) | SuppressedLink(text) => {
+                text.chars().filter(|&c| c != '\n').count()
+            },
+            MaskedLink { text, .. } => text.chars().count(),
+            UserMention(id) | RoleMention(id) | ChannelMention(id) => id.to_string().len() + 1,
+            EveryoneMention(name) => name.len() + 1,
+            Timestamp(..) => TIMESTAMP_WIDTH,
+        })
+        .sum()
+}
+
+/// Hide `||spoilers||` in text that is shown without rendering its markdown, like reply excerpts
+pub fn mask_spoilers(text: &str) -> String {
+    lazy_static! {
+        static ref SPOILER: Regex = Regex::new(r"\|\|(.+?)\|\|").unwrap();
+    }
+
+    SPOILER
+        .replace_all(text, |caps: &Captures| {
+            format!("||{}||", SPOILER_GLYPH.repeat(caps[1].chars().count()))
+        })
+        .into_owned()
+}
+
+/// Mentions that could not be resolved to a name are shown with their id
+fn format_mention(weechat: &impl WeechatHost, mention: &str) -> String {
+    format!(
+        "{}{}{}",
+        weechat.color("lightblue"),
+        mention,
+        weechat.color("resetcolor")
+    )
+}
+
+/// Format a `<t:seconds:style>` timestamp in local time like the official client
+fn format_timestamp(seconds: i64, style: Option<char>) -> String {
+    let time = match Local.timestamp_opt(seconds, 0).single() {
+        Some(time) => time,
+        None => return seconds.to_string(),
+    };
+    let format = match style {
+        Some('t') => "%H:%M",
+        Some('T') => "%H:%M:%S",
+        Some('d') => "%d/%m/%Y",
+        Some('D') => "%-d %B %Y",
+        Some('F') => "%A, %-d %B %Y %H:%M",
+        Some('R') => return format_relative(seconds - Local::now().timestamp()),
+        _ => "%-d %B %Y %H:%M",
+    };
+    time.format(format).to_string()
+}
+
+/// Describe an offset from now in its largest whole unit, such as "in 2 days" or "3 hours ago"
+fn format_relative(delta: i64) -> String {
+    const UNITS: [(i64, &str); 6] = [
+        (365 * 24 * 60 * 60, "year"),
+        (30 * 24 * 60 * 60, "month"),
+        (24 * 60 * 60, "day"),
+        (60 * 60, "hour"),
+        (60, "minute"),
+        (1, "second"),
+    ];
+
+    let (size, unit) = UNITS
+        .iter()
+        .find(|(size, _)| delta.abs() >= *size)
+        .unwrap_or(&UNITS[UNITS.len() - 1]);
+    let count = delta.abs() / size;
+    let plural = if count == 1 { "" } else { "s" };
+    if delta >= 0 {
+        format!("in {} {}{}", count, unit, plural)
+    } else {
+        format!("{} {}{} ago", count, unit, plural)
+    }
+}
+
+/// Color each token of a code block, colors are set again on every line since WeeChat does not
+/// carry them over from one line to the next
+fn highlight_code(weechat: &impl WeechatHost, language: &str, code: &str) -> String {
+    let reset = weechat.color("reset");
+    let mut out = String::new();
+    for (kind, text) in syntax::tokenize(language, code) {
+        let color = weechat.color(&code_color(weechat, kind)).into_owned();
+        for (i, piece) in text.split('\n').enumerate() {
+            if i > 0 {
+                out.push('\n');
+            }
+            if !piece.is_empty() {
+                out.push_str(&format!("{}{}{}", color, piece, reset));
+            }
+        }
+    }
+    out.trim_end_matches('\n').to_owned()
+}
+
+/// The color of a token from the `weecord.look` section
+fn code_color(weechat: &impl WeechatHost, kind: TokenKind) -> String {
+    let (option, default) = match kind {
+        TokenKind::Text => ("code", "*8"),
+        TokenKind::Keyword => ("code_keyword", "lightmagenta"),
+        TokenKind::String => ("code_string", "green"),
+        TokenKind::Number => ("code_number", "cyan"),
+        TokenKind::Comment => ("code_comment", "/darkgray"),
+        TokenKind::DiffAdded => ("diff_added", "green"),
+        TokenKind::DiffRemoved => ("diff_removed", "red"),
+        TokenKind::DiffHunk => ("diff_hunk", "cyan"),
+    };
+    weechat
+        .string_option(&format!("weecord.look.{}", option))
+        .unwrap_or_else(|| default.to_owned())
+}
+
 fn strip_leading_bracket(line: &str) -> &str {
     &line[line.find("> ").map(|x| x + 2).unwrap_or(0)..]
 }
@@ -94,3 +346,69 @@ fn strip_leading_bracket(line: &str) -> &str {
 fn format_block_quote<'a>(lines: impl Iterator<Item = &'a str>) -> String {
     lines.fold(String::new(), |acc, x| format!("{}▎{}\n", acc, x))
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{discord_to_weechat, format_relative};
+    use crate::weechat_utils::host::fake::FakeHost;
+    use serenity::{cache::CacheRwLock, model::prelude::*, prelude::RwLock};
+    use std::sync::Arc;
+
+    #[test]
+    fn relative_timestamps_use_the_largest_unit() {
+        assert_eq!(format_relative(90), "in 1 minute");
+        assert_eq!(format_relative(-3 * 60 * 60), "3 hours ago");
+        assert_eq!(format_relative(0), "in 0 seconds");
+    }
+
+    #[test]
+    fn mentions_are_resolved_from_the_cache() {
+        let cache = CacheRwLock::default();
+        let channel: GuildChannel = serde_json::from_value(serde_json::json!({
+            "id": "4",
+            "guild_id": "200",
+            "name": "general",
+            "type": 0,
+            "position": 0,
+            "permission_overwrites": [],
+            "nsfw": false,
+        }))
+        .unwrap();
+        cache
+            .write()
+            .channels
+            .insert(ChannelId(4), Arc::new(RwLock::new(channel)));
+
+        assert_eq!(
+            discord_to_weechat(&FakeHost::default(), &cache, "<#4> <#5> <@&3>", false),
+            "{lightblue}#general{resetcolor} {lightblue}#5{resetcolor} {lightblue}@&3{resetcolor}"
+        );
+    }
+
+    #[test]
+    fn headers_and_lists_are_styled() {
+        assert_eq!(
+            discord_to_weechat(
+                &FakeHost::default(),
+                &CacheRwLock::default(),
+                "# Title\n## **Sub**\n- one\n  * two\n3. three",
+                false
+            ),
+            "{bold}{underline}Title{-underline}{-bold}\n{bold}{bold}Sub{-bold}{-bold}\n• one\n  • \
+             two\n3. three"
+        );
+    }
+
+    #[test]
+    fn escaped_mentions_stay_text() {
+        assert_eq!(
+            discord_to_weechat(
+                &FakeHost::default(),
+                &CacheRwLock::default(),
+                r"\<@1>",
+                false
+            ),
+            "<@1>"
+        );
+    }
+}
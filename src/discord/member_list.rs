@@ -0,0 +1,607 @@
+//! Discord's lazy member list (gateway op 14)
+//!
+//! Large guilds (as flagged by Discord) never send their full member list, instead the client
+//! subscribes to ranges of a channel's sorted list and Discord keeps those ranges up to
+//! date with `GUILD_MEMBER_LIST_UPDATE` events. Like the official client, only the first page and
+//! the part of the list scrolled into view are subscribed. The list is mirrored in a `MemberList`
+//! and drawn into the nicklist of every buffer showing it, in the order Discord sorted it.
+
+use crate::{on_main, utils, weechat_utils::BufferHost};
+use lazy_static::lazy_static;
+use serde_json::Value;
+use serenity::{
+    client::bridge::gateway,
+    model::prelude::*,
+    prelude::{Context, Mutex},
+};
+use std::collections::HashMap;
+
+/// Discord serves ranges of 100 items and accepts at most 3 ranges per channel
+const RANGE_SIZE: u64 = 100;
+const MAX_RANGES: usize = 3;
+
+/// A `/bar scroll` of the nicklist
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Scroll {
+    Start,
+    End,
+    /// A number of lines, negative scrolls up
+    By(i64),
+}
+
+/// Parse the value of `/bar scroll nicklist <window> <value>`, percentages are of the `visible`
+/// lines. Horizontal scrolls are ignored.
+pub fn parse_scroll(value: &str, visible: u64) -> Option<Scroll> {
+    let value = value.trim_start_matches('y');
+    match value {
+        "b" => return Some(Scroll::Start),
+        "e" => return Some(Scroll::End),
+        _ => {},
+    }
+
+    let (sign, amount) = match value.chars().next()? {
+        '+' => (1, &value[1..]),
+        '-' => (-1, &value[1..]),
+        _ => return None,
+    };
+    let lines = if amount.ends_with('%') {
+        let percent: i64 = amount.trim_end_matches('%').parse().ok()?;
+        percent * visible as i64 / 100
+    } else {
+        amount.parse().ok()?
+    };
+    Some(Scroll::By(sign * lines))
+}
+
+/// The ranges covering a window of `visible` items starting at `offset`, along with the first page
+/// which is always subscribed
+fn window_ranges(offset: u64, visible: u64, len: u64) -> Vec<[u64; 2]> {
+    let mut ranges = vec![[0, RANGE_SIZE - 1]];
+    let first = offset / RANGE_SIZE;
+    let last = (offset + visible.max(1) - 1) / RANGE_SIZE;
+    for page in first.max(1)..=last {
+        if ranges.len() == MAX_RANGES || page * RANGE_SIZE >= len {
+            break;
+        }
+        ranges.push([page * RANGE_SIZE, (page + 1) * RANGE_SIZE - 1]);
+    }
+    ranges
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Item {
+    /// A group header followed by its members, the id is a role id, "online" or "offline"
+    Group {
+        id: String,
+    },
+    Member {
+        name: String,
+    },
+}
+
+impl Item {
+    fn from_value(value: &Value) -> Option<Item> {
+        if let Some(group) = value.get("group") {
+            return Some(Item::Group {
+                id: group["id"].as_str()?.to_owned(),
+            });
+        }
+
+        let member = value.get("member")?;
+        let name = member["nick"]
+            .as_str()
+            .or_else(|| member["user"]["username"].as_str())?
+            .to_owned();
+        Some(Item::Member { name })
+    }
+}
+
+/// A mirror of one member list, items outside the synced ranges are unknown
+#[derive(Debug, Default)]
+pub struct MemberList {
+    items: Vec<Option<Item>>,
+    /// Total number of items (groups and members) in the list
+    len: u64,
+}
+
+impl MemberList {
+    /// Apply the `groups` and `ops` of a `GUILD_MEMBER_LIST_UPDATE`
+    pub fn update(&mut self, data: &Value) {
+        if let Some(groups) = data["groups"].as_array() {
+            self.len = groups
+                .iter()
+                .map(|group| group["count"].as_u64().unwrap_or_default() + 1)
+                .sum();
+        }
+
+        for op in data["ops"].as_array().into_iter().flatten() {
+            self.apply(op);
+        }
+    }
+
+    fn apply(&mut self, op: &Value) {
+        let index = op["index"].as_u64().map(|index| index as usize);
+        let range = op["range"].as_array().and_then(|range| {
+            Some((
+                range.get(0)?.as_u64()? as usize,
+                range.get(1)?.as_u64()? as usize,
+            ))
+        });
+
+        match (op["op"].as_str(), index, range) {
+            (Some("SYNC"), _, Some((start, _))) => {
+                let items = op["items"].as_array().cloned().unwrap_or_default();
+                self.grow(start + items.len());
+                for (i, item) in items.iter().enumerate() {
+                    self.items[start + i] = Item::from_value(item);
+                }
+            },
+            // Sent for ranges that leave the subscribed window, Discord stops updating them
+            (Some("INVALIDATE"), _, Some((start, end))) => self.forget(start, end),
+            (Some("INSERT"), Some(index), _) => {
+                self.grow(index);
+                self.items.insert(index, Item::from_value(&op["item"]));
+            },
+            (Some("UPDATE"), Some(index), _) => {
+                self.grow(index + 1);
+                self.items[index] = Item::from_value(&op["item"]);
+            },
+            (Some("DELETE"), Some(index), _) => {
+                if index < self.items.len() {
+                    self.items.remove(index);
+                }
+            },
+            _ => {},
+        }
+    }
+
+    fn grow(&mut self, len: usize) {
+        if self.items.len() < len {
+            self.items.resize(len, None);
+        }
+    }
+
+    /// Forget the items from `start` to `end` (inclusive), they are no longer kept up to date
+    fn forget(&mut self, start: usize, end: usize) {
+        let end = (end + 1).min(self.items.len());
+        if start < end {
+            for item in &mut self.items[start..end] {
+                *item = None;
+            }
+        }
+    }
+
+    /// Forget every item outside of `ranges`
+    fn retain_ranges(&mut self, ranges: &[[u64; 2]]) {
+        for (index, item) in self.items.iter_mut().enumerate() {
+            let index = index as u64;
+            if !ranges
+                .iter()
+                .any(|range| range[0] <= index && index <= range[1])
+            {
+                *item = None;
+            }
+        }
+    }
+
+    /// Known members by group, in list order
+    pub fn groups(&self) -> Vec<(&str, Vec<&Item>)> {
+        let mut groups: Vec<(&str, Vec<&Item>)> = Vec::new();
+        for item in self.items.iter().flatten() {
+            match item {
+                Item::Group { id } => groups.push((id, Vec::new())),
+                Item::Member { .. } => {
+                    if let Some((_, members)) = groups.last_mut() {
+                        members.push(item);
+                    }
+                },
+            }
+        }
+        groups.retain(|(_, members)| !members.is_empty());
+        groups
+    }
+}
+
+struct Subscription {
+    /// Subscribed channels of a guild and their ranges, only the focused channel requests more
+    /// than the first page
+    channels: HashMap<ChannelId, Vec<[u64; 2]>>,
+    focused: ChannelId,
+    /// The first item in view in the nicklist of the focused channel
+    offset: u64,
+}
+
+/// Nicklist group and the nicks drawn into it
+type Rendered = Vec<(String, String, Vec<String>)>;
+
+#[derive(Default)]
+struct State {
+    subscriptions: HashMap<(String, GuildId), Subscription>,
+    /// Lists by account, guild and list id
+    lists: HashMap<(String, GuildId, String), MemberList>,
+    /// The list shown by each channel, several channels can share a list
+    channel_lists: HashMap<(String, ChannelId), String>,
+    /// Groups and nicks drawn into each buffer, so they can be removed on the next draw
+    drawn: HashMap<String, Vec<(String, String)>>,
+}
+
+lazy_static! {
+    static ref STATE: Mutex<State> = Mutex::new(State::default());
+}
+
+/// Large guilds only send members that are online or talking, their nicklists use the member list
+pub fn is_large(guild: &Guild) -> bool {
+    guild.large
+}
+
+/// Subscribe to the member list of a channel when its buffer is focused, the previously focused
+/// channel of the guild drops back to its first page
+pub fn subscribe(account: &str, ctx: &Context, guild_id: GuildId, channel_id: ChannelId) {
+    let mut guard = STATE.lock();
+    let state = &mut *guard;
+    let subscription = state
+        .subscriptions
+        .entry((account.to_owned(), guild_id))
+        .or_insert_with(|| Subscription {
+            channels: HashMap::new(),
+            focused: channel_id,
+            offset: 0,
+        });
+
+    let mut changed = false;
+    if subscription.focused != channel_id {
+        let previous = subscription.focused;
+        subscription.focused = channel_id;
+        subscription.offset = 0;
+        if let Some(ranges) = subscription.channels.get_mut(&previous) {
+            if ranges.len() > 1 {
+                ranges.truncate(1);
+                changed = true;
+                if let Some(list) = channel_list(
+                    &mut state.lists,
+                    &state.channel_lists,
+                    account,
+                    guild_id,
+                    previous,
+                ) {
+                    list.retain_ranges(ranges);
+                }
+            }
+        }
+    }
+    if !subscription.channels.contains_key(&channel_id) {
+        subscription
+            .channels
+            .insert(channel_id, vec![[0, RANGE_SIZE - 1]]);
+        changed = true;
+    }
+
+    if changed {
+        send_subscription(ctx, guild_id, subscription);
+    }
+}
+
+/// Move the window of the focused channel after its nicklist was scrolled, subscribing to the
+/// ranges that came into view and dropping the ones that left it
+pub fn scroll(
+    account: &str,
+    ctx: &Context,
+    guild_id: GuildId,
+    channel_id: ChannelId,
+    scroll: Scroll,
+    visible: u64,
+) {
+    let mut guard = STATE.lock();
+    let state = &mut *guard;
+    let subscription = match state.subscriptions.get_mut(&(account.to_owned(), guild_id)) {
+        Some(subscription) if subscription.focused == channel_id => subscription,
+        _ => return,
+    };
+    let list = match channel_list(
+        &mut state.lists,
+        &state.channel_lists,
+        account,
+        guild_id,
+        channel_id,
+    ) {
+        Some(list) => list,
+        None => return,
+    };
+
+    let max_offset = list.len.saturating_sub(visible);
+    subscription.offset = match scroll {
+        Scroll::Start => 0,
+        Scroll::End => max_offset,
+        Scroll::By(lines) => (subscription.offset as i64 + lines).max(0) as u64,
+    }
+    .min(max_offset);
+
+    let ranges = window_ranges(subscription.offset, visible, list.len);
+    if subscription.channels.get(&channel_id) != Some(&ranges) {
+        list.retain_ranges(&ranges);
+        subscription.channels.insert(channel_id, ranges);
+        send_subscription(ctx, guild_id, subscription);
+    }
+}
+
+/// The list shown by a channel, once Discord has sent it
+fn channel_list<'a>(
+    lists: &'a mut HashMap<(String, GuildId, String), MemberList>,
+    channel_lists: &HashMap<(String, ChannelId), String>,
+    account: &str,
+    guild_id: GuildId,
+    channel_id: ChannelId,
+) -> Option<&'a mut MemberList> {
+    let list_id = channel_lists.get(&(account.to_owned(), channel_id))?;
+    lists.get_mut(&(account.to_owned(), guild_id, list_id.clone()))
+}
+
+fn send_subscription(ctx: &Context, guild_id: GuildId, subscription: &Subscription) {
+    let channels: HashMap<String, Vec<Vec<u64>>> = subscription
+        .channels
+        .iter()
+        .map(|(channel, ranges)| {
+            let ranges = ranges.iter().map(|range| range.to_vec()).collect();
+            (channel.0.to_string(), ranges)
+        })
+        .collect();
+
+    let msg = json::object! {
+        "op" => 14,
+        "d" => json::object! {
+            "guild_id" => guild_id.0.to_string(),
+            "typing" => true,
+            "activities" => true,
+            "channels" => channels,
+        }
+    };
+    ctx.shard
+        .websocket_message(gateway::Message::Text(msg.to_string()));
+}
+
+/// Apply a `GUILD_MEMBER_LIST_UPDATE` and redraw the nicklists showing the list
+pub fn handle_update(account: &str, ctx: &Context, data: &Value) {
+    let guild_id = match data["guild_id"].as_str().and_then(|id| id.parse().ok()) {
+        Some(id) => GuildId(id),
+        None => return,
+    };
+    let list_id = match data["id"].as_str() {
+        Some(id) => id.to_owned(),
+        None => return,
+    };
+    let guild = match guild_id.to_guild_cached(ctx) {
+        Some(guild) => guild,
+        None => return,
+    };
+
+    let mut guard = STATE.lock();
+    let state = &mut *guard;
+    let list = state
+        .lists
+        .entry((account.to_owned(), guild_id, list_id.clone()))
+        .or_default();
+    list.update(data);
+
+    // List ids are opaque hashes, a list nothing shows yet belongs to the focused channel
+    let subscription = match state.subscriptions.get(&(account.to_owned(), guild_id)) {
+        Some(subscription) => subscription,
+        None => return,
+    };
+    state
+        .channel_lists
+        .entry((account.to_owned(), subscription.focused))
+        .or_insert_with(|| list_id.clone());
+
+    if !is_large(&guild.read()) {
+        return;
+    }
+
+    let guild = guild.read();
+    let rendered: Rendered = list
+        .groups()
+        .into_iter()
+        .enumerate()
+        .map(|(position, (id, members))| {
+            let (name, color) = match id {
+                "online" => ("Online".to_owned(), "grey".to_owned()),
+                "offline" => ("Offline".to_owned(), "grey".to_owned()),
+                id => id
+                    .parse()
+                    .ok()
+                    .and_then(|id| guild.roles.get(&RoleId(id)))
+                    .map(|role| {
+                        let color = utils::rgb_to_ansi(role.colour).to_string();
+                        (role.name.clone(), color)
+                    })
+                    .unwrap_or_else(|| (id.to_owned(), "grey".to_owned())),
+            };
+            let nicks = members
+                .into_iter()
+                .filter_map(|member| match member {
+                    Item::Member { name } => Some(name.clone()),
+                    Item::Group { .. } => None,
+                })
+                .collect();
+            // WeeChat sorts groups by name, the number is hidden and keeps Discord's order
+            (format!("{:05}|{}", position, name), color, nicks)
+        })
+        .collect();
+
+    let buffers: Vec<String> = state
+        .channel_lists
+        .iter()
+        .filter(|((list_account, _), id)| list_account == account && **id == list_id)
+        .map(|((_, channel), _)| utils::buffer_id_for_channel(account, Some(guild_id), *channel))
+        .collect();
+    drop(guild);
+    drop(guard);
+
+    on_main(move |weecord| {
+        let mut state = STATE.lock();
+        for buffer_name in buffers {
+            let buffer = match weecord.buffer_manager.get_buffer(&buffer_name) {
+                Some(buffer) => buffer,
+                None => continue,
+            };
+            let drawn = state.drawn.entry(buffer_name).or_default();
+            draw(&**weecord, &**buffer, &rendered, drawn);
+        }
+    });
+}
+
+fn draw(
+    weechat: &impl crate::weechat_utils::WeechatHost,
+    buffer: &impl BufferHost,
+    rendered: &Rendered,
+    drawn: &mut Vec<(String, String)>,
+) {
+    for (group, nick) in drawn.drain(..) {
+        buffer.nicklist_remove_nick(Some(&group), &nick);
+    }
+    for (group, color, nicks) in rendered {
+        buffer.nicklist_add_group(group, color);
+        for nick in nicks {
+            buffer.nicklist_add_nick(Some(group), nick, &utils::nick_color(weechat, nick), "");
+            drawn.push((group.clone(), nick.clone()));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse_scroll, window_ranges, Item, MemberList, Scroll};
+    use serde_json::json;
+
+    fn member(name: &str) -> serde_json::Value {
+        json!({ "member": { "user": { "id": "1", "username": name }, "nick": null } })
+    }
+
+    fn group(id: &str, count: u64) -> serde_json::Value {
+        json!({ "group": { "id": id, "count": count } })
+    }
+
+    fn names(list: &MemberList) -> Vec<(&str, Vec<String>)> {
+        list.groups()
+            .into_iter()
+            .map(|(id, members)| {
+                let names = members
+                    .into_iter()
+                    .filter_map(|member| match member {
+                        Item::Member { name } => Some(name.clone()),
+                        Item::Group { .. } => None,
+                    })
+                    .collect();
+                (id, names)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn applies_sync_insert_update_and_delete() {
+        let mut list = MemberList::default();
+        list.update(&json!({
+            "groups": [{ "id": "online", "count": 2 }, { "id": "offline", "count": 1 }],
+            "ops": [{
+                "op": "SYNC",
+                "range": [0, 99],
+                "items": [group("online", 2), member("alice"), member("bob"),
+                          group("offline", 1), member("carol")],
+            }],
+        }));
+        assert_eq!(
+            names(&list),
+            vec![
+                ("online", vec!["alice".to_owned(), "bob".to_owned()]),
+                ("offline", vec!["carol".to_owned()]),
+            ]
+        );
+
+        list.update(&json!({
+            "ops": [
+                { "op": "DELETE", "index": 4 },
+                { "op": "INSERT", "index": 2, "item": member("carol") },
+                { "op": "UPDATE", "index": 1, "item": member("alicia") },
+            ],
+        }));
+        assert_eq!(
+            names(&list),
+            vec![(
+                "online",
+                vec!["alicia".to_owned(), "carol".to_owned(), "bob".to_owned()]
+            )]
+        );
+    }
+
+    #[test]
+    fn syncs_the_mock_member_list() {
+        use mock_discord::{Fixture, GatewayClient, MockDiscord, GENERAL_CHANNEL_ID, GUILD_ID};
+
+        let server = MockDiscord::start(Fixture::default()).unwrap();
+        let mut gateway = GatewayClient::connect(&server);
+        gateway.identify();
+        gateway.send(json::object! {
+            "op" => 14,
+            "d" => json::object! {
+                "guild_id" => GUILD_ID.to_string(),
+                "channels" => json::object! {
+                    &GENERAL_CHANNEL_ID.to_string() => vec![vec![0, 99]],
+                },
+            },
+        });
+        let update = gateway.recv_dispatch("GUILD_MEMBER_LIST_UPDATE");
+
+        let mut list = MemberList::default();
+        list.update(&serde_json::from_str(&update.dump()).unwrap());
+        assert_eq!(
+            names(&list),
+            vec![("offline", vec!["weecord".to_owned(), "alice".to_owned()])]
+        );
+        assert_eq!(window_ranges(0, 50, list.len), vec![[0, 99]]);
+    }
+
+    #[test]
+    fn subscribes_only_to_the_ranges_in_view() {
+        assert_eq!(window_ranges(0, 50, 450), vec![[0, 99]]);
+        assert_eq!(window_ranges(80, 50, 450), vec![[0, 99], [100, 199]]);
+        assert_eq!(window_ranges(250, 50, 450), vec![[0, 99], [200, 299]]);
+        assert_eq!(
+            window_ranges(280, 50, 450),
+            vec![[0, 99], [200, 299], [300, 399]]
+        );
+        // Nothing past the end of the list
+        assert_eq!(window_ranges(80, 50, 100), vec![[0, 99]]);
+    }
+
+    #[test]
+    fn forgets_items_that_leave_the_window() {
+        let mut list = MemberList::default();
+        let items: Vec<_> = std::iter::once(group("online", 199))
+            .chain((0..199).map(|i| member(&format!("user{}", i))))
+            .collect();
+        list.update(&json!({
+            "groups": [{ "id": "online", "count": 199 }],
+            "ops": [
+                { "op": "SYNC", "range": [0, 99], "items": items[..100].to_vec() },
+                { "op": "SYNC", "range": [100, 199], "items": items[100..].to_vec() },
+            ],
+        }));
+        assert_eq!(names(&list)[0].1.len(), 199);
+
+        list.update(&json!({ "ops": [{ "op": "INVALIDATE", "range": [100, 199] }] }));
+        assert_eq!(names(&list)[0].1.len(), 99);
+
+        list.retain_ranges(&[[0, 49]]);
+        assert_eq!(names(&list)[0].1.len(), 49);
+    }
+
+    #[test]
+    fn parses_nicklist_scrolls() {
+        assert_eq!(parse_scroll("+100%", 40), Some(Scroll::By(40)));
+        assert_eq!(parse_scroll("-50%", 40), Some(Scroll::By(-20)));
+        assert_eq!(parse_scroll("y+10", 40), Some(Scroll::By(10)));
+        assert_eq!(parse_scroll("b", 40), Some(Scroll::Start));
+        assert_eq!(parse_scroll("ye", 40), Some(Scroll::End));
+        assert_eq!(parse_scroll("x+10", 40), None);
+        assert_eq!(parse_scroll("10", 40), None);
+    }
+}
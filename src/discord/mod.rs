@@ -1,48 +1,94 @@
 use self::client::DiscordClient;
 use crate::Discord;
 use lazy_static::lazy_static;
-use serenity::{client::Context, prelude::Mutex};
-use std::{sync::Arc, thread};
+use serenity::{
+    client::Context,
+    model::id::UserId,
+    prelude::{Mutex, RwLock},
+};
+use std::{
+    collections::{HashMap, HashSet},
+    sync::Arc,
+    thread,
+};
 
 mod client;
 mod event_handler;
 pub mod formatting;
+pub mod member_list;
+pub mod notify;
+pub mod rest;
+pub mod syntax;
+pub mod threads;
 
-pub use event_handler::TYPING_EVENTS;
+pub use client::{connection_state, ConnectionState};
+pub use event_handler::{TypingEntry, TYPING_EVENTS};
 
-pub static mut CONTEXT: Option<Context> = None;
+lazy_static! {
+    // Shared so a reconnect replacing a context doesn't free it under threads still using it
+    static ref CONTEXTS: RwLock<HashMap<String, Arc<Context>>> = RwLock::new(HashMap::new());
+    pub(crate) static ref DISCORD: Arc<Mutex<HashMap<String, DiscordClient>>> =
+        Arc::new(Mutex::new(HashMap::new()));
+    // Users with Nitro, who can send custom emoji from guilds other than the current one
+    static ref PREMIUM_USERS: RwLock<HashSet<UserId>> = RwLock::new(HashSet::new());
+}
 
-pub fn get_ctx() -> Option<&'static Context> {
-    unsafe { CONTEXT.as_ref() }
+pub fn get_ctx(account: &str) -> Option<Arc<Context>> {
+    CONTEXTS.read().get(account).cloned()
 }
 
-lazy_static! {
-    pub(crate) static ref DISCORD: Arc<Mutex<Option<DiscordClient>>> = Arc::new(Mutex::new(None));
+pub(crate) fn set_ctx(account: &str, ctx: Context) {
+    CONTEXTS.write().insert(account.to_owned(), Arc::new(ctx));
 }
 
-pub fn init(weecord: &Discord, token: &str, irc_mode: bool) {
-    let (discord_client, events) = match DiscordClient::start(weecord, token) {
+pub fn has_premium(user: UserId) -> bool {
+    PREMIUM_USERS.read().contains(&user)
+}
+
+/// Look up whether the current user has Nitro, serenity's `CurrentUser` does not carry it
+pub(crate) fn load_premium(token: &str, user: UserId) -> Result<(), String> {
+    let me: serde_json::Value = rest::request(token, reqwest::Method::GET, "/users/@me", &[])?
+        .json()
+        .map_err(|e| e.to_string())?;
+    let premium = me["premium_type"].as_u64().unwrap_or(0) > 0;
+    if premium {
+        PREMIUM_USERS.write().insert(user);
+    } else {
+        PREMIUM_USERS.write().remove(&user);
+    }
+    Ok(())
+}
+
+pub fn init(weecord: &Discord, account: &str, token: &str, irc_mode: bool) {
+    let (discord_client, events) = match DiscordClient::start(weecord, account, token) {
         Ok(d) => d,
         Err(e) => {
             // Cannot use plugin_print because we haven't finished init
             weecord.print(&format!(
-                "discord: An error occurred connecting to discord: {}",
-                e
+                "discord: An error occurred connecting account {} to discord: {}",
+                account, e
             ));
             return;
         },
     };
 
+    let account = account.to_owned();
+    DISCORD.lock().insert(account.clone(), discord_client);
+
     thread::spawn(move || {
         if let Ok(ready) = events.recv() {
-            crate::plugin_print("Discord connected");
+            crate::plugin_print(&format!("Discord connected ({})", account));
             if irc_mode {
-                crate::buffers::create_autojoin_buffers(&ready);
+                crate::buffers::create_autojoin_buffers(&account, &ready);
             } else {
-                crate::buffers::create_buffers(&ready);
+                crate::buffers::create_buffers(&account, &ready);
             }
         }
-    });
 
-    *DISCORD.lock() = Some(discord_client);
+        // Any later ready event is a new session, fetch whatever was missed while disconnected
+        for _ in events.iter() {
+            crate::plugin_print(&format!("Discord reconnected ({})", account));
+            crate::buffers::backfill_history(&account);
+        }
+    });
 }
@@ -0,0 +1,552 @@
+//! Notification and mute settings of guilds and channels, mirrored from Discord's user guild
+//! settings. They are parsed from JSON since our serenity fork only knows about `muted`, so the
+//! settings taken from the ready event are mutes only until Discord sends the full settings of a
+//! guild in an update.
+
+use crate::{discord::rest, weechat_utils::BufferHost};
+use chrono::{DateTime, Utc};
+use lazy_static::lazy_static;
+use serde_json::{json, Value};
+use serenity::{
+    cache::CacheRwLock,
+    model::{
+        gateway::Ready,
+        guild::DefaultMessageNotificationLevel,
+        id::{ChannelId, GuildId},
+    },
+    prelude::Mutex,
+};
+use std::{collections::HashMap, time::Duration};
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum NotifyLevel {
+    All,
+    Mentions,
+    Nothing,
+}
+
+impl NotifyLevel {
+    /// Parse Discord's `message_notifications`, `3` means the level is inherited
+    fn from_discord(value: &Value) -> Option<NotifyLevel> {
+        match value.as_u64()? {
+            0 => Some(NotifyLevel::All),
+            1 => Some(NotifyLevel::Mentions),
+            2 => Some(NotifyLevel::Nothing),
+            _ => None,
+        }
+    }
+
+    fn to_discord(self) -> u64 {
+        match self {
+            NotifyLevel::All => 0,
+            NotifyLevel::Mentions => 1,
+            NotifyLevel::Nothing => 2,
+        }
+    }
+
+    pub fn parse(name: &str) -> Option<NotifyLevel> {
+        match name {
+            "all" => Some(NotifyLevel::All),
+            "mentions" => Some(NotifyLevel::Mentions),
+            "none" | "nothing" => Some(NotifyLevel::Nothing),
+            _ => None,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            NotifyLevel::All => "all",
+            NotifyLevel::Mentions => "mentions",
+            NotifyLevel::Nothing => "none",
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum Mute {
+    #[default]
+    Unmuted,
+    Forever,
+    /// Muted until a unix timestamp in seconds
+    Until(i64),
+}
+
+impl Mute {
+    /// Parse `muted` and the `mute_config` that says when the mute ends
+    fn from_value(value: &Value) -> Mute {
+        if !value["muted"].as_bool().unwrap_or_default() {
+            return Mute::Unmuted;
+        }
+        match value["mute_config"]["end_time"]
+            .as_str()
+            .and_then(|end| DateTime::parse_from_rfc3339(end).ok())
+        {
+            Some(end) => Mute::Until(end.timestamp()),
+            None => Mute::Forever,
+        }
+    }
+
+    pub fn is_muted(self) -> bool {
+        match self {
+            Mute::Unmuted => false,
+            Mute::Forever => true,
+            Mute::Until(end) => end > Utc::now().timestamp(),
+        }
+    }
+
+    pub fn to_buffer(self, buffer: &impl BufferHost) {
+        buffer.set_localvar("muted", &(self.is_muted() as u8).to_string());
+        let until = match self {
+            Mute::Until(end) => end.to_string(),
+            _ => String::new(),
+        };
+        buffer.set_localvar("muted_until", &until);
+    }
+
+    pub fn from_buffer(buffer: &impl BufferHost) -> Mute {
+        if buffer.get_localvar("muted").as_deref() != Some("1") {
+            return Mute::Unmuted;
+        }
+        match buffer
+            .get_localvar("muted_until")
+            .and_then(|end| end.parse().ok())
+        {
+            Some(end) => Mute::Until(end),
+            None => Mute::Forever,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct ChannelSettings {
+    /// `None` when the channel uses the level of its guild
+    pub level: Option<NotifyLevel>,
+    pub mute: Mute,
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct GuildSettings {
+    /// `None` when the guild uses its default level
+    pub level: Option<NotifyLevel>,
+    pub mute: Mute,
+    pub suppress_everyone: bool,
+    pub suppress_roles: bool,
+    pub channels: HashMap<ChannelId, ChannelSettings>,
+}
+
+/// The settings that apply to the messages of one channel
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Notify {
+    pub level: NotifyLevel,
+    pub suppress_everyone: bool,
+    pub suppress_roles: bool,
+}
+
+impl Default for Notify {
+    fn default() -> Self {
+        Notify {
+            level: NotifyLevel::All,
+            suppress_everyone: false,
+            suppress_roles: false,
+        }
+    }
+}
+
+impl Notify {
+    /// Store the settings in the localvars of a buffer, where printing messages reads them
+    pub fn to_buffer(self, buffer: &impl BufferHost) {
+        buffer.set_localvar("notify_level", self.level.name());
+        buffer.set_localvar(
+            "suppress_everyone",
+            &(self.suppress_everyone as u8).to_string(),
+        );
+        buffer.set_localvar("suppress_roles", &(self.suppress_roles as u8).to_string());
+    }
+
+    pub fn from_buffer(buffer: &impl BufferHost) -> Notify {
+        let flag = |name: &str| buffer.get_localvar(name).as_deref() == Some("1");
+        Notify {
+            level: buffer
+                .get_localvar("notify_level")
+                .and_then(|level| NotifyLevel::parse(&level))
+                .unwrap_or(NotifyLevel::All),
+            suppress_everyone: flag("suppress_everyone"),
+            suppress_roles: flag("suppress_roles"),
+        }
+    }
+}
+
+impl GuildSettings {
+    /// Parse a user guild settings object, settings for direct messages have no guild and are
+    /// ignored
+    pub fn from_value(value: &Value) -> Option<(GuildId, GuildSettings)> {
+        let guild_id = GuildId(parse_id(&value["guild_id"])?);
+
+        // Overrides are a list in payloads and a map keyed by channel when serialized by serenity
+        let overrides: Vec<(Option<&str>, &Value)> = match &value["channel_overrides"] {
+            Value::Array(overrides) => overrides.iter().map(|value| (None, value)).collect(),
+            Value::Object(overrides) => overrides
+                .iter()
+                .map(|(key, value)| (Some(key.as_str()), value))
+                .collect(),
+            _ => Vec::new(),
+        };
+        let channels = overrides
+            .into_iter()
+            .filter_map(|(key, channel)| {
+                let id = parse_id(&channel["channel_id"]).or_else(|| key?.parse().ok())?;
+                let id = ChannelId(id);
+                let settings = ChannelSettings {
+                    level: NotifyLevel::from_discord(&channel["message_notifications"]),
+                    mute: Mute::from_value(channel),
+                };
+                Some((id, settings))
+            })
+            .collect();
+
+        Some((
+            guild_id,
+            GuildSettings {
+                level: NotifyLevel::from_discord(&value["message_notifications"]),
+                mute: Mute::from_value(value),
+                suppress_everyone: value["suppress_everyone"].as_bool().unwrap_or_default(),
+                suppress_roles: value["suppress_roles"].as_bool().unwrap_or_default(),
+                channels,
+            },
+        ))
+    }
+
+    /// A muted guild mutes all of its channels
+    pub fn channel_mute(&self, channel: ChannelId) -> Mute {
+        if self.mute.is_muted() {
+            return self.mute;
+        }
+        self.channels
+            .get(&channel)
+            .map(|channel| channel.mute)
+            .unwrap_or_default()
+    }
+
+    /// The settings of a channel, `default` is the default level of the guild
+    pub fn notify(&self, channel: ChannelId, default: NotifyLevel) -> Notify {
+        let level = self
+            .channels
+            .get(&channel)
+            .and_then(|channel| channel.level)
+            .or(self.level)
+            .unwrap_or(default);
+        Notify {
+            level,
+            suppress_everyone: self.suppress_everyone,
+            suppress_roles: self.suppress_roles,
+        }
+    }
+}
+
+/// Ids are strings in payloads and numbers when serialized by serenity
+fn parse_id(value: &Value) -> Option<u64> {
+    value
+        .as_u64()
+        .or_else(|| value.as_str().and_then(|id| id.parse().ok()))
+}
+
+lazy_static! {
+    // The guild settings of each account
+    static ref SETTINGS: Mutex<HashMap<String, HashMap<GuildId, GuildSettings>>> =
+        Mutex::new(HashMap::new());
+}
+
+/// Take the settings of every guild from the ready event
+pub fn load(account: &str, ready: &Ready) {
+    let settings = ready
+        .user_guild_settings
+        .iter()
+        .filter_map(|(guild_id, settings)| {
+            // Settings are serialized one guild at a time, the key of direct message settings
+            // has no guild and could not be a JSON key
+            let mut value = serde_json::to_value(settings).ok()?;
+            if !value.is_object() {
+                return None;
+            }
+            value["guild_id"] = serde_json::to_value(guild_id).ok()?;
+            GuildSettings::from_value(&value)
+        })
+        .collect();
+    SETTINGS.lock().insert(account.to_owned(), settings);
+}
+
+/// Replace the settings of a guild from a `USER_GUILD_SETTINGS_UPDATE` payload, returning the
+/// guild they belong to
+pub fn update(account: &str, raw: &Value) -> Option<GuildId> {
+    let (guild_id, settings) = GuildSettings::from_value(raw)?;
+    SETTINGS
+        .lock()
+        .entry(account.to_owned())
+        .or_default()
+        .insert(guild_id, settings);
+    Some(guild_id)
+}
+
+pub fn guild_settings(account: &str, guild: GuildId) -> GuildSettings {
+    SETTINGS
+        .lock()
+        .get(account)
+        .and_then(|guilds| guilds.get(&guild))
+        .cloned()
+        .unwrap_or_default()
+}
+
+/// The settings of a guild channel, falling back to the default level of the guild
+pub fn channel_notify(
+    account: &str,
+    cache: &CacheRwLock,
+    guild: GuildId,
+    channel: ChannelId,
+) -> Notify {
+    let default = match guild
+        .to_guild_cached(cache)
+        .map(|guild| guild.read().default_message_notifications)
+    {
+        Some(DefaultMessageNotificationLevel::Mentions) => NotifyLevel::Mentions,
+        _ => NotifyLevel::All,
+    };
+    guild_settings(account, guild).notify(channel, default)
+}
+
+/// Change the level of a guild, or of one of its channels, on Discord and locally
+pub fn set_level(
+    token: &str,
+    account: &str,
+    guild: GuildId,
+    channel: Option<ChannelId>,
+    level: NotifyLevel,
+) -> Result<(), String> {
+    let change = json!({ "message_notifications": level.to_discord() });
+    patch_settings(token, account, guild, channel, change)
+}
+
+/// Mute or unmute a guild, or one of its channels, on Discord and locally. A mute without a
+/// duration lasts until it is undone.
+pub fn set_mute(
+    token: &str,
+    account: &str,
+    guild: GuildId,
+    channel: Option<ChannelId>,
+    muted: bool,
+    duration: Option<Duration>,
+) -> Result<(), String> {
+    let config = match duration {
+        Some(duration) if muted => json!({
+            "selected_time_window": duration.as_secs(),
+            "end_time": (Utc::now() + chrono::Duration::seconds(duration.as_secs() as i64))
+                .to_rfc3339(),
+        }),
+        _ => Value::Null,
+    };
+    let change = json!({ "muted": muted, "mute_config": config });
+    patch_settings(token, account, guild, channel, change)
+}
+
+/// Apply a change to the settings of a guild or a channel override, the settings Discord
+/// responds with replace ours
+fn patch_settings(
+    token: &str,
+    account: &str,
+    guild: GuildId,
+    channel: Option<ChannelId>,
+    change: Value,
+) -> Result<(), String> {
+    let body = match channel {
+        Some(channel) => json!({ "channel_overrides": { channel.0.to_string(): change } }),
+        None => change,
+    };
+    let settings: Value = rest::send_json(
+        token,
+        reqwest::Method::PATCH,
+        &format!("/users/@me/guilds/{}/settings", guild.0),
+        &body,
+    )?
+    .json()
+    .map_err(|e| e.to_string())?;
+
+    update(account, &settings);
+    Ok(())
+}
+
+/// Parse a mute duration such as `30m`, `8h` or `1d`, a bare number is in minutes
+pub fn parse_duration(duration: &str) -> Option<Duration> {
+    let split = duration
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(duration.len());
+    let count: u64 = duration[..split].parse().ok()?;
+    let unit = match &duration[split..] {
+        "s" => 1,
+        "" | "m" => 60,
+        "h" => 60 * 60,
+        "d" => 24 * 60 * 60,
+        "w" => 7 * 24 * 60 * 60,
+        _ => return None,
+    };
+    Some(Duration::from_secs(count.checked_mul(unit)?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{guild_settings, load, parse_duration, GuildSettings, Mute, NotifyLevel};
+    use serenity::model::{
+        gateway::Ready,
+        id::{ChannelId, GuildId},
+    };
+    use std::time::Duration;
+
+    #[test]
+    fn channel_levels_override_the_guild() {
+        let (guild_id, settings) = GuildSettings::from_value(&serde_json::json!({
+            "guild_id": "200",
+            "muted": false,
+            "message_notifications": 1,
+            "suppress_everyone": true,
+            "suppress_roles": false,
+            "channel_overrides": [
+                { "channel_id": "300", "muted": false, "message_notifications": 0 },
+                { "channel_id": "301", "muted": true, "message_notifications": 3 },
+            ],
+        }))
+        .unwrap();
+
+        assert_eq!(guild_id, GuildId(200));
+        let all = settings.notify(ChannelId(300), NotifyLevel::All);
+        assert_eq!(all.level, NotifyLevel::All);
+        assert!(all.suppress_everyone);
+        assert_eq!(
+            settings.notify(ChannelId(301), NotifyLevel::All).level,
+            NotifyLevel::Mentions
+        );
+        assert_eq!(settings.channels[&ChannelId(301)].mute, Mute::Forever);
+        assert_eq!(settings.channel_mute(ChannelId(301)), Mute::Forever);
+        assert_eq!(settings.channel_mute(ChannelId(300)), Mute::Unmuted);
+    }
+
+    #[test]
+    fn ready_settings_keep_mutes() {
+        let ready: Ready = serde_json::from_value(serde_json::json!({
+            "v": 6,
+            "session_id": "session",
+            "_trace": [],
+            "user": {
+                "id": "100",
+                "username": "me",
+                "discriminator": "0001",
+                "avatar": null,
+                "bot": false,
+                "verified": true,
+                "mfa_enabled": false,
+                "email": null,
+            },
+            "guilds": [],
+            "private_channels": [],
+            "presences": [],
+            "relationships": [],
+            "read_state": [],
+            "user_settings": { "guild_positions": [] },
+            "user_guild_settings": [
+                {
+                    "guild_id": "200",
+                    "muted": false,
+                    "message_notifications": 1,
+                    "suppress_everyone": false,
+                    "suppress_roles": false,
+                    "mobile_push": true,
+                    "channel_overrides": [
+                        { "channel_id": "300", "muted": true, "message_notifications": 3 },
+                    ],
+                },
+                {
+                    "guild_id": "201",
+                    "muted": true,
+                    "message_notifications": 0,
+                    "suppress_everyone": false,
+                    "suppress_roles": false,
+                    "mobile_push": true,
+                    "channel_overrides": [],
+                },
+                {
+                    "guild_id": null,
+                    "muted": false,
+                    "message_notifications": 0,
+                    "suppress_everyone": false,
+                    "suppress_roles": false,
+                    "mobile_push": true,
+                    "channel_overrides": [],
+                },
+            ],
+        }))
+        .unwrap();
+
+        load("ready", &ready);
+
+        let settings = guild_settings("ready", GuildId(200));
+        assert_eq!(settings.mute, Mute::Unmuted);
+        assert_eq!(settings.channel_mute(ChannelId(300)), Mute::Forever);
+        assert_eq!(settings.channel_mute(ChannelId(301)), Mute::Unmuted);
+        assert_eq!(guild_settings("ready", GuildId(201)).mute, Mute::Forever);
+        assert_eq!(
+            guild_settings("ready", GuildId(202)),
+            GuildSettings::default()
+        );
+    }
+
+    #[test]
+    fn numeric_ids_are_accepted() {
+        let (guild_id, settings) = GuildSettings::from_value(&serde_json::json!({
+            "guild_id": 200,
+            "muted": false,
+            "channel_overrides": { "300": { "muted": true } },
+        }))
+        .unwrap();
+
+        assert_eq!(guild_id, GuildId(200));
+        assert_eq!(settings.channel_mute(ChannelId(300)), Mute::Forever);
+    }
+
+    #[test]
+    fn timed_mutes_end() {
+        let (_, settings) = GuildSettings::from_value(&serde_json::json!({
+            "guild_id": "200",
+            "muted": true,
+            "mute_config": { "end_time": "2020-01-01T00:00:00+00:00", "selected_time_window": 3600 },
+        }))
+        .unwrap();
+
+        assert_eq!(settings.mute, Mute::Until(1_577_836_800));
+        assert!(!settings.mute.is_muted());
+        assert!(Mute::Until(i64::MAX).is_muted());
+    }
+
+    #[test]
+    fn parses_mute_durations() {
+        assert_eq!(parse_duration("15"), Some(Duration::from_secs(15 * 60)));
+        assert_eq!(parse_duration("8h"), Some(Duration::from_secs(8 * 60 * 60)));
+        assert_eq!(
+            parse_duration("1d"),
+            Some(Duration::from_secs(24 * 60 * 60))
+        );
+        assert_eq!(parse_duration("h"), None);
+        assert_eq!(parse_duration("3y"), None);
+    }
+
+    #[test]
+    fn inherited_levels_use_the_guild_default() {
+        let (_, settings) = GuildSettings::from_value(&serde_json::json!({
+            "guild_id": "200",
+            "message_notifications": 3,
+        }))
+        .unwrap();
+
+        assert_eq!(
+            settings.notify(ChannelId(300), NotifyLevel::Mentions).level,
+            NotifyLevel::Mentions
+        );
+        assert!(GuildSettings::from_value(&serde_json::json!({ "guild_id": null })).is_none());
+    }
+}
@@ -0,0 +1,110 @@
+//! Requests to REST endpoints that serenity does not provide routes for
+//!
+//! These go through the same reqwest version serenity uses, with one client shared by every
+//! request.
+
+use lazy_static::lazy_static;
+use reqwest::{Client, Method, Response};
+
+const DEFAULT_API_BASE: &str = "https://discordapp.com/api/v6";
+/// Threads only exist from api v9 on
+const DEFAULT_API_V9_BASE: &str = "https://discord.com/api/v9";
+
+lazy_static! {
+    static ref CLIENT: Client = Client::new();
+    /// `WEECORD_API_BASE` points these requests at another server, such as `mock_discord`
+    static ref API_BASE: String =
+        std::env::var("WEECORD_API_BASE").unwrap_or_else(|_| DEFAULT_API_BASE.to_owned());
+    static ref API_V9_BASE: String =
+        std::env::var("WEECORD_API_BASE").unwrap_or_else(|_| DEFAULT_API_V9_BASE.to_owned());
+}
+
+/// A REST api root, requests are made relative to it
+pub struct Api<'a> {
+    base: &'a str,
+}
+
+impl<'a> Api<'a> {
+    pub fn new(base: &'a str) -> Api<'a> {
+        Api { base }
+    }
+
+    /// The Discord api, or the server named by `WEECORD_API_BASE`
+    pub fn discord() -> Api<'static> {
+        Api { base: &API_BASE }
+    }
+
+    /// The v9 Discord api, or the server named by `WEECORD_API_BASE`
+    pub fn discord_v9() -> Api<'static> {
+        Api { base: &API_V9_BASE }
+    }
+
+    fn url(&self, path: &str) -> String {
+        format!("{}{}", self.base, path)
+    }
+
+    /// Send an authorized request to `path`, any non-success status is an error
+    pub fn request(
+        &self,
+        token: &str,
+        method: Method,
+        path: &str,
+        params: &[(&str, String)],
+    ) -> Result<Response, String> {
+        let response = CLIENT
+            .request(method, &self.url(path))
+            .header("Authorization", token)
+            .query(params)
+            .send()
+            .map_err(|e| e.to_string())?;
+
+        check_status(response)
+    }
+
+    /// Send an authorized request with a JSON body
+    pub fn send_json(
+        &self,
+        token: &str,
+        method: Method,
+        path: &str,
+        body: &serde_json::Value,
+    ) -> Result<Response, String> {
+        let response = CLIENT
+            .request(method, &self.url(path))
+            .header("Authorization", token)
+            .json(body)
+            .send()
+            .map_err(|e| e.to_string())?;
+
+        check_status(response)
+    }
+}
+
+/// Send an authorized request to `path` (relative to the api root), any non-success status is an
+/// error
+pub fn request(
+    token: &str,
+    method: Method,
+    path: &str,
+    params: &[(&str, String)],
+) -> Result<Response, String> {
+    Api::discord().request(token, method, path, params)
+}
+
+/// Send an authorized request with a JSON body
+pub fn send_json(
+    token: &str,
+    method: Method,
+    path: &str,
+    body: &serde_json::Value,
+) -> Result<Response, String> {
+    Api::discord().send_json(token, method, path, body)
+}
+
+fn check_status(response: Response) -> Result<Response, String> {
+    if response.status().is_success() {
+        Ok(response)
+    } else {
+        Err(response.status().to_string())
+    }
+}
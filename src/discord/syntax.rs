@@ -0,0 +1,273 @@
+//! A small tokenizer for syntax highlighting code blocks. It only tells keywords, strings,
+//! numbers and comments apart, which is as much as a terminal color scheme can show anyway.
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum TokenKind {
+    Text,
+    Keyword,
+    String,
+    Number,
+    Comment,
+    DiffAdded,
+    DiffRemoved,
+    DiffHunk,
+}
+
+struct Language {
+    keywords: &'static [&'static str],
+    line_comments: &'static [&'static str],
+    block_comment: Option<(&'static str, &'static str)>,
+    /// Quotes that start a string, checked in order so `"""` must come before `"`
+    quotes: &'static [&'static str],
+}
+
+const RUST: Language = Language {
+    keywords: &[
+        "as", "async", "await", "break", "const", "continue", "crate", "dyn", "else", "enum",
+        "extern", "false", "fn", "for", "if", "impl", "in", "let", "loop", "match", "mod", "move",
+        "mut", "pub", "ref", "return", "self", "Self", "static", "struct", "super", "trait",
+        "true", "type", "unsafe", "use", "where", "while",
+    ],
+    line_comments: &["//"],
+    block_comment: Some(("/*", "*/")),
+    quotes: &["\""],
+};
+
+const PYTHON: Language = Language {
+    keywords: &[
+        "and", "as", "assert", "async", "await", "break", "class", "continue", "def", "del",
+        "elif", "else", "except", "False", "finally", "for", "from", "global", "if", "import",
+        "in", "is", "lambda", "None", "nonlocal", "not", "or", "pass", "raise", "return", "True",
+        "try", "while", "with", "yield",
+    ],
+    line_comments: &["#"],
+    block_comment: None,
+    quotes: &["\"\"\"", "'''", "\"", "'"],
+};
+
+const JAVASCRIPT: Language = Language {
+    keywords: &[
+        "async",
+        "await",
+        "break",
+        "case",
+        "catch",
+        "class",
+        "const",
+        "continue",
+        "default",
+        "delete",
+        "do",
+        "else",
+        "export",
+        "extends",
+        "false",
+        "finally",
+        "for",
+        "from",
+        "function",
+        "if",
+        "import",
+        "in",
+        "instanceof",
+        "interface",
+        "let",
+        "new",
+        "null",
+        "of",
+        "return",
+        "static",
+        "super",
+        "switch",
+        "this",
+        "throw",
+        "true",
+        "try",
+        "type",
+        "typeof",
+        "undefined",
+        "var",
+        "void",
+        "while",
+        "yield",
+    ],
+    line_comments: &["//"],
+    block_comment: Some(("/*", "*/")),
+    quotes: &["\"", "'", "`"],
+};
+
+const JSON: Language = Language {
+    keywords: &["true", "false", "null"],
+    line_comments: &[],
+    block_comment: None,
+    quotes: &["\""],
+};
+
+const SHELL: Language = Language {
+    keywords: &[
+        "case", "do", "done", "elif", "else", "esac", "export", "fi", "for", "function", "if",
+        "in", "local", "return", "then", "until", "while",
+    ],
+    line_comments: &["#"],
+    block_comment: None,
+    quotes: &["\"", "'"],
+};
+
+fn language(name: &str) -> Option<&'static Language> {
+    match name.to_lowercase().as_str() {
+        "rust" | "rs" => Some(&RUST),
+        "python" | "py" => Some(&PYTHON),
+        "javascript" | "js" | "jsx" | "typescript" | "ts" | "tsx" => Some(&JAVASCRIPT),
+        "json" => Some(&JSON),
+        "shell" | "sh" | "bash" | "zsh" | "console" => Some(&SHELL),
+        _ => None,
+    }
+}
+
+/// Split code into tokens, code in an unknown language is a single text token
+pub fn tokenize<'a>(language_name: &str, code: &'a str) -> Vec<(TokenKind, &'a str)> {
+    if matches!(language_name.to_lowercase().as_str(), "diff" | "patch") {
+        return tokenize_diff(code);
+    }
+    match language(language_name) {
+        Some(language) => tokenize_with(language, code),
+        None => vec![(TokenKind::Text, code)],
+    }
+}
+
+fn tokenize_diff(code: &str) -> Vec<(TokenKind, &str)> {
+    code.split_inclusive('\n')
+        .map(|line| {
+            let kind = if line.starts_with("@@") {
+                TokenKind::DiffHunk
+            } else if line.starts_with('+') {
+                TokenKind::DiffAdded
+            } else if line.starts_with('-') {
+                TokenKind::DiffRemoved
+            } else {
+                TokenKind::Text
+            };
+            (kind, line)
+        })
+        .collect()
+}
+
+fn tokenize_with<'a>(language: &Language, code: &'a str) -> Vec<(TokenKind, &'a str)> {
+    let mut tokens: Vec<(TokenKind, &str)> = Vec::new();
+    let mut push = |kind, text: &'a str| match tokens.last_mut() {
+        // Runs of plain text are kept together
+        Some((TokenKind::Text, last)) if kind == TokenKind::Text => {
+            let start = last.as_ptr() as usize - code.as_ptr() as usize;
+            *last = &code[start..start + last.len() + text.len()];
+        },
+        _ => tokens.push((kind, text)),
+    };
+
+    let mut i = 0;
+    while i < code.len() {
+        let rest = &code[i..];
+        let c = rest.chars().next().unwrap();
+
+        let (kind, len) = if language.line_comments.iter().any(|c| rest.starts_with(c)) {
+            (TokenKind::Comment, rest.find('\n').unwrap_or(rest.len()))
+        } else if let Some((start, end)) = language
+            .block_comment
+            .filter(|(start, _)| rest.starts_with(start))
+        {
+            let len = rest[start.len()..]
+                .find(end)
+                .map_or(rest.len(), |found| start.len() + found + end.len());
+            (TokenKind::Comment, len)
+        } else if let Some(quote) = language.quotes.iter().find(|q| rest.starts_with(*q)) {
+            (TokenKind::String, string_len(rest, quote))
+        } else if c.is_ascii_digit() {
+            let len = rest
+                .find(|c: char| !(c.is_ascii_alphanumeric() || c == '_' || c == '.'))
+                .unwrap_or(rest.len());
+            (TokenKind::Number, len)
+        } else if c.is_alphabetic() || c == '_' {
+            let len = rest
+                .find(|c: char| !(c.is_alphanumeric() || c == '_'))
+                .unwrap_or(rest.len());
+            let kind = if language.keywords.iter().any(|k| *k == &rest[..len]) {
+                TokenKind::Keyword
+            } else {
+                TokenKind::Text
+            };
+            (kind, len)
+        } else {
+            (TokenKind::Text, c.len_utf8())
+        };
+
+        push(kind, &rest[..len]);
+        i += len;
+    }
+
+    tokens
+}
+
+/// Length of a string starting with `quote`, up to and including the closing quote
+fn string_len(rest: &str, quote: &str) -> usize {
+    let mut escaped = false;
+    for (i, c) in rest[quote.len()..].char_indices() {
+        if escaped {
+            escaped = false;
+        } else if c == '\\' {
+            escaped = true;
+        } else if rest[quote.len() + i..].starts_with(quote) {
+            return quote.len() + i + quote.len();
+        }
+    }
+    rest.len()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{tokenize, TokenKind::*};
+
+    #[test]
+    fn tokenizes_rust() {
+        assert_eq!(
+            tokenize("rs", "let x = \"a\\\"b\"; // hi\nx + 10"),
+            vec![
+                (Keyword, "let"),
+                (Text, " x = "),
+                (String, "\"a\\\"b\""),
+                (Text, "; "),
+                (Comment, "// hi"),
+                (Text, "\nx + "),
+                (Number, "10"),
+            ]
+        );
+    }
+
+    #[test]
+    fn tokenizes_python_triple_quotes() {
+        assert_eq!(
+            tokenize("python", "def f():\n    '''doc'''"),
+            vec![
+                (Keyword, "def"),
+                (Text, " f():\n    "),
+                (String, "'''doc'''"),
+            ]
+        );
+    }
+
+    #[test]
+    fn tokenizes_diff_lines() {
+        assert_eq!(
+            tokenize("diff", "@@ -1 +1 @@\n-old\n+new\n same"),
+            vec![
+                (DiffHunk, "@@ -1 +1 @@\n"),
+                (DiffRemoved, "-old\n"),
+                (DiffAdded, "+new\n"),
+                (Text, " same"),
+            ]
+        );
+    }
+
+    #[test]
+    fn unknown_languages_are_plain() {
+        assert_eq!(tokenize("", "let x"), vec![(Text, "let x")]);
+    }
+}
@@ -0,0 +1,177 @@
+//! Threads are newer than our serenity fork, which can neither deserialize their channel types nor
+//! dispatch their gateway events. They are parsed from the raw payloads and tracked here instead.
+
+use crate::discord::rest::Api;
+use lazy_static::lazy_static;
+use reqwest::Method;
+use serde_json::Value;
+use serenity::{
+    model::id::{ChannelId, GuildId, UserId},
+    prelude::Mutex,
+};
+use std::collections::HashMap;
+
+/// Channel types of announcement, public and private threads
+const THREAD_KINDS: [u64; 3] = [10, 11, 12];
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Thread {
+    pub id: ChannelId,
+    pub guild_id: GuildId,
+    pub parent_id: ChannelId,
+    pub name: String,
+    pub owner_id: Option<UserId>,
+    pub archived: bool,
+}
+
+impl Thread {
+    /// Parse a channel object, channels that are not threads are ignored
+    pub fn from_value(value: &Value) -> Option<Thread> {
+        if !THREAD_KINDS.contains(&value["type"].as_u64()?) {
+            return None;
+        }
+
+        Some(Thread {
+            id: ChannelId(parse_id(&value["id"])?),
+            guild_id: GuildId(parse_id(&value["guild_id"])?),
+            parent_id: ChannelId(parse_id(&value["parent_id"])?),
+            name: value["name"].as_str()?.to_owned(),
+            owner_id: parse_id(&value["owner_id"]).map(UserId),
+            archived: value["thread_metadata"]["archived"]
+                .as_bool()
+                .unwrap_or_default(),
+        })
+    }
+}
+
+fn parse_id(value: &Value) -> Option<u64> {
+    value.as_str()?.parse().ok()
+}
+
+lazy_static! {
+    // Every thread seen by each account
+    static ref THREADS: Mutex<HashMap<String, HashMap<ChannelId, Thread>>> =
+        Mutex::new(HashMap::new());
+}
+
+pub fn insert(account: &str, thread: Thread) {
+    THREADS
+        .lock()
+        .entry(account.to_owned())
+        .or_default()
+        .insert(thread.id, thread);
+}
+
+pub fn remove(account: &str, id: ChannelId) -> Option<Thread> {
+    THREADS.lock().get_mut(account)?.remove(&id)
+}
+
+pub fn get(account: &str, id: ChannelId) -> Option<Thread> {
+    THREADS.lock().get(account)?.get(&id).cloned()
+}
+
+/// Known threads of a channel, active ones first
+pub fn for_channel(account: &str, parent: ChannelId) -> Vec<Thread> {
+    let mut threads: Vec<_> = THREADS
+        .lock()
+        .get(account)
+        .map(|threads| {
+            threads
+                .values()
+                .filter(|thread| thread.parent_id == parent)
+                .cloned()
+                .collect()
+        })
+        .unwrap_or_default();
+    threads.sort_by_key(|thread| (thread.archived, thread.name.to_lowercase()));
+    threads
+}
+
+/// Fetch the active and archived public threads of a channel
+pub fn fetch(
+    api: &Api,
+    token: &str,
+    guild: GuildId,
+    parent: ChannelId,
+) -> Result<Vec<Thread>, String> {
+    let get = |path: String| -> Result<Value, String> {
+        api.request(token, Method::GET, &path, &[])?
+            .json()
+            .map_err(|e| e.to_string())
+    };
+    let active = get(format!("/guilds/{}/threads/active", guild.0))?;
+    let archived = get(format!("/channels/{}/threads/archived/public", parent.0))?;
+
+    let threads = active["threads"]
+        .as_array()
+        .into_iter()
+        .chain(archived["threads"].as_array())
+        .flatten()
+        .filter_map(Thread::from_value)
+        .filter(|thread| thread.parent_id == parent)
+        .collect();
+    Ok(threads)
+}
+
+/// Add the current user to a thread, its messages are only received once joined
+pub fn join(api: &Api, token: &str, thread: ChannelId) -> Result<(), String> {
+    // An empty body rather than none, Discord wants a length on PUT requests
+    api.send_json(
+        token,
+        Method::PUT,
+        &format!("/channels/{}/thread-members/@me", thread.0),
+        &serde_json::json!({}),
+    )
+    .map(|_| ())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{fetch, join, Thread};
+    use crate::discord::rest::Api;
+    use mock_discord::{
+        Fixture, MockDiscord, ARCHIVED_THREAD_ID, GENERAL_CHANNEL_ID, GUILD_ID, THREAD_ID,
+    };
+    use serenity::model::id::{ChannelId, GuildId};
+
+    #[test]
+    fn ignores_other_channels() {
+        let text = serde_json::json!({
+            "id": "1",
+            "guild_id": "2",
+            "parent_id": "3",
+            "name": "general",
+            "type": 0,
+        });
+        assert_eq!(Thread::from_value(&text), None);
+    }
+
+    #[test]
+    fn fetches_and_joins_threads_from_the_mock() {
+        let server = MockDiscord::start(Fixture::default()).unwrap();
+        let api_url = server.api_url();
+        let api = Api::new(&api_url);
+
+        let threads = fetch(
+            &api,
+            "mock-token",
+            GuildId(GUILD_ID),
+            ChannelId(GENERAL_CHANNEL_ID),
+        )
+        .unwrap();
+        let found: Vec<_> = threads
+            .iter()
+            .map(|thread| (thread.id.0, thread.name.as_str(), thread.archived))
+            .collect();
+        assert_eq!(
+            found,
+            vec![
+                (THREAD_ID, "ideas", false),
+                (ARCHIVED_THREAD_ID, "old plans", true)
+            ]
+        );
+
+        join(&api, "mock-token", ChannelId(THREAD_ID)).unwrap();
+        assert!(join(&api, "mock-token", ChannelId(GENERAL_CHANNEL_ID)).is_err());
+    }
+}
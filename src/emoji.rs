@@ -0,0 +1,175 @@
+//! Unicode emoji shortcodes, so `:thumbsup:` can be typed and completed like in the official client
+
+use lazy_static::lazy_static;
+use regex::{Captures, Regex};
+
+/// Shortcodes for the most used Unicode emoji, named as in the official client
+pub const SHORTCODES: &[(&str, &str)] = &[
+    ("+1", "👍"),
+    ("-1", "👎"),
+    ("100", "💯"),
+    ("angry", "😠"),
+    ("anguished", "😧"),
+    ("astonished", "😲"),
+    ("beers", "🍻"),
+    ("blush", "😊"),
+    ("boom", "💥"),
+    ("broken_heart", "💔"),
+    ("bug", "🐛"),
+    ("cat", "🐱"),
+    ("clap", "👏"),
+    ("coffee", "☕"),
+    ("cold_sweat", "😰"),
+    ("confused", "😕"),
+    ("cookie", "🍪"),
+    ("cry", "😢"),
+    ("dog", "🐶"),
+    ("disappointed", "😞"),
+    ("dizzy_face", "😵"),
+    ("eyes", "👀"),
+    ("facepalm", "🤦"),
+    ("fearful", "😨"),
+    ("fire", "🔥"),
+    ("flushed", "😳"),
+    ("frowning", "😦"),
+    ("ghost", "👻"),
+    ("gift", "🎁"),
+    ("grimacing", "😬"),
+    ("grin", "😁"),
+    ("grinning", "😀"),
+    ("heart", "❤️"),
+    ("heart_eyes", "😍"),
+    ("heavy_check_mark", "✔️"),
+    ("hugging", "🤗"),
+    ("hushed", "😯"),
+    ("innocent", "😇"),
+    ("joy", "😂"),
+    ("kiss", "💋"),
+    ("kissing_heart", "😘"),
+    ("laughing", "😆"),
+    ("man_shrugging", "🤷‍♂️"),
+    ("mask", "😷"),
+    ("muscle", "💪"),
+    ("neutral_face", "😐"),
+    ("no_mouth", "😶"),
+    ("ok", "🆗"),
+    ("ok_hand", "👌"),
+    ("open_mouth", "😮"),
+    ("partying_face", "🥳"),
+    ("pensive", "😔"),
+    ("persevere", "😣"),
+    ("point_down", "👇"),
+    ("point_left", "👈"),
+    ("point_right", "👉"),
+    ("point_up", "☝️"),
+    ("pray", "🙏"),
+    ("rage", "😡"),
+    ("raised_hands", "🙌"),
+    ("relaxed", "☺️"),
+    ("relieved", "😌"),
+    ("rocket", "🚀"),
+    ("rofl", "🤣"),
+    ("rolling_eyes", "🙄"),
+    ("scream", "😱"),
+    ("see_no_evil", "🙈"),
+    ("shrug", "🤷"),
+    ("skull", "💀"),
+    ("sleeping", "😴"),
+    ("sleepy", "😪"),
+    ("slight_frown", "🙁"),
+    ("slight_smile", "🙂"),
+    ("smile", "😄"),
+    ("smiley", "😃"),
+    ("smirk", "😏"),
+    ("sob", "😭"),
+    ("sparkles", "✨"),
+    ("star", "⭐"),
+    ("star_struck", "🤩"),
+    ("stuck_out_tongue", "😛"),
+    ("stuck_out_tongue_winking_eye", "😜"),
+    ("sunglasses", "😎"),
+    ("sweat", "😓"),
+    ("sweat_smile", "😅"),
+    ("tada", "🎉"),
+    ("thinking", "🤔"),
+    ("thumbsdown", "👎"),
+    ("thumbsup", "👍"),
+    ("tired_face", "😫"),
+    ("triumph", "😤"),
+    ("unamused", "😒"),
+    ("upside_down", "🙃"),
+    ("warning", "⚠️"),
+    ("wave", "👋"),
+    ("weary", "😩"),
+    ("white_check_mark", "✅"),
+    ("wink", "😉"),
+    ("woman_shrugging", "🤷‍♀️"),
+    ("worried", "😟"),
+    ("x", "❌"),
+    ("yum", "😋"),
+    ("zany_face", "🤪"),
+    ("zipper_mouth", "🤐"),
+];
+
+pub fn shortcode_to_unicode(name: &str) -> Option<&'static str> {
+    SHORTCODES
+        .iter()
+        .find(|(shortcode, _)| *shortcode == name)
+        .map(|(_, emoji)| *emoji)
+}
+
+/// Spans whose text is sent as is: code blocks, inline code, urls and custom emoji (`<:name:id>`)
+///
+/// Patterns expanding `:name:` list these first so a match inside them is skipped.
+pub(crate) const VERBATIM_SPANS: &str = r"(?s)```.*?```|``.+?``|`[^`]*`|https?://\S+|<a?:\w+:\d+>";
+
+/// Replace known `:shortcode:`s with their Unicode emoji, a leading `\` escapes a shortcode
+pub fn expand_shortcodes(input: &str) -> String {
+    lazy_static! {
+        static ref SHORTCODE_SYNTAX: Regex =
+            Regex::new(&format!(r"{}|(\\?):([\w+-]+):", VERBATIM_SPANS)).unwrap();
+    }
+
+    SHORTCODE_SYNTAX
+        .replace_all(input, |caps: &Captures| {
+            match caps
+                .get(2)
+                .and_then(|name| shortcode_to_unicode(name.as_str()))
+            {
+                Some(emoji) if caps[1].is_empty() => emoji.to_owned(),
+                _ => caps[0].to_owned(),
+            }
+        })
+        .into_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::expand_shortcodes;
+
+    #[test]
+    fn expands_known_shortcodes() {
+        assert_eq!(expand_shortcodes("nice :thumbsup: :+1:"), "nice 👍 👍");
+        assert_eq!(
+            expand_shortcodes(":not_an_emoji: 12:30:00"),
+            ":not_an_emoji: 12:30:00"
+        );
+        assert_eq!(expand_shortcodes("\\:thumbsup:"), "\\:thumbsup:");
+    }
+
+    #[test]
+    fn leaves_custom_emoji_alone() {
+        assert_eq!(
+            expand_shortcodes("<:fire:123> <a:fire:456> :fire:"),
+            "<:fire:123> <a:fire:456> 🔥"
+        );
+    }
+
+    #[test]
+    fn leaves_code_and_urls_alone() {
+        assert_eq!(
+            expand_shortcodes("`:fire:` :fire: ```\n:fire:\n``` https://example.com/:fire:"),
+            "`:fire:` 🔥 ```\n:fire:\n``` https://example.com/:fire:"
+        );
+    }
+}
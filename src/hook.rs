@@ -1,18 +1,13 @@
 use crate::{
     buffers::load_pin_buffer_history,
     command::Args,
-    discord, on_main, plugin_print, utils,
+    discord, emoji, on_main, plugin_print, utils,
     utils::{BufferExt, ChannelExt},
 };
 use crossbeam_channel::unbounded;
 use lazy_static::lazy_static;
-use serenity::{
-    client::bridge::gateway,
-    model::{channel::ReactionType, prelude::*},
-    prelude::*,
-};
+use serenity::{model::prelude::*, prelude::*};
 use std::{
-    iter::FromIterator,
     sync::Arc,
     thread,
     time::{Duration, SystemTime, UNIX_EPOCH},
@@ -30,11 +25,14 @@ pub struct HookHandles {
     _query_handle: weechat::CommandRunHook<()>,
     _nick_handle: weechat::CommandRunHook<()>,
     _join_handle: weechat::CommandRunHook<()>,
+    _nicklist_scroll_handle: weechat::CommandRunHook<()>,
     _guild_completion_handle: weechat::CompletionHook<()>,
     _channel_completion_handle: weechat::CompletionHook<()>,
     _dm_completion_handle: weechat::CompletionHook<()>,
     _nick_completion_handle: weechat::CompletionHook<()>,
     _role_completion_handle: weechat::CompletionHook<()>,
+    _account_completion_handle: weechat::CompletionHook<()>,
+    _emoji_completion_handle: weechat::CompletionHook<()>,
 }
 
 pub fn init(weechat: &Weechat) -> HookHandles {
@@ -59,9 +57,10 @@ pub fn init(weechat: &Weechat) -> HookHandles {
                 return ReturnCode::Error;
             };
 
-            handle_query(&Args::from_cmd(
-                &command.replace("/query ", "/discord query "),
-            ))
+            handle_query(
+                &buffer.account(),
+                &Args::from_cmd(&command.replace("/query ", "/discord query ")),
+            )
         },
         None,
     );
@@ -78,6 +77,12 @@ pub fn init(weechat: &Weechat) -> HookHandles {
         None,
     );
 
+    let _nicklist_scroll_handle = weechat.hook_command_run(
+        "/bar scroll nicklist *",
+        |_, ref buffer, ref command| handle_nicklist_scroll(buffer, command),
+        None,
+    );
+
     let _guild_completion_handle = weechat.hook_completion(
         "weecord_guild_completion",
         "Completion for discord guilds",
@@ -113,6 +118,20 @@ pub fn init(weechat: &Weechat) -> HookHandles {
         None,
     );
 
+    let _account_completion_handle = weechat.hook_completion(
+        "weecord_account_completion",
+        "Completion for configured Discord accounts",
+        |_, ref buffer, _, completions| handle_account_completion(buffer, completions),
+        None,
+    );
+
+    let _emoji_completion_handle = weechat.hook_completion(
+        "weecord_emoji",
+        "Completion for custom and Unicode emoji",
+        |_, ref buffer, _, completions| handle_emoji_completion(buffer, completions),
+        None,
+    );
+
     HookHandles {
         _buffer_switch_handle,
         _buffer_typing_handle,
@@ -120,11 +139,14 @@ pub fn init(weechat: &Weechat) -> HookHandles {
         _query_handle,
         _nick_handle,
         _join_handle,
+        _nicklist_scroll_handle,
         _guild_completion_handle,
         _channel_completion_handle,
         _dm_completion_handle,
         _nick_completion_handle,
         _role_completion_handle,
+        _account_completion_handle,
+        _emoji_completion_handle,
     }
 }
 
@@ -138,7 +160,7 @@ pub fn buffer_input(buffer: Buffer, text: &str) {
     let guild = buffer.guild_id();
 
     if let Some(channel) = channel {
-        let ctx = match crate::discord::get_ctx() {
+        let ctx = match crate::discord::get_ctx(&buffer.account()) {
             Some(ctx) => ctx,
             _ => return,
         };
@@ -146,9 +168,10 @@ pub fn buffer_input(buffer: Buffer, text: &str) {
         if let Some(edit) = parsing::parse_line_edit(text) {
             let weechat = buffer.get_weechat();
             match edit {
-                parsing::LineEdit::Delete { line } => {
-                    if let Err(e) = crate::utils::get_users_nth_message(&ctx, channel, line)
-                        .map(|msg| channel.delete_message(&ctx.http, msg.id))
+                Ok(parsing::LineEdit::Delete { target }) => {
+                    if let Err(e) =
+                        utils::resolve_target_message(&ctx, &buffer, channel, target, true)
+                            .map(|msg| channel.delete_message(&ctx.http, msg.id))
                     {
                         buffer.print(&format!(
                             "{}\tAn error occurred deleting a message: {}",
@@ -157,60 +180,76 @@ pub fn buffer_input(buffer: Buffer, text: &str) {
                         ));
                     }
                 },
-                parsing::LineEdit::Sub {
-                    line,
-                    old,
-                    new,
-                    options,
-                } => {
-                    // TODO: Clean this up, (try block)?
-                    if let Err(e) =
-                        crate::utils::get_users_nth_message(&ctx, channel, line).map(|mut msg| {
-                            let orig = msg.content.clone();
-                            msg.edit(ctx, |e| {
-                                if options.map(|o| o.contains('g')).unwrap_or_default() {
-                                    e.content(orig.replace(old, new))
-                                } else {
-                                    e.content(orig.replacen(old, new, 1))
-                                }
-                            })
-                        })
-                    {
-                        buffer.print(&format!(
-                            "{}\tAn error occurred editing a message: {}",
-                            weechat.get_prefix("network"),
-                            e
-                        ));
-                    }
+                Ok(parsing::LineEdit::Sub { target, expr }) => {
+                    edit_message(&ctx, &buffer, channel, target, &expr)
                 },
+                Err(e) => buffer.print(&format!("{}\t{}", weechat.get_prefix("error"), e)),
             }
             return;
         }
         if let Some(reaction) = parsing::parse_reaction(text) {
-            if let Ok(msgs) =
-                channel.messages(ctx, |retriever| retriever.limit(reaction.line as u64))
-            {
-                for (i, msg) in msgs.iter().enumerate() {
-                    if i + 1 == reaction.line {
+            let weechat = buffer.get_weechat();
+            let reaction_type = match utils::reaction_type(&ctx.cache, guild, &reaction.emoji) {
+                Some(reaction_type) => reaction_type,
+                None => {
+                    buffer.print(&format!(
+                        "{}\tUnknown emoji: {:?}",
+                        weechat.get_prefix("error"),
+                        reaction.emoji
+                    ));
+                    return;
+                },
+            };
+            let result =
+                utils::resolve_target_message(&ctx, &buffer, channel, reaction.target, false)
+                    .and_then(|msg| {
                         if reaction.add {
-                            let _ =
-                                msg.react(ctx, ReactionType::Unicode(reaction.unicode.to_string()));
+                            msg.react(&*ctx, reaction_type).map(|_| ())
                         } else {
-                            let _ = channel.delete_reaction(
-                                &ctx,
-                                msg.id,
-                                None,
-                                ReactionType::Unicode(reaction.unicode.to_string()),
-                            );
+                            channel.delete_reaction(&*ctx, msg.id, None, reaction_type)
                         }
-                    }
-                }
+                    });
+            if let Err(e) = result {
+                buffer.print(&format!(
+                    "{}\tAn error occurred reacting to a message: {}",
+                    weechat.get_prefix("network"),
+                    e
+                ));
+            }
+            return;
+        }
+        if let Some(reply) = parsing::parse_reply(text) {
+            let content = utils::create_mentions(&ctx.cache, guild, reply.content);
+            let content = utils::expand_guild_emojis(&ctx.cache, guild, &content);
+            let content = emoji::expand_shortcodes(&content);
+            let parent = utils::resolve_target_message(&ctx, &buffer, channel, reply.target, false);
+            let result = parent.and_then(|parent| {
+                channel.send_message(&ctx.http, |m| {
+                    m.content(content);
+                    m.0.insert(
+                        "message_reference",
+                        serde_json::json!({
+                            "message_id": parent.id.0.to_string(),
+                            "channel_id": channel.0.to_string(),
+                        }),
+                    );
+                    m
+                })
+            });
+            if let Err(e) = result {
+                let weechat = buffer.get_weechat();
+                buffer.print(&format!(
+                    "{}\tAn error occurred replying to a message: {}",
+                    weechat.get_prefix("network"),
+                    e
+                ));
             }
             return;
         }
         let text = utils::create_mentions(&ctx.cache, guild, text);
         let text = utils::expand_guild_emojis(&ctx.cache, guild, &text);
-        if let Err(e) = channel.say(ctx, text) {
+        let text = emoji::expand_shortcodes(&text);
+        if let Err(e) = channel.say(&*ctx, text) {
             let weechat = buffer.get_weechat();
             buffer.print(&format!(
                 "{}\tUnable to send message to {}: {:#?}",
@@ -222,6 +261,64 @@ pub fn buffer_input(buffer: Buffer, text: &str) {
     }
 }
 
+/// Apply a sed style edit to one of the user's messages. The edit is shown in the buffer right
+/// away and the original is put back if Discord rejects it.
+fn edit_message(
+    ctx: &Context,
+    buffer: &Buffer,
+    channel: ChannelId,
+    target: parsing::Target,
+    expr: &parsing::SedExpr,
+) {
+    let weechat = buffer.get_weechat();
+    let msg = match utils::resolve_target_message(ctx, buffer, channel, target, true) {
+        Ok(msg) => msg,
+        Err(e) => {
+            buffer.print(&format!(
+                "{}\tAn error occurred editing a message: {}",
+                weechat.get_prefix("network"),
+                e
+            ));
+            return;
+        },
+    };
+    let content = match expr.apply(&msg.content) {
+        Some(content) => content,
+        None => {
+            buffer.print(&format!(
+                "{}\tThe pattern does not match the message",
+                weechat.get_prefix("error")
+            ));
+            return;
+        },
+    };
+
+    let buffer_name = buffer.get_name().to_string();
+    let weecord = crate::upgrade_plugin(&weechat);
+    if let Some(manager) = weecord.buffer_manager.get_buffer(&buffer_name) {
+        let mut preview = msg.clone();
+        preview.content = content.clone();
+        manager.replace_message(&ctx.cache, &msg.id, &preview);
+    }
+
+    let ctx = ctx.clone();
+    thread::spawn(move || {
+        if let Err(e) = channel.edit_message(&ctx.http, msg.id, |m| m.content(content)) {
+            let error = e.to_string();
+            on_main(move |weecord| {
+                if let Some(buffer) = weecord.buffer_manager.get_buffer(&buffer_name) {
+                    buffer.replace_message(&ctx.cache, &msg.id, &msg);
+                    buffer.print(&format!(
+                        "{}\tAn error occurred editing a message: {}",
+                        buffer.get_weechat().get_prefix("network"),
+                        error
+                    ));
+                }
+            });
+        }
+    });
+}
+
 fn handle_buffer_switch(data: weechat::SignalHookValue) -> ReturnCode {
     if let weechat::SignalHookValue::Pointer(buffer_ptr) = data {
         let buffer = unsafe { crate::utils::buffer_from_ptr(buffer_ptr) };
@@ -255,58 +352,29 @@ fn handle_buffer_switch(data: weechat::SignalHookValue) -> ReturnCode {
         }
 
         let channel_id = buffer.channel_id();
+        let account = buffer.account();
+        if let Some(ctx) = discord::get_ctx(&account) {
+            crate::buffers::expire_mute(&account, &ctx.cache, &buffer);
+        }
 
         thread::spawn(move || {
             if rx.recv().is_err() {
                 return;
             }
-            let ctx = match discord::get_ctx() {
+            let ctx = match discord::get_ctx(&account) {
                 Some(s) => s,
                 None => return,
             };
-            if let Some(channel) = channel_id.and_then(|id| id.to_channel_cached(&ctx)) {
+            if let Some(channel) = channel_id.and_then(|id| id.to_channel_cached(&*ctx)) {
                 if let Some(guild_channel) = channel.clone().guild() {
                     let guild_id = guild_channel.read().guild_id;
-                    use std::collections::{HashMap, HashSet};
-                    lazy_static! {
-                        static ref CHANNELS: Arc<Mutex<HashMap<GuildId, HashSet<ChannelId>>>> =
-                            Arc::new(Mutex::new(HashMap::new()));
-                    }
-
-                    let mut channels = CHANNELS.lock();
-                    let send = if let Some(guild_channels) = channels.get_mut(&guild_id) {
-                        guild_channels.insert(channel.id())
-                    } else {
-                        channels
-                            .insert(guild_id, HashSet::from_iter(vec![channel.id()].into_iter()));
-                        true
-                    };
-                    if send {
-                        let channels = channels.get(&guild_id).unwrap();
-                        let channels_obj: HashMap<String, Vec<Vec<_>>> = HashMap::from_iter(
-                            channels
-                                .iter()
-                                .map(|ch| (format!("{}", ch.0), vec![vec![0, 99]])),
-                        );
-
-                        let msg = json::object! {
-                            "op" => 14,
-                            "d" => json::object! {
-                                "guild_id" => format!("{}", guild_id.0),
-                                "typing" => true,
-                                "activities" => true,
-                                "channels" => channels_obj,
-                            }
-                        };
-                        ctx.shard
-                            .websocket_message(gateway::Message::Text(msg.to_string()));
-                    }
+                    discord::member_list::subscribe(&account, &ctx, guild_id, channel.id());
                 }
 
                 if let Some(rs) = ctx.cache.read().read_state.get(&channel.id()) {
                     if let Some(last_message_id) = channel.last_message() {
                         if rs.last_message_id != last_message_id {
-                            let _ = channel.id().ack_message(&ctx, last_message_id);
+                            let _ = channel.id().ack_message(&*ctx, last_message_id);
                         }
                     }
                 }
@@ -338,8 +406,9 @@ fn handle_buffer_typing(weechat: &Weechat, data: weechat::SignalHookValue) -> Re
                 if *LAST_TYPING_TIMESTAMP.lock() + 9 < timestamp_now {
                     *LAST_TYPING_TIMESTAMP.lock() = timestamp_now;
 
+                    let account = buffer.account();
                     std::thread::spawn(move || {
-                        let ctx = match discord::get_ctx() {
+                        let ctx = match discord::get_ctx(&account) {
                             Some(s) => s,
                             None => return,
                         };
@@ -369,7 +438,7 @@ fn handle_channel_completion(buffer: &Buffer, completion: weechat::Completion) -
     };
 
     // Match mangled name to the real name
-    let ctx = match discord::get_ctx() {
+    let ctx = match discord::get_ctx(&buffer.account()) {
         Some(s) => s,
         None => return ReturnCode::Ok,
     };
@@ -397,8 +466,8 @@ fn handle_channel_completion(buffer: &Buffer, completion: weechat::Completion) -
     ReturnCode::Ok
 }
 
-fn handle_guild_completion(_buffer: &Buffer, completion: weechat::Completion) -> ReturnCode {
-    let ctx = match discord::get_ctx() {
+fn handle_guild_completion(buffer: &Buffer, completion: weechat::Completion) -> ReturnCode {
+    let ctx = match discord::get_ctx(&buffer.account()) {
         Some(s) => s,
         None => return ReturnCode::Ok,
     };
@@ -409,8 +478,8 @@ fn handle_guild_completion(_buffer: &Buffer, completion: weechat::Completion) ->
     ReturnCode::Ok
 }
 
-fn handle_dm_completion(_buffer: &Buffer, completion: weechat::Completion) -> ReturnCode {
-    let ctx = match discord::get_ctx() {
+fn handle_dm_completion(buffer: &Buffer, completion: weechat::Completion) -> ReturnCode {
+    let ctx = match discord::get_ctx(&buffer.account()) {
         Some(s) => s,
         None => return ReturnCode::Ok,
     };
@@ -421,14 +490,14 @@ fn handle_dm_completion(_buffer: &Buffer, completion: weechat::Completion) -> Re
 }
 
 fn handle_nick_completion(buffer: &Buffer, completion: weechat::Completion) -> ReturnCode {
-    let ctx = match discord::get_ctx() {
+    let ctx = match discord::get_ctx(&buffer.account()) {
         Some(s) => s,
         None => return ReturnCode::Ok,
     };
 
     let channel_id = buffer.channel_id();
 
-    if let Some(Channel::Guild(channel)) = channel_id.and_then(|c| c.to_channel(ctx).ok()) {
+    if let Some(Channel::Guild(channel)) = channel_id.and_then(|c| c.to_channel(&*ctx).ok()) {
         let channel = channel.read();
 
         if let Ok(members) = channel.members(&ctx.cache) {
@@ -446,7 +515,7 @@ fn handle_nick_completion(buffer: &Buffer, completion: weechat::Completion) -> R
 }
 
 fn handle_role_completion(buffer: &Buffer, completion: weechat::Completion) -> ReturnCode {
-    let ctx = match discord::get_ctx() {
+    let ctx = match discord::get_ctx(&buffer.account()) {
         Some(s) => s,
         None => return ReturnCode::Ok,
     };
@@ -465,9 +534,43 @@ fn handle_role_completion(buffer: &Buffer, completion: weechat::Completion) -> R
     ReturnCode::Ok
 }
 
+fn handle_account_completion(buffer: &Buffer, completion: weechat::Completion) -> ReturnCode {
+    let weechat = buffer.get_weechat();
+    for account in &crate::upgrade_plugin(&weechat).config.accounts {
+        completion.add(&account.name);
+    }
+    ReturnCode::Ok
+}
+
+fn handle_emoji_completion(buffer: &Buffer, completion: weechat::Completion) -> ReturnCode {
+    // Emoji of the current guild are completed first
+    if let Some(ctx) = discord::get_ctx(&buffer.account()) {
+        let cache = ctx.cache.read();
+        let current_guild = buffer.guild_id().and_then(|id| cache.guilds.get(&id));
+        let other_guilds = cache
+            .guilds
+            .values()
+            .filter(|guild| Some(guild.read().id) != buffer.guild_id());
+        for guild in current_guild.into_iter().chain(other_guilds) {
+            for emoji in guild.read().emojis.values() {
+                completion.add_with_options(
+                    &format!(":{}:", emoji.name),
+                    false,
+                    CompletionPosition::End,
+                );
+            }
+        }
+    }
+
+    for (shortcode, _) in emoji::SHORTCODES {
+        completion.add_with_options(&format!(":{}:", shortcode), false, CompletionPosition::End);
+    }
+    ReturnCode::Ok
+}
+
 // TODO: Make this faster
 // TODO: Handle command options
-pub fn handle_query(args: &Args) -> ReturnCode {
+pub fn handle_query(account: &str, args: &Args) -> ReturnCode {
     let mut owned_args = args.clone();
 
     let mut noswitch = false;
@@ -494,8 +597,9 @@ pub fn handle_query(args: &Args) -> ReturnCode {
         None => return ReturnCode::Ok,
     };
 
+    let account = account.to_owned();
     thread::spawn(move || {
-        let ctx = match crate::discord::get_ctx() {
+        let ctx = match crate::discord::get_ctx(&account) {
             Some(ctx) => ctx,
             _ => return,
         };
@@ -514,9 +618,9 @@ pub fn handle_query(args: &Args) -> ReturnCode {
         }
 
         if found_members.is_empty() {
-            let guilds = current_user.guilds(ctx).expect("Unable to fetch guilds");
+            let guilds = current_user.guilds(&*ctx).expect("Unable to fetch guilds");
             for guild in &guilds {
-                if let Some(guild) = guild.id.to_guild_cached(ctx) {
+                if let Some(guild) = guild.id.to_guild_cached(&*ctx) {
                     let guild = guild.read().clone();
                     for m in guild.members_containing(&target.to_lowercase(), false, true) {
                         found_members.push(m.user.read().clone());
@@ -529,13 +633,14 @@ pub fn handle_query(args: &Args) -> ReturnCode {
         let current_user_name = current_user.name.clone();
 
         if let Some(target) = found_members.get(0) {
-            if let Ok(chan) = target.create_dm_channel(ctx) {
+            if let Ok(chan) = target.create_dm_channel(&*ctx) {
                 on_main(move |weecord| {
-                    let ctx = match crate::discord::get_ctx() {
+                    let ctx = match crate::discord::get_ctx(&account) {
                         Some(ctx) => ctx,
                         _ => return,
                     };
                     crate::buffers::create_buffer_from_dm(
+                        &account,
                         &ctx.cache,
                         &weecord,
                         Channel::Private(Arc::new(RwLock::new(chan))),
@@ -560,10 +665,11 @@ fn handle_nick(buffer: &Buffer, command: &str) -> ReturnCode {
         return ReturnCode::Ok;
     };
 
+    let account = buffer.account();
     let guilds;
     let mut substr;
     {
-        let ctx = match crate::discord::get_ctx() {
+        let ctx = match crate::discord::get_ctx(&account) {
             Some(ctx) => ctx,
             _ => return ReturnCode::Error,
         };
@@ -578,7 +684,7 @@ fn handle_nick(buffer: &Buffer, command: &str) -> ReturnCode {
 
             // TODO: Error handling
             current_user
-                .guilds(ctx)
+                .guilds(&*ctx)
                 .unwrap_or_default()
                 .iter()
                 .map(|g| g.id)
@@ -590,7 +696,7 @@ fn handle_nick(buffer: &Buffer, command: &str) -> ReturnCode {
 
     thread::spawn(move || {
         {
-            let ctx = match crate::discord::get_ctx() {
+            let ctx = match crate::discord::get_ctx(&account) {
                 Some(ctx) => ctx,
                 _ => return,
             };
@@ -601,7 +707,7 @@ fn handle_nick(buffer: &Buffer, command: &str) -> ReturnCode {
                 } else {
                     Some(substr.as_str())
                 };
-                let _ = guild.edit_nickname(ctx, new_nick);
+                let _ = guild.edit_nickname(&*ctx, new_nick);
                 // Make it less spammy
                 if should_sleep {
                     thread::sleep(Duration::from_secs(1));
@@ -612,11 +718,48 @@ fn handle_nick(buffer: &Buffer, command: &str) -> ReturnCode {
     ReturnCode::OkEat
 }
 
+/// Fetch the part of a large guild's member list scrolled into view, the scroll itself is left to
+/// WeeChat
+fn handle_nicklist_scroll(buffer: &Buffer, command: &str) -> ReturnCode {
+    // `/bar scroll nicklist <window> <value>`
+    let value = match command.split_whitespace().nth(4) {
+        Some(value) => value,
+        None => return ReturnCode::Ok,
+    };
+    let (guild_id, channel_id) = match (buffer.guild_id(), buffer.channel_id()) {
+        (Some(guild_id), Some(channel_id)) => (guild_id, channel_id),
+        _ => return ReturnCode::Ok,
+    };
+    let ctx = match discord::get_ctx(&buffer.account()) {
+        Some(ctx) => ctx,
+        None => return ReturnCode::Ok,
+    };
+
+    // The nicklist is roughly as tall as the terminal
+    let visible = buffer
+        .get_weechat()
+        .info_get("term_height", "")
+        .and_then(|height| height.parse().ok())
+        .unwrap_or(50);
+    if let Some(scroll) = discord::member_list::parse_scroll(value, visible) {
+        discord::member_list::scroll(
+            &buffer.account(),
+            &ctx,
+            guild_id,
+            channel_id,
+            scroll,
+            visible,
+        );
+    }
+    ReturnCode::Ok
+}
+
 fn handle_join(buffer: &Buffer, command: &str) -> ReturnCode {
     let verbose = buffer.guild_id().is_some();
 
     crate::command::join(
         &buffer.get_weechat(),
+        &buffer.account(),
         &crate::command::Args::from_cmd(&format!("/discord {}", &command[1..])),
         verbose,
     )
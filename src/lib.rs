@@ -5,13 +5,16 @@ mod buffers;
 mod command;
 mod config;
 mod discord;
+mod emoji;
 mod hook;
+mod message_store;
+mod search;
 mod sync;
 mod utils;
 mod weechat_utils;
 
 use crate::weechat_utils::BufferManager;
-pub use sync::{on_main, on_main_blocking, upgrade_plugin};
+pub use sync::{on_main, on_main_blocking, try_on_main_blocking, upgrade_plugin};
 
 use std::borrow::Cow;
 use weechat::{weechat_plugin, ArgsWeechat, ConfigOption, Weechat, WeechatPlugin, WeechatResult};
@@ -20,6 +23,7 @@ pub struct Discord {
     weechat: Weechat,
     config: config::Config,
     buffer_manager: BufferManager,
+    message_store: message_store::MessageStore,
     _sync_handle: sync::SyncHandle,
     _hook_handles: hook::HookHandles,
     _bar_handles: bar_items::BarHandles,
@@ -35,20 +39,29 @@ impl WeechatPlugin for Discord {
         let _bar_handles = bar_items::init(&weechat);
         let config = config::init(&weechat);
         let buffer_manager = buffers::init(&weechat);
+        let message_store = message_store::MessageStore::new(&weechat, config.message_log.value());
 
-        let autostart = config.autostart.value();
+        let autostart_accounts: Vec<_> = config
+            .accounts
+            .iter()
+            .filter(|account| account.autostart.value())
+            .map(|account| account.name.clone())
+            .collect();
 
         let weecord = Discord {
             weechat,
             config,
             buffer_manager,
+            message_store,
             _sync_handle,
             _hook_handles,
             _bar_handles,
         };
 
-        if !args.contains(&"-a".to_owned()) && autostart {
-            weecord.connect();
+        if !args.contains(&"-a".to_owned()) {
+            for account in &autostart_accounts {
+                weecord.connect(account);
+            }
         }
 
         Ok(weecord)
@@ -56,13 +69,23 @@ impl WeechatPlugin for Discord {
 }
 
 impl Discord {
-    fn connect(&self) {
-        if crate::discord::DISCORD.lock().is_some() {
+    fn connect(&self, account: &str) {
+        if crate::discord::DISCORD.lock().contains_key(account) {
             plugin_print("Already connected");
             return;
         }
 
-        let token = self.config.token.value().into_owned();
+        let account_config = match self.config.account(account) {
+            Some(account_config) => account_config,
+            None => {
+                self.print(&format!(
+                    "Error: unknown account \"{}\", accounts are listed in weecord.main.accounts",
+                    account
+                ));
+                return;
+            },
+        };
+        let token = account_config.token.value().into_owned();
 
         let token = if token.starts_with("${sec.data") {
             self.eval_string_expression(&token).map(Cow::into_owned)
@@ -71,10 +94,19 @@ impl Discord {
         };
         if let Some(t) = token {
             if !t.is_empty() {
-                discord::init(&self, &t, self.config.irc_mode.value());
-            } else {
+                discord::init(&self, account, &t, account_config.irc_mode.value());
+            } else if account == config::MAIN_ACCOUNT {
                 self.print("Error: weecord.main.token is not set. To set it, run:");
                 self.print("/discord token 123456789ABCDEF");
+            } else {
+                self.print(&format!(
+                    "Error: weecord.{}.token is not set. To set it, run:",
+                    account
+                ));
+                self.print(&format!(
+                    "/discord -account {} token 123456789ABCDEF",
+                    account
+                ));
             }
         } else {
             self.print("Error: failed to evaluate token option, expected valid ${sec.data...}");
@@ -0,0 +1,250 @@
+//! An on-disk log of the messages shown in buffers, so history can be drawn without waiting for
+//! Discord and stays readable while disconnected.
+
+use crossbeam_channel::{unbounded, Receiver, Sender};
+use serde_json::json;
+use serenity::model::{
+    channel::Message,
+    id::{ChannelId, MessageId},
+};
+use std::{
+    collections::BTreeMap,
+    fs::{self, File, OpenOptions},
+    io::{BufRead, BufReader, Write},
+    path::{Path, PathBuf},
+    sync::Arc,
+    thread::{self, JoinHandle},
+};
+use weechat::Weechat;
+
+/// Logs with fewer records than this are never compacted
+const COMPACT_THRESHOLD: usize = 1000;
+
+/// How many pages of `message_load_count` messages a log keeps when it is compacted
+const KEPT_PAGES: usize = 4;
+
+enum Job {
+    Insert(PathBuf, Vec<Message>),
+    Delete(PathBuf, MessageId),
+    Clear(PathBuf),
+    Compact(PathBuf, usize),
+}
+
+/// Owns the writer thread, which is joined once every clone of the store is gone
+struct Writer {
+    sender: Option<Sender<Job>>,
+    handle: Option<JoinHandle<()>>,
+}
+
+impl Drop for Writer {
+    fn drop(&mut self) {
+        // Closing the channel lets the thread finish the queued jobs and exit
+        self.sender.take();
+        if let Some(handle) = self.handle.take() {
+            let _ = handle.join();
+        }
+    }
+}
+
+/// Stores messages under `<weechat_dir>/weecord/<account>/<channel>.log`
+///
+/// Each log is append-only, a line records either the latest version of a message or its deletion,
+/// keyed by the message id. All writes happen in order on a dedicated thread, so neither the main
+/// thread nor a loading buffer waits on the disk. Logs are trimmed to their newest messages when
+/// they grow too large.
+#[derive(Clone)]
+pub struct MessageStore {
+    dir: Option<PathBuf>,
+    writer: Arc<Writer>,
+}
+
+impl MessageStore {
+    /// A store that keeps nothing when `enabled` is false
+    pub fn new(weechat: &Weechat, enabled: bool) -> MessageStore {
+        let dir = if enabled {
+            weechat
+                .info_get("weechat_dir", "")
+                .map(|dir| PathBuf::from(dir.as_ref()).join("weecord"))
+        } else {
+            None
+        };
+
+        let (sender, receiver) = unbounded();
+        let handle = thread::spawn(move || write_jobs(receiver));
+
+        MessageStore {
+            dir,
+            writer: Arc::new(Writer {
+                sender: Some(sender),
+                handle: Some(handle),
+            }),
+        }
+    }
+
+    fn log_path(&self, account: &str, channel: ChannelId) -> Option<PathBuf> {
+        Some(
+            self.dir
+                .as_ref()?
+                .join(account)
+                .join(format!("{}.log", channel.0)),
+        )
+    }
+
+    fn send(&self, job: Job) {
+        if let Some(sender) = &self.writer.sender {
+            let _ = sender.send(job);
+        }
+    }
+
+    /// Record new or edited messages, all messages must be from the same channel
+    pub fn insert(&self, account: &str, msgs: &[Message]) {
+        if let Some(path) = msgs
+            .first()
+            .and_then(|msg| self.log_path(account, msg.channel_id))
+        {
+            self.send(Job::Insert(path, msgs.to_vec()));
+        }
+    }
+
+    /// Record that a message was deleted
+    pub fn delete(&self, account: &str, channel: ChannelId, id: MessageId) {
+        if let Some(path) = self.log_path(account, channel) {
+            self.send(Job::Delete(path, id));
+        }
+    }
+
+    /// Forget the stored messages of a channel, used when newer history could not be fetched
+    /// without a gap
+    pub fn clear(&self, account: &str, channel: ChannelId) {
+        if let Some(path) = self.log_path(account, channel) {
+            self.send(Job::Clear(path));
+        }
+    }
+
+    /// The newest `limit` stored messages of a channel, oldest first
+    ///
+    /// This reads the whole log, so it should not be called on the main thread.
+    pub fn load(&self, account: &str, channel: ChannelId, limit: usize) -> Vec<Message> {
+        let path = match self.log_path(account, channel) {
+            Some(path) => path,
+            None => return Vec::new(),
+        };
+        let (records, messages) = match read_log(&path) {
+            Some(log) => log,
+            None => return Vec::new(),
+        };
+
+        // Drop superseded edits, deleted messages and everything but the newest few pages
+        let keep = limit.max(1) * KEPT_PAGES;
+        if records > COMPACT_THRESHOLD.max(keep * 2) {
+            self.send(Job::Compact(path, keep));
+        }
+
+        let skip = messages.len().saturating_sub(limit);
+        messages
+            .into_iter()
+            .skip(skip)
+            .map(|(_, msg)| msg)
+            .collect()
+    }
+}
+
+fn write_jobs(receiver: Receiver<Job>) {
+    for job in receiver {
+        match job {
+            Job::Insert(path, msgs) => {
+                let records: Vec<_> = msgs
+                    .iter()
+                    .filter_map(|msg| {
+                        Some(json!({
+                            "id": msg.id.0.to_string(),
+                            "message": serde_json::to_value(msg).ok()?,
+                        }))
+                    })
+                    .collect();
+                append(&path, &records);
+            },
+            Job::Delete(path, id) => {
+                // Nothing was stored for this channel, so there is nothing to hide
+                if path.exists() {
+                    append(
+                        &path,
+                        &[json!({
+                            "id": id.0.to_string(),
+                            "deleted": true,
+                        })],
+                    );
+                }
+            },
+            Job::Clear(path) => {
+                let _ = fs::remove_file(path);
+            },
+            Job::Compact(path, keep) => {
+                // Read again, messages may have been appended since the log was loaded
+                if let Some((_, messages)) = read_log(&path) {
+                    let skip = messages.len().saturating_sub(keep);
+                    compact(&path, messages.values().skip(skip));
+                }
+            },
+        }
+    }
+}
+
+fn append(path: &Path, records: &[serde_json::Value]) {
+    if let Some(parent) = path.parent() {
+        let _ = fs::create_dir_all(parent);
+    }
+    if let Ok(mut file) = OpenOptions::new().create(true).append(true).open(path) {
+        for record in records {
+            let _ = writeln!(file, "{}", record);
+        }
+    }
+}
+
+/// The number of records in a log and the messages it holds, keyed by id
+fn read_log(path: &Path) -> Option<(usize, BTreeMap<MessageId, Message>)> {
+    let file = File::open(path).ok()?;
+
+    let mut messages: BTreeMap<MessageId, Message> = BTreeMap::new();
+    let mut records = 0;
+    // A line still being appended fails to parse and is skipped
+    for line in BufReader::new(file).lines().filter_map(Result::ok) {
+        let record: serde_json::Value = match serde_json::from_str(&line) {
+            Ok(record) => record,
+            Err(_) => continue,
+        };
+        let id = match record["id"].as_str().and_then(|id| id.parse().ok()) {
+            Some(id) => MessageId(id),
+            None => continue,
+        };
+        records += 1;
+
+        if record["deleted"].as_bool().unwrap_or_default() {
+            messages.remove(&id);
+        } else if let Ok(msg) = serde_json::from_value(record["message"].clone()) {
+            messages.insert(id, msg);
+        }
+    }
+
+    Some((records, messages))
+}
+
+fn compact<'a>(path: &Path, messages: impl Iterator<Item = &'a Message>) {
+    let tmp_path = path.with_extension("log.tmp");
+    let written = File::create(&tmp_path).and_then(|mut file| {
+        for msg in messages {
+            let record = json!({
+                "id": msg.id.0.to_string(),
+                "message": serde_json::to_value(msg)?,
+            });
+            writeln!(file, "{}", record)?;
+        }
+        Ok(())
+    });
+
+    if written.is_ok() {
+        let _ = fs::rename(&tmp_path, path);
+    } else {
+        let _ = fs::remove_file(&tmp_path);
+    }
+}
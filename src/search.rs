@@ -0,0 +1,297 @@
+//! Server-side message search, results are listed in a dedicated buffer and can be jumped to
+
+use crate::{
+    buffers, discord, on_main, plugin_print,
+    utils::BufferExt,
+    weechat_utils::{formatting_utils, BufferHost, MessageManager},
+    Discord,
+};
+use serenity::{model::prelude::*, prelude::*};
+use std::{sync::Arc, thread};
+use weechat::ConfigOption;
+
+/// Where a search runs, Discord only indexes whole guilds and DM channels
+#[derive(Clone, Copy)]
+enum Scope {
+    Guild(GuildId),
+    Channel(ChannelId),
+}
+
+/// Search the guild or DM of the current buffer and list the results in a search buffer
+pub fn search(account: &str, buffer: &impl BufferHost, input: &str) {
+    let query = parsing::parse_search(input);
+    if query.terms.is_empty() && query.author.is_none() && query.has.is_empty() {
+        plugin_print("search requires a query");
+        return;
+    }
+
+    let ctx = match discord::get_ctx(account) {
+        Some(ctx) => ctx,
+        _ => {
+            plugin_print("search requires a connection to Discord");
+            return;
+        },
+    };
+
+    let scope = match (buffer.guild_id(), buffer.channel_id()) {
+        (Some(guild), _) => Scope::Guild(guild),
+        (None, Some(channel)) => Scope::Channel(channel),
+        (None, None) => {
+            plugin_print("search must be run from a guild or DM buffer");
+            return;
+        },
+    };
+
+    let mut params = Vec::new();
+    if !query.terms.is_empty() {
+        params.push(("content", query.terms.join(" ")));
+    }
+    for has in &query.has {
+        params.push(("has", (*has).to_owned()));
+    }
+    if let Some(channel_name) = query.channel {
+        match resolve_channel(&ctx, scope, channel_name) {
+            Some(channel) => params.push(("channel_id", channel.0.to_string())),
+            None => {
+                plugin_print(&format!("Unknown channel \"{}\"", channel_name));
+                return;
+            },
+        }
+    }
+    if let Some(author_name) = query.author {
+        match resolve_author(&ctx, scope, author_name) {
+            Some(author) => params.push(("author_id", author.0.to_string())),
+            None => {
+                plugin_print(&format!("Unknown user \"{}\"", author_name));
+                return;
+            },
+        }
+    }
+
+    let account = account.to_owned();
+    let title = input.to_owned();
+    thread::spawn(move || {
+        let ctx = match discord::get_ctx(&account) {
+            Some(ctx) => ctx,
+            _ => return,
+        };
+
+        let results = match fetch_results(&ctx.http.token, scope, &params) {
+            Ok(results) => results,
+            Err(e) => {
+                plugin_print(&format!("Search failed: {}", e));
+                return;
+            },
+        };
+
+        on_main(move |weecord| {
+            let ctx = match discord::get_ctx(&account) {
+                Some(ctx) => ctx,
+                _ => return,
+            };
+            let buffer = create_search_buffer(weecord, &account, &title);
+            let guild = match scope {
+                Scope::Guild(guild) => Some(guild),
+                Scope::Channel(_) => None,
+            };
+            print_results(weecord, &ctx, &buffer, guild, &results);
+        });
+    });
+}
+
+fn resolve_channel(ctx: &Context, scope: Scope, name: &str) -> Option<ChannelId> {
+    let guild = match scope {
+        Scope::Guild(guild) => guild.to_guild_cached(ctx)?,
+        Scope::Channel(_) => return None,
+    };
+    let guild = guild.read();
+    guild
+        .channels
+        .values()
+        .map(|channel| channel.read())
+        .find(|channel| {
+            channel.name.to_lowercase() == name.to_lowercase() || channel.id.0.to_string() == name
+        })
+        .map(|channel| channel.id)
+}
+
+fn resolve_author(ctx: &Context, scope: Scope, name: &str) -> Option<UserId> {
+    let current_user = ctx.cache.read().user.clone();
+    if current_user.name.to_lowercase() == name.to_lowercase() {
+        return Some(current_user.id);
+    }
+
+    match scope {
+        Scope::Guild(guild) => {
+            let guild = guild.to_guild_cached(ctx)?;
+            let guild = guild.read();
+            let member = guild.member_named(name)?;
+            let id = member.user.read().id;
+            Some(id)
+        },
+        Scope::Channel(channel) => {
+            let channel = channel.to_channel_cached(ctx)?.private()?;
+            let recipient = channel.read().recipient.read().clone();
+            if recipient.name.to_lowercase() == name.to_lowercase() {
+                Some(recipient.id)
+            } else {
+                None
+            }
+        },
+    }
+}
+
+/// Run the search, returning the matching messages (without their surrounding context)
+fn fetch_results(
+    token: &str,
+    scope: Scope,
+    params: &[(&str, String)],
+) -> Result<Vec<Message>, String> {
+    let path = match scope {
+        Scope::Guild(guild) => format!("/guilds/{}/messages/search", guild.0),
+        Scope::Channel(channel) => format!("/channels/{}/messages/search", channel.0),
+    };
+
+    let mut response = discord::rest::request(token, reqwest::Method::GET, &path, params)?;
+
+    // Discord answers with 202 while it is still indexing the guild or channel
+    if response.status() == reqwest::StatusCode::ACCEPTED {
+        return Err("Discord is still indexing, try again shortly".to_owned());
+    }
+
+    let body: serde_json::Value = response.json().map_err(|e| e.to_string())?;
+    let groups = body["messages"].as_array().cloned().unwrap_or_default();
+
+    // Each group is a hit surrounded by context messages
+    Ok(groups
+        .into_iter()
+        .filter_map(|group| {
+            let hit = group
+                .as_array()?
+                .iter()
+                .find(|msg| msg["hit"].as_bool().unwrap_or_default())?
+                .clone();
+            serde_json::from_value(hit).ok()
+        })
+        .collect())
+}
+
+fn create_search_buffer(weecord: &Discord, account: &str, query: &str) -> Arc<MessageManager> {
+    let buffer = weecord
+        .buffer_manager
+        .get_or_create_buffer(&format!("Search.{}", account));
+    buffer.clear();
+    buffer.switch_to();
+
+    // There is no channel history to load
+    buffer.set_history_loaded();
+    buffer.set_localvar("account", account);
+    buffer.set_localvar("type", "search");
+    buffer.set_title(&format!("Search results for \"{}\"", query));
+    buffer.set_short_name("search");
+    buffer
+}
+
+fn print_results(
+    weecord: &Discord,
+    ctx: &Context,
+    buffer: &MessageManager,
+    guild: Option<GuildId>,
+    results: &[Message],
+) {
+    if results.is_empty() {
+        buffer.print("No results");
+        return;
+    }
+
+    for (i, msg) in results.iter().enumerate() {
+        let n = i + 1;
+        let channel_name = match msg.channel_id.to_channel_cached(ctx) {
+            Some(Channel::Guild(channel)) => format!("#{}", channel.read().name),
+            Some(_) => "DM".to_owned(),
+            None => format!("#{}", msg.channel_id.0),
+        };
+        let (prefix, content, _) =
+            formatting_utils::render_msg(&ctx.cache, &weecord.weechat, msg, guild, false);
+
+        buffer.print_tags_dated(
+            msg.timestamp.timestamp(),
+            "notify_none",
+            &format!("{}\t[{}] {}: {}", prefix, n, channel_name, content),
+        );
+        buffer.set_localvar(
+            &format!("search_result_{}", n),
+            &format!(
+                "{}.{}.{}",
+                guild.map(|guild| guild.0).unwrap_or_default(),
+                msg.channel_id.0,
+                msg.id.0
+            ),
+        );
+    }
+    buffer.print("Use /discord jump <n> to open a result, eg /discord jump 1");
+}
+
+/// Open the channel of a search result and load the messages around it
+pub fn jump(weecord: &Discord, buffer: &impl BufferHost, n: &str) {
+    let result = match buffer.get_localvar(&format!("search_result_{}", n)) {
+        Some(result) => result.into_owned(),
+        None => {
+            plugin_print("jump requires a result number from a search buffer");
+            return;
+        },
+    };
+    let mut ids = result.split('.').filter_map(|id| id.parse::<u64>().ok());
+    let (guild, channel, message) = match (ids.next(), ids.next(), ids.next()) {
+        (Some(guild), Some(channel), Some(message)) => {
+            (GuildId(guild), ChannelId(channel), MessageId(message))
+        },
+        _ => return,
+    };
+
+    let account = buffer.account();
+    let ctx = match discord::get_ctx(&account) {
+        Some(ctx) => ctx,
+        _ => return,
+    };
+    let nick = ctx.cache.read().user.name.clone();
+
+    let guild = if guild.0 == 0 {
+        let channel = match channel.to_channel_cached(&*ctx) {
+            Some(channel) => channel,
+            None => return,
+        };
+        if let Channel::Group(_) = channel {
+            buffers::create_buffer_from_group(&account, &ctx.cache, weecord, channel, &nick);
+        } else {
+            buffers::create_buffer_from_dm(&account, &ctx.cache, weecord, channel, &nick, false);
+        }
+        None
+    } else {
+        let guild_lock = match guild.to_guild_cached(&*ctx) {
+            Some(guild) => guild,
+            None => return,
+        };
+        let guild_lock = guild_lock.read();
+        let channel = match guild_lock.channels.get(&channel) {
+            Some(channel) => channel.clone(),
+            None => return,
+        };
+        buffers::create_guild_buffer(&account, guild, &guild_lock.name);
+        buffers::create_buffer_from_channel(
+            &account,
+            &ctx.cache,
+            &guild_lock.name,
+            &channel.read(),
+            &nick,
+        );
+        Some(guild)
+    };
+
+    let buffer_name = crate::utils::buffer_id_for_channel(&account, guild, channel);
+    if let Some(buffer) = weecord.buffer_manager.get_buffer(&buffer_name) {
+        let fetch_count = weecord.config.message_fetch_count.value();
+        buffers::load_history_around(&buffer, message, fetch_count);
+        buffer.switch_to();
+    }
+}
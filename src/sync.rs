@@ -1,87 +1,610 @@
+//! Running code on WeeChat's main thread from other threads. Jobs are queued and the main thread
+//! is woken through a pipe watched with `hook_fd`, which runs them in bounded batches.
+
 use crate::Discord;
-use crossbeam_channel::{unbounded, Sender};
+use crossbeam_channel::{bounded, RecvTimeoutError, Sender};
 use lazy_static::lazy_static;
 use parking_lot::Mutex;
 use std::{
-    any::Any, cell::RefCell, collections::VecDeque, mem::transmute, sync::Arc, thread,
-    time::Duration,
+    collections::VecDeque,
+    fmt,
+    io::{ErrorKind, Read, Write},
+    os::unix::net::UnixStream,
+    panic::Location,
+    sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
+    thread::{self, ThreadId},
+    time::{Duration, Instant},
 };
-use weechat::Weechat;
+use weechat::{FdHookMode, Weechat};
+
+/// Most jobs run per wakeup, the rest wait until WeeChat has had a chance to redraw
+const BATCH_SIZE: usize = 64;
+/// Longest a batch may keep the main thread busy
+const BATCH_TIME: Duration = Duration::from_millis(10);
+/// How long a blocking call waits behind another blocking job before it is reported as a
+/// deadlock (or as a slow job, for calls without a timeout), only checked in debug builds
+const DEADLOCK_AFTER: Duration = Duration::from_secs(10);
 
 /// Created upon sync initialization, must not be dropped while the plugin is running
-pub struct SyncHandle(weechat::TimerHook<()>);
+pub struct SyncHandle(weechat::FdHook<(), UnixStream>);
 
-enum Job {
-    Nonblocking(Box<dyn FnOnce(&Discord) + Send>),
-    Blocking(
-        Box<dyn FnOnce(&Discord) -> Box<dyn Any + Send> + Send>,
-        Sender<Box<dyn Any + Send>>,
-    ),
+lazy_static! {
+    static ref DISPATCHER: Dispatcher<Discord> =
+        Dispatcher::new(plugin, DEADLOCK_AFTER).report_slow_jobs(report_slow_job);
 }
 
-lazy_static! {
-    static ref JOB_QUEUE: Mutex<RefCell<VecDeque<Job>>> = Mutex::new(RefCell::new(VecDeque::new()));
-    static ref MAIN_THREAD: Arc<Mutex<Option<thread::ThreadId>>> = Arc::new(Mutex::new(None));
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SyncError {
+    /// The job had not started when the timeout ran out and was taken off the queue
+    Timeout,
+    /// The main thread has been running the blocking job queued at `busy_with` for longer than
+    /// the deadlock threshold, most likely it is waiting for the thread that made this call
+    Deadlock {
+        busy_with: &'static Location<'static>,
+    },
+    /// The job was dropped without returning, because it panicked
+    Dropped,
 }
 
-/// Initialize thread synchronization, this function must be called on the main thread
-pub fn init(weechat: &weechat::Weechat) -> SyncHandle {
-    *MAIN_THREAD.lock() = Some(thread::current().id());
+impl fmt::Display for SyncError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SyncError::Timeout => write!(f, "timed out waiting for the main thread"),
+            SyncError::Deadlock { busy_with } => write!(
+                f,
+                "blocking call would deadlock, the main thread is waiting in a job queued at {}",
+                busy_with
+            ),
+            SyncError::Dropped => write!(f, "main thread job panicked"),
+        }
+    }
+}
 
-    // TODO: Dynamic delay
-    SyncHandle(weechat.hook_timer(Duration::from_millis(25), 0, 0, |_, _, _| tick(), None))
+/// Counters of the main thread job queue
+pub struct QueueStats {
+    /// Jobs waiting to run
+    pub depth: usize,
+    /// Most jobs that have been waiting at once
+    pub peak_depth: usize,
+    /// Jobs run so far
+    pub processed: usize,
+    /// Times the main thread was woken to run jobs
+    pub wakeups: usize,
 }
 
-pub fn on_main<F: 'static + FnOnce(&Discord) + Send>(cb: F) {
-    if std::thread::current().id() == MAIN_THREAD.lock().unwrap() {
-        // already on the main thread, run closure now
-        cb(unsafe { &crate::__PLUGIN.as_ref().unwrap() });
-    } else {
-        // queue closure for later
-        JOB_QUEUE
-            .lock()
-            .borrow_mut()
-            .push_back(Job::Nonblocking(Box::new(cb)));
-    }
-}
-
-pub fn on_main_blocking<R: Send, F: FnOnce(&Discord) -> R + Send, ER: 'static + Send>(cb: F) -> ER {
-    let cb = unsafe {
-        // This should be ok because the lifetime does not actually
-        // have to be valid for 'static, just until the function returns
-        transmute::<
-            Box<dyn FnOnce(&Discord) -> R + Send>,
-            Box<dyn 'static + FnOnce(&Discord) -> ER + Send>,
-        >(Box::new(cb))
-    };
+enum Job<T: 'static> {
+    Nonblocking(Box<dyn FnOnce(&T) + Send>),
+    Blocking(ScopedJob<T>),
+}
+
+/// A job queued by a blocking call, the closure lives on the stack of the waiting thread
+struct ScopedJob<T: 'static> {
+    id: u64,
+    /// Points to the `Slot` of the waiting call
+    slot: *const (),
+    /// `run_scoped` for the types of the slot
+    run: unsafe fn(*const (), &T),
+    caller: &'static Location<'static>,
+}
 
-    if std::thread::current().id() == MAIN_THREAD.lock().unwrap() {
-        cb(unsafe { &crate::__PLUGIN.as_ref().unwrap() })
-    } else {
-        let (tx, rx) = unbounded();
-        let job = Job::Blocking(Box::new(move |data| Box::new(cb(data))), tx);
-        JOB_QUEUE.lock().borrow_mut().push_back(job);
+// The closure and its result are both `Send`, which `call` requires before creating the job
+unsafe impl<T> Send for ScopedJob<T> {}
 
-        let rcv: Box<dyn Any + Send> = rx.recv().expect("rx can't fail");
-        *rcv.downcast::<ER>().expect("downcast can't fail")
+/// The closure of a blocking call and where to send its result, the main thread takes both out
+/// before running the closure and does not touch the slot after that
+type Slot<F, R> = Mutex<Option<(F, Sender<R>)>>;
+
+/// # Safety
+/// `slot` must point to a live `Slot<F, R>`
+unsafe fn run_scoped<T, F: FnOnce(&T) -> R, R>(slot: *const (), data: &T) {
+    let taken = (*(slot as *const Slot<F, R>)).lock().take();
+    if let Some((cb, tx)) = taken {
+        let _ = tx.send(cb(data));
     }
 }
 
-fn tick() {
-    match JOB_QUEUE.lock().borrow_mut().pop_front() {
-        Some(Job::Nonblocking(cb)) => {
-            (cb)(unsafe { &crate::__PLUGIN.as_ref().unwrap() });
-        },
-        Some(Job::Blocking(cb, tx)) => {
-            let result = (cb)(unsafe { &crate::__PLUGIN.as_ref().unwrap() });
-            let _ = tx.send(result);
+/// A queue of jobs for the main thread, which is handed `T` to run them with
+struct Dispatcher<T: 'static> {
+    data: fn() -> &'static T,
+    deadlock_after: Duration,
+    /// Called with where the job was queued when a call without a timeout has been waiting
+    /// behind it for longer than `deadlock_after`
+    slow_job: fn(&'static Location<'static>),
+    main_thread: Mutex<Option<ThreadId>>,
+    queue: Mutex<VecDeque<Job<T>>>,
+    next_id: AtomicU64,
+    /// When the main thread started the blocking job it is running, and where it was queued
+    running: Mutex<Option<(Instant, &'static Location<'static>)>>,
+    /// Written to whenever jobs are queued, WeeChat watches the other end
+    waker: Mutex<Option<UnixStream>>,
+    /// Set while a wakeup is written but not yet handled, so a burst of jobs writes a single byte
+    wake_pending: AtomicBool,
+    peak_depth: AtomicUsize,
+    processed: AtomicUsize,
+    wakeups: AtomicUsize,
+}
+
+impl<T: 'static> Dispatcher<T> {
+    fn new(data: fn() -> &'static T, deadlock_after: Duration) -> Self {
+        Dispatcher {
+            data,
+            deadlock_after,
+            slow_job: |_| {},
+            main_thread: Mutex::new(None),
+            queue: Mutex::new(VecDeque::new()),
+            next_id: AtomicU64::new(0),
+            running: Mutex::new(None),
+            waker: Mutex::new(None),
+            wake_pending: AtomicBool::new(false),
+            peak_depth: AtomicUsize::new(0),
+            processed: AtomicUsize::new(0),
+            wakeups: AtomicUsize::new(0),
+        }
+    }
+
+    fn report_slow_jobs(self, slow_job: fn(&'static Location<'static>)) -> Self {
+        Dispatcher { slow_job, ..self }
+    }
+
+    /// Jobs run on the thread this is called from
+    fn set_main_thread(&self) {
+        *self.main_thread.lock() = Some(thread::current().id());
+    }
+
+    fn on_main_thread(&self) -> bool {
+        *self.main_thread.lock() == Some(thread::current().id())
+    }
+
+    fn set_waker(&self, writer: UnixStream) {
+        *self.waker.lock() = Some(writer);
+        if !self.queue.lock().is_empty() {
+            self.wake();
+        }
+    }
+
+    fn stats(&self) -> QueueStats {
+        QueueStats {
+            depth: self.queue.lock().len(),
+            peak_depth: self.peak_depth.load(Ordering::Relaxed),
+            processed: self.processed.load(Ordering::Relaxed),
+            wakeups: self.wakeups.load(Ordering::Relaxed),
+        }
+    }
+
+    fn spawn<F: 'static + FnOnce(&T) + Send>(&self, cb: F) {
+        if self.on_main_thread() {
+            cb((self.data)());
+        } else {
+            self.push(Job::Nonblocking(Box::new(cb)));
+        }
+    }
+
+    /// Run `cb` on the main thread and wait for its result. Gives up when `timeout` runs out
+    /// before the job started, and in debug builds when the main thread is stuck in another
+    /// blocking job. Without a timeout a stuck job is only reported, as it may just be slow.
+    #[track_caller]
+    fn call<R: Send, F: FnOnce(&T) -> R + Send>(
+        &self,
+        cb: F,
+        timeout: Option<Duration>,
+    ) -> Result<R, SyncError> {
+        if self.on_main_thread() {
+            return Ok(cb((self.data)()));
+        }
+
+        let (tx, rx) = bounded(1);
+        let slot: Slot<F, R> = Mutex::new(Some((cb, tx)));
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        self.push(Job::Blocking(ScopedJob {
+            id,
+            slot: &slot as *const Slot<F, R> as *const (),
+            run: run_scoped::<T, F, R>,
+            caller: Location::caller(),
+        }));
+
+        // `slot` must outlive the job, every return below either has the result, which is sent
+        // after the main thread is done with the slot, or took the job off the queue
+        let started = Instant::now();
+        let mut reported = false;
+        loop {
+            let wait = match timeout {
+                Some(timeout) => timeout
+                    .checked_sub(started.elapsed())
+                    .unwrap_or_default()
+                    .min(self.deadlock_after),
+                None => self.deadlock_after,
+            };
+            match rx.recv_timeout(wait) {
+                Ok(result) => return Ok(result),
+                Err(RecvTimeoutError::Disconnected) => return Err(SyncError::Dropped),
+                Err(RecvTimeoutError::Timeout) => {},
+            }
+
+            let timed_out = matches!(timeout, Some(timeout) if started.elapsed() >= timeout);
+            let deadlock = if cfg!(debug_assertions) {
+                self.blocked_since(started)
+            } else {
+                None
+            };
+            if let (None, Some(busy_with)) = (timeout, deadlock) {
+                if !reported {
+                    (self.slow_job)(busy_with);
+                    reported = true;
+                }
+                continue;
+            }
+            if !timed_out && deadlock.is_none() {
+                continue;
+            }
+
+            if self.cancel(id) {
+                return Err(match deadlock {
+                    Some(busy_with) => SyncError::Deadlock { busy_with },
+                    None => SyncError::Timeout,
+                });
+            }
+            // The main thread has already taken the job, so it is running
+            return rx.recv().map_err(|_| SyncError::Dropped);
+        }
+    }
+
+    /// Where the blocking job the main thread has been running since before `since` was queued
+    fn blocked_since(&self, since: Instant) -> Option<&'static Location<'static>> {
+        match *self.running.lock() {
+            Some((started, caller)) if started <= since => Some(caller),
+            _ => None,
+        }
+    }
+
+    /// Take a blocking job off the queue, false if the main thread has already taken it
+    fn cancel(&self, id: u64) -> bool {
+        let mut queue = self.queue.lock();
+        let position = queue
+            .iter()
+            .position(|job| matches!(job, Job::Blocking(job) if job.id == id));
+        match position {
+            Some(position) => {
+                queue.remove(position);
+                true
+            },
+            None => false,
+        }
+    }
+
+    fn push(&self, job: Job<T>) {
+        let depth = {
+            let mut queue = self.queue.lock();
+            queue.push_back(job);
+            queue.len()
+        };
+        self.peak_depth.fetch_max(depth, Ordering::Relaxed);
+        self.wake();
+    }
+
+    /// Make WeeChat call back into the plugin, unless a wakeup is already on its way
+    fn wake(&self) {
+        // Jobs queued before the waker is set run on the first wakeup after it
+        let mut waker = self.waker.lock();
+        let writer = match waker.as_mut() {
+            Some(writer) => writer,
+            None => return,
+        };
+        if !self.wake_pending.swap(true, Ordering::AcqRel) {
+            match writer.write(&[1]) {
+                // A full pipe already has a wakeup in it
+                Ok(_) => {},
+                Err(e) if e.kind() == ErrorKind::WouldBlock => {},
+                // Nothing is on its way, so let the next job try again
+                Err(_) => self.wake_pending.store(false, Ordering::Release),
+            }
+        }
+    }
+
+    /// Run queued jobs until the queue is empty or the batch is used up, in which case the
+    /// main thread is woken again for the rest
+    fn run_batch(&self) {
+        // Cleared before taking jobs so any job queued from now on wakes the main thread again
+        self.wake_pending.store(false, Ordering::Release);
+        self.wakeups.fetch_add(1, Ordering::Relaxed);
+
+        let started = Instant::now();
+        for _ in 0..BATCH_SIZE {
+            // The lock is released before running the job, jobs may queue more jobs
+            let job = self.queue.lock().pop_front();
+            match job {
+                Some(Job::Nonblocking(cb)) => cb((self.data)()),
+                Some(Job::Blocking(job)) => {
+                    *self.running.lock() = Some((Instant::now(), job.caller));
+                    // The waiting call can no longer cancel the job, so its slot stays alive
+                    // until the result is sent
+                    unsafe { (job.run)(job.slot, (self.data)()) };
+                    *self.running.lock() = None;
+                },
+                None => return,
+            }
+            self.processed.fetch_add(1, Ordering::Relaxed);
+
+            if started.elapsed() >= BATCH_TIME {
+                break;
+            }
+        }
+
+        if !self.queue.lock().is_empty() {
+            self.wake();
+        }
+    }
+}
+
+fn report_slow_job(busy_with: &'static Location<'static>) {
+    crate::plugin_print(&format!(
+        "A blocking call has been waiting over {}s behind the main thread job queued at {}",
+        DEADLOCK_AFTER.as_secs(),
+        busy_with
+    ));
+}
+
+fn plugin() -> &'static Discord {
+    unsafe { crate::__PLUGIN.as_ref().unwrap() }
+}
+
+/// Initialize thread synchronization, this function must be called on the main thread
+pub fn init(weechat: &weechat::Weechat) -> SyncHandle {
+    DISPATCHER.set_main_thread();
+
+    let (reader, writer) =
+        UnixStream::pair().expect("Unable to create the main thread wakeup pipe");
+    reader
+        .set_nonblocking(true)
+        .expect("Unable to make the wakeup pipe nonblocking");
+    writer
+        .set_nonblocking(true)
+        .expect("Unable to make the wakeup pipe nonblocking");
+    DISPATCHER.set_waker(writer);
+
+    SyncHandle(weechat.hook_fd(
+        reader,
+        FdHookMode::Read,
+        |_, reader| {
+            drain(reader);
+            DISPATCHER.run_batch();
         },
-        None => {},
+        None,
+    ))
+}
+
+pub fn stats() -> QueueStats {
+    DISPATCHER.stats()
+}
+
+fn drain(reader: &mut UnixStream) {
+    let mut buf = [0; 64];
+    loop {
+        match reader.read(&mut buf) {
+            Ok(0) => break,
+            Ok(_) => continue,
+            Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+            Err(_) => break,
+        }
     }
 }
 
+pub fn on_main<F: 'static + FnOnce(&Discord) + Send>(cb: F) {
+    DISPATCHER.spawn(cb)
+}
+
+/// Run `cb` on the main thread and wait for its result, `cb` may borrow from the caller
+#[track_caller]
+pub fn on_main_blocking<R: Send, F: FnOnce(&Discord) -> R + Send>(cb: F) -> R {
+    match DISPATCHER.call(cb, None) {
+        Ok(result) => result,
+        Err(e) => panic!("{}", e),
+    }
+}
+
+/// Like `on_main_blocking`, but gives up if the main thread has not started `cb` within `timeout`
+#[track_caller]
+pub fn try_on_main_blocking<R: Send, F: FnOnce(&Discord) -> R + Send>(
+    timeout: Duration,
+    cb: F,
+) -> Result<R, SyncError> {
+    DISPATCHER.call(cb, Some(timeout))
+}
+
 #[must_use]
 pub fn upgrade_plugin(weechat: &Weechat) -> &Discord {
     let _ = weechat;
-    unsafe { crate::__PLUGIN.as_ref().unwrap() }
+    plugin()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Dispatcher, SyncError};
+    use std::{
+        os::unix::net::UnixStream,
+        sync::{
+            atomic::{AtomicBool, AtomicUsize, Ordering},
+            Arc,
+        },
+        thread,
+        time::{Duration, Instant},
+    };
+
+    /// Run jobs on the current thread until `done`
+    fn pump<T>(dispatcher: &Dispatcher<T>, done: impl Fn() -> bool) {
+        let deadline = Instant::now() + Duration::from_secs(10);
+        while !done() {
+            assert!(Instant::now() < deadline, "jobs did not finish");
+            dispatcher.run_batch();
+            thread::yield_now();
+        }
+    }
+
+    #[test]
+    fn blocking_calls_from_many_workers() {
+        static COUNT: AtomicUsize = AtomicUsize::new(0);
+        let dispatcher = Arc::new(Dispatcher::new(|| &COUNT, Duration::from_secs(5)));
+        dispatcher.set_main_thread();
+
+        let workers: Vec<_> = (0..8)
+            .map(|i| {
+                let dispatcher = Arc::clone(&dispatcher);
+                thread::spawn(move || {
+                    // Borrowed by the jobs, not moved into them
+                    let local = [i; 10];
+                    (0..10)
+                        .map(|_| {
+                            dispatcher
+                                .call(
+                                    |count| {
+                                        count.fetch_add(1, Ordering::SeqCst);
+                                        local.iter().sum::<usize>()
+                                    },
+                                    None,
+                                )
+                                .unwrap()
+                        })
+                        .sum::<usize>()
+                })
+            })
+            .collect();
+
+        pump(&dispatcher, || COUNT.load(Ordering::SeqCst) == 80);
+        let sums: Vec<_> = workers.into_iter().map(|w| w.join().unwrap()).collect();
+        assert_eq!(sums, (0..8).map(|i| i * 100).collect::<Vec<_>>());
+        assert_eq!(dispatcher.stats().processed, 80);
+        assert_eq!(dispatcher.stats().depth, 0);
+    }
+
+    #[test]
+    fn nonblocking_jobs_run_in_order() {
+        static ORDER: AtomicUsize = AtomicUsize::new(0);
+        let dispatcher = Arc::new(Dispatcher::new(|| &ORDER, Duration::from_secs(5)));
+        dispatcher.set_main_thread();
+
+        let worker = {
+            let dispatcher = Arc::clone(&dispatcher);
+            thread::spawn(move || {
+                for i in 0..100 {
+                    dispatcher.spawn(move |order| {
+                        assert_eq!(order.fetch_add(1, Ordering::SeqCst), i);
+                    });
+                }
+            })
+        };
+        worker.join().unwrap();
+
+        assert_eq!(dispatcher.stats().peak_depth, 100);
+        pump(&dispatcher, || ORDER.load(Ordering::SeqCst) == 100);
+        // Jobs run in bounded batches
+        assert!(dispatcher.stats().wakeups >= 2);
+    }
+
+    #[test]
+    fn blocking_calls_time_out_and_leave_the_queue() {
+        static RAN: AtomicBool = AtomicBool::new(false);
+        let dispatcher = Arc::new(Dispatcher::new(|| &RAN, Duration::from_secs(5)));
+        dispatcher.set_main_thread();
+
+        let worker = {
+            let dispatcher = Arc::clone(&dispatcher);
+            thread::spawn(move || {
+                dispatcher.call(
+                    |ran| ran.store(true, Ordering::SeqCst),
+                    Some(Duration::from_millis(50)),
+                )
+            })
+        };
+
+        assert_eq!(worker.join().unwrap(), Err(SyncError::Timeout));
+        assert_eq!(dispatcher.stats().depth, 0);
+        dispatcher.run_batch();
+        assert!(!RAN.load(Ordering::SeqCst));
+    }
+
+    #[test]
+    #[cfg(debug_assertions)]
+    fn nested_blocking_calls_are_reported() {
+        static UNUSED: AtomicBool = AtomicBool::new(false);
+        let dispatcher = Arc::new(Dispatcher::new(|| &UNUSED, Duration::from_millis(100)));
+        dispatcher.set_main_thread();
+        let done = Arc::new(AtomicBool::new(false));
+
+        let worker = {
+            let dispatcher = Arc::clone(&dispatcher);
+            let done = Arc::clone(&done);
+            thread::spawn(move || {
+                // The main thread waits for a second worker that itself waits for the main thread
+                let result = dispatcher.call(
+                    |_| {
+                        let dispatcher = Arc::clone(&dispatcher);
+                        thread::spawn(move || {
+                            dispatcher.call(|_| (), Some(Duration::from_secs(10)))
+                        })
+                        .join()
+                        .unwrap()
+                    },
+                    None,
+                );
+                done.store(true, Ordering::SeqCst);
+                result
+            })
+        };
+
+        pump(&dispatcher, || done.load(Ordering::SeqCst));
+        match worker.join().unwrap() {
+            Ok(Err(SyncError::Deadlock { busy_with })) => {
+                assert!(busy_with.file().ends_with("sync.rs"))
+            },
+            other => panic!("expected a deadlock, got {:?}", other),
+        }
+        assert_eq!(dispatcher.stats().depth, 0);
+    }
+
+    #[test]
+    #[cfg(debug_assertions)]
+    fn slow_jobs_are_reported_and_waited_for() {
+        static REPORTED: AtomicBool = AtomicBool::new(false);
+        let dispatcher = Arc::new(
+            Dispatcher::new(|| &REPORTED, Duration::from_millis(50))
+                .report_slow_jobs(|_| REPORTED.store(true, Ordering::SeqCst)),
+        );
+        dispatcher.set_main_thread();
+        let done = Arc::new(AtomicBool::new(false));
+
+        let worker = {
+            let dispatcher = Arc::clone(&dispatcher);
+            let done = Arc::clone(&done);
+            thread::spawn(move || {
+                // The second call waits behind a slow job, without a timeout it keeps waiting
+                let waiting = dispatcher.call(
+                    |_| {
+                        let dispatcher = Arc::clone(&dispatcher);
+                        let waiting = thread::spawn(move || dispatcher.call(|_| 1, None));
+                        thread::sleep(Duration::from_millis(200));
+                        waiting
+                    },
+                    None,
+                );
+                let result = waiting.unwrap().join().unwrap();
+                done.store(true, Ordering::SeqCst);
+                result
+            })
+        };
+
+        pump(&dispatcher, || done.load(Ordering::SeqCst));
+        assert_eq!(worker.join().unwrap(), Ok(1));
+        assert!(REPORTED.load(Ordering::SeqCst));
+    }
+
+    #[test]
+    fn failed_wakeups_are_retried() {
+        static UNUSED: AtomicBool = AtomicBool::new(false);
+        let dispatcher = Dispatcher::new(|| &UNUSED, Duration::from_secs(5));
+        let (reader, writer) = UnixStream::pair().unwrap();
+        drop(reader);
+        dispatcher.set_waker(writer);
+
+        dispatcher.spawn(|_| ());
+        assert!(!dispatcher.wake_pending.load(Ordering::SeqCst));
+    }
 }
@@ -1,4 +1,7 @@
-use crate::weechat_utils::MessageManager;
+use crate::{
+    config::MAIN_ACCOUNT,
+    weechat_utils::{BufferHost, MessageManager, WeechatHost},
+};
 use indexmap::IndexMap;
 use lazy_static::lazy_static;
 use regex::Regex;
@@ -74,25 +77,21 @@ pub fn format_user_status_prefix(weechat: &Weechat, status: Option<OnlineStatus>
     )
 }
 
-pub fn nick_color(weechat: &Weechat, nick: &String) -> String {
+pub fn nick_color(weechat: &impl WeechatHost, nick: &str) -> String {
     weechat
         .info_get("nick_color_name", nick)
-        .unwrap_or_else(|| {
-            weechat
-                .info_get("irc_nick_color_name", nick)
-                .unwrap_or_else(|| Cow::from("reset"))
-        })
-        .to_string()
+        .or_else(|| weechat.info_get("irc_nick_color_name", nick))
+        .unwrap_or_else(|| "reset".to_owned())
 }
 
-pub fn format_nick_color(weechat: &Weechat, nick: &String) -> String {
+pub fn format_nick_color(weechat: &impl WeechatHost, nick: &str) -> String {
     let color_name = nick_color(weechat, nick);
     let prefix = weechat.color(&color_name);
     let suffix = weechat.color("reset");
     return prefix.to_string() + nick + &suffix.to_string();
 }
 
-pub fn colorize_string(weechat: &Weechat, color: &str, string: &str) -> String {
+pub fn colorize_string(weechat: &impl WeechatHost, color: &str, string: &str) -> String {
     if string.is_empty() {
         string.to_owned()
     } else {
@@ -138,6 +137,8 @@ impl ChannelExt for Channel {
 }
 
 pub trait BufferExt {
+    /// The account the buffer belongs to, buffers without one belong to the main account
+    fn account(&self) -> String;
     fn channel_id(&self) -> Option<ChannelId>;
     fn guild_id(&self) -> Option<GuildId>;
 
@@ -148,7 +149,13 @@ pub trait BufferExt {
     fn set_nicks_loaded(&self);
 }
 
-impl BufferExt for Buffer {
+impl<B: BufferHost + ?Sized> BufferExt for B {
+    fn account(&self) -> String {
+        self.get_localvar("account")
+            .map(Cow::into_owned)
+            .unwrap_or_else(|| MAIN_ACCOUNT.to_owned())
+    }
+
     fn channel_id(&self) -> Option<ChannelId> {
         self.get_localvar("channelid")
             .and_then(|ch| ch.parse::<u64>().ok())
@@ -230,19 +237,36 @@ pub fn parse_id(id: &str) -> Option<GuildOrChannel> {
     }
 }
 
-pub fn get_irc_mode(weechat: &weechat::Weechat) -> bool {
-    crate::upgrade_plugin(weechat).config.irc_mode.value()
+pub fn get_irc_mode(weechat: &weechat::Weechat, account: &str) -> bool {
+    crate::upgrade_plugin(weechat)
+        .config
+        .account(account)
+        .map(|account| account.irc_mode.value())
+        .unwrap_or_default()
+}
+
+// Buffers of the main account are not prefixed to keep the names used before multiple accounts
+fn account_prefix(account: &str) -> String {
+    if account == MAIN_ACCOUNT {
+        String::new()
+    } else {
+        format!("{}.", account)
+    }
 }
 
-pub fn buffer_id_for_guild(id: GuildId) -> String {
-    format!("{}", id.0)
+pub fn buffer_id_for_guild(account: &str, id: GuildId) -> String {
+    format!("{}{}", account_prefix(account), id.0)
 }
 
-pub fn buffer_id_for_channel(guild_id: Option<GuildId>, channel_id: ChannelId) -> String {
+pub fn buffer_id_for_channel(
+    account: &str,
+    guild_id: Option<GuildId>,
+    channel_id: ChannelId,
+) -> String {
     if let Some(guild_id) = guild_id {
-        format!("{}.{}", guild_id, channel_id.0)
+        format!("{}{}.{}", account_prefix(account), guild_id, channel_id.0)
     } else {
-        format!("Private.{}", channel_id.0)
+        format!("{}Private.{}", account_prefix(account), channel_id.0)
     }
 }
 
@@ -253,12 +277,9 @@ pub unsafe fn buffer_from_ptr(buffer_ptr: *mut std::ffi::c_void) -> Buffer {
     )
 }
 
-pub fn buffer_is_muted(buffer: &Buffer) -> bool {
-    if let Some(muted) = buffer.get_localvar("muted") {
-        muted == "1"
-    } else {
-        false
-    }
+/// Timed mutes end on their own, without waiting for the buffer to be updated
+pub fn buffer_is_muted(buffer: &impl BufferHost) -> bool {
+    crate::discord::notify::Mute::from_buffer(buffer).is_muted()
 }
 
 pub fn search_channel(
@@ -326,6 +347,52 @@ pub fn flatten_guilds(
     channels
 }
 
+/// Find the message an edit, reaction or reply targets among the messages printed in a buffer,
+/// `author` only counts that user's messages towards a line number
+pub fn find_target_message(
+    weecord: &crate::Discord,
+    buffer: &impl BufferHost,
+    target: parsing::Target,
+    author: Option<UserId>,
+) -> Option<Message> {
+    let manager = weecord.buffer_manager.get_buffer(&buffer.name())?;
+    match target {
+        parsing::Target::Line(n) => manager.nth_message(n, author),
+        parsing::Target::Ref(tag) => manager
+            .message_by_ref(tag)
+            .filter(|msg| author.map_or(true, |author| msg.author.id == author)),
+    }
+}
+
+/// Resolve the message an edit, reaction or reply targets, messages are only fetched from
+/// Discord when a line number reaches past the messages printed in the buffer
+pub fn resolve_target_message(
+    ctx: &Context,
+    buffer: &Buffer,
+    channel: ChannelId,
+    target: parsing::Target,
+    own_only: bool,
+) -> serenity::Result<Message> {
+    let author = if own_only {
+        Some(ctx.cache.read().user.id)
+    } else {
+        None
+    };
+    let weecord = crate::upgrade_plugin(&buffer.get_weechat());
+    if let Some(msg) = find_target_message(weecord, buffer, target, author) {
+        return Ok(msg);
+    }
+
+    match target {
+        parsing::Target::Line(n) if own_only => get_users_nth_message(ctx, channel, n),
+        parsing::Target::Line(n) => get_nth_message(ctx, channel, n),
+        // Tags are only given to printed messages, so there is nothing to fetch
+        parsing::Target::Ref(_) => Err(serenity::Error::Model(
+            serenity::model::ModelError::ItemMissing,
+        )),
+    }
+}
+
 pub fn get_users_nth_message(
     ctx: &Context,
     channel: ChannelId,
@@ -352,6 +419,22 @@ pub fn get_users_nth_message(
         })
 }
 
+pub fn get_nth_message(ctx: &Context, channel: ChannelId, n: usize) -> serenity::Result<Message> {
+    if n > 100 {
+        return Err(serenity::Error::ExceededLimit(
+            "Cannot fetch more than 100 items".into(),
+            n as u32,
+        ));
+    }
+    channel
+        .messages(&ctx.http, |retriever| retriever.limit(n as u64))
+        .and_then(|msgs| {
+            msgs.into_iter().nth(n - 1).ok_or(serenity::Error::Model(
+                serenity::model::ModelError::ItemMissing,
+            ))
+        })
+}
+
 // TODO: Role mentions
 /// Parse user input and replace mentions with Discords internal representation
 ///
@@ -433,51 +516,107 @@ pub fn create_mentions(cache: &CacheRwLock, guild_id: Option<GuildId>, input: &s
     out
 }
 
+/// Replace `:name:` with custom emoji of the current guild, falling back to the other guilds with
+/// Nitro
 pub fn expand_guild_emojis(cache: &CacheRwLock, guild_id: Option<GuildId>, input: &str) -> String {
-    let mut out = String::from(input);
     lazy_static! {
-        static ref EMOJI_SYNTAX: Regex = Regex::new(r"(.?):(\w+):").unwrap();
+        static ref EMOJI_SYNTAX: Regex =
+            Regex::new(&format!(r"{}|(\\?):(\w+):", crate::emoji::VERBATIM_SPANS)).unwrap();
     }
 
-    let emojis = EMOJI_SYNTAX.captures_iter(input);
-    if let Some(guild) = guild_id.and_then(|id| id.to_guild_cached(cache)) {
-        let guild = guild.read();
-        for emoji_match in emojis {
-            if let Some(prefix) = emoji_match.get(1) {
-                if prefix.as_str() == "\\" {
-                    continue;
-                }
-            }
-            if let Some(emoji_match) = emoji_match.get(2) {
-                let emoji_name = emoji_match.as_str();
-                if let Some(guild_emoji) =
-                    guild.emojis.values().find(|emoji| emoji.name == emoji_name)
-                {
-                    out = out.replace(&format!(":{}:", emoji_name), &guild_emoji.mention());
-                }
+    EMOJI_SYNTAX
+        .replace_all(input, |caps: &regex::Captures| {
+            match caps
+                .get(2)
+                .and_then(|name| find_guild_emoji(cache, guild_id, name.as_str()))
+            {
+                Some(emoji) if caps[1].is_empty() => emoji.mention(),
+                _ => caps[0].to_owned(),
             }
-        }
-    }
-    out
+        })
+        .into_owned()
 }
 
-/// Remove the guild id from global emojis
-pub fn clean_emojis(input: &str) -> String {
-    let mut out = String::from(input);
+/// Find a custom emoji by name, preferring the current guild's emoji
+///
+/// Emoji of other guilds can only be used with Nitro, so they are only searched for Nitro users
+pub fn find_guild_emoji(
+    cache: &CacheRwLock,
+    guild_id: Option<GuildId>,
+    name: &str,
+) -> Option<Emoji> {
+    let cache = cache.read();
+    let current_guild = guild_id.and_then(|id| cache.guilds.get(&id));
+    let other_guilds = if crate::discord::has_premium(cache.user.id) {
+        Some(cache.guilds.values())
+    } else {
+        None
+    };
+    let emoji = current_guild
+        .into_iter()
+        .chain(other_guilds.into_iter().flatten())
+        .find_map(|guild| {
+            guild
+                .read()
+                .emojis
+                .values()
+                .find(|emoji| emoji.name == name)
+                .cloned()
+        });
+    emoji
+}
 
-    lazy_static! {
-        static ref GLOBAL_EMOJI: Regex = Regex::new(r"<:(.*?):(\d*?)>").unwrap();
+/// Resolve a parsed reaction emoji, `:name:` is tried as a Unicode shortcode before custom emoji
+pub fn reaction_type(
+    cache: &CacheRwLock,
+    guild_id: Option<GuildId>,
+    emoji: &parsing::ReactionEmoji,
+) -> Option<ReactionType> {
+    match *emoji {
+        parsing::ReactionEmoji::Unicode(unicode) => Some(ReactionType::Unicode(unicode.to_owned())),
+        parsing::ReactionEmoji::Named(name) => {
+            if let Some(unicode) = crate::emoji::shortcode_to_unicode(name) {
+                return Some(ReactionType::Unicode(unicode.to_owned()));
+            }
+            find_guild_emoji(cache, guild_id, name).map(|emoji| ReactionType::Custom {
+                animated: emoji.animated,
+                id: emoji.id,
+                name: Some(emoji.name),
+            })
+        },
     }
+}
 
-    let global_emoji = GLOBAL_EMOJI.captures_iter(input);
-    for emoji_match in global_emoji {
-        let emoji_name = emoji_match.get(1).unwrap().as_str();
+/// Whether spoilers in a guild (or DMs) are shown by default (`weecord.look.show_spoilers`)
+pub fn spoilers_shown(weechat: &impl WeechatHost, guild: Option<GuildId>) -> bool {
+    let shown = weechat
+        .string_option("weecord.look.show_spoilers")
+        .unwrap_or_default();
+    let key = guild.map_or_else(|| "dm".to_owned(), |guild| guild.0.to_string());
+    shown
+        .split(',')
+        .map(str::trim)
+        .any(|item| item == "*" || item == key)
+}
 
-        out = out.replace(
-            emoji_match.get(0).unwrap().as_str(),
-            &format!(":{}:", emoji_name),
-        );
+/// Replace custom emoji (`<:name:id>` or animated `<a:name:id>`) with `:name:`, optionally
+/// followed by the emoji's image url
+pub fn clean_emojis(input: &str, with_url: bool) -> String {
+    lazy_static! {
+        static ref CUSTOM_EMOJI: Regex = Regex::new(r"<(a?):(\w+):(\d+)>").unwrap();
     }
 
-    out
+    CUSTOM_EMOJI
+        .replace_all(input, |caps: &regex::Captures| {
+            if with_url {
+                let extension = if caps[1].is_empty() { "png" } else { "gif" };
+                format!(
+                    ":{}: (https://cdn.discordapp.com/emojis/{}.{})",
+                    &caps[2], &caps[3], extension
+                )
+            } else {
+                format!(":{}:", &caps[2])
+            }
+        })
+        .into_owned()
 }
@@ -1,60 +1,77 @@
-use crate::weechat_utils::message_manager::MessageManager;
+use crate::weechat_utils::{host::BufferSource, message_manager::MessageManager};
 use std::{cell::RefCell, collections::HashMap, sync::Arc};
 use weechat::Weechat;
 
 /// Manages all buffers for the plugin
-pub struct BufferManager {
-    weechat: Weechat,
-    buffers: RefCell<HashMap<String, Arc<MessageManager>>>,
+pub struct BufferManager<S: BufferSource = Weechat> {
+    source: S,
+    buffers: RefCell<HashMap<String, Arc<MessageManager<S::Buffer>>>>,
 }
 
-impl BufferManager {
-    pub(crate) fn new(weechat: Weechat) -> BufferManager {
+impl<S: BufferSource> BufferManager<S> {
+    pub(crate) fn new(source: S) -> BufferManager<S> {
         BufferManager {
-            weechat,
+            source,
             buffers: RefCell::new(HashMap::new()),
         }
     }
 
-    pub fn get_buffer(&self, name: &str) -> Option<Arc<MessageManager>> {
+    pub fn get_buffer(&self, name: &str) -> Option<Arc<MessageManager<S::Buffer>>> {
         if let Some(buffer) = self.buffers.borrow().get(name) {
             return Some(Arc::clone(buffer));
         }
 
-        if let Some(buffer) = self.weechat.buffer_search("weecord", name) {
-            let msg_manager = MessageManager::new(buffer);
-            self.buffers
-                .borrow_mut()
-                .insert(name.into(), Arc::new(msg_manager));
-            Some(Arc::clone(self.buffers.borrow().get(name).unwrap()))
-        } else {
-            None
-        }
+        let buffer = self.source.buffer_search(name)?;
+        Some(self.manage(name, buffer))
     }
 
-    pub fn get_or_create_buffer(&self, name: &str) -> Arc<MessageManager> {
-        if let Some(buffer) = self.buffers.borrow().get(name) {
-            return Arc::clone(buffer);
-        }
+    /// All buffers currently managed by the plugin
+    pub fn buffers(&self) -> Vec<Arc<MessageManager<S::Buffer>>> {
+        self.buffers.borrow().values().cloned().collect()
+    }
 
-        if let Some(buffer) = self.weechat.buffer_search("weecord", name) {
-            let msg_manager = MessageManager::new(buffer);
-            self.buffers
-                .borrow_mut()
-                .insert(name.into(), Arc::new(msg_manager));
-            Arc::clone(self.buffers.borrow().get(name).unwrap())
-        } else {
-            let msg_manager = MessageManager::new(self.weechat.buffer_new::<(), ()>(
-                name,
-                Some(|_, b, i| crate::hook::buffer_input(b, &i)),
-                None,
-                None,
-                None,
-            ));
-            self.buffers
-                .borrow_mut()
-                .insert(name.into(), Arc::new(msg_manager));
-            Arc::clone(self.buffers.borrow().get(name).unwrap())
+    pub fn get_or_create_buffer(&self, name: &str) -> Arc<MessageManager<S::Buffer>> {
+        if let Some(buffer) = self.get_buffer(name) {
+            return buffer;
         }
+
+        let buffer = self.source.buffer_new(name);
+        self.manage(name, buffer)
+    }
+
+    fn manage(&self, name: &str, buffer: S::Buffer) -> Arc<MessageManager<S::Buffer>> {
+        let msg_manager = Arc::new(MessageManager::new(buffer));
+        self.buffers
+            .borrow_mut()
+            .insert(name.into(), Arc::clone(&msg_manager));
+        msg_manager
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::BufferManager;
+    use crate::weechat_utils::host::{fake::FakeHost, BufferHost, BufferSource};
+    use std::sync::Arc;
+
+    #[test]
+    fn finds_existing_buffers_before_creating_them() {
+        let host = FakeHost::default();
+        host.buffer_new("weecord.main.1")
+            .set_localvar("channel", "general");
+        let manager = BufferManager::new(host.clone());
+
+        let existing = manager.get_buffer("weecord.main.1").unwrap();
+        assert_eq!(existing.get_localvar("channel").unwrap(), "general");
+        assert!(manager.get_buffer("weecord.main.2").is_none());
+
+        let created = manager.get_or_create_buffer("weecord.main.2");
+        assert_eq!(created.name(), "weecord.main.2");
+        assert!(host.buffers.borrow().contains_key("weecord.main.2"));
+        assert!(Arc::ptr_eq(
+            &created,
+            &manager.get_or_create_buffer("weecord.main.2")
+        ));
+        assert_eq!(manager.buffers().len(), 2);
     }
 }
@@ -0,0 +1,281 @@
+//! Draws message embeds as a block of lines behind a side bar in the embed's color
+
+use crate::{discord::formatting, weechat_utils::WeechatHost};
+use serenity::{
+    cache::CacheRwLock,
+    model::channel::{Embed, EmbedField},
+};
+use std::borrow::Cow;
+
+const BAR: &str = "▎";
+/// Discord shows at most three inline fields side by side
+const INLINE_FIELDS_PER_ROW: usize = 3;
+const INLINE_FIELD_SPACING: usize = 2;
+
+/// Render an embed to lines prefixed with the side bar, ready to print. The title, description and
+/// fields are markdown and formatted like message content.
+pub fn render_embed(
+    weechat: &impl WeechatHost,
+    cache: &CacheRwLock,
+    embed: &Embed,
+    reveal_spoilers: bool,
+) -> String {
+    let markdown =
+        |text: &str| formatting::discord_to_weechat(weechat, cache, text, reveal_spoilers);
+    let bold = weechat.color("bold").into_owned();
+    let unbold = weechat.color("-bold").into_owned();
+    let dim = weechat.color("8").into_owned();
+    let reset = weechat.color("reset").into_owned();
+
+    let mut lines = Vec::new();
+
+    if let Some(provider) = embed.provider.as_ref().and_then(|p| p.name.as_ref()) {
+        lines.push(format!("{}{}{}", dim, provider, reset));
+    }
+    if let Some(ref author) = embed.author {
+        let mut line = format!("{}{}{}", bold, author.name, unbold);
+        if let Some(ref url) = author.url {
+            line.push_str(&format!(" ({})", url));
+        }
+        lines.push(line);
+    }
+    match (&embed.title, &embed.url) {
+        (Some(title), Some(url)) => {
+            let underline = weechat.color("underline").into_owned();
+            let no_underline = weechat.color("-underline").into_owned();
+            lines.push(format!(
+                "{}{}{}{}{} <{}>",
+                bold,
+                underline,
+                markdown(title),
+                no_underline,
+                unbold,
+                url
+            ));
+        },
+        (Some(title), None) => lines.push(format!("{}{}{}", bold, markdown(title), unbold)),
+        (None, Some(url)) => lines.push(url.clone()),
+        (None, None) => {},
+    }
+    if let Some(ref description) = embed.description {
+        lines.extend(markdown(description).lines().map(ToOwned::to_owned));
+    }
+
+    let mut fields = embed.fields.iter().peekable();
+    while let Some(field) = fields.next() {
+        if !field.inline {
+            lines.push(format!("{}{}{}", bold, markdown(&field.name), unbold));
+            lines.extend(markdown(&field.value).lines().map(ToOwned::to_owned));
+            continue;
+        }
+
+        let mut row = vec![field];
+        while row.len() < INLINE_FIELDS_PER_ROW {
+            match fields.peek() {
+                Some(next) if next.inline => row.push(fields.next().unwrap()),
+                _ => break,
+            }
+        }
+        lines.extend(layout_inline_fields(weechat, cache, &row, reveal_spoilers));
+    }
+
+    if let Some(ref image) = embed.image {
+        lines.push(image.url.clone());
+    }
+    if let Some(ref thumbnail) = embed.thumbnail {
+        lines.push(thumbnail.url.clone());
+    }
+    if let Some(ref video) = embed.video {
+        lines.push(video.url.clone());
+    }
+
+    let footer = embed.footer.as_ref().map(|footer| footer.text.clone());
+    let timestamp = embed.timestamp.as_ref().map(|ts| format_timestamp(ts));
+    let footer = match (footer, timestamp) {
+        (Some(footer), Some(timestamp)) => Some(format!("{} • {}", footer, timestamp)),
+        (footer, timestamp) => footer.or(timestamp),
+    };
+    if let Some(footer) = footer {
+        lines.push(format!("{}{}{}", dim, footer, reset));
+    }
+
+    // Embeds without a color use the default bar
+    let bar = if embed.colour.0 == 0 {
+        BAR.to_owned()
+    } else {
+        let color = crate::utils::rgb_to_ansi(embed.colour).to_string();
+        format!("{}{}{}", weechat.color(&color), BAR, reset)
+    };
+    lines
+        .iter()
+        .map(|line| format!("{}{}", bar, line))
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+/// Lay out a row of inline fields side by side, names on the first line and values below
+fn layout_inline_fields(
+    weechat: &impl WeechatHost,
+    cache: &CacheRwLock,
+    row: &[&EmbedField],
+    reveal_spoilers: bool,
+) -> Vec<String> {
+    let bold = weechat.color("bold");
+    let unbold = weechat.color("-bold");
+    let columns: Vec<Vec<(String, usize)>> = row
+        .iter()
+        .map(|field| {
+            let mut column: Vec<_> = cells(weechat, cache, &field.name, reveal_spoilers)
+                .into_iter()
+                .map(|(name, width)| (format!("{}{}{}", bold, name, unbold), width))
+                .collect();
+            column.extend(cells(weechat, cache, &field.value, reveal_spoilers));
+            column
+        })
+        .collect();
+    let widths: Vec<usize> = columns
+        .iter()
+        .map(|column| {
+            column
+                .iter()
+                .map(|(_, width)| *width)
+                .max()
+                .unwrap_or_default()
+        })
+        .collect();
+    let height = columns.iter().map(Vec::len).max().unwrap_or_default();
+
+    (0..height)
+        .map(|i| {
+            let mut line = String::new();
+            for (c, column) in columns.iter().enumerate() {
+                let (cell, width) = column
+                    .get(i)
+                    .map(|(cell, width)| (cell.as_str(), *width))
+                    .unwrap_or_default();
+                let is_last = c + 1 == columns.len();
+                let padding = if is_last {
+                    0
+                } else {
+                    widths[c] - width + INLINE_FIELD_SPACING
+                };
+                line.push_str(cell);
+                line.push_str(&" ".repeat(padding));
+            }
+            line.trim_end().to_owned()
+        })
+        .collect()
+}
+
+/// The formatted lines of markdown, each with the number of columns it takes on screen
+fn cells(
+    weechat: &impl WeechatHost,
+    cache: &CacheRwLock,
+    text: &str,
+    reveal_spoilers: bool,
+) -> Vec<(String, usize)> {
+    let formatted = formatting::discord_to_weechat(weechat, cache, text, reveal_spoilers);
+    let plain = formatting::discord_to_weechat(&Uncolored(weechat), cache, text, reveal_spoilers);
+    formatted
+        .lines()
+        .map(ToOwned::to_owned)
+        .zip(plain.lines().map(|line| line.chars().count()))
+        .collect()
+}
+
+/// A host drawing no colors, markdown formatted with it is only the text shown on screen
+struct Uncolored<'a, H>(&'a H);
+
+impl<H: WeechatHost> WeechatHost for Uncolored<'_, H> {
+    fn color(&self, _name: &str) -> Cow<str> {
+        Cow::Borrowed("")
+    }
+
+    fn get_prefix(&self, prefix: &str) -> Cow<str> {
+        self.0.get_prefix(prefix)
+    }
+
+    fn string_option(&self, name: &str) -> Option<String> {
+        self.0.string_option(name)
+    }
+
+    fn info_get(&self, name: &str, arguments: &str) -> Option<String> {
+        self.0.info_get(name, arguments)
+    }
+
+    fn update_bar_item(&self, name: &str) {
+        self.0.update_bar_item(name)
+    }
+
+    fn plugin_print(&self, msg: &str) {
+        self.0.plugin_print(msg)
+    }
+}
+
+/// Shorten an ISO 8601 timestamp to its date, hours and minutes
+fn format_timestamp(timestamp: &str) -> String {
+    timestamp.replacen('T', " ", 1).chars().take(16).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{layout_inline_fields, render_embed};
+    use crate::weechat_utils::host::fake::FakeHost;
+    use serenity::{
+        cache::CacheRwLock,
+        model::channel::{Embed, EmbedField},
+    };
+
+    fn field(name: &str, value: &str, inline: bool) -> EmbedField {
+        serde_json::from_value(serde_json::json!({
+            "name": name,
+            "value": value,
+            "inline": inline,
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn inline_fields_are_laid_out_side_by_side() {
+        let fields = [
+            field("Wins", "**10**", true),
+            field("Losses", "3\n4", true),
+            field("K", "1", true),
+        ];
+        let row: Vec<_> = fields.iter().collect();
+
+        // Widths only count the text shown, not the colors of the formatted markdown
+        assert_eq!(
+            layout_inline_fields(&FakeHost::default(), &CacheRwLock::default(), &row, false),
+            vec![
+                "{bold}Wins{-bold}  {bold}Losses{-bold}  {bold}K{-bold}",
+                "{bold}10{-bold}    3       1",
+                "      4",
+            ]
+        );
+    }
+
+    #[test]
+    fn embed_text_is_formatted_as_markdown() {
+        let embed: Embed = serde_json::from_value(serde_json::json!({
+            "type": "rich",
+            "color": 0,
+            "title": "**Big** news",
+            "description": "**hi**\nthere",
+            "fields": [{ "name": "Score", "value": "`3`", "inline": false }],
+        }))
+        .unwrap();
+
+        assert_eq!(
+            render_embed(&FakeHost::default(), &CacheRwLock::default(), &embed, false),
+            [
+                "▎{bold}{bold}Big{-bold} news{-bold}",
+                "▎{bold}hi{-bold}",
+                "▎there",
+                "▎{bold}Score{-bold}",
+                "▎{*8}3{reset}",
+            ]
+            .join("\n")
+        );
+    }
+}
@@ -0,0 +1,361 @@
+//! Traits describing the parts of WeeChat the plugin draws with, so rendering and nicklist logic
+//! can run against an in-memory host as well as a live WeeChat process.
+
+use std::borrow::Cow;
+use weechat::{Buffer, ConfigOption, NickArgs, Weechat};
+
+/// Global WeeChat functionality: colors, prefixes, options, bar items and the core buffer
+pub trait WeechatHost {
+    fn color(&self, name: &str) -> Cow<str>;
+    fn get_prefix(&self, prefix: &str) -> Cow<str>;
+    fn string_option(&self, name: &str) -> Option<String>;
+    fn info_get(&self, name: &str, arguments: &str) -> Option<String>;
+    fn update_bar_item(&self, name: &str);
+    /// Print a message from the plugin to the core buffer, must be called on the main thread
+    fn plugin_print(&self, msg: &str);
+}
+
+/// Finding and creating the plugin's buffers
+pub trait BufferSource {
+    type Buffer: BufferHost;
+
+    /// Find a buffer of the plugin by name
+    fn buffer_search(&self, name: &str) -> Option<Self::Buffer>;
+    /// Create a buffer whose input is sent to Discord
+    fn buffer_new(&self, name: &str) -> Self::Buffer;
+}
+
+/// A single buffer: printing, localvars and the nicklist
+pub trait BufferHost {
+    type Host: WeechatHost;
+
+    fn host(&self) -> Self::Host;
+    fn name(&self) -> Cow<str>;
+
+    fn print(&self, msg: &str);
+    fn print_tags_dated(&self, date: i64, tags: &str, msg: &str);
+    fn clear(&self);
+
+    fn get_localvar(&self, name: &str) -> Option<Cow<str>>;
+    fn set_localvar(&self, name: &str, value: &str);
+
+    /// Create a nicklist group, has no effect if the group already exists
+    fn nicklist_add_group(&self, name: &str, color: &str);
+    fn nicklist_has_group(&self, name: &str) -> bool;
+    /// Add a nick to the root of the nicklist or to an existing group, has no effect if a nick of
+    /// that name is already anywhere in the buffer
+    fn nicklist_add_nick(&self, group: Option<&str>, name: &str, color: &str, prefix: &str);
+    /// Remove a nick from a group (or the root), returning whether it was present
+    fn nicklist_remove_nick(&self, group: Option<&str>, name: &str) -> bool;
+    /// Remove a group and the nicks in it, returning whether it was present
+    fn nicklist_remove_group(&self, name: &str) -> bool;
+}
+
+impl WeechatHost for Weechat {
+    fn color(&self, name: &str) -> Cow<str> {
+        Weechat::color(self, name)
+    }
+
+    fn get_prefix(&self, prefix: &str) -> Cow<str> {
+        Weechat::get_prefix(self, prefix)
+    }
+
+    fn string_option(&self, name: &str) -> Option<String> {
+        self.get_string_option(name)
+            .map(|option| option.value().into_owned())
+    }
+
+    fn info_get(&self, name: &str, arguments: &str) -> Option<String> {
+        Weechat::info_get(self, name, arguments).map(Cow::into_owned)
+    }
+
+    fn update_bar_item(&self, name: &str) {
+        Weechat::update_bar_item(self, name)
+    }
+
+    fn plugin_print(&self, msg: &str) {
+        self.print(&format!("discord: {}", msg))
+    }
+}
+
+impl BufferSource for Weechat {
+    type Buffer = Buffer;
+
+    fn buffer_search(&self, name: &str) -> Option<Buffer> {
+        Weechat::buffer_search(self, "weecord", name)
+    }
+
+    fn buffer_new(&self, name: &str) -> Buffer {
+        Weechat::buffer_new::<(), ()>(
+            self,
+            name,
+            Some(|_, b, i| crate::hook::buffer_input(b, &i)),
+            None,
+            None,
+            None,
+        )
+    }
+}
+
+impl BufferHost for Buffer {
+    type Host = Weechat;
+
+    fn host(&self) -> Weechat {
+        self.get_weechat()
+    }
+
+    fn name(&self) -> Cow<str> {
+        self.get_name()
+    }
+
+    fn print(&self, msg: &str) {
+        Buffer::print(self, msg)
+    }
+
+    fn print_tags_dated(&self, date: i64, tags: &str, msg: &str) {
+        Buffer::print_tags_dated(self, date, tags, msg)
+    }
+
+    fn clear(&self) {
+        Buffer::clear(self)
+    }
+
+    fn get_localvar(&self, name: &str) -> Option<Cow<str>> {
+        Buffer::get_localvar(self, name)
+    }
+
+    fn set_localvar(&self, name: &str, value: &str) {
+        Buffer::set_localvar(self, name, value)
+    }
+
+    fn nicklist_add_group(&self, name: &str, color: &str) {
+        if self.search_nicklist_group(name).is_none() {
+            self.add_group(name, color, true, None);
+        }
+    }
+
+    fn nicklist_has_group(&self, name: &str) -> bool {
+        self.search_nicklist_group(name).is_some()
+    }
+
+    fn nicklist_add_nick(&self, group: Option<&str>, name: &str, color: &str, prefix: &str) {
+        let group = group.and_then(|group| self.search_nicklist_group(group));
+        self.add_nick(
+            NickArgs {
+                name,
+                color,
+                prefix,
+                ..Default::default()
+            },
+            group.as_ref(),
+        );
+    }
+
+    fn nicklist_remove_nick(&self, group: Option<&str>, name: &str) -> bool {
+        let group = match group.map(|group| self.search_nicklist_group(group)) {
+            Some(Some(group)) => Some(group),
+            Some(None) => return false,
+            None => None,
+        };
+        if let Some(nick) = self.search_nick(name, group.as_ref()) {
+            nick.remove();
+            true
+        } else {
+            false
+        }
+    }
+
+    fn nicklist_remove_group(&self, name: &str) -> bool {
+        if let Some(group) = self.search_nicklist_group(name) {
+            group.remove();
+            true
+        } else {
+            false
+        }
+    }
+}
+
+/// An in-memory host which records everything drawn to it
+#[cfg(test)]
+pub mod fake {
+    use super::{BufferHost, BufferSource, WeechatHost};
+    use std::{
+        borrow::Cow,
+        cell::RefCell,
+        collections::{BTreeMap, HashMap},
+        rc::Rc,
+    };
+
+    /// Colors render as `{name}` so tests can see where they were inserted
+    #[derive(Clone, Default)]
+    pub struct FakeHost {
+        pub options: Rc<RefCell<HashMap<String, String>>>,
+        pub updated_bar_items: Rc<RefCell<Vec<String>>>,
+        /// Messages printed to the core buffer
+        pub printed: Rc<RefCell<Vec<String>>>,
+        pub buffers: Rc<RefCell<HashMap<String, FakeBuffer>>>,
+    }
+
+    impl BufferSource for FakeHost {
+        type Buffer = FakeBuffer;
+
+        fn buffer_search(&self, name: &str) -> Option<FakeBuffer> {
+            self.buffers.borrow().get(name).cloned()
+        }
+
+        fn buffer_new(&self, name: &str) -> FakeBuffer {
+            let buffer = FakeBuffer {
+                host: self.clone(),
+                name: name.to_owned(),
+                ..FakeBuffer::default()
+            };
+            self.buffers
+                .borrow_mut()
+                .insert(name.to_owned(), buffer.clone());
+            buffer
+        }
+    }
+
+    impl WeechatHost for FakeHost {
+        fn color(&self, name: &str) -> Cow<str> {
+            format!("{{{}}}", name).into()
+        }
+
+        fn get_prefix(&self, prefix: &str) -> Cow<str> {
+            format!("[{}]", prefix).into()
+        }
+
+        fn string_option(&self, name: &str) -> Option<String> {
+            self.options.borrow().get(name).cloned()
+        }
+
+        fn info_get(&self, _name: &str, _arguments: &str) -> Option<String> {
+            None
+        }
+
+        fn update_bar_item(&self, name: &str) {
+            self.updated_bar_items.borrow_mut().push(name.to_owned());
+        }
+
+        fn plugin_print(&self, msg: &str) {
+            self.printed.borrow_mut().push(msg.to_owned());
+        }
+    }
+
+    #[derive(Clone, Debug, PartialEq)]
+    pub struct Line {
+        pub date: i64,
+        pub tags: String,
+        pub message: String,
+    }
+
+    /// Clones share their contents, like handles to the same WeeChat buffer
+    #[derive(Clone, Default)]
+    pub struct FakeBuffer {
+        pub host: FakeHost,
+        pub name: String,
+        pub lines: Rc<RefCell<Vec<Line>>>,
+        pub localvars: Rc<RefCell<HashMap<String, String>>>,
+        /// Group name to nicks, `None` is the nicklist root
+        pub nicklist: Rc<RefCell<BTreeMap<Option<String>, Vec<String>>>>,
+    }
+
+    impl FakeBuffer {
+        pub fn messages(&self) -> Vec<String> {
+            self.lines
+                .borrow()
+                .iter()
+                .map(|l| l.message.clone())
+                .collect()
+        }
+    }
+
+    impl BufferHost for FakeBuffer {
+        type Host = FakeHost;
+
+        fn host(&self) -> FakeHost {
+            self.host.clone()
+        }
+
+        fn name(&self) -> Cow<str> {
+            Cow::Borrowed(&self.name)
+        }
+
+        fn print(&self, msg: &str) {
+            self.print_tags_dated(0, "", msg)
+        }
+
+        fn print_tags_dated(&self, date: i64, tags: &str, msg: &str) {
+            self.lines.borrow_mut().push(Line {
+                date,
+                tags: tags.to_owned(),
+                message: msg.to_owned(),
+            });
+        }
+
+        fn clear(&self) {
+            self.lines.borrow_mut().clear();
+        }
+
+        fn get_localvar(&self, name: &str) -> Option<Cow<str>> {
+            self.localvars.borrow().get(name).cloned().map(Cow::Owned)
+        }
+
+        fn set_localvar(&self, name: &str, value: &str) {
+            self.localvars
+                .borrow_mut()
+                .insert(name.to_owned(), value.to_owned());
+        }
+
+        fn nicklist_add_group(&self, name: &str, _color: &str) {
+            self.nicklist
+                .borrow_mut()
+                .entry(Some(name.to_owned()))
+                .or_default();
+        }
+
+        fn nicklist_has_group(&self, name: &str) -> bool {
+            self.nicklist.borrow().contains_key(&Some(name.to_owned()))
+        }
+
+        fn nicklist_add_nick(&self, group: Option<&str>, name: &str, _color: &str, _prefix: &str) {
+            // Like WeeChat, nick names are unique across all groups
+            if self
+                .nicklist
+                .borrow()
+                .values()
+                .flatten()
+                .any(|nick| nick == name)
+            {
+                return;
+            }
+            self.nicklist
+                .borrow_mut()
+                .entry(group.map(ToOwned::to_owned))
+                .or_default()
+                .push(name.to_owned());
+        }
+
+        fn nicklist_remove_nick(&self, group: Option<&str>, name: &str) -> bool {
+            let mut nicklist = self.nicklist.borrow_mut();
+            let nicks = match nicklist.get_mut(&group.map(ToOwned::to_owned)) {
+                Some(nicks) => nicks,
+                None => return false,
+            };
+            match nicks.iter().position(|nick| nick == name) {
+                Some(index) => {
+                    nicks.remove(index);
+                    true
+                },
+                None => false,
+            }
+        }
+
+        fn nicklist_remove_group(&self, name: &str) -> bool {
+            self.nicklist
+                .borrow_mut()
+                .remove(&Some(name.to_owned()))
+                .is_some()
+        }
+    }
+}
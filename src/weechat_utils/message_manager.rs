@@ -1,39 +1,114 @@
-use crate::utils::BufferExt;
+use crate::{discord::notify::Notify, utils::BufferExt, weechat_utils::BufferHost};
 use serenity::{
     cache::CacheRwLock,
     model::{
         channel::Message,
-        id::{MessageId, UserId},
+        id::{MessageId, RoleId, UserId},
     },
 };
-use std::{cell::RefCell, ops::Deref, sync::Arc};
+use std::{
+    cell::RefCell,
+    collections::{HashMap, HashSet},
+    ops::Deref,
+    sync::Arc,
+};
 use weechat::Buffer;
 
+/// Reference tags run from `a0` to `z9` before being reused
+const REF_COUNT: usize = 26 * 10;
+
 /// MessageRenderer wraps a weechat buffer and facilitates editing the buffer and drawing the
 /// messages
-pub struct MessageManager {
-    buffer: Buffer,
+pub struct MessageManager<B: BufferHost = Buffer> {
+    buffer: B,
     messages: Arc<RefCell<Vec<Message>>>,
+    refs: RefCell<MessageRefs>,
+    /// Messages whose spoilers are shown
+    revealed: RefCell<HashSet<MessageId>>,
+    nicks: RefCell<ListedNicks>,
+}
+
+/// Where guild members are listed in the nicklist, so they can be found again after their name or
+/// roles change, and the group drawn for each hoisted role
+#[derive(Default)]
+struct ListedNicks {
+    /// Group (`None` for the root) and nick of each member
+    members: HashMap<UserId, (Option<String>, String)>,
+    role_groups: HashMap<RoleId, String>,
+}
+
+/// Short reference tags which stay attached to a message for as long as it is in the buffer, so
+/// it can be targeted even after newer messages arrive
+#[derive(Default)]
+struct MessageRefs {
+    next: usize,
+    by_id: HashMap<MessageId, String>,
+}
+
+impl MessageRefs {
+    /// Give a message the next tag, taking it from the message that held it before
+    fn assign(&mut self, id: MessageId) {
+        if self.by_id.contains_key(&id) {
+            return;
+        }
+
+        let tag = format!(
+            "{}{}",
+            (b'a' + (self.next / 10) as u8) as char,
+            self.next % 10
+        );
+        self.next = (self.next + 1) % REF_COUNT;
+        self.by_id.retain(|_, old| *old != tag);
+        self.by_id.insert(id, tag);
+    }
+
+    fn find(&self, tag: &str) -> Option<MessageId> {
+        self.by_id
+            .iter()
+            .find(|(_, it)| it.as_str() == tag)
+            .map(|(id, _)| *id)
+    }
 }
 
-impl MessageManager {
+impl<B: BufferHost> MessageManager<B> {
     /// Create a new MessageManager from a buffer
-    pub fn new(buffer: Buffer) -> MessageManager {
+    pub fn new(buffer: B) -> MessageManager<B> {
         MessageManager {
             buffer,
             messages: Arc::new(RefCell::new(Vec::new())),
+            refs: RefCell::new(MessageRefs::default()),
+            revealed: RefCell::new(HashSet::new()),
+            nicks: RefCell::new(ListedNicks::default()),
         }
     }
 
     /// Format and print message to the buffer
     fn print_msg(&self, cache: &CacheRwLock, msg: &Message, notify: bool) -> Vec<UserId> {
-        let weechat = self.buffer.get_weechat();
+        let weechat = self.buffer.host();
         let maybe_guild = self.buffer.guild_id();
-        let (prefix, content, unknown_users) =
-            formatting_utils::render_msg(cache, &weechat, msg, maybe_guild);
+        let reveal = self.revealed.borrow().contains(&msg.id);
+        let (prefix, mut content, unknown_users) =
+            formatting_utils::render_msg(cache, &weechat, msg, maybe_guild, reveal);
+        // Messages whose tag was reused print without one rather than taking a new tag, which
+        // would shift the tags of every message after them
+        let tag = self.refs.borrow().by_id.get(&msg.id).cloned();
+        let message_refs = weechat
+            .string_option("weecord.main.message_refs")
+            .as_deref()
+            == Some("on");
+        if let (true, Some(tag)) = (message_refs, tag) {
+            content = format!(
+                "{}[{}]{} {}",
+                weechat.color("8"),
+                tag,
+                weechat.color("reset"),
+                content
+            );
+        }
         self.buffer.print_tags_dated(
             msg.timestamp.timestamp(),
-            &formatting_utils::msg_tags(cache, msg, notify).join(","),
+            &formatting_utils::msg_tags(cache, msg, notify, Notify::from_buffer(&self.buffer))
+                .join(","),
             &format!("{}\t{}", prefix, content),
         );
         unknown_users
@@ -50,16 +125,172 @@ impl MessageManager {
     /// Removes all content from the buffer
     pub fn clear(&self) {
         self.messages.borrow_mut().clear();
+        *self.refs.borrow_mut() = MessageRefs::default();
+        self.revealed.borrow_mut().clear();
         self.buffer.clear();
     }
 
+    /// Add a member to the nicklist, removing them from where they were listed before
+    pub fn list_member(&self, user: UserId, group: Option<&str>, nick: &str, color: &str) {
+        self.unlist_member(user);
+        self.buffer.nicklist_add_nick(group, nick, color, "");
+        self.nicks
+            .borrow_mut()
+            .members
+            .insert(user, (group.map(ToOwned::to_owned), nick.to_owned()));
+    }
+
+    /// Whether a member other than `user` is listed under `nick`, nicks are unique in a buffer
+    pub fn nick_listed_by_other(&self, nick: &str, user: UserId) -> bool {
+        self.nicks
+            .borrow()
+            .members
+            .iter()
+            .any(|(listed, (_, listed_nick))| *listed != user && listed_nick == nick)
+    }
+
+    /// Remove a member from the nicklist, returning whether they were listed
+    pub fn unlist_member(&self, user: UserId) -> bool {
+        let listed = self.nicks.borrow_mut().members.remove(&user);
+        match listed {
+            Some((group, nick)) => self.buffer.nicklist_remove_nick(group.as_deref(), &nick),
+            None => false,
+        }
+    }
+
+    /// Create the group of a hoisted role, removing the group it had under another name
+    pub fn add_role_group(&self, role: RoleId, group: &str, color: &str) {
+        let old = self.nicks.borrow().role_groups.get(&role).cloned();
+        if let Some(old) = old.filter(|old| old != group) {
+            self.remove_group(&old);
+        }
+        self.buffer.nicklist_add_group(group, color);
+        self.nicks
+            .borrow_mut()
+            .role_groups
+            .insert(role, group.to_owned());
+    }
+
+    /// Remove the group of a role, returning the members that were listed in it
+    pub fn remove_role_group(&self, role: RoleId) -> Vec<UserId> {
+        let group = self.nicks.borrow_mut().role_groups.remove(&role);
+        match group {
+            Some(group) => self.remove_group(&group),
+            None => Vec::new(),
+        }
+    }
+
+    fn remove_group(&self, group: &str) -> Vec<UserId> {
+        self.buffer.nicklist_remove_group(group);
+        let mut nicks = self.nicks.borrow_mut();
+        let members: Vec<_> = nicks
+            .members
+            .iter()
+            .filter(|(_, (listed_group, _))| listed_group.as_deref() == Some(group))
+            .map(|(user, _)| *user)
+            .collect();
+        for user in &members {
+            nicks.members.remove(user);
+        }
+        members
+    }
+
+    /// Show the spoilers of a message, it is redrawn the next time it is replaced
+    pub fn reveal_spoilers(&self, id: MessageId) {
+        self.revealed.borrow_mut().insert(id);
+    }
+
+    /// Find a printed message by its reference tag
+    pub fn message_by_ref(&self, tag: &str) -> Option<Message> {
+        let id = self.refs.borrow().find(tag)?;
+        self.messages
+            .borrow()
+            .iter()
+            .find(|msg| msg.id == id)
+            .cloned()
+    }
+
+    /// The nth most recent printed message, counting from 1, optionally only counting messages
+    /// by one author
+    pub fn nth_message(&self, n: usize, author: Option<UserId>) -> Option<Message> {
+        self.messages
+            .borrow()
+            .iter()
+            .rev()
+            .filter(|msg| author.map_or(true, |author| msg.author.id == author))
+            .nth(n.checked_sub(1)?)
+            .cloned()
+    }
+
     /// Add a message to the end of a buffer (chronologically)
     pub fn add_message(&self, cache: &CacheRwLock, msg: &Message, notify: bool) -> Vec<UserId> {
+        self.refs.borrow_mut().assign(msg.id);
         let unknown_users = self.print_msg(cache, msg, notify);
         self.messages.borrow_mut().push(msg.clone());
         unknown_users
     }
 
+    /// The id of the newest message in the buffer
+    pub fn last_message_id(&self) -> Option<MessageId> {
+        self.messages.borrow().last().map(|msg| msg.id)
+    }
+
+    /// The id of the oldest message in the buffer
+    pub fn first_message_id(&self) -> Option<MessageId> {
+        self.messages.borrow().first().map(|msg| msg.id)
+    }
+
+    /// Add a page of messages older than every printed message to the start of the buffer,
+    /// `msgs` must be oldest first
+    pub fn prepend_messages(&self, cache: &CacheRwLock, msgs: &[Message]) {
+        let oldest = self.first_message_id();
+        let older: Vec<_> = msgs
+            .iter()
+            .filter(|msg| oldest.map(|oldest| msg.id < oldest).unwrap_or(true))
+            .cloned()
+            .collect();
+        if older.is_empty() {
+            return;
+        }
+
+        // Older messages are tagged after the ones already shown, newest first
+        for msg in older.iter().rev() {
+            self.refs.borrow_mut().assign(msg.id);
+        }
+        self.messages.borrow_mut().splice(0..0, older);
+        self.redraw_buffer(cache);
+    }
+
+    /// Add messages that may be older than the newest printed message, skipping any that are
+    /// already present and keeping the buffer in chronological order
+    pub fn merge_messages(&self, cache: &CacheRwLock, msgs: &[Message]) -> Vec<UserId> {
+        let mut unknown_users = Vec::new();
+        let mut needs_redraw = false;
+        for msg in msgs {
+            let position = {
+                let messages = self.messages.borrow();
+                if messages.iter().any(|it| it.id == msg.id) {
+                    continue;
+                }
+                messages.iter().position(|it| it.id > msg.id)
+            };
+
+            match position {
+                Some(index) => {
+                    self.refs.borrow_mut().assign(msg.id);
+                    self.messages.borrow_mut().insert(index, msg.clone());
+                    needs_redraw = true;
+                },
+                None => unknown_users.extend(self.add_message(cache, msg, false)),
+            }
+        }
+
+        if needs_redraw {
+            self.redraw_buffer(cache);
+        }
+        unknown_users
+    }
+
     // Overwrite a previously printed message, has no effect if the message does not exist
     pub fn replace_message(
         &self,
@@ -80,9 +311,10 @@ impl MessageManager {
         self.redraw_buffer(cache);
         let (_, _, unknown_users) = formatting_utils::render_msg(
             cache,
-            &self.buffer.get_weechat(),
+            &self.buffer.host(),
             msg,
             self.buffer.guild_id(),
+            false,
         );
         unknown_users
     }
@@ -93,11 +325,13 @@ impl MessageManager {
         let mut unknown_users = Vec::new();
         if let Some(index) = index {
             let msg = self.messages.borrow_mut().remove(index);
+            self.refs.borrow_mut().by_id.remove(id);
             unknown_users = formatting_utils::render_msg(
                 cache,
-                &self.buffer.get_weechat(),
+                &self.buffer.host(),
                 &msg,
                 self.buffer.guild_id(),
+                false,
             )
             .2;
         };
@@ -107,30 +341,44 @@ impl MessageManager {
     }
 }
 
-impl Deref for MessageManager {
-    type Target = Buffer;
+impl<B: BufferHost> Deref for MessageManager<B> {
+    type Target = B;
 
     fn deref(&self) -> &Self::Target {
         &self.buffer
     }
 }
 
-mod formatting_utils {
+pub mod formatting_utils {
     use crate::{
-        discord::formatting,
-        utils::{colorize_string, format_nick_color},
+        discord::{
+            formatting,
+            notify::{Notify, NotifyLevel},
+        },
+        utils::{colorize_string, format_nick_color, ChannelExt},
+        weechat_utils::{embed_renderer, WeechatHost},
     };
+    use lazy_static::lazy_static;
+    use regex::{Captures, Regex};
     use serenity::{
         cache::CacheRwLock,
         model::{
             channel::{Channel, Message},
-            id::{GuildId, UserId},
+            id::{ChannelId, GuildId, RoleId, UserId},
         },
     };
     use std::str::FromStr;
-    use weechat::{ConfigOption, Weechat};
 
-    pub fn msg_tags(cache: &CacheRwLock, msg: &Message, notify: bool) -> Vec<String> {
+    const REPLY_EXCERPT_LENGTH: usize = 50;
+
+    /// Tags of a message, `notify` is false for messages that should never notify such as our
+    /// own or those loaded from history
+    pub fn msg_tags(
+        cache: &CacheRwLock,
+        msg: &Message,
+        notify: bool,
+        settings: Notify,
+    ) -> Vec<String> {
         let is_private = if let Some(channel) = msg.channel(cache) {
             if let Channel::Private(_) = channel {
                 true
@@ -141,38 +389,87 @@ mod formatting_utils {
             false
         };
 
-        let self_mentioned = msg.mentions_user_id(cache.read().user.id);
+        // @here also sets `mention_everyone`
+        let everyone_mentioned = msg.mention_everyone && !settings.suppress_everyone;
+        let role_mentioned = !settings.suppress_roles && own_role_mentioned(cache, msg);
+        // What Discord counts as a mention, both for highlights and the "mentions only" level
+        let mentioned =
+            msg.mentions_user_id(cache.read().user.id) || everyone_mentioned || role_mentioned;
 
         let mut tags = Vec::new();
-        if notify {
-            if self_mentioned {
-                tags.push("notify_highlight");
-            } else if is_private {
-                tags.push("notify_private");
-            } else {
-                tags.push("notify_message");
-            };
-        } else {
-            tags.push("notify_none");
+        tags.push(match settings.level {
+            _ if !notify => "notify_none",
+            NotifyLevel::Nothing => "notify_none",
+            _ if mentioned => "notify_highlight",
+            _ if is_private => "notify_private",
+            NotifyLevel::Mentions => "notify_none",
+            NotifyLevel::All => "notify_message",
+        });
+        if role_mentioned {
+            tags.push("discord_mention_role");
+        }
+        if everyone_mentioned {
+            tags.push("discord_mention_everyone");
+        }
+        if is_action(msg) {
+            tags.push("irc_action");
         }
 
         tags.into_iter().map(ToString::to_string).collect()
     }
 
+    /// Whether the message mentions a role the current user has, the member is cached by
+    /// `event_handler::message` if the guild hadn't loaded it
+    fn own_role_mentioned(cache: &CacheRwLock, msg: &Message) -> bool {
+        let guild_id = match msg.guild_id {
+            Some(guild_id) if !msg.mention_roles.is_empty() => guild_id,
+            _ => return false,
+        };
+        let cache = cache.read();
+        let guild = match cache.guilds.get(&guild_id) {
+            Some(guild) => guild.read(),
+            None => return false,
+        };
+        guild
+            .members
+            .get(&cache.user.id)
+            .map(|member| {
+                member
+                    .roles
+                    .iter()
+                    .any(|role| msg.mention_roles.contains(role))
+            })
+            .unwrap_or(false)
+    }
+
+    /// Messages that are italic as a whole are shown as actions, the way `/me` is on IRC
+    fn is_action(msg: &Message) -> bool {
+        use serenity::model::channel::MessageType;
+        matches!(msg.kind, MessageType::Regular) && formatting::action_text(&msg.content).is_some()
+    }
+
     pub fn render_msg(
         cache: &CacheRwLock,
-        weechat: &Weechat,
+        weechat: &impl WeechatHost,
         msg: &Message,
         guild: Option<GuildId>,
+        reveal_spoilers: bool,
     ) -> (String, String, Vec<UserId>) {
-        let opts = serenity::utils::ContentSafeOptions::new()
-            .clean_here(false)
-            .clean_everyone(false)
-            .clean_user(false);
-
-        let mut msg_content = serenity::utils::content_safe(&cache, &msg.content, &opts);
-        msg_content = crate::utils::clean_emojis(&msg_content);
+        let reveal_spoilers = reveal_spoilers || crate::utils::spoilers_shown(weechat, guild);
+        // Mentions are left in place, the markdown renderer resolves roles and channels and
+        // users are replaced below
+        let emoji_urls = weechat
+            .string_option("weecord.main.custom_emoji")
+            .as_deref()
+            == Some("name_url");
+        let mut msg_content = crate::utils::clean_emojis(&msg.content, emoji_urls);
         let unknown_users = clean_users(cache, &mut msg_content, true, guild);
+        let action = is_action(msg);
+        if action {
+            if let Some(text) = formatting::action_text(&msg_content).map(ToOwned::to_owned) {
+                msg_content = text;
+            }
+        }
 
         if msg.edited_timestamp.is_some() {
             let edited_text = weechat.color("8").into_owned()
@@ -188,62 +485,22 @@ mod formatting_utils {
             msg_content.push_str(&attachement.proxy_url);
         }
 
+        // Embeds and reactions are already formatted, they are added after the markdown
+        let mut trailer = String::new();
         for embed in &msg.embeds {
-            if !msg_content.is_empty() {
-                msg_content.push('\n');
-            }
-            if let Some(ref author) = embed.author {
-                msg_content.push('▎');
-                msg_content.push_str(&format!(
-                    "{}{}{}",
-                    weechat.color("bold"),
-                    author.name,
-                    weechat.color("reset"),
-                ));
-                if let Some(url) = &author.url {
-                    msg_content.push_str(&format!(" ({})", url));
-                }
-                msg_content.push('\n');
-            }
-            if let Some(ref title) = embed.title {
-                msg_content.push_str(
-                    &title
-                        .lines()
-                        .fold(String::new(), |acc, x| format!("{}▎{}\n", acc, x)),
-                );
-                msg_content.push('\n');
-            }
-            if let Some(ref description) = embed.description {
-                msg_content.push_str(
-                    &description
-                        .lines()
-                        .fold(String::new(), |acc, x| format!("{}▎{}\n", acc, x)),
-                );
-                msg_content.push('\n');
-            }
-            for field in &embed.fields {
-                msg_content.push_str(&field.name);
-                msg_content.push_str(
-                    &field
-                        .value
-                        .lines()
-                        .fold(String::new(), |acc, x| format!("{}▎{}\n", acc, x)),
-                );
-                msg_content.push('\n');
-            }
-            if let Some(ref footer) = embed.footer {
-                msg_content.push_str(
-                    &footer
-                        .text
-                        .lines()
-                        .fold(String::new(), |acc, x| format!("{}▎{}\n", acc, x)),
-                );
-                msg_content.push('\n');
+            if !msg_content.is_empty() || !trailer.is_empty() {
+                trailer.push('\n');
             }
+            trailer.push_str(&embed_renderer::render_embed(
+                weechat,
+                cache,
+                embed,
+                reveal_spoilers,
+            ));
         }
 
-        if msg.reactions.len() > 0 {
-            msg_content.push('\n');
+        if !msg.reactions.is_empty() {
+            trailer.push('\n');
         }
 
         use serenity::model::channel::ReactionType;
@@ -254,46 +511,46 @@ mod formatting_utils {
                 _ => None,
             }
             .map(|reaction_string| {
-                msg_content.push_str(&format!("[{} {}] ", reaction_string, reaction.count).as_str())
+                trailer.push_str(&format!("[{} {}] ", reaction_string, reaction.count).as_str())
             });
         }
 
-        if msg.reactions.len() > 0 {
-            msg_content.push('\n');
+        if !msg.reactions.is_empty() {
+            trailer.push('\n');
         }
 
         let mut prefix = String::new();
 
-        if let Some(nick_prefix) = weechat.get_string_option("weechat.look.nick_prefix") {
-            if let Some(color) = weechat.get_string_option("weechat.color.chat_nick_prefix") {
-                prefix.push_str(&colorize_string(
-                    weechat,
-                    &color.value(),
-                    &nick_prefix.value(),
-                ))
+        if let Some(nick_prefix) = weechat.string_option("weechat.look.nick_prefix") {
+            if let Some(color) = weechat.string_option("weechat.color.chat_nick_prefix") {
+                prefix.push_str(&colorize_string(weechat, &color, &nick_prefix))
             }
         }
 
         let author = format_nick_color(weechat, &author_display_name(cache, &msg, guild));
         prefix.push_str(&author);
 
-        if let Some(nick_suffix) = weechat.get_string_option("weechat.look.nick_suffix") {
-            if let Some(color) = weechat.get_string_option("weechat.color.chat_nick_suffix") {
-                prefix.push_str(&colorize_string(
-                    weechat,
-                    &color.value(),
-                    &nick_suffix.value(),
-                ))
+        if let Some(nick_suffix) = weechat.string_option("weechat.look.nick_suffix") {
+            if let Some(color) = weechat.string_option("weechat.color.chat_nick_suffix") {
+                prefix.push_str(&colorize_string(weechat, &color, &nick_suffix))
             }
         }
 
         use serenity::model::channel::MessageType::*;
         if let Regular = msg.kind {
-            (
-                prefix,
-                formatting::discord_to_weechat(weechat, &msg_content),
-                unknown_users,
-            )
+            let mut content =
+                formatting::discord_to_weechat(weechat, cache, &msg_content, reveal_spoilers);
+            content.push_str(&trailer);
+            let prefix = if action {
+                content = format!("{} {}", author, content);
+                weechat.get_prefix("action").into_owned()
+            } else {
+                prefix
+            };
+            if let Some(header) = render_reply_header(cache, weechat, msg, guild, reveal_spoilers) {
+                content = header + &content;
+            }
+            (prefix, content, unknown_users)
         } else {
             let (prefix, body) = match msg.kind {
                 GroupRecipientAddition | MemberJoin => {
@@ -332,6 +589,49 @@ mod formatting_utils {
         }
     }
 
+    /// Render a single line quoting the author and a short excerpt of the message being replied to
+    fn render_reply_header(
+        cache: &CacheRwLock,
+        weechat: &impl WeechatHost,
+        msg: &Message,
+        guild: Option<GuildId>,
+        reveal_spoilers: bool,
+    ) -> Option<String> {
+        let parent = msg.referenced_message.as_ref()?;
+
+        let author = format_nick_color(weechat, &author_display_name(cache, parent, guild));
+        // Mentions are resolved before the excerpt is shortened, so none is left half written
+        let mut content = crate::utils::clean_emojis(&parent.content, false);
+        clean_users(cache, &mut content, false, guild);
+        clean_roles(cache, &mut content);
+        clean_channels(cache, &mut content);
+        let mut excerpt = content.lines().collect::<Vec<_>>().join(" ");
+        if excerpt.is_empty() && !parent.attachments.is_empty() {
+            excerpt = "[attachment]".to_owned();
+        }
+        if !reveal_spoilers {
+            excerpt = formatting::mask_spoilers(&excerpt);
+        }
+
+        Some(format!(
+            "{}┌ {}{}: {}{}\n",
+            weechat.color("8"),
+            author,
+            weechat.color("8"),
+            shorten(&excerpt, REPLY_EXCERPT_LENGTH),
+            weechat.color("reset"),
+        ))
+    }
+
+    /// Truncate a string to at most `max` characters, adding an ellipsis if anything was removed
+    fn shorten(s: &str, max: usize) -> String {
+        if s.chars().count() > max {
+            s.chars().take(max - 1).chain(Some('…')).collect()
+        } else {
+            s.to_owned()
+        }
+    }
+
     pub fn author_display_name(
         cache: &CacheRwLock,
         msg: &Message,
@@ -360,8 +660,20 @@ mod formatting_utils {
         while let Some(mut mention_start) = s[progress..].find("<@") {
             mention_start += progress;
 
+            // An escaped mention is shown as written
+            let backslashes = s[..mention_start]
+                .chars()
+                .rev()
+                .take_while(|&c| c == '\\')
+                .count();
+            if backslashes % 2 == 1 {
+                progress = mention_start + "<@".len();
+                continue;
+            }
+
             if let Some(mut mention_end) = s[mention_start..].find('>') {
                 mention_end += mention_start;
+                let mention = mention_start..mention_end + 1;
                 mention_start += "<@".len();
 
                 let has_exclamation = if s[mention_start..]
@@ -410,11 +722,8 @@ mod formatting_utils {
                         }
                     };
 
-                    let code_start = if has_exclamation { "<@!" } else { "<@" };
-                    let to_replace = format!("{}{}>", code_start, &s[mention_start..mention_end]);
-
                     if let Some(replacement) = replacement {
-                        *s = s.replace(&to_replace, &replacement);
+                        s.replace_range(mention, &replacement);
                     } else {
                         progress = mention_end;
                     }
@@ -436,4 +745,439 @@ mod formatting_utils {
         }
         unknown_users
     }
+
+    /// Replace role mentions with the role name, the reply header is not rendered as markdown
+    fn clean_roles(cache: &CacheRwLock, s: &mut String) {
+        lazy_static! {
+            static ref ROLE_MENTION: Regex = Regex::new(r"<@&(\d+)>").unwrap();
+        }
+        *s = ROLE_MENTION
+            .replace_all(s, |caps: &Captures| {
+                let id = caps[1].parse().unwrap_or_default();
+                RoleId(id)
+                    .to_role_cached(cache)
+                    .map(|role| format!("@{}", role.name))
+                    .unwrap_or_else(|| format!("@&{}", id))
+            })
+            .into_owned();
+    }
+
+    /// Replace channel mentions with the channel name
+    fn clean_channels(cache: &CacheRwLock, s: &mut String) {
+        lazy_static! {
+            static ref CHANNEL_MENTION: Regex = Regex::new(r"<#(\d+)>").unwrap();
+        }
+        *s = CHANNEL_MENTION
+            .replace_all(s, |caps: &Captures| {
+                let id = caps[1].parse().unwrap_or_default();
+                ChannelId(id)
+                    .to_channel_cached(cache)
+                    .map(|channel| format!("#{}", channel.name()))
+                    .unwrap_or_else(|| format!("#{}", id))
+            })
+            .into_owned();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::MessageManager;
+    use crate::weechat_utils::{host::fake::FakeBuffer, BufferHost};
+    use serenity::{
+        cache::CacheRwLock,
+        model::{
+            channel::Message,
+            id::{MessageId, RoleId, UserId},
+            user::User,
+        },
+        prelude::RwLock,
+    };
+    use std::sync::Arc;
+
+    fn message(id: u64, content: &str) -> Message {
+        serde_json::from_value(message_json(id, content)).unwrap()
+    }
+
+    fn message_json(id: u64, content: &str) -> serde_json::Value {
+        serde_json::json!({
+            "id": id.to_string(),
+            "channel_id": "300",
+            "author": {
+                "id": "101",
+                "username": "alice",
+                "discriminator": "0001",
+                "avatar": null,
+            },
+            "content": content,
+            "timestamp": "2020-01-01T00:00:00.000000+00:00",
+            "edited_timestamp": null,
+            "tts": false,
+            "mention_everyone": false,
+            "mentions": [],
+            "mention_roles": [],
+            "attachments": [],
+            "embeds": [],
+            "reactions": [],
+            "pinned": false,
+            "type": 0,
+        })
+    }
+
+    #[test]
+    fn prints_prefix_and_content() {
+        let cache = CacheRwLock::default();
+        let manager = MessageManager::new(FakeBuffer::default());
+
+        manager.add_message(&cache, &message(1, "hello"), true);
+
+        let lines = manager.lines.borrow();
+        assert_eq!(lines.len(), 1);
+        assert_eq!(lines[0].message, "{reset}alice{reset}\thello");
+        assert_eq!(lines[0].tags, "notify_message");
+        assert_eq!(lines[0].date, 1_577_836_800);
+    }
+
+    #[test]
+    fn replies_show_the_referenced_message() {
+        let cache = CacheRwLock::default();
+        let manager = MessageManager::new(FakeBuffer::default());
+        // The v6 gateway sends replies as regular messages with the parent attached
+        let mut payload = message_json(2, "agreed");
+        payload["message_reference"] = serde_json::json!({
+            "channel_id": "300",
+            "message_id": "1",
+        });
+        payload["referenced_message"] = message_json(1, "first\nline");
+        let msg: Message = serde_json::from_value(payload).unwrap();
+        assert_eq!(
+            msg.referenced_message.as_ref().map(|parent| parent.id),
+            Some(MessageId(1))
+        );
+
+        manager.add_message(&cache, &msg, true);
+
+        assert_eq!(
+            manager.lines.borrow()[0].message,
+            "{reset}alice{reset}\t{8}┌ {reset}alice{reset}{8}: first line{reset}\nagreed"
+        );
+    }
+
+    #[test]
+    fn reply_headers_resolve_mentions_before_shortening() {
+        let cache = CacheRwLock::default();
+        let user: User = serde_json::from_value(serde_json::json!({
+            "id": "5",
+            "username": "bob",
+            "discriminator": "0001",
+            "avatar": null,
+        }))
+        .unwrap();
+        cache
+            .write()
+            .users
+            .insert(UserId(5), Arc::new(RwLock::new(user)));
+        let manager = MessageManager::new(FakeBuffer::default());
+        let mut payload = message_json(2, "agreed");
+        payload["referenced_message"] =
+            message_json(1, "thanks <@5>, ask <@&7> in <#8> about the release <@5>");
+        let msg: Message = serde_json::from_value(payload).unwrap();
+
+        manager.add_message(&cache, &msg, true);
+
+        assert_eq!(
+            manager.lines.borrow()[0].message,
+            "{reset}alice{reset}\t{8}┌ {reset}alice{reset}{8}: \
+             thanks @bob, ask @&7 in #8 about the release @bob{reset}\nagreed"
+        );
+    }
+
+    #[test]
+    fn escaped_user_mentions_are_not_replaced() {
+        let cache = CacheRwLock::default();
+        let user: User = serde_json::from_value(serde_json::json!({
+            "id": "5",
+            "username": "bob",
+            "discriminator": "0001",
+            "avatar": null,
+        }))
+        .unwrap();
+        cache
+            .write()
+            .users
+            .insert(UserId(5), Arc::new(RwLock::new(user)));
+        let manager = MessageManager::new(FakeBuffer::default());
+
+        manager.add_message(&cache, &message(1, r"<@5> \<@5>"), true);
+
+        assert_eq!(
+            manager.lines.borrow()[0].message,
+            "{reset}alice{reset}\t@bob#0001 <@5>"
+        );
+    }
+
+    #[test]
+    fn embeds_are_added_after_the_content_is_formatted() {
+        let cache = CacheRwLock::default();
+        let manager = MessageManager::new(FakeBuffer::default());
+        let mut msg = message(1, "*see* below");
+        msg.embeds.push(
+            serde_json::from_value(serde_json::json!({
+                "type": "rich",
+                "color": 0,
+                "description": "**hi**",
+            }))
+            .unwrap(),
+        );
+
+        manager.add_message(&cache, &msg, true);
+
+        assert_eq!(
+            manager.lines.borrow()[0].message,
+            "{reset}alice{reset}\t{italic}see{-italic} below\n▎{bold}hi{-bold}"
+        );
+    }
+
+    #[test]
+    fn renders_markdown_with_host_colors() {
+        let cache = CacheRwLock::default();
+        let manager = MessageManager::new(FakeBuffer::default());
+
+        manager.add_message(&cache, &message(1, "**bold**"), false);
+
+        assert_eq!(
+            manager.messages(),
+            vec!["{reset}alice{reset}\t{bold}bold{-bold}"]
+        );
+        assert_eq!(manager.lines.borrow()[0].tags, "notify_none");
+    }
+
+    #[test]
+    fn replace_and_delete_redraw_buffer() {
+        let cache = CacheRwLock::default();
+        let manager = MessageManager::new(FakeBuffer::default());
+
+        manager.add_message(&cache, &message(1, "one"), false);
+        manager.add_message(&cache, &message(2, "two"), false);
+        manager.replace_message(&cache, &1.into(), &message(1, "uno"));
+        assert_eq!(
+            manager.messages(),
+            vec!["{reset}alice{reset}\tuno", "{reset}alice{reset}\ttwo"]
+        );
+
+        manager.delete_message(&cache, &1.into());
+        assert_eq!(manager.messages(), vec!["{reset}alice{reset}\ttwo"]);
+    }
+
+    #[test]
+    fn message_refs_are_stable() {
+        let cache = CacheRwLock::default();
+        let manager = MessageManager::new(FakeBuffer::default());
+        manager
+            .host
+            .options
+            .borrow_mut()
+            .insert("weecord.main.message_refs".into(), "on".into());
+
+        manager.add_message(&cache, &message(2, "two"), false);
+        manager.add_message(&cache, &message(3, "three"), false);
+        manager.prepend_messages(&cache, &[message(1, "one")]);
+
+        assert_eq!(
+            manager.messages(),
+            vec![
+                "{reset}alice{reset}\t{8}[a2]{reset} one",
+                "{reset}alice{reset}\t{8}[a0]{reset} two",
+                "{reset}alice{reset}\t{8}[a1]{reset} three"
+            ]
+        );
+        assert_eq!(manager.message_by_ref("a0").unwrap().content, "two");
+        assert_eq!(manager.nth_message(1, None).unwrap().content, "three");
+        assert_eq!(manager.nth_message(3, None).unwrap().content, "one");
+        assert!(manager.nth_message(4, None).is_none());
+
+        manager.delete_message(&cache, &2.into());
+        assert!(manager.message_by_ref("a0").is_none());
+        assert_eq!(manager.message_by_ref("a1").unwrap().content, "three");
+    }
+
+    #[test]
+    fn reused_refs_do_not_shift_on_redraw() {
+        let cache = CacheRwLock::default();
+        let manager = MessageManager::new(FakeBuffer::default());
+        manager
+            .host
+            .options
+            .borrow_mut()
+            .insert("weecord.main.message_refs".into(), "on".into());
+
+        for id in 1..=262 {
+            manager.add_message(&cache, &message(id, "msg"), false);
+        }
+        assert_eq!(manager.message_by_ref("a0").unwrap().id, MessageId(261));
+        assert_eq!(manager.message_by_ref("a1").unwrap().id, MessageId(262));
+        assert_eq!(manager.message_by_ref("a2").unwrap().id, MessageId(3));
+
+        manager.replace_message(&cache, &262.into(), &message(262, "edited"));
+        let messages = manager.messages();
+        assert_eq!(messages.len(), 262);
+        assert_eq!(messages[0], "{reset}alice{reset}\tmsg");
+        assert_eq!(messages[1], "{reset}alice{reset}\tmsg");
+        assert_eq!(messages[2], "{reset}alice{reset}\t{8}[a2]{reset} msg");
+        assert_eq!(messages[259], "{reset}alice{reset}\t{8}[z9]{reset} msg");
+        assert_eq!(messages[260], "{reset}alice{reset}\t{8}[a0]{reset} msg");
+        assert_eq!(messages[261], "{reset}alice{reset}\t{8}[a1]{reset} edited");
+        assert_eq!(manager.message_by_ref("a2").unwrap().id, MessageId(3));
+    }
+
+    #[test]
+    fn spoilers_are_hidden_until_revealed() {
+        let cache = CacheRwLock::default();
+        let manager = MessageManager::new(FakeBuffer::default());
+
+        manager.add_message(&cache, &message(1, "it was ||him||"), false);
+        assert_eq!(
+            manager.messages(),
+            vec!["{reset}alice{reset}\tit was {8}||███||{resetcolor}"]
+        );
+
+        manager.reveal_spoilers(1.into());
+        manager.replace_message(&cache, &1.into(), &message(1, "it was ||him||"));
+        assert_eq!(
+            manager.messages(),
+            vec!["{reset}alice{reset}\tit was {italic}||him||{-italic}"]
+        );
+    }
+
+    #[test]
+    fn italic_messages_are_actions() {
+        let cache = CacheRwLock::default();
+        let manager = MessageManager::new(FakeBuffer::default());
+
+        manager.add_message(&cache, &message(1, "_waves **hard**_"), true);
+        manager.add_message(&cache, &message(2, "_a_ and _b_"), true);
+
+        let lines = manager.lines.borrow();
+        assert_eq!(
+            lines[0].message,
+            "[action]\t{reset}alice{reset} waves {bold}hard{-bold}"
+        );
+        assert_eq!(lines[0].tags, "notify_message,irc_action");
+        assert_eq!(
+            lines[1].message,
+            "{reset}alice{reset}\t{italic}a{-italic} and {italic}b{-italic}"
+        );
+        assert_eq!(lines[1].tags, "notify_message");
+    }
+
+    #[test]
+    fn notify_levels_set_the_notify_tag() {
+        let cache = CacheRwLock::default();
+        let manager = MessageManager::new(FakeBuffer::default());
+
+        manager.set_localvar("notify_level", "mentions");
+        manager.add_message(&cache, &message(1, "hello"), true);
+        let mut everyone = message(2, "@everyone hello");
+        everyone.mention_everyone = true;
+        manager.add_message(&cache, &everyone, true);
+        manager.set_localvar("suppress_everyone", "1");
+        manager.add_message(&cache, &everyone, true);
+        manager.set_localvar("notify_level", "none");
+        manager.add_message(&cache, &message(3, "hello"), true);
+
+        let tags: Vec<_> = manager
+            .lines
+            .borrow()
+            .iter()
+            .map(|line| line.tags.clone())
+            .collect();
+        assert_eq!(
+            tags,
+            vec![
+                "notify_none",
+                "notify_highlight,discord_mention_everyone",
+                "notify_none",
+                "notify_none"
+            ]
+        );
+    }
+
+    #[test]
+    fn everyone_mentions_highlight_unless_suppressed() {
+        let cache = CacheRwLock::default();
+        let manager = MessageManager::new(FakeBuffer::default());
+        let mut everyone = message(1, "@here hello");
+        everyone.mention_everyone = true;
+
+        manager.add_message(&cache, &everyone, true);
+        manager.add_message(&cache, &everyone, false);
+        manager.set_localvar("suppress_everyone", "1");
+        manager.add_message(&cache, &everyone, true);
+
+        let tags: Vec<_> = manager
+            .lines
+            .borrow()
+            .iter()
+            .map(|line| line.tags.clone())
+            .collect();
+        assert_eq!(
+            tags,
+            vec![
+                "notify_highlight,discord_mention_everyone",
+                "notify_none,discord_mention_everyone",
+                "notify_message"
+            ]
+        );
+    }
+
+    #[test]
+    fn prepend_keeps_chronological_order() {
+        let cache = CacheRwLock::default();
+        let manager = MessageManager::new(FakeBuffer::default());
+
+        manager.add_message(&cache, &message(3, "three"), false);
+        manager.prepend_messages(&cache, &[message(1, "one"), message(2, "two")]);
+        manager.prepend_messages(&cache, &[message(2, "two"), message(3, "three")]);
+
+        assert_eq!(manager.first_message_id(), Some(1.into()));
+        assert_eq!(
+            manager.messages(),
+            vec![
+                "{reset}alice{reset}\tone",
+                "{reset}alice{reset}\ttwo",
+                "{reset}alice{reset}\tthree"
+            ]
+        );
+    }
+
+    #[test]
+    fn renamed_role_groups_replace_the_old_group() {
+        let manager = MessageManager::new(FakeBuffer::default());
+
+        manager.add_role_group(RoleId(1), "1|Mods", "red");
+        manager.list_member(UserId(101), Some("1|Mods"), "alice", "");
+        manager.add_role_group(RoleId(1), "1|Moderators", "red");
+        manager.list_member(UserId(101), Some("1|Moderators"), "alice", "");
+
+        let nicklist = manager.nicklist.borrow();
+        assert!(!nicklist.contains_key(&Some("1|Mods".to_owned())));
+        assert_eq!(
+            nicklist.get(&Some("1|Moderators".to_owned())),
+            Some(&vec!["alice".to_owned()])
+        );
+    }
+
+    #[test]
+    fn removing_a_role_group_returns_its_members() {
+        let manager = MessageManager::new(FakeBuffer::default());
+
+        manager.add_role_group(RoleId(1), "1|Mods", "red");
+        manager.list_member(UserId(101), Some("1|Mods"), "alice", "");
+        manager.list_member(UserId(102), None, "bob", "");
+
+        assert_eq!(manager.remove_role_group(RoleId(1)), vec![UserId(101)]);
+        assert!(manager.remove_role_group(RoleId(1)).is_empty());
+        assert!(!manager.nicklist_has_group("1|Mods"));
+        assert!(!manager.unlist_member(UserId(101)));
+        assert!(manager.unlist_member(UserId(102)));
+    }
 }
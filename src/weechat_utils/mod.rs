@@ -1,4 +1,7 @@
 mod buffer_manager;
+mod embed_renderer;
 pub use buffer_manager::BufferManager;
+pub mod host;
+pub use host::{BufferHost, WeechatHost};
 mod message_manager;
-pub use message_manager::MessageManager;
+pub use message_manager::{formatting_utils, MessageManager};